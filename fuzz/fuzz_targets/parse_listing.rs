@@ -0,0 +1,19 @@
+//! Parses arbitrary directory listings, and checks that whatever parses survives a round trip.
+
+#![no_main]
+
+use cz2020_protocol::{parse_listing, write_listing};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(listing) = parse_listing(data) {
+        let mut written = Vec::new();
+        write_listing(
+            &mut written,
+            listing
+                .as_ref()
+                .map(|(requested, entries)| (requested.as_str(), entries.as_slice())),
+        );
+        assert_eq!(parse_listing(&written), Ok(listing));
+    }
+});
@@ -0,0 +1,26 @@
+//! Feeds arbitrary bytes to the parser of responses, in pieces like they arrive over USB. Parsing
+//! may reject the input, but must never panic, and always has to make progress.
+
+#![no_main]
+
+use buf_redux::Buffer;
+use cz2020_usbtool::cmds::Response;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = Buffer::new();
+    // The first byte decides how the rest is split up, to find bugs with partial frames
+    let (piece, data) = match data.split_first() {
+        Some((piece, data)) => (*piece as usize + 1, data),
+        None => return,
+    };
+
+    for chunk in data.chunks(piece) {
+        input.push_bytes(chunk);
+        let mut left = input.len();
+        while Response::try_read(&mut input).is_some() {
+            assert!(input.len() < left, "try_read didn't consume a frame");
+            left = input.len();
+        }
+    }
+});
@@ -0,0 +1,55 @@
+/* The C interface of cz2020-usbtool, built with `cargo build --release --features capi`.
+ *
+ * Functions that can fail return 0 on success and -1 on failure, or NULL for cz_connect.
+ * cz_last_error then describes what went wrong. Every call blocks until the badge answered. */
+
+#ifndef CZ2020_USBTOOL_H
+#define CZ2020_USBTOOL_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct CzBadge CzBadge;
+
+/* Called with each entry of cz_list. kind is 'f' for files, 'd' for directories, and the type
+ * character that the firmware sent for other entries. */
+typedef void (*CzListCallback)(char kind, const char *name, void *user);
+
+/* Called with each piece of output of the badge, on a separate thread */
+typedef void (*CzLogCallback)(const uint8_t *data, size_t len, void *user);
+
+/* The message of the last error on this thread, or NULL. Valid until the next call on this
+ * thread. */
+const char *cz_last_error(void);
+
+/* Connects to the badge with the given serial number or <bus>-<address>, or to the first badge
+ * that is found if device is NULL */
+CzBadge *cz_connect(const char *device);
+
+/* Closes the connection and frees badge, after waiting for running log callbacks */
+void cz_close(CzBadge *badge);
+
+int cz_list(const CzBadge *badge, const char *dir, CzListCallback callback, void *user);
+
+/* On success, *data and *len hold the contents of the file. Free them with cz_free. */
+int cz_read(const CzBadge *badge, const char *file, uint8_t **data, size_t *len);
+void cz_free(uint8_t *data, size_t len);
+
+/* Replaces the file if it exists */
+int cz_write(const CzBadge *badge, const char *file, const uint8_t *data, size_t len);
+
+/* Fails if the app crashes while starting */
+int cz_run(const CzBadge *badge, const char *app);
+
+/* Calls callback with the output of the badge until the connection is closed */
+int cz_subscribe_logs(const CzBadge *badge, CzLogCallback callback, void *user);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,313 @@
+//! The wire format of the USB protocol of the CampZone 2020 badge, without any I/O, so the command
+//! line tool and a WebUSB frontend in the browser can share it.
+//!
+//! Every message, in both directions, is a 12 byte header followed by the payload:
+//!
+//! | Bytes | Field |
+//! |-------|-------|
+//! | 0..2  | Command, little endian |
+//! | 2..6  | Length of the payload, little endian |
+//! | 6..8  | `de ad` |
+//! | 8..12 | Message id, little endian. Responses use the id of the request. |
+//!
+//! Strings in payloads are terminated by a NUL byte, and text the badge sends has one char per
+//! byte.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::convert::TryInto;
+
+pub const HEADER_LEN: usize = 12;
+pub const MAGIC: [u8; 2] = [0xde, 0xad];
+
+/// The command field of each kind of message
+pub mod command {
+    /// Runs a file, given without the /flash prefix
+    pub const RUN_FILE: u16 = 0;
+    pub const HEARTBEAT: u16 = 1;
+    pub const SERIAL_IN: u16 = 2;
+    /// Output of the badge. Sent by the badge with message id 0.
+    pub const LOG: u16 = 3;
+    pub const FETCH_DIR: u16 = 4096;
+    pub const FETCH_FILE: u16 = 4097;
+    /// Creates or replaces a file. The path is followed by the contents.
+    pub const WRITE_FILE: u16 = 4098;
+    pub const DELETE_PATH: u16 = 4099;
+    pub const COPY_FILE: u16 = 4100;
+    pub const MOVE_FILE: u16 = 4101;
+    pub const CREATE_DIR: u16 = 4102;
+}
+
+/// The payload of the heartbeat
+pub const HEARTBEAT_PAYLOAD: &[u8] = b"beat\0";
+
+/// The response to a command that succeeded
+const OK: &[u8] = b"ok\0";
+
+const DIRECTORY_NOT_FOUND: &[u8] = b"Directory_not_found";
+
+/// Appends a header to `out`. The payload has to follow.
+pub fn write_header(out: &mut Vec<u8>, command: u16, payload_len: u32, message_id: u32) {
+    out.extend_from_slice(&command.to_le_bytes());
+    out.extend_from_slice(&payload_len.to_le_bytes());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&message_id.to_le_bytes());
+}
+
+/// Appends `s` and its terminating NUL to `out`
+pub fn write_null_terminated(out: &mut Vec<u8>, s: &str) {
+    assert!(
+        !s.contains('\0'),
+        "Strings sent to the badge can't contain NUL"
+    );
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+}
+
+/// Bytes as the badge sends them, one char per byte
+pub fn latin1(data: &[u8]) -> String {
+    data.iter().map(|b| *b as char).collect()
+}
+
+/// Appends `text` to `out` with one byte per char, the reverse of `latin1`
+pub fn write_latin1(out: &mut Vec<u8>, text: &str) {
+    out.extend(text.chars().map(|c| c as u8));
+}
+
+/// A complete message in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub command: u16,
+    pub message_id: u32,
+    pub payload: &'a [u8],
+}
+
+impl Frame<'_> {
+    /// The number of bytes of the input the frame takes up, including the header
+    pub fn size(&self) -> usize {
+        HEADER_LEN + self.payload.len()
+    }
+}
+
+/// What `next_frame` found at the start of the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next<'a> {
+    /// The first byte doesn't start a header, so it should be dropped
+    Garbage,
+    /// The input ends before the message does. `needed` is the total length, if the header is
+    /// complete.
+    Incomplete {
+        needed: Option<usize>,
+    },
+    Frame(Frame<'a>),
+}
+
+/// Looks for a message at the start of `input`. After a frame, drop `frame.size()` bytes from
+/// the input; after garbage, drop one byte and try again.
+pub fn next_frame(input: &[u8]) -> Next<'_> {
+    if input.len() < HEADER_LEN {
+        return Next::Incomplete { needed: None };
+    }
+    if input[6..8] != MAGIC {
+        return Next::Garbage;
+    }
+
+    let len = u32::from_le_bytes(input[2..6].try_into().unwrap()) as usize;
+    // Subtracting, as adding could overflow with a garbage length on 32 bit targets
+    if input.len() - HEADER_LEN < len {
+        return Next::Incomplete {
+            needed: Some(HEADER_LEN.saturating_add(len)),
+        };
+    }
+
+    Next::Frame(Frame {
+        command: u16::from_le_bytes(input[0..2].try_into().unwrap()),
+        message_id: u32::from_le_bytes(input[8..12].try_into().unwrap()),
+        payload: &input[HEADER_LEN..HEADER_LEN + len],
+    })
+}
+
+/// Whether the response to a command that doesn't return data reports success
+pub fn is_ok(payload: &[u8]) -> bool {
+    payload == OK
+}
+
+/// Appends the response to a command that doesn't return data
+pub fn write_status(out: &mut Vec<u8>, ok: bool) {
+    out.extend_from_slice(if ok { OK } else { b"error\0" });
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsEntry {
+    File(String),
+    Directory(String),
+    /// An entry of a type this crate doesn't know, like a symlink or mount point that newer
+    /// firmware might list, with the character that the firmware marked its type with
+    Other(char, String),
+}
+
+impl FsEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            FsEntry::File(name) | FsEntry::Directory(name) | FsEntry::Other(_, name) => name,
+        }
+    }
+}
+
+/// Parses the response to `FETCH_DIR` into the requested path and its entries, or `None` if the
+/// directory doesn't exist. Empty lines, like after a trailing newline, are skipped.
+pub fn parse_listing(payload: &[u8]) -> Option<(String, Vec<FsEntry>)> {
+    if payload == DIRECTORY_NOT_FOUND {
+        return None;
+    }
+
+    let text = latin1(payload);
+    let mut lines = text.split('\n');
+    let requested = lines.next().unwrap_or_default().to_owned();
+    let entries = lines
+        .filter_map(|line| match line.chars().next() {
+            Some('f') => Some(FsEntry::File(line[1..].to_owned())),
+            Some('d') => Some(FsEntry::Directory(line[1..].to_owned())),
+            Some(kind) => Some(FsEntry::Other(kind, line[kind.len_utf8()..].to_owned())),
+            None => None,
+        })
+        .collect();
+
+    Some((requested, entries))
+}
+
+/// Appends the response to `FETCH_DIR`, the reverse of `parse_listing`
+pub fn write_listing(out: &mut Vec<u8>, listing: Option<(&str, &[FsEntry])>) {
+    let (requested, entries) = match listing {
+        Some(listing) => listing,
+        None => {
+            out.extend_from_slice(DIRECTORY_NOT_FOUND);
+            return;
+        }
+    };
+
+    write_latin1(out, requested);
+    for entry in entries {
+        out.push(b'\n');
+        out.push(match entry {
+            FsEntry::File(_) => b'f',
+            FsEntry::Directory(_) => b'd',
+            FsEntry::Other(kind, _) => *kind as u8,
+        });
+        write_latin1(out, entry.name());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn message(command: u16, message_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_header(&mut out, command, payload.len() as u32, message_id);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[test]
+    fn frame() {
+        let mut input = message(command::FETCH_FILE, 7, b"data");
+        input.extend_from_slice(b"next");
+
+        let frame = match next_frame(&input) {
+            Next::Frame(frame) => frame,
+            next => panic!("{:?}", next),
+        };
+        assert_eq!(frame.command, command::FETCH_FILE);
+        assert_eq!(frame.message_id, 7);
+        assert_eq!(frame.payload, b"data");
+        assert_eq!(frame.size(), HEADER_LEN + 4);
+    }
+
+    #[test]
+    fn frame_without_payload() {
+        let input = message(command::HEARTBEAT, 1, b"");
+        assert!(matches!(next_frame(&input), Next::Frame(frame) if frame.payload.is_empty()));
+    }
+
+    #[test]
+    fn incomplete_frames() {
+        let input = message(command::LOG, 0, b"hello");
+        assert_eq!(next_frame(&input[..5]), Next::Incomplete { needed: None });
+        assert_eq!(
+            next_frame(&input[..HEADER_LEN + 2]),
+            Next::Incomplete {
+                needed: Some(HEADER_LEN + 5)
+            }
+        );
+    }
+
+    #[test]
+    fn garbage_before_frame() {
+        let mut input = b"x".to_vec();
+        input.extend(message(command::LOG, 0, b"hello"));
+        assert_eq!(next_frame(&input), Next::Garbage);
+        assert!(matches!(next_frame(&input[1..]), Next::Frame(_)));
+    }
+
+    #[test]
+    fn huge_length_is_incomplete() {
+        let mut input = Vec::new();
+        write_header(&mut input, command::LOG, u32::MAX, 0);
+        assert!(matches!(
+            next_frame(&input),
+            Next::Incomplete { needed: Some(_) }
+        ));
+    }
+
+    #[test]
+    fn listing_round_trip() {
+        let entries = vec![
+            FsEntry::File("main.py".to_owned()),
+            FsEntry::Directory("apps".to_owned()),
+            FsEntry::Other('l', "link".to_owned()),
+        ];
+        let mut payload = Vec::new();
+        write_listing(&mut payload, Some(("/flash", &entries)));
+
+        assert_eq!(
+            parse_listing(&payload),
+            Some(("/flash".to_owned(), entries))
+        );
+    }
+
+    #[test]
+    fn listing_of_empty_directory() {
+        assert_eq!(
+            parse_listing(b"/flash/apps"),
+            Some(("/flash/apps".to_owned(), vec![]))
+        );
+    }
+
+    #[test]
+    fn listing_of_missing_directory() {
+        let mut payload = Vec::new();
+        write_listing(&mut payload, None);
+
+        assert_eq!(payload, b"Directory_not_found");
+        assert_eq!(parse_listing(&payload), None);
+    }
+
+    #[test]
+    fn listing_skips_empty_lines() {
+        assert_eq!(
+            parse_listing(b"/flash\nfmain.py\n\ndapps\n"),
+            Some((
+                "/flash".to_owned(),
+                vec![
+                    FsEntry::File("main.py".to_owned()),
+                    FsEntry::Directory("apps".to_owned()),
+                ]
+            ))
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! `get --tar` writes files and directories of the badge to stdout as a tar archive, fetching each
+//! file as it is added, so `get /flash/apps --tar | tar x` needs no temporary directory.
+
+use crate::{
+    client::{BadgeFs, ClientError, FileKind},
+    cmds::FsEntry,
+    path::BadgePath,
+};
+use std::{
+    error::Error,
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tar::{Builder, EntryType, Header};
+use tracing::{debug, warn};
+
+/// Adds `path` and everything below it to the archive, named relative to the parent of `path`
+async fn append<W: Write>(
+    client: &BadgeFs,
+    archive: &mut Builder<W>,
+    path: &BadgePath,
+    mtime: u64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let prefix = match path.parent() {
+        Some(parent) if !parent.is_root() => parent.as_str().len() + 1,
+        _ => 1,
+    };
+
+    let kind = client.metadata(path.clone()).await?.kind;
+    let mut stack = vec![(path.clone(), kind)];
+    while let Some((path, kind)) = stack.pop() {
+        let name = &path.as_str()[prefix..];
+        let mut header = Header::new_gnu();
+        header.set_mtime(mtime);
+        match kind {
+            FileKind::File => {
+                let data = client.open(path.clone()).read_to_end().await?;
+                debug!("Adding {} ({} bytes)", path, data.len());
+                header.set_entry_type(EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(data.len() as u64);
+                archive.append_data(&mut header, name, &data[..])?;
+            }
+            FileKind::Other(kind) => warn!("Skipping {}, which has type {:?}", path, kind),
+            FileKind::Directory => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                archive.append_data(&mut header, format!("{}/", name), &[][..])?;
+
+                let mut children = Vec::new();
+                let mut entries = client.read_dir(path.clone());
+                loop {
+                    match entries.next_entry().await {
+                        Ok(Some(FsEntry::File(name))) => {
+                            children.push((path.join(name)?, FileKind::File))
+                        }
+                        Ok(Some(FsEntry::Directory(name))) => {
+                            children.push((path.join(name)?, FileKind::Directory))
+                        }
+                        Ok(Some(FsEntry::Other(kind, name))) => {
+                            children.push((path.join(name)?, FileKind::Other(kind)))
+                        }
+                        Ok(None) => break,
+                        Err(e) if e.downcast_ref::<ClientError>().is_some() => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                // Reversed, so the stack hands them out in order of their names
+                children.sort_by(|(a, _), (b, _)| b.file_name().cmp(a.file_name()));
+                stack.extend(children);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a tar archive of `paths` to `out`
+pub async fn write_tar<W: Write>(
+    client: &BadgeFs,
+    paths: &[BadgePath],
+    out: W,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // The badge doesn't keep modification times
+    let mtime = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut archive = Builder::new(out);
+    for path in paths.iter() {
+        append(client, &mut archive, path, mtime).await?;
+    }
+    archive.into_inner()?.flush()?;
+
+    Ok(())
+}
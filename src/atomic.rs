@@ -0,0 +1,36 @@
+//! Writes that never leave a half-written file behind. The data goes to a temporary file next to
+//! the target, which is then moved over it, so an interrupted transfer leaves the old file intact.
+
+use crate::{device::Badge, path::BadgePath};
+use std::error::Error;
+use tracing::debug;
+
+/// The hidden file next to `path` that is written first
+fn temporary(path: &BadgePath) -> Result<BadgePath, Box<dyn Error + Send + Sync>> {
+    let parent = path.parent().unwrap_or_else(BadgePath::root);
+    Ok(parent.join(format!(".{}.tmp", path.file_name()))?)
+}
+
+/// Replaces the contents of `path` with `data`, or leaves it unchanged if that fails
+pub async fn write_file(
+    badge: &Badge,
+    path: &BadgePath,
+    data: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let tmp = temporary(path)?;
+    if let Err(e) = badge.write_file(&tmp, data).await {
+        // Don't leave a partial temporary file behind either
+        let _ = badge.delete_path(&tmp).await;
+        return Err(e);
+    }
+
+    // Whether a move replaces an existing file depends on the filesystem, so if it doesn't, the
+    // old file is deleted first. The complete new file is already on the badge by then.
+    if let Err(e) = badge.move_file(&tmp, path).await {
+        debug!("Unable to move {} over {}: {}", tmp, path, e);
+        badge.delete_path(path).await?;
+        badge.move_file(&tmp, path).await?;
+    }
+
+    Ok(())
+}
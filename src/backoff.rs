@@ -0,0 +1,58 @@
+//! Exponential backoff with jitter for everything that waits for the badge and tries again, so a
+//! badge that doesn't respond isn't flooded with wake-up input and several processes waiting for
+//! the same badge don't poll in lockstep.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// The delay before the first retry
+    pub initial: Duration,
+    /// The delay doesn't grow beyond this
+    pub max: Duration,
+    /// Each delay is this many times longer than the one before
+    pub factor: f64,
+    /// Each delay is randomly up to this fraction shorter or longer, like 0.2 for 20%
+    pub jitter: f64,
+}
+
+impl Backoff {
+    /// Doubles from `initial` up to `max`, with 20% jitter
+    pub const fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            factor: 2.0,
+            jitter: 0.2,
+        }
+    }
+
+    /// The same delay every time, without jitter
+    pub const fn constant(delay: Duration) -> Backoff {
+        Backoff {
+            initial: delay,
+            max: delay,
+            factor: 1.0,
+            jitter: 0.0,
+        }
+    }
+
+    /// The delay before retry `attempt`, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self.initial.as_secs_f64() * self.factor.powi(attempt.min(64) as i32);
+        let delay = exponential.min(self.max.as_secs_f64());
+        // Between -1 and 1
+        let random = random() as f64 / u64::MAX as f64 * 2.0 - 1.0;
+        Duration::from_secs_f64((delay * (1.0 + self.jitter * random)).max(0.0))
+    }
+}
+
+/// A random number from the randomly seeded hasher of the standard library, which is plenty for
+/// jitter
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
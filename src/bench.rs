@@ -0,0 +1,93 @@
+use crate::{device::Badge, output::Output, path::BadgePath};
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Timings of all transfers of one size in one direction
+struct Samples {
+    size: usize,
+    durations: Vec<Duration>,
+}
+
+impl Samples {
+    fn percentile(&self, p: usize) -> Duration {
+        let mut sorted = self.durations.clone();
+        sorted.sort();
+        sorted[(sorted.len() - 1) * p / 100]
+    }
+
+    /// Throughput in KiB/s, based on the total time spent transferring
+    fn throughput(&self) -> f64 {
+        let total: Duration = self.durations.iter().sum();
+        (self.size * self.durations.len()) as f64 / 1024. / total.as_secs_f64()
+    }
+
+    fn print(&self, output: Output, direction: &str) {
+        output.bench_result(
+            self.size,
+            direction,
+            self.throughput(),
+            [
+                self.percentile(50),
+                self.percentile(90),
+                self.percentile(100),
+            ],
+        );
+    }
+}
+
+/// Deterministic data that isn't all zeroes, so a corrupted or shifted download is noticed
+fn payload(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+/// Uploads and downloads `iterations` payloads of each size to `path` and prints the results.
+/// The file is deleted afterwards.
+pub async fn bench(
+    badge: &Badge,
+    path: &BadgePath,
+    sizes: &[usize],
+    iterations: usize,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    output.bench_header();
+
+    for &size in sizes {
+        let data = payload(size);
+        let mut upload = Samples {
+            size,
+            durations: Vec::new(),
+        };
+        let mut download = Samples {
+            size,
+            durations: Vec::new(),
+        };
+
+        for _ in 0..iterations.max(1) {
+            let start = Instant::now();
+            badge.write_file(path, &data).await?;
+            upload.durations.push(start.elapsed());
+
+            let start = Instant::now();
+            let received = badge.fetch_file(path).await?;
+            download.durations.push(start.elapsed());
+
+            if received != data {
+                warn!(
+                    "Downloaded {} bytes, which don't match the {} bytes that were uploaded",
+                    received.len(),
+                    data.len()
+                );
+            }
+        }
+
+        upload.print(output, "upload");
+        download.print(output, "download");
+    }
+
+    badge.delete_path(path).await?;
+
+    Ok(())
+}
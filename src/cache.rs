@@ -0,0 +1,227 @@
+use crate::{client::FileKind, cmds::FsEntry, path::BadgePath};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+/// Hex-encoded SHA-256 of `data`. The Python side can compute the same with `uhashlib`.
+pub fn hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedEntry {
+    pub kind: FileKind,
+    pub len: Option<u64>,
+    pub hash: Option<String>,
+    pub updated: SystemTime,
+}
+
+/// The last-known tree of a badge, persisted between invocations.
+///
+/// Stored as one line per path: `kind len hash updated path`, separated by tabs, with `-` for
+/// unknown values. The path comes last, so it can contain anything except a newline.
+pub struct MetadataCache {
+    file: Option<PathBuf>,
+    entries: Mutex<BTreeMap<BadgePath, CachedEntry>>,
+}
+
+/// `~/.cache/cz2020-usbtool`, or the same under `$XDG_CACHE_HOME`
+pub fn cache_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")),
+    }
+    .map(|dir| dir.join("cz2020-usbtool"))
+}
+
+fn parse_line(line: &str) -> Option<(BadgePath, CachedEntry)> {
+    let mut fields = line.splitn(5, '\t');
+    let kind = match fields.next()? {
+        "f" => FileKind::File,
+        "d" => FileKind::Directory,
+        other => {
+            let mut chars = other.chars();
+            match (chars.next(), chars.next()) {
+                (Some(kind), None) => FileKind::Other(kind),
+                _ => return None,
+            }
+        }
+    };
+    let len = match fields.next()? {
+        "-" => None,
+        len => Some(len.parse().ok()?),
+    };
+    let hash = match fields.next()? {
+        "-" => None,
+        hash => Some(hash.to_owned()),
+    };
+    let updated = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+    let path = BadgePath::new(fields.next()?).ok()?;
+
+    Some((
+        path,
+        CachedEntry {
+            kind,
+            len,
+            hash,
+            updated,
+        },
+    ))
+}
+
+impl MetadataCache {
+    /// Loads the cache of the badge with the given id. A missing or unreadable cache file results in an empty cache.
+    pub fn load(id: &str) -> MetadataCache {
+        let file = cache_dir().map(|dir| dir.join(format!("{}.tsv", id)));
+        let mut entries = BTreeMap::new();
+        if let Some(contents) = file.as_ref().and_then(|file| fs::read_to_string(file).ok()) {
+            entries.extend(contents.lines().filter_map(parse_line));
+        }
+
+        debug!("Loaded {} cached entries from {:?}", entries.len(), file);
+        MetadataCache {
+            file,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => {
+                warn!("No cache directory found, set $XDG_CACHE_HOME or $HOME");
+                return Ok(());
+            }
+        };
+
+        let mut contents = Vec::new();
+        for (path, entry) in self.entries.lock().unwrap().iter() {
+            writeln!(
+                contents,
+                "{}\t{}\t{}\t{}\t{}",
+                match entry.kind {
+                    FileKind::File => 'f',
+                    FileKind::Directory => 'd',
+                    FileKind::Other(kind) => kind,
+                },
+                entry
+                    .len
+                    .map(|len| len.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                entry.hash.as_deref().unwrap_or("-"),
+                entry
+                    .updated
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                path
+            )?;
+        }
+
+        fs::create_dir_all(file.parent().unwrap())?;
+        let tmp = file.with_extension("tsv.tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, file)
+    }
+
+    pub fn get(&self, path: &BadgePath) -> Option<CachedEntry> {
+        self.entries.lock().unwrap().get(path).cloned()
+    }
+
+    /// The cached entries directly inside `dir`
+    pub fn children(&self, dir: &BadgePath) -> Vec<(BadgePath, CachedEntry)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(path, _)| path.parent().as_ref() == Some(dir))
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect()
+    }
+
+    /// Replaces the cached children of `dir` with a fresh listing. Entries that no longer exist are
+    /// removed along with everything below them; sizes and hashes of unchanged files are kept.
+    pub fn record_dir(&self, dir: &BadgePath, listing: &[FsEntry]) {
+        let mut entries = self.entries.lock().unwrap();
+        let now = SystemTime::now();
+        let stale = entries
+            .keys()
+            .filter(|path| {
+                path.parent().as_ref() == Some(dir)
+                    && !listing.iter().any(|entry| entry.name() == path.file_name())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in stale {
+            remove_tree(&mut entries, &path);
+        }
+
+        entries.insert(
+            dir.clone(),
+            CachedEntry {
+                kind: FileKind::Directory,
+                len: None,
+                hash: None,
+                updated: now,
+            },
+        );
+        for entry in listing {
+            let path = match dir.join(entry.name()) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            let kind = match entry {
+                FsEntry::File(_) => FileKind::File,
+                FsEntry::Directory(_) => FileKind::Directory,
+                FsEntry::Other(kind, _) => FileKind::Other(*kind),
+            };
+
+            match entries.get_mut(&path) {
+                Some(cached) if cached.kind == kind => cached.updated = now,
+                _ => {
+                    entries.insert(
+                        path,
+                        CachedEntry {
+                            kind,
+                            len: None,
+                            hash: None,
+                            updated: now,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Records the contents of a file that was just read from or written to the badge
+    pub fn record_file(&self, path: &BadgePath, contents: &[u8]) {
+        self.entries.lock().unwrap().insert(
+            path.clone(),
+            CachedEntry {
+                kind: FileKind::File,
+                len: Some(contents.len() as u64),
+                hash: Some(hash(contents)),
+                updated: SystemTime::now(),
+            },
+        );
+    }
+
+    pub fn remove(&self, path: &BadgePath) {
+        remove_tree(&mut self.entries.lock().unwrap(), path);
+    }
+}
+
+fn remove_tree(entries: &mut BTreeMap<BadgePath, CachedEntry>, path: &BadgePath) {
+    let prefix = format!("{}/", path);
+    entries.retain(|other, _| other != path && !other.as_str().starts_with(&prefix));
+}
@@ -0,0 +1,102 @@
+use crate::{
+    cmds::{Command, ResponseData},
+    device::Badge,
+    path::BadgePath,
+};
+use std::{fmt, time::Duration};
+use tokio::{
+    sync::broadcast::RecvError,
+    time::{timeout, Instant},
+};
+use tracing::debug;
+
+/// How long to wait for the badge to answer a probe. Probes aren't retried, so a firmware that
+/// ignores a command can't stall the connection.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait for the Python shell to print a prompt
+const PROMPT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What the firmware of a badge supports, as far as it can be observed. The protocol has no
+/// version command and no framing options, so this is determined by trying things out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The firmware answers the file commands. If not, file operations go through the Python shell.
+    pub file_commands: bool,
+
+    /// The Python shell is idle and answers input. If not, optional operations that would need it
+    /// aren't attempted.
+    pub python_shell: bool,
+}
+
+impl Default for Capabilities {
+    /// Assumes everything is supported until probed
+    fn default() -> Capabilities {
+        Capabilities {
+            file_commands: true,
+            python_shell: true,
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "file_commands: {}", self.file_commands)?;
+        writeln!(f, "python_shell: {}", self.python_shell)
+    }
+}
+
+async fn probe_file_commands(badge: &Badge) -> bool {
+    let request = match badge.cmd_once(Command::FetchDir {
+        path: BadgePath::root(),
+    }) {
+        Ok(request) => request,
+        Err(_) => return false,
+    };
+
+    match timeout(PROBE_TIMEOUT, request).await {
+        Ok(Ok(ResponseData::DirectoryListing(_))) => true,
+        Ok(Ok(response)) => {
+            debug!("Unexpected response to FetchDir: {:?}", response);
+            false
+        }
+        Ok(Err(_)) | Err(_) => false,
+    }
+}
+
+/// Whether the Python shell answers with a prompt, which it only does while no app is running.
+/// Sends an empty line, and waits a moment for the prompt if it is busy.
+pub async fn shell_is_idle(badge: &Badge) -> bool {
+    let mut output = badge.subscribe();
+    if badge.serial_in("\r\n").await.is_err() {
+        return false;
+    }
+
+    let deadline = Instant::now() + PROMPT_TIMEOUT;
+    let mut text = String::new();
+    while !text.contains(">>> ") {
+        match timeout(
+            deadline.saturating_duration_since(Instant::now()),
+            output.recv(),
+        )
+        .await
+        {
+            Ok(Ok(received)) => text.push_str(&received),
+            Ok(Err(RecvError::Lagged(_))) => {}
+            Ok(Err(RecvError::Closed)) | Err(_) => return false,
+        }
+    }
+
+    true
+}
+
+/// Tries out what the firmware of `badge` supports
+pub async fn probe(badge: &Badge) -> Capabilities {
+    let capabilities = Capabilities {
+        file_commands: probe_file_commands(badge).await,
+        python_shell: shell_is_idle(badge).await,
+    };
+    debug!("Capabilities: {:?}", capabilities);
+
+    capabilities
+}
@@ -0,0 +1,294 @@
+//! A C interface, built with the `capi` feature, so programs in other languages can talk to the
+//! badge without implementing the protocol themselves. See `include/cz2020_usbtool.h`.
+//!
+//! Functions that can fail return 0 on success and -1 on failure, or a null pointer for
+//! `cz_connect`. `cz_last_error` then describes what went wrong. Every call blocks until the badge
+//! answered.
+
+use crate::{
+    client::BadgeFs,
+    cmds::FsEntry,
+    device::{self, Device},
+    path::BadgePath,
+    run,
+};
+use std::{
+    cell::RefCell,
+    error::Error,
+    ffi::{CStr, CString},
+    future::Future,
+    os::raw::{c_char, c_int, c_void},
+    ptr, slice,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+use tokio::{runtime::Runtime, sync::broadcast::RecvError};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(e: impl ToString) {
+    // Messages don't contain nul bytes, but they must not make this panic if they do
+    let message = CString::new(e.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Turns a result into the return value of a C function
+fn status(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            set_error(e);
+            -1
+        }
+    }
+}
+
+/// A path passed from C
+unsafe fn path(path: *const c_char) -> Result<BadgePath> {
+    if path.is_null() {
+        Err("The path is null")?
+    }
+    Ok(BadgePath::new(CStr::from_ptr(path).to_str()?)?)
+}
+
+/// The user data of a callback, which is passed back as is
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+// The caller promises that the callback can be called from another thread
+unsafe impl Send for UserData {}
+
+/// A connection to a badge
+pub struct CzBadge {
+    badge: Arc<device::Badge>,
+    client: BadgeFs,
+    runtime: Mutex<Runtime>,
+    /// The threads of `cz_subscribe_logs`, which `cz_close` waits for
+    forwarders: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl CzBadge {
+    fn block_on<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        self.runtime.lock().unwrap().block_on(future)
+    }
+}
+
+/// Called with each entry of `cz_list`. `kind` is `'f'` for files and `'d'` for directories.
+pub type CzListCallback = extern "C" fn(kind: c_char, name: *const c_char, user: *mut c_void);
+
+/// Called with each piece of output of the badge
+pub type CzLogCallback = extern "C" fn(data: *const u8, len: usize, user: *mut c_void);
+
+/// The message of the last error on this thread, or null if nothing failed yet. Valid until the
+/// next call on this thread.
+#[no_mangle]
+pub extern "C" fn cz_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Connects to the badge with the given serial number or `<bus>-<address>`, or to the first badge
+/// that is found if `device` is null. Free the connection with `cz_close`.
+///
+/// # Safety
+/// `device` must be null or a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cz_connect(device: *const c_char) -> *mut CzBadge {
+    let connect = || -> Result<CzBadge> {
+        let device = if device.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(device).to_str()?)
+        };
+        let context = rusb::Context::new()?;
+        let device = Device::find(&context, device)?;
+        let badge = device::Badge::builder().build(device).start();
+        let badge = CzBadge {
+            client: BadgeFs::new(badge.clone()),
+            badge,
+            runtime: Mutex::new(Runtime::new()?),
+            forwarders: Mutex::new(Vec::new()),
+        };
+
+        badge.block_on(async {
+            badge.badge.heartbeat().await?;
+            badge.badge.probe().await;
+            Ok(())
+        })?;
+        Ok(badge)
+    };
+
+    match connect() {
+        Ok(badge) => Box::into_raw(Box::new(badge)),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Closes the connection and frees `badge`. Waits for log callbacks that are running, and they
+/// aren't called anymore afterwards.
+///
+/// # Safety
+/// `badge` must come from `cz_connect` and can't be used afterwards. Can't be called from a log
+/// callback.
+#[no_mangle]
+pub unsafe extern "C" fn cz_close(badge: *mut CzBadge) {
+    if !badge.is_null() {
+        let badge = Box::from_raw(badge);
+        badge.badge.close();
+        // They stop once the output is closed
+        for forwarder in badge.forwarders.lock().unwrap().drain(..) {
+            let _ = forwarder.join();
+        }
+    }
+}
+
+/// Calls `callback` with each entry of the directory `dir`
+///
+/// # Safety
+/// `badge` must come from `cz_connect` and `dir` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cz_list(
+    badge: *const CzBadge,
+    dir: *const c_char,
+    callback: CzListCallback,
+    user: *mut c_void,
+) -> c_int {
+    let badge = &*badge;
+    status((|| {
+        let mut entries = badge.client.read_dir(path(dir)?);
+        badge.block_on(async {
+            while let Some(entry) = entries.next_entry().await? {
+                let kind = match entry {
+                    FsEntry::File(_) => b'f',
+                    FsEntry::Directory(_) => b'd',
+                    FsEntry::Other(kind, _) => kind as u8,
+                };
+                let name = CString::new(entry.name())?;
+                callback(kind as c_char, name.as_ptr(), user);
+            }
+            Ok(())
+        })
+    })())
+}
+
+/// Fetches a file. On success, `*data` and `*len` hold its contents, which must be freed with
+/// `cz_free`.
+///
+/// # Safety
+/// `badge` must come from `cz_connect`, `file` must be a nul-terminated string, and `data` and
+/// `len` must be valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn cz_read(
+    badge: *const CzBadge,
+    file: *const c_char,
+    data: *mut *mut u8,
+    len: *mut usize,
+) -> c_int {
+    let badge = &*badge;
+    status((|| {
+        let file = path(file)?;
+        let contents = badge
+            .block_on(badge.badge.fetch_file(&file))?
+            .to_vec()
+            .into_boxed_slice();
+        *len = contents.len();
+        *data = Box::into_raw(contents) as *mut u8;
+        Ok(())
+    })())
+}
+
+/// Frees the contents returned by `cz_read`
+///
+/// # Safety
+/// `data` and `len` must come from one call to `cz_read`.
+#[no_mangle]
+pub unsafe extern "C" fn cz_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Writes `len` bytes to a file, replacing it if it exists
+///
+/// # Safety
+/// `badge` must come from `cz_connect`, `file` must be a nul-terminated string, and `data` must
+/// point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cz_write(
+    badge: *const CzBadge,
+    file: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let badge = &*badge;
+    status((|| {
+        let file = path(file)?;
+        let data = match len {
+            0 => &[][..],
+            len => slice::from_raw_parts(data, len),
+        };
+        badge.block_on(badge.badge.write_file(&file, data))
+    })())
+}
+
+/// Runs an app, and fails if it crashes while starting
+///
+/// # Safety
+/// `badge` must come from `cz_connect` and `app` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cz_run(badge: *const CzBadge, app: *const c_char) -> c_int {
+    let badge = &*badge;
+    status((|| {
+        let app = path(app)?;
+        badge.block_on(run::run(&badge.badge, &app, &[]))
+    })())
+}
+
+/// Calls `callback` with the output of the badge from now on, on a separate thread, until the
+/// connection is closed
+///
+/// # Safety
+/// `badge` must come from `cz_connect`, and `callback` must be safe to call from another thread
+/// with `user`.
+#[no_mangle]
+pub unsafe extern "C" fn cz_subscribe_logs(
+    badge: *const CzBadge,
+    callback: CzLogCallback,
+    user: *mut c_void,
+) -> c_int {
+    let badge = &*badge;
+    let mut output = badge.badge.subscribe();
+    let user = UserData(user);
+    status((|| {
+        let mut runtime = Runtime::new()?;
+        let forwarder = thread::Builder::new()
+            .name("cz_subscribe_logs".to_owned())
+            .spawn(move || {
+                let user = user;
+                runtime.block_on(async {
+                    loop {
+                        match output.recv().await {
+                            Ok(text) => {
+                                // One char per byte
+                                let data = text.chars().map(|c| c as u8).collect::<Vec<_>>();
+                                callback(data.as_ptr(), data.len(), user.0);
+                            }
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                })
+            })?;
+        badge.forwarders.lock().unwrap().push(forwarder);
+        Ok(())
+    })())
+}
@@ -0,0 +1,407 @@
+use crate::{
+    cache::MetadataCache,
+    capabilities,
+    cmds::{DirectoryListingResponse, FsEntry},
+    device::Badge,
+    path::BadgePath,
+    repl,
+};
+use bytes::Bytes;
+use std::{collections::VecDeque, error::Error, fmt, ops::Deref, sync::Arc};
+use thiserror::Error;
+use tracing::debug;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("No such file or directory: {}", .0)]
+    NotFound(BadgePath),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    /// Neither a file nor a directory, with the character the firmware marked its type with
+    Other(char),
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub len: u64,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+}
+
+/// Filesystem-style access to the badge. The protocol only knows how to transfer entire files,
+/// so file handles keep a copy of the contents and ranged reads and writes operate on that copy.
+#[derive(Clone)]
+pub struct BadgeFs {
+    badge: Arc<Badge>,
+    cache: Option<Arc<MetadataCache>>,
+}
+
+impl BadgeFs {
+    pub fn new(badge: Arc<Badge>) -> BadgeFs {
+        BadgeFs { badge, cache: None }
+    }
+
+    /// Records every listing and file that is transferred in `cache`
+    pub fn with_cache(mut self, cache: Arc<MetadataCache>) -> BadgeFs {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn read_dir(&self, path: BadgePath) -> ReadDir {
+        ReadDir {
+            badge: self.badge.clone(),
+            cache: self.cache.clone(),
+            path,
+            entries: None,
+        }
+    }
+
+    /// Opens a file. The contents are not fetched until they are first needed.
+    pub fn open(&self, path: BadgePath) -> BadgeFile {
+        BadgeFile {
+            badge: self.badge.clone(),
+            cache: self.cache.clone(),
+            path,
+            contents: None,
+            synced_len: 0,
+            dirty_from: None,
+            shell_unavailable: !self.badge.capabilities().python_shell,
+        }
+    }
+
+    /// Whether `path` exists, found by listing its parent so files don't have to be fetched
+    pub async fn exists(&self, path: &BadgePath) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.is_root() => parent,
+            _ => return Ok(true),
+        };
+
+        let mut entries = self.read_dir(parent);
+        loop {
+            match entries.next_entry().await {
+                Ok(Some(entry)) if entry.name() == path.file_name() => return Ok(true),
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(false),
+                Err(e) if e.downcast_ref::<ClientError>().is_some() => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The kind and size of `path`. Listings don't include sizes, so finding the size of a file
+    /// downloads all of it, unless the metadata cache already knows it.
+    pub async fn metadata(
+        &self,
+        path: BadgePath,
+    ) -> Result<Metadata, Box<dyn Error + Send + Sync>> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.is_root() => parent,
+            _ => {
+                // Top-level directories such as /flash can't be listed from their parent
+                return Ok(Metadata {
+                    kind: FileKind::Directory,
+                    len: 0,
+                });
+            }
+        };
+
+        let mut entries = self.read_dir(parent);
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.name() == path.file_name() {
+                return Ok(match entry {
+                    FsEntry::Directory(_) => Metadata {
+                        kind: FileKind::Directory,
+                        len: 0,
+                    },
+                    FsEntry::File(_) => Metadata {
+                        kind: FileKind::File,
+                        len: match self.cached_len(&path) {
+                            Some(len) => len,
+                            None => self.open(path.clone()).len().await?,
+                        },
+                    },
+                    FsEntry::Other(kind, _) => Metadata {
+                        kind: FileKind::Other(kind),
+                        len: 0,
+                    },
+                });
+            }
+        }
+
+        Err(ClientError::NotFound(path))?
+    }
+
+    /// The size of a file as the cache last saw it transferred
+    fn cached_len(&self, path: &BadgePath) -> Option<u64> {
+        let entry = self.cache.as_ref()?.get(path)?;
+        match entry.kind {
+            FileKind::File => entry.len,
+            _ => None,
+        }
+    }
+}
+
+pub struct ReadDir {
+    badge: Arc<Badge>,
+    cache: Option<Arc<MetadataCache>>,
+    path: BadgePath,
+    entries: Option<VecDeque<FsEntry>>,
+}
+
+impl ReadDir {
+    /// Returns the next entry in the directory. The listing is fetched on the first call.
+    pub async fn next_entry(&mut self) -> Result<Option<FsEntry>, Box<dyn Error + Send + Sync>> {
+        if self.entries.is_none() {
+            match self.badge.fetch_dir(&self.path).await? {
+                DirectoryListingResponse::Found {
+                    requested: _,
+                    entries,
+                } => {
+                    if let Some(cache) = &self.cache {
+                        cache.record_dir(&self.path, &entries);
+                    }
+                    self.entries = Some(entries.into())
+                }
+                DirectoryListingResponse::DirectoryNotFound => {
+                    if let Some(cache) = &self.cache {
+                        cache.remove(&self.path);
+                    }
+                    Err(ClientError::NotFound(self.path.clone()))?
+                }
+            }
+        }
+
+        Ok(self
+            .entries
+            .as_mut()
+            .and_then(|entries| entries.pop_front()))
+    }
+}
+
+/// The local copy of a file. It stays the buffer the response was received in until it is first
+/// written to, so reading it doesn't copy it.
+enum Contents {
+    Received(Bytes),
+    Modified(Vec<u8>),
+}
+
+impl Contents {
+    fn to_mut(&mut self) -> &mut Vec<u8> {
+        if let Contents::Received(data) = self {
+            *self = Contents::Modified(data.to_vec());
+        }
+        match self {
+            Contents::Modified(data) => data,
+            Contents::Received(_) => unreachable!(),
+        }
+    }
+
+    /// Shares the contents, without copying them
+    fn share(&mut self) -> Bytes {
+        if let Contents::Modified(data) = self {
+            *self = Contents::Received(Bytes::from(std::mem::take(data)));
+        }
+        match self {
+            Contents::Received(data) => data.clone(),
+            Contents::Modified(_) => unreachable!(),
+        }
+    }
+}
+
+impl Deref for Contents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Contents::Received(data) => data,
+            Contents::Modified(data) => data,
+        }
+    }
+}
+
+pub struct BadgeFile {
+    badge: Arc<Badge>,
+    cache: Option<Arc<MetadataCache>>,
+    path: BadgePath,
+    contents: Option<Contents>,
+    /// The length of the file on the badge, as of the last transfer
+    synced_len: u64,
+    /// The lowest offset that was written since the last transfer
+    dirty_from: Option<u64>,
+    /// Set once writing through the Python shell failed, so later writes don't wait for it again
+    shell_unavailable: bool,
+}
+
+impl fmt::Debug for BadgeFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BadgeFile")
+            .field("path", &self.path)
+            .field("len", &self.cached_len())
+            .finish()
+    }
+}
+
+impl BadgeFile {
+    pub fn set_path(&mut self, path: BadgePath) {
+        self.path = path;
+    }
+
+    /// The length of the file, if the contents have been fetched
+    pub fn cached_len(&self) -> Option<u64> {
+        self.contents.as_ref().map(|x| x.len() as u64)
+    }
+
+    /// Whether there are writes that haven't been sent to the badge yet
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_from.is_some()
+    }
+
+    /// Drops the local copy, so the contents are fetched again on the next access. Writes that
+    /// haven't been flushed are lost.
+    pub fn invalidate(&mut self) {
+        self.contents = None;
+        self.dirty_from = None;
+    }
+
+    async fn contents(&mut self) -> Result<&mut Contents, Box<dyn Error + Send + Sync>> {
+        if self.contents.is_none() {
+            let contents = self.badge.fetch_file(&self.path).await?;
+            self.record(&contents);
+            self.synced_len = contents.len() as u64;
+            self.contents = Some(Contents::Received(contents));
+        }
+
+        Ok(self.contents.as_mut().unwrap())
+    }
+
+    fn record(&self, contents: &[u8]) {
+        if let Some(cache) = &self.cache {
+            cache.record_file(&self.path, contents);
+        }
+    }
+
+    pub async fn len(&mut self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        Ok(self.contents().await?.len() as u64)
+    }
+
+    pub async fn is_empty(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self.len().await? == 0)
+    }
+
+    pub async fn read_at(
+        &mut self,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let contents = self.contents().await?;
+        let start = (offset as usize).min(contents.len());
+        let end = (start + buf.len()).min(contents.len());
+        buf[..end - start].copy_from_slice(&contents[start..end]);
+
+        Ok(end - start)
+    }
+
+    pub async fn read_to_end(&mut self) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+        Ok(self.contents().await?.share())
+    }
+
+    /// Writes `data` at `offset` of the local copy, growing the file and filling any gap with
+    /// zeroes if needed. Nothing is sent to the badge until `flush` is called.
+    pub async fn write_at(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let contents = self.contents().await?.to_mut();
+        let start = offset as usize;
+        let end = start + data.len();
+        contents.resize(end.max(contents.len()), 0);
+        contents[start..end].copy_from_slice(data);
+
+        if !data.is_empty() {
+            self.dirty_from = Some(self.dirty_from.map_or(offset, |from| from.min(offset)));
+        }
+
+        Ok(data.len())
+    }
+
+    /// Adds `data` to the end of the local copy
+    pub async fn append(&mut self, data: &[u8]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let len = self.len().await?;
+        self.write_at(len, data).await
+    }
+
+    /// Sends the writes since the last transfer to the badge. If they only added to the end of the
+    /// file, just the new data is sent through the Python shell when it is idle; otherwise the
+    /// entire file is sent.
+    pub async fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let dirty_from = match self.dirty_from {
+            Some(dirty_from) => dirty_from,
+            None => return Ok(()),
+        };
+
+        let contents = self.contents.as_mut().unwrap().share();
+        let synced_len = self.synced_len;
+        if !(dirty_from >= synced_len
+            && self
+                .write_range(synced_len, &contents[synced_len as usize..])
+                .await)
+        {
+            self.badge.write_file(&self.path, &contents).await?;
+        }
+
+        self.record(&contents);
+        self.synced_len = contents.len() as u64;
+        self.dirty_from = None;
+
+        Ok(())
+    }
+
+    async fn write_range(&mut self, offset: u64, data: &[u8]) -> bool {
+        if self.shell_unavailable {
+            return false;
+        }
+        // The code would be typed into a running app instead
+        if !capabilities::shell_is_idle(&self.badge).await {
+            debug!(
+                "The Python shell is busy, sending the entire file {}",
+                self.path
+            );
+            return false;
+        }
+
+        match repl::write_range(&self.badge, &self.path, offset, data).await {
+            Ok(()) => true,
+            Err(e) => {
+                debug!(
+                    "Unable to write {} through the Python shell, sending the entire file: {}",
+                    self.path, e
+                );
+                self.shell_unavailable = true;
+                false
+            }
+        }
+    }
+
+    pub async fn set_len(&mut self, len: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut new_data = self.contents().await?.to_vec();
+        new_data.resize(len as usize, 0);
+
+        self.badge.write_file(&self.path, &new_data).await?;
+        self.record(&new_data);
+        self.synced_len = new_data.len() as u64;
+        self.dirty_from = None;
+        self.contents = Some(Contents::Modified(new_data));
+
+        Ok(())
+    }
+}
@@ -1,116 +1,222 @@
+use crate::{path::BadgePath, share};
 use buf_redux::Buffer;
-use log::{debug, trace, warn};
-use std::{convert::TryInto, error::Error, ffi::CString, io::Write};
+use bytes::Bytes;
+use cz2020_protocol::{
+    self as protocol, command, latin1, write_latin1, write_null_terminated, Next,
+};
+use std::{error::Error, panic};
+use tracing::{debug, error, trace, warn};
+
+pub use cz2020_protocol::FsEntry;
+
+/// Which commands go first when several are waiting to be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Sent right away, regardless of the pipelining depth
+    Interactive,
+    /// Waits for a place in the pipeline
+    Bulk,
+}
 
 #[derive(Debug, Clone)]
 pub enum Command {
     CreateDir {
-        path: String,
+        path: BadgePath,
     },
     /// Don't include trailing slash
     FetchDir {
-        path: String,
+        path: BadgePath,
     },
 
     CreateFile {
-        path: String,
+        path: BadgePath,
     },
     FetchFile {
-        path: String,
+        path: BadgePath,
     },
     CopyFile {
-        from: String,
-        to: String,
+        from: BadgePath,
+        to: BadgePath,
     },
     MoveFile {
-        from: String,
-        to: String,
+        from: BadgePath,
+        to: BadgePath,
     },
+    /// Cloning is cheap, because the data is shared between clones
     WriteFile {
-        path: String,
-        data: Vec<u8>,
+        path: BadgePath,
+        data: Bytes,
     },
 
     /// Don't include /flash prefix
     RunFile {
-        path: String,
+        path: BadgePath,
     },
 
     DeletePath {
-        path: String,
+        path: BadgePath,
     },
     SerialIn {
-        data: Vec<u8>,
+        data: Bytes,
     },
     Heartbeat,
-}
-
-fn str_to_null_terminated_buf<S: AsRef<str>>(s: S) -> Vec<u8> {
-    CString::new(s.as_ref())
-        .unwrap()
-        .as_bytes_with_nul()
-        .try_into()
-        .unwrap()
+    /// Only understood by a process that shares the badge, see `Badge::pause_writes`
+    PauseWrites {
+        paused: bool,
+    },
 }
 
 impl Command {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Appends the payload of the command to `out`, without copying the data more than once
+    pub fn write_payload(&self, out: &mut Vec<u8>) {
         match self {
             Command::CreateDir { path }
             | Command::FetchDir { path }
             | Command::CreateFile { path }
             | Command::FetchFile { path }
             | Command::RunFile { path }
-            | Command::DeletePath { path } => str_to_null_terminated_buf(path),
+            | Command::DeletePath { path } => write_null_terminated(out, path.as_str()),
 
             Command::CopyFile { from, to } | Command::MoveFile { from, to } => {
-                let mut v = Vec::new();
-                v.write(CString::new(from.as_str()).unwrap().as_bytes_with_nul())
-                    .unwrap();
-                v.write(CString::new(to.as_str()).unwrap().as_bytes_with_nul())
-                    .unwrap();
-
-                v
+                write_null_terminated(out, from.as_str());
+                write_null_terminated(out, to.as_str());
             }
             Command::WriteFile { path, data } => {
-                let mut v = str_to_null_terminated_buf(path);
-                v.write(data).unwrap();
+                write_null_terminated(out, path.as_str());
+                out.extend_from_slice(data);
+            }
+            Command::SerialIn { data } => out.extend_from_slice(data),
+            Command::Heartbeat => out.extend_from_slice(protocol::HEARTBEAT_PAYLOAD),
+            Command::PauseWrites { paused } => out.push(*paused as u8),
+        }
+    }
 
-                v
+    /// The length of the payload in bytes
+    pub fn payload_len(&self) -> usize {
+        match self {
+            Command::CreateDir { path }
+            | Command::FetchDir { path }
+            | Command::CreateFile { path }
+            | Command::FetchFile { path }
+            | Command::RunFile { path }
+            | Command::DeletePath { path } => path.as_str().len() + 1,
+            Command::CopyFile { from, to } | Command::MoveFile { from, to } => {
+                from.as_str().len() + to.as_str().len() + 2
             }
-            Command::SerialIn { data } => data.clone(),
-            Command::Heartbeat => str_to_null_terminated_buf("beat"),
+            Command::WriteFile { path, data } => path.as_str().len() + 1 + data.len(),
+            Command::SerialIn { data } => data.len(),
+            Command::Heartbeat => protocol::HEARTBEAT_PAYLOAD.len(),
+            Command::PauseWrites { .. } => 1,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::CreateDir { .. } => "CreateDir",
+            Command::FetchDir { .. } => "FetchDir",
+            Command::CreateFile { .. } => "CreateFile",
+            Command::FetchFile { .. } => "FetchFile",
+            Command::CopyFile { .. } => "CopyFile",
+            Command::MoveFile { .. } => "MoveFile",
+            Command::WriteFile { .. } => "WriteFile",
+            Command::RunFile { .. } => "RunFile",
+            Command::DeletePath { .. } => "DeletePath",
+            Command::SerialIn { .. } => "SerialIn",
+            Command::Heartbeat => "Heartbeat",
+            Command::PauseWrites { .. } => "PauseWrites",
         }
     }
 
+    /// Keystrokes and heartbeats are small and someone is waiting for them, so they don't queue
+    /// behind file transfers
+    pub fn priority(&self) -> Priority {
+        match self {
+            Command::SerialIn { .. } | Command::Heartbeat | Command::PauseWrites { .. } => {
+                Priority::Interactive
+            }
+            _ => Priority::Bulk,
+        }
+    }
+
+    /// Whether the command changes the filesystem of the badge, so it waits while writes are paused
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::CreateDir { .. }
+                | Command::CreateFile { .. }
+                | Command::CopyFile { .. }
+                | Command::MoveFile { .. }
+                | Command::WriteFile { .. }
+                | Command::DeletePath { .. }
+        )
+    }
+
     pub fn command(&self) -> u16 {
         match self {
-            Command::CreateDir { path: _ } => 4102,
-            Command::FetchDir { path: _ } => 4096,
-            Command::CreateFile { path: _ } => 4098,
-            Command::FetchFile { path: _ } => 4097,
-            Command::CopyFile { from: _, to: _ } => 4100,
-            Command::MoveFile { from: _, to: _ } => 4101,
-            Command::WriteFile { path: _, data: _ } => 4098,
-            Command::RunFile { path: _ } => 0,
-            Command::DeletePath { path: _ } => 4099,
-            Command::SerialIn { data: _ } => 2,
-            Command::Heartbeat => 1,
+            Command::CreateDir { .. } => command::CREATE_DIR,
+            Command::FetchDir { .. } => command::FETCH_DIR,
+            Command::CreateFile { .. } => command::WRITE_FILE,
+            Command::FetchFile { .. } => command::FETCH_FILE,
+            Command::CopyFile { .. } => command::COPY_FILE,
+            Command::MoveFile { .. } => command::MOVE_FILE,
+            Command::WriteFile { .. } => command::WRITE_FILE,
+            Command::RunFile { .. } => command::RUN_FILE,
+            Command::DeletePath { .. } => command::DELETE_PATH,
+            Command::SerialIn { .. } => command::SERIAL_IN,
+            Command::Heartbeat => command::HEARTBEAT,
+            Command::PauseWrites { .. } => share::PAUSE_WRITES,
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum FsEntry {
-    File(String),
-    Directory(String),
+/// Splits a NUL-terminated path off the start of `payload`
+fn split_path(payload: &[u8]) -> Option<(BadgePath, &[u8])> {
+    let end = payload.iter().position(|b| *b == 0)?;
+    let path = BadgePath::new(std::str::from_utf8(&payload[..end]).ok()?).ok()?;
+    Some((path, &payload[end + 1..]))
 }
 
-impl FsEntry {
-    pub fn name(&self) -> &str {
-        match self {
-            FsEntry::File(name) | FsEntry::Directory(name) => name,
-        }
+impl Command {
+    /// Reads a command as the badge receives it, the reverse of `command` and `write_payload`. Used
+    /// to serve commands of other processes over a shared connection.
+    pub fn parse(command: u16, payload: &[u8]) -> Option<Command> {
+        let path = || split_path(payload).map(|(path, _)| path);
+        let two_paths = || {
+            let (from, rest) = split_path(payload)?;
+            let (to, _) = split_path(rest)?;
+            Some((from, to))
+        };
+
+        Some(match command {
+            command::RUN_FILE => Command::RunFile { path: path()? },
+            command::HEARTBEAT => Command::Heartbeat,
+            command::SERIAL_IN => Command::SerialIn {
+                data: Bytes::copy_from_slice(payload),
+            },
+            command::FETCH_DIR => Command::FetchDir { path: path()? },
+            command::FETCH_FILE => Command::FetchFile { path: path()? },
+            command::WRITE_FILE => {
+                let (path, data) = split_path(payload)?;
+                Command::WriteFile {
+                    path,
+                    data: Bytes::copy_from_slice(data),
+                }
+            }
+            command::DELETE_PATH => Command::DeletePath { path: path()? },
+            command::COPY_FILE => {
+                let (from, to) = two_paths()?;
+                Command::CopyFile { from, to }
+            }
+            command::MOVE_FILE => {
+                let (from, to) = two_paths()?;
+                Command::MoveFile { from, to }
+            }
+            command::CREATE_DIR => Command::CreateDir { path: path()? },
+            share::PAUSE_WRITES => Command::PauseWrites {
+                paused: payload.first() == Some(&1),
+            },
+            _ => return None,
+        })
     }
 }
 
@@ -131,11 +237,48 @@ pub enum ResponseData {
     DirectoryListing(DirectoryListingResponse),
 
     /// If you request the contents of a non-existant file, you will get "Can\'t open file" back as contents
-    FileContents(Vec<u8>),
+    FileContents(Bytes),
     Ok,
     Error,
-    Timeout,
     Unknown,
+    /// The response couldn't be parsed, for the reason given
+    Malformed(String),
+}
+
+impl ResponseData {
+    /// The command and payload the badge sent for this response to a `request` command, or `None`
+    /// if the badge didn't send anything
+    pub fn encode(&self, request: u16) -> Option<(u16, Vec<u8>)> {
+        let mut payload = Vec::new();
+        let command = match self {
+            ResponseData::Log { text } => {
+                write_latin1(&mut payload, text);
+                command::LOG
+            }
+            ResponseData::DirectoryListing(DirectoryListingResponse::Found {
+                requested,
+                entries,
+            }) => {
+                protocol::write_listing(&mut payload, Some((requested, entries)));
+                command::FETCH_DIR
+            }
+            ResponseData::DirectoryListing(DirectoryListingResponse::DirectoryNotFound) => {
+                protocol::write_listing(&mut payload, None);
+                command::FETCH_DIR
+            }
+            ResponseData::FileContents(data) => {
+                payload.extend_from_slice(data);
+                command::FETCH_FILE
+            }
+            ResponseData::Ok | ResponseData::Error => {
+                protocol::write_status(&mut payload, matches!(self, ResponseData::Ok));
+                request
+            }
+            ResponseData::Unknown | ResponseData::Malformed(_) => return None,
+        };
+
+        Some((command, payload))
+    }
 }
 
 pub struct Response {
@@ -144,73 +287,183 @@ pub struct Response {
 }
 
 impl Response {
-    pub fn try_read(input: &mut Buffer) -> Result<Option<Response>, Box<dyn Error>> {
-        loop {
-            if input.len() < 12 {
-                return Ok(None);
+    /// Parses the payload of a response to `command`
+    fn parse(command: u16, data: &[u8]) -> Result<ResponseData, Box<dyn Error + Send + Sync>> {
+        Ok(match command {
+            command::LOG => ResponseData::Log { text: latin1(data) },
+            command::FETCH_DIR => {
+                ResponseData::DirectoryListing(match protocol::parse_listing(data) {
+                    Some((requested, entries)) => {
+                        DirectoryListingResponse::Found { requested, entries }
+                    }
+                    None => DirectoryListingResponse::DirectoryNotFound,
+                })
             }
-
-            let check = &input.buf()[6..8];
-            if check == [0xde, 0xad] {
-                break;
-            } else {
-                warn!("Invalid magic numbers in header: {:?}!", check);
-                input.consume(1);
+            command::FETCH_FILE => ResponseData::FileContents(Bytes::copy_from_slice(data)),
+            command::RUN_FILE
+            | command::HEARTBEAT
+            | command::SERIAL_IN
+            | command::WRITE_FILE
+            | command::DELETE_PATH
+            | command::COPY_FILE
+            | command::MOVE_FILE
+            | command::CREATE_DIR
+            | share::PAUSE_WRITES => {
+                if protocol::is_ok(data) {
+                    ResponseData::Ok
+                } else {
+                    ResponseData::Error
+                }
             }
-        }
-
-        let len = u32::from_le_bytes(input.buf()[2..6].try_into().unwrap()) as usize;
-        if input.len() < 12 + len {
-            debug!("Waiting on {}+12 input bytes", len);
-            return Ok(None);
-        }
+            _ => ResponseData::Unknown,
+        })
+    }
 
-        let command = u16::from_le_bytes(input.buf()[0..2].try_into().unwrap());
-        let message_id = u32::from_le_bytes(input.buf()[8..12].try_into().unwrap());
-        let data = &input.buf()[12..12 + len];
-        let data_str = data.iter().map(|b| *b as char).collect::<String>();
+    /// Reads the next response from `input`, or `None` if it isn't complete yet. A response that
+    /// can't be parsed, even one that makes the parser panic, becomes `ResponseData::Malformed`, so
+    /// a single bad frame doesn't take down the connection.
+    pub fn try_read(input: &mut Buffer) -> Option<Response> {
+        let frame = loop {
+            match protocol::next_frame(input.buf()) {
+                Next::Frame(frame) => break frame,
+                Next::Incomplete { needed } => {
+                    if let Some(needed) = needed {
+                        debug!("Waiting on {} input bytes", needed);
+                    }
+                    return None;
+                }
+                Next::Garbage => {
+                    warn!("Invalid magic numbers in header: {:?}!", &input.buf()[6..8]);
+                    input.consume(1);
+                }
+            }
+        };
+        let data = frame.payload;
 
+        // Only formatted when tracing is enabled
         trace!(
             "Received response: command={}, message_id={}, len={}, data={:?}, data_str={:?}",
-            command,
-            message_id,
-            len,
+            frame.command,
+            frame.message_id,
+            data.len(),
             data,
-            data_str
+            latin1(data)
         );
 
-        let data = match command {
-            3 => ResponseData::Log { text: data_str },
-            4096 => ResponseData::DirectoryListing(match data_str.as_str() {
-                "Directory_not_found" => DirectoryListingResponse::DirectoryNotFound,
-                _ => {
-                    let mut split = data_str.split('\n');
-                    DirectoryListingResponse::Found {
-                        requested: split.next().unwrap().to_owned(),
-                        entries: split
-                            .map(|x| match x.chars().next() {
-                                Some('f') => FsEntry::File(x[1..].to_owned()),
-                                Some('d') => FsEntry::Directory(x[1..].to_owned()),
-                                other => panic!("Unexpected type: {:?}", other),
-                            })
-                            .collect(),
-                    }
-                }
-            }),
-            4097 => ResponseData::FileContents(data.into()),
-            0 | 1 | 2 | 4098 | 4099 | 4100 | 4101 | 4102 => {
-                if data == [111, 107, 0] {
-                    ResponseData::Ok
-                } else {
-                    ResponseData::Error
-                }
+        let message_id = frame.message_id;
+        let size = frame.size();
+        let data = match panic::catch_unwind(|| Response::parse(frame.command, data)) {
+            Ok(Ok(data)) => data,
+            Ok(Err(e)) => {
+                warn!(
+                    "Unable to parse a response to command {}: {}",
+                    frame.command, e
+                );
+                ResponseData::Malformed(e.to_string())
+            }
+            Err(panic) => {
+                let reason = panic
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "the parser panicked".to_owned());
+                error!(
+                    "Parsing a response to command {} panicked: {}",
+                    frame.command, reason
+                );
+                ResponseData::Malformed(reason)
             }
-            _ => ResponseData::Unknown,
         };
 
         debug!("{:?}", data);
-        input.consume(12 + len);
+        input.consume(size);
+
+        Some(Response { message_id, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(command: u16, message_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        protocol::write_header(&mut out, command, payload.len() as u32, message_id);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn log(text: &str) -> Vec<u8> {
+        frame(command::LOG, 0, text.as_bytes())
+    }
+
+    /// Feeds `input` in reads of `chunk` bytes, like the receive loop does
+    fn read_chunked(input: &[u8], chunk: usize) -> Vec<Response> {
+        let mut buffer = Buffer::new_ringbuf();
+        let mut responses = Vec::new();
+        for read in input.chunks(chunk) {
+            buffer.push_bytes(read);
+            while let Some(response) = Response::try_read(&mut buffer) {
+                responses.push(response);
+            }
+        }
+        assert!(buffer.is_empty());
+        responses
+    }
+
+    fn describe(response: &Response) -> String {
+        match &response.data {
+            ResponseData::Log { text } => format!("{} log {}", response.message_id, text),
+            ResponseData::FileContents(data) => {
+                format!("{} file of {} bytes", response.message_id, data.len())
+            }
+            data => format!("{} {:?}", response.message_id, data),
+        }
+    }
+
+    #[test]
+    fn reads_a_big_file_split_across_reads() {
+        let contents: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut input = log("before");
+        input.extend(frame(command::FETCH_FILE, 7, &contents));
+        input.extend(log("between"));
+        input.extend(frame(command::FETCH_FILE, 8, b""));
+        input.extend(log("after"));
+
+        let responses = read_chunked(&input, 256);
+        let described: Vec<_> = responses.iter().map(describe).collect();
+        assert_eq!(
+            described,
+            [
+                "0 log before",
+                "7 file of 100000 bytes",
+                "0 log between",
+                "8 file of 0 bytes",
+                "0 log after",
+            ]
+        );
+        match &responses[1].data {
+            ResponseData::FileContents(data) => assert_eq!(&data[..], &contents[..]),
+            data => panic!("Expected the file, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn reads_frames_a_byte_at_a_time() {
+        let mut input = log("one");
+        input.extend(frame(command::WRITE_FILE, 3, b"ok\0"));
+        input.extend(frame(command::WRITE_FILE, 4, b"no\0"));
+
+        let described: Vec<_> = read_chunked(&input, 1).iter().map(describe).collect();
+        assert_eq!(described, ["0 log one", "3 Ok", "4 Error"]);
+    }
+
+    #[test]
+    fn skips_garbage_before_a_header() {
+        let mut input = b"\xde\xadjunk\0\0\0\0\0\0\0\0\0\0\0\0".to_vec();
+        input.extend(log("found"));
 
-        return Ok(Some(Response { message_id, data }));
+        let described: Vec<_> = read_chunked(&input, 5).iter().map(describe).collect();
+        assert_eq!(described, ["0 log found"]);
     }
 }
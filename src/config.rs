@@ -0,0 +1,154 @@
+//! Reads and writes the settings of the firmware, which are stored in NVS as strings and integers
+//! under a namespace and key, like `owner/nickname`. NVS can't be enumerated from the Python shell,
+//! so `config list` only shows the keys the firmware is known to use.
+
+use crate::{
+    device::Badge,
+    output::Output,
+    repl::{self, python_literal},
+};
+use std::{error::Error, str::FromStr, time::Duration};
+use structopt::StructOpt;
+use thiserror::Error;
+
+const REPL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings of the firmware that `config list` shows when they are set
+const KNOWN_KEYS: &[&str] = &[
+    "owner/nickname",
+    "system/wifi.ssid",
+    "system/wifi.password",
+    "system/orientation",
+    "system/brightness",
+];
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid key {:?}. Use <namespace>/<key>, like owner/nickname.", .0)]
+    InvalidKey(String),
+
+    #[error("{} is not set", .0)]
+    NotSet(Key),
+
+    #[error("{} holds an integer, but {:?} isn't one", .0, .1)]
+    NotAnInteger(Key, String),
+}
+
+/// A setting, as namespace and key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    namespace: String,
+    key: String,
+}
+
+impl FromStr for Key {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Key, ConfigError> {
+        match s.split_once('/') {
+            Some((namespace, key)) if !namespace.is_empty() && !key.is_empty() => Ok(Key {
+                namespace: namespace.to_owned(),
+                key: key.to_owned(),
+            }),
+            _ => Err(ConfigError::InvalidKey(s.to_owned())),
+        }
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.key)
+    }
+}
+
+#[derive(StructOpt, Clone)]
+pub enum ConfigCommand {
+    #[structopt(about = "Prints the value of a setting, like owner/nickname")]
+    Get { key: Key },
+
+    #[structopt(about = "Changes a setting. Existing settings keep their type.")]
+    Set {
+        key: Key,
+
+        value: String,
+
+        #[structopt(long, help = "Store a new setting as an integer instead of a string")]
+        int: bool,
+    },
+
+    #[structopt(about = "Prints the settings that the firmware is known to use")]
+    List,
+}
+
+/// The type and value of a setting as `str<TAB>value` or `int<TAB>value`, or `None` if it isn't set
+async fn get(
+    badge: &Badge,
+    key: &Key,
+) -> Result<Option<(String, String)>, Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import machine
+for t in ('str', 'int'):
+ try:
+  v = getattr(machine, 'nvs_get' + t)({}, {})
+ except Exception:
+  continue
+ if v is not None:
+  print('%s\\t%s' % (t, v))
+  break",
+        python_literal(&key.namespace),
+        python_literal(&key.key)
+    );
+
+    let output = repl::exec(badge, &code, REPL_TIMEOUT).await?;
+    Ok(output
+        .trim_end_matches('\n')
+        .split_once('\t')
+        .map(|(kind, value)| (kind.to_owned(), value.to_owned())))
+}
+
+/// Runs a `config` subcommand
+pub async fn command(
+    badge: &Badge,
+    command: ConfigCommand,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match command {
+        ConfigCommand::Get { key } => match get(badge, &key).await? {
+            Some((_, value)) => println!("{}", value),
+            None => Err(ConfigError::NotSet(key))?,
+        },
+        ConfigCommand::Set { key, value, int } => {
+            let int = match get(badge, &key).await? {
+                Some((kind, _)) => kind == "int",
+                None => int,
+            };
+            let literal = if int {
+                value
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| ConfigError::NotAnInteger(key.clone(), value.clone()))?
+                    .to_string()
+            } else {
+                python_literal(&value)
+            };
+            let code = format!(
+                "import machine\nmachine.nvs_set{}({}, {}, {})",
+                if int { "int" } else { "str" },
+                python_literal(&key.namespace),
+                python_literal(&key.key),
+                literal
+            );
+            repl::exec(badge, &code, REPL_TIMEOUT).await?;
+        }
+        ConfigCommand::List => {
+            for key in KNOWN_KEYS.iter() {
+                let key = key.parse()?;
+                if let Some((_, value)) = get(badge, &key).await? {
+                    output.setting(&key.to_string(), &value);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use crate::{
+    cache::hash,
+    device::Badge,
+    path::BadgePath,
+    repl::{self, python_literal},
+};
+use std::{error::Error, time::Duration};
+use tracing::{debug, info};
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Files smaller than this are always sent in full, because hashing costs more than it saves
+const MIN_DELTA_SIZE: usize = 64 * 1024;
+
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The size of the file on the badge and the hashes of each of its blocks, or `None` if it
+/// doesn't exist
+async fn remote_hashes(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<Option<(usize, Vec<String>)>, Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import uhashlib, ubinascii
+try:
+ f = open({}, 'rb')
+ print(f.seek(0, 2))
+ f.seek(0)
+except OSError:
+ f = None
+ print('missing')
+while f:
+ b = f.read({})
+ if not b:
+  f.close()
+  break
+ print(ubinascii.hexlify(uhashlib.sha256(b).digest()).decode())",
+        python_literal(path.as_str()),
+        BLOCK_SIZE
+    );
+
+    let output = repl::exec(badge, &code, REPL_TIMEOUT).await?;
+    let lines = output
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .collect::<Vec<_>>();
+    match lines.split_first() {
+        Some((first, _)) if first == "missing" => Ok(None),
+        Some((size, hashes)) => Ok(Some((size.parse()?, hashes.to_vec()))),
+        None => Err(repl::ReplError::Exception("no output".to_owned()))?,
+    }
+}
+
+/// Writes `data` to `path`, only sending the blocks that differ from the file already on the
+/// badge. The changed blocks are uploaded to a temporary file and patched into place by a Python
+/// snippet. Falls back to sending the entire file when that is simpler.
+pub async fn write_file(
+    badge: &Badge,
+    path: &BadgePath,
+    data: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if data.len() < MIN_DELTA_SIZE || !badge.capabilities().python_shell {
+        return badge.write_file(path, data).await;
+    }
+
+    let (remote_size, remote) = match remote_hashes(badge, path).await {
+        Ok(Some(remote)) => remote,
+        Ok(None) => return badge.write_file(path, data).await,
+        Err(e) => {
+            info!(
+                "Unable to hash {} on the badge, sending it in full: {}",
+                path, e
+            );
+            return badge.write_file(path, data).await;
+        }
+    };
+
+    // MicroPython can't truncate files, so shrinking requires a full upload
+    if remote_size > data.len() {
+        return badge.write_file(path, data).await;
+    }
+
+    let mut offsets = Vec::new();
+    let mut changed = Vec::new();
+    for (index, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        if remote.get(index) != Some(&hash(block)) {
+            offsets.push(index * BLOCK_SIZE);
+            changed.extend_from_slice(block);
+        }
+    }
+
+    debug!(
+        "{} of {} blocks of {} changed",
+        offsets.len(),
+        data.len().div_ceil(BLOCK_SIZE),
+        path
+    );
+    if offsets.is_empty() {
+        return Ok(());
+    }
+
+    if changed.len() == data.len() {
+        return badge.write_file(path, data).await;
+    }
+
+    let patch = BadgePath::new(format!("{}.delta", path))?;
+    badge.write_file(&patch, &changed).await?;
+
+    let code = format!(
+        "import uos
+src = open({patch}, 'rb')
+dst = open({path}, 'r+b')
+for offset in {offsets:?}:
+ dst.seek(offset)
+ dst.write(src.read({block}))
+dst.close()
+src.close()
+uos.remove({patch})",
+        patch = python_literal(patch.as_str()),
+        path = python_literal(path.as_str()),
+        offsets = offsets,
+        block = BLOCK_SIZE
+    );
+    repl::exec(badge, &code, REPL_TIMEOUT).await?;
+
+    Ok(())
+}
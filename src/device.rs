@@ -1,34 +1,273 @@
-use crate::cmds::{Command, DirectoryListingResponse, Response, ResponseData};
+use crate::{
+    backoff::Backoff,
+    capabilities::{self, Capabilities},
+    cmds::{Command, DirectoryListingResponse, Priority, Response, ResponseData},
+    health::{Health, HeartbeatTracker},
+    latency::{LatencyTrace, Phases},
+    lock::{self, DeviceLock, LockError},
+    pacing::Pacer,
+    path::BadgePath,
+    repl_fs, share,
+    stats::{BadgeStats, Metrics},
+};
 use buf_redux::Buffer;
-use log::{debug, info, trace, warn};
+use bytes::Bytes;
+use cz2020_protocol::Next;
 use rusb::{Context, DeviceHandle, UsbContext};
 use std::{
     collections::HashMap,
     error::Error,
+    io::{ErrorKind, Read, Write},
+    os::unix::net::UnixStream,
+    pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
     },
-    task::{Poll, Waker},
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
-use std::{future::Future, io::Write};
+use std::{fmt, future::Future};
 use thiserror::Error;
+use tokio::sync::{broadcast, oneshot, RwLock, RwLockWriteGuard, Semaphore};
+use tracing::{debug, debug_span, error, field, info, trace, warn, Instrument, Span};
+
+pub const VENDOR_ID: u16 = 0xcafe;
+pub const PRODUCT_ID: u16 = 0x4011;
+pub const ENDPOINT_OUT: u8 = 0x03;
+pub const ENDPOINT_IN: u8 = 0x83;
+
+/// The maximum size of a USB packet of the badge
+const PACKET_SIZE: usize = 64;
+const DEFAULT_RECEIVE_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How often requests that took too long are timed out
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The longest a single read from the badge blocks, so the receive thread notices soon after
+/// `close()` that it should stop, instead of after the whole receive timeout
+const RECEIVE_SLICE: Duration = Duration::from_millis(100);
+
+/// Room made for a response that spans several reads is capped, in case its length is garbage
+const MAX_RESERVE: usize = 1024 * 1024;
 
 #[derive(Error, Debug)]
 pub enum LibUsbError {
     #[error("No device found")]
     NoDeviceFound,
+
+    #[error("No badge with id {} is connected", .0)]
+    NoSuchDevice(String),
+
+    #[error("Unable to open the badge: {}", .0)]
+    Open(#[from] rusb::Error),
+
+    #[error("{}", .0)]
+    Locked(#[from] LockError),
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub firmware_version: String,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl DeviceInfo {
+    /// A name that identifies this badge, even when multiple badges are connected
+    pub fn id(&self) -> String {
+        match &self.serial_number {
+            Some(serial) if !serial.is_empty() => serial.clone(),
+            _ => self.port(),
+        }
+    }
+
+    /// Where the badge is connected, as `<bus>-<address>`. Unlike the serial number, this is known
+    /// without opening the device.
+    pub fn port(&self) -> String {
+        format!("{:03}-{:03}", self.bus, self.address)
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bus: {:03}", self.bus)?;
+        writeln!(f, "address: {:03}", self.address)?;
+        writeln!(f, "id: {:04x}:{:04x}", self.vendor_id, self.product_id)?;
+        writeln!(f, "firmware_version: {}", self.firmware_version)?;
+        writeln!(
+            f,
+            "manufacturer: {}",
+            self.manufacturer.as_deref().unwrap_or("<unknown>")
+        )?;
+        writeln!(
+            f,
+            "product: {}",
+            self.product.as_deref().unwrap_or("<unknown>")
+        )?;
+        writeln!(
+            f,
+            "serial_number: {}",
+            self.serial_number.as_deref().unwrap_or("<unknown>")
+        )
+    }
+}
+
+/// How the badge is reached
+enum Link {
+    Usb(DeviceHandle<Context>),
+    /// Through another process that has the badge open, see `share`
+    Shared(UnixStream),
 }
 
 pub struct Device {
-    handle: DeviceHandle<Context>,
+    link: Link,
+    info: DeviceInfo,
+    /// Held while the badge is open. Shared badges are locked by the process that shares them.
+    _lock: Option<DeviceLock>,
 }
 
 impl Device {
+    /// Opens the first badge. Other badges aren't opened, which would reset them.
     pub fn new(context: &Context) -> Result<Device, LibUsbError> {
-        for device in context.devices().unwrap().iter() {
-            let device_desc = device.device_descriptor().unwrap();
+        let device = Device::badges(context)?
+            .into_iter()
+            .next()
+            .ok_or(LibUsbError::NoDeviceFound)?;
+        Device::open(&device)
+    }
+
+    /// Opens a specific USB device, which should be a badge
+    pub fn open(device: &rusb::Device<Context>) -> Result<Device, LibUsbError> {
+        // Before resetting, which would disturb another process that is using the badge
+        let lock = lock::acquire(&format!(
+            "{:03}-{:03}",
+            device.bus_number(),
+            device.address()
+        ))?;
+        let mut handle = device.open()?;
+        handle.reset()?;
+
+        let info = Device::read_info(device, &handle)?;
+        Ok(Device {
+            link: Link::Usb(handle),
+            info,
+            _lock: Some(lock),
+        })
+    }
+
+    /// A badge that another process has open and shares on `stream`
+    pub(crate) fn shared(stream: UnixStream, info: DeviceInfo) -> Device {
+        Device {
+            link: Link::Shared(stream),
+            info,
+            _lock: None,
+        }
+    }
+
+    fn read_info(
+        device: &rusb::Device<Context>,
+        handle: &DeviceHandle<Context>,
+    ) -> Result<DeviceInfo, rusb::Error> {
+        let device_desc = device.device_descriptor()?;
+        let version = device_desc.device_version();
+        Ok(DeviceInfo {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id: device_desc.vendor_id(),
+            product_id: device_desc.product_id(),
+            firmware_version: format!(
+                "{}.{}.{}",
+                version.major(),
+                version.minor(),
+                version.sub_minor()
+            ),
+            manufacturer: handle.read_manufacturer_string_ascii(&device_desc).ok(),
+            product: handle.read_product_string_ascii(&device_desc).ok(),
+            serial_number: handle.read_serial_number_string_ascii(&device_desc).ok(),
+        })
+    }
+
+    fn is_badge(device: &rusb::Device<Context>) -> bool {
+        device
+            .device_descriptor()
+            .map(|desc| desc.vendor_id() == VENDOR_ID && desc.product_id() == PRODUCT_ID)
+            .unwrap_or(false)
+    }
+
+    /// Describes every connected badge without resetting it, so badges that are in use by other
+    /// processes aren't disturbed. Badges that can't be opened are reported and left out.
+    pub fn list(context: &Context) -> Result<Vec<DeviceInfo>, LibUsbError> {
+        let mut result = Vec::new();
+        for device in context.devices()?.iter() {
+            if Device::is_badge(&device) {
+                match Device::describe(&device) {
+                    Ok(info) => result.push(info),
+                    Err(e) => warn!(
+                        "Skipping the badge at {:03}-{:03}: {}",
+                        device.bus_number(),
+                        device.address(),
+                        e
+                    ),
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn describe(device: &rusb::Device<Context>) -> Result<DeviceInfo, rusb::Error> {
+        Device::read_info(device, &device.open()?)
+    }
+
+    /// Opens the badge with `id`, which is either its serial number or its port, or the first
+    /// badge without `id`. Other badges aren't reset.
+    pub fn find(context: &Context, id: Option<&str>) -> Result<Device, LibUsbError> {
+        // Opening would reset a badge that another process is using
+        if let Some(device) = share::connect(id) {
+            return Ok(device);
+        }
+
+        let id = match id {
+            Some(id) => id,
+            None => return Device::new(context),
+        };
+
+        for device in context.devices()?.iter() {
+            if !Device::is_badge(&device) {
+                continue;
+            }
+
+            let info = match Device::describe(&device) {
+                Ok(info) => info,
+                Err(e) => {
+                    warn!(
+                        "Skipping the badge at {:03}-{:03}: {}",
+                        device.bus_number(),
+                        device.address(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if info.port() == id || info.serial_number.as_deref() == Some(id) {
+                return Device::open(&device);
+            }
+        }
+
+        Err(LibUsbError::NoSuchDevice(id.to_owned()))
+    }
+
+    /// The connected badges, without opening them
+    fn badges(context: &Context) -> Result<Vec<rusb::Device<Context>>, LibUsbError> {
+        let mut result = Vec::new();
+        for device in context.devices()?.iter() {
+            let device_desc = device.device_descriptor()?;
 
             debug!(
                 "Bus {:03} Device {:03} ID {:04x}:{:04x}",
@@ -38,28 +277,53 @@ impl Device {
                 device_desc.product_id()
             );
 
-            if device_desc.vendor_id() == 0xcafe && device_desc.product_id() == 0x4011 {
+            if device_desc.vendor_id() == VENDOR_ID && device_desc.product_id() == PRODUCT_ID {
                 trace!("Found badge!");
-
-                let mut handle = device.open().unwrap();
-                handle.reset().unwrap();
-
-                return Ok(Device { handle });
+                result.push(device);
             }
         }
 
-        Err(LibUsbError::NoDeviceFound)
+        Ok(result)
+    }
+
+    /// Opens every connected badge
+    pub fn all(context: &Context) -> Result<Vec<Device>, LibUsbError> {
+        let result = Device::badges(context)?
+            .iter()
+            .map(Device::open)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if result.is_empty() {
+            Err(LibUsbError::NoDeviceFound)
+        } else {
+            Ok(result)
+        }
     }
 }
 
 impl Device {
-    fn send(&self, data: &[u8]) -> Result<(), Box<dyn Error>> {
-        let timeout = Duration::from_secs(10000);
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// Whether another process has the badge open and shares it
+    fn is_shared(&self) -> bool {
+        matches!(self.link, Link::Shared(_))
+    }
+
+    fn send(&self, data: &[u8], timeout: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
         debug!("Sending bytes {:?}", data);
-        let mut total_sent = 0;
+        let handle = match &self.link {
+            Link::Usb(handle) => handle,
+            Link::Shared(stream) => {
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok((&*stream).write_all(data)?);
+            }
+        };
 
+        let mut total_sent = 0;
         loop {
-            let sent = self.handle.write_bulk(3, &data[total_sent..], timeout)?;
+            let sent = handle.write_bulk(ENDPOINT_OUT, &data[total_sent..], timeout)?;
             total_sent += sent;
 
             if total_sent >= data.len() {
@@ -70,17 +334,34 @@ impl Device {
         Ok(())
     }
 
-    fn receive(&self, data: &mut [u8]) -> Result<usize, Box<dyn Error>> {
-        Ok(
-            match self.handle.read_bulk(131, data, Duration::from_secs(15)) {
-                Ok(len) => len,
-                Err(rusb::Error::Timeout) => 0,
-                other => other?,
-            },
-        )
+    fn receive(
+        &self,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let handle = match &self.link {
+            Link::Usb(handle) => handle,
+            Link::Shared(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                return match (&*stream).read(data) {
+                    Ok(0) => Err(share::ShareError::Closed)?,
+                    Ok(len) => Ok(len),
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        Ok(0)
+                    }
+                    Err(e) => Err(e)?,
+                };
+            }
+        };
+
+        Ok(match handle.read_bulk(ENDPOINT_IN, data, timeout) {
+            Ok(len) => len,
+            Err(rusb::Error::Timeout) => 0,
+            other => other?,
+        })
     }
 
-    fn reset(&self) -> Result<(), Box<dyn Error>> {
+    fn reset(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
         info!("Resetting USB device");
         // self.handle.reset()?;
 
@@ -89,40 +370,110 @@ impl Device {
 }
 
 struct BadgeData {
-    wakers: HashMap<u32, Arc<Mutex<BadgeRequestData>>>,
+    pending: HashMap<u32, PendingRequest>,
     last_message_id: u32,
 }
 
 pub struct Badge {
     device: Device,
-    abort: AtomicBool,
+    /// Set once the badge is closed. Shared with requests, so they can tell closing from a timeout.
+    abort: Arc<AtomicBool>,
     data: Mutex<BadgeData>,
+    stats: BadgeStats,
+    heartbeats: HeartbeatTracker,
+    output: Mutex<Option<broadcast::Sender<String>>>,
+    pipeline: Option<Semaphore>,
+    /// Held while a packet is sent in pieces, so other packets don't end up in between
+    pacer: Option<Mutex<Pacer>>,
+    config: BadgeConfig,
+    capabilities: Mutex<Capabilities>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// Read by every write while it runs, and taken by `pause_writes`
+    writes: RwLock<()>,
 }
 
-pub struct BadgeRequestData {
-    response: Option<Response>,
-    waker: Option<Waker>,
+/// A command that was sent and is waiting for its response
+struct PendingRequest {
+    /// Hands the response to the `BadgeRequest`. Dropping it without sending times the request out.
+    sender: oneshot::Sender<ResponseData>,
+    command: &'static str,
     at: Instant,
+    /// Heartbeats only count towards the health of the connection
+    heartbeat: bool,
+    /// The phases up to and including the write, for `--latency-trace`
+    phases: Phases,
+}
+
+/// A request that is waiting for its response, as listed by `Badge::outstanding`
+#[derive(Debug, Clone)]
+pub struct Outstanding {
+    pub message_id: u32,
+    pub command: &'static str,
+    /// How long ago the request was sent
+    pub pending: Duration,
+}
+
+impl fmt::Display for Outstanding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} pending for {:.1?}",
+            self.message_id, self.command, self.pending
+        )
+    }
+}
+
+/// Holds off writes to the badge while it is kept, see `Badge::pause_writes`
+pub struct WritePause<'a> {
+    badge: &'a Badge,
+    _guard: RwLockWriteGuard<'a, ()>,
 }
 
+impl Drop for WritePause<'_> {
+    fn drop(&mut self) {
+        if self.badge.device.is_shared() {
+            // Not waited for, the process that shares the badge also resumes when we disconnect
+            if let Err(e) = self.badge.cmd_once(Command::PauseWrites { paused: false }) {
+                debug!("Unable to resume writes: {}", e);
+            }
+        }
+    }
+}
+
+/// Resolves to the response of a command. The response is moved into the request when it arrives,
+/// so the badge doesn't keep it around and it is never copied.
 pub struct BadgeRequest {
-    data: Arc<Mutex<BadgeRequestData>>,
+    receiver: oneshot::Receiver<ResponseData>,
+    command: &'static str,
+    message_id: u32,
+    at: Instant,
+    closed: Arc<AtomicBool>,
 }
 
 impl Future for BadgeRequest {
-    type Output = ResponseData;
+    type Output = Result<ResponseData, BadgeError>;
 
     fn poll(
-        self: std::pin::Pin<&mut Self>,
+        mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
-        let mut data = self.data.lock().unwrap();
-        if let Some(response) = &data.response {
-            Poll::Ready(response.data.clone())
-        } else {
-            data.waker = Some(cx.waker().clone());
-            Poll::Pending
-        }
+        let (command, message_id, elapsed) = (self.command, self.message_id, self.at.elapsed());
+        let closed = self.closed.clone();
+        // The sender is dropped when the request times out, or when the badge is closed
+        Pin::new(&mut self.receiver).poll(cx).map(|result| {
+            result.map_err(|_| {
+                if closed.load(Ordering::Relaxed) {
+                    BadgeError::Closed
+                } else {
+                    BadgeError::Timeout {
+                        command,
+                        message_id,
+                        attempts: 1,
+                        elapsed,
+                    }
+                }
+            })
+        })
     }
 }
 
@@ -133,91 +484,449 @@ pub enum BadgeError {
 
     #[error("Execution of the command failed")]
     CommandFailed,
+
+    #[error("The badge didn't respond to {} (message {}) within {:.1?}, after {} attempt(s). Check that the badge is connected and not busy running an app, or raise --timeout or --retries.", .command, .message_id, .elapsed, .attempts)]
+    Timeout {
+        command: &'static str,
+        /// Of the last attempt
+        message_id: u32,
+        attempts: u32,
+        elapsed: Duration,
+    },
+
+    #[error("The connection to the badge was closed")]
+    Closed,
+
+    #[error("Listing {} failed: {} A listing is sent in one response, which a very large directory may not fit in. Stop the running app, so it can be listed in parts through the Python shell.", .0, .1)]
+    ListingFailed(BadgePath, String),
 }
 
-impl Badge {
-    pub fn new(device: Device) -> Badge {
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of times a command is retried when the badge doesn't respond, or `None` to retry forever
+    pub max_retries: Option<u32>,
+
+    /// Number of failed attempts after which each retry also sends some serial input to wake up the badge
+    pub wake_after: u32,
+
+    /// Delay before sending the wake-up input, which grows with each attempt
+    pub backoff: Backoff,
+
+    /// Reset the USB device after this many failed attempts
+    pub reset_every: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: None,
+            wake_after: 2,
+            backoff: Backoff::new(Duration::from_millis(500), Duration::from_secs(8)),
+            reset_every: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BadgeConfig {
+    pub heartbeat_interval: Duration,
+
+    /// How long to wait for a response before a request is considered to have timed out
+    pub request_timeout: Duration,
+    pub send_timeout: Duration,
+    /// The longest a single read waits for data, which is at most 100 ms regardless, so closing
+    /// the badge doesn't wait for it
+    pub receive_timeout: Duration,
+    pub retry: RetryPolicy,
+
+    /// Maximum number of commands that are waiting for a response at the same time, or `None` for no limit
+    pub pipelining_depth: Option<usize>,
+
+    /// Number of output messages a subscriber can fall behind before it starts missing messages
+    pub output_capacity: usize,
+
+    /// Perform file operations through the Python shell instead of the file commands
+    pub via_repl: bool,
+
+    /// Maximum number of bytes read from the badge at once. A multiple of the USB packet size of
+    /// 64 bytes, as the badge can send more than fits otherwise.
+    pub receive_buffer_size: usize,
+
+    /// Maximum number of bytes sent per second, or `None` to send as fast as possible. Saturating
+    /// the USB handler of the badge can starve the running app and trigger the watchdog.
+    pub rate_limit: Option<u64>,
+
+    /// Print the requests that have been waiting for a response for at least this long, every so
+    /// often, or `None` to not print them
+    pub debug_requests: Option<Duration>,
+
+    /// Records how long each phase of every command took
+    pub latency_trace: Option<Arc<LatencyTrace>>,
+}
+
+impl Default for BadgeConfig {
+    fn default() -> BadgeConfig {
+        BadgeConfig {
+            heartbeat_interval: Duration::from_millis(250),
+            request_timeout: Duration::from_secs(10),
+            send_timeout: Duration::from_secs(10000),
+            receive_timeout: Duration::from_secs(15),
+            retry: RetryPolicy::default(),
+            pipelining_depth: None,
+            output_capacity: 1024,
+            via_repl: false,
+            receive_buffer_size: DEFAULT_RECEIVE_BUFFER_SIZE,
+            rate_limit: None,
+            debug_requests: None,
+            latency_trace: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BadgeBuilder {
+    config: BadgeConfig,
+}
+
+impl BadgeBuilder {
+    pub fn heartbeat_interval(mut self, interval: Duration) -> BadgeBuilder {
+        self.config.heartbeat_interval = interval;
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> BadgeBuilder {
+        self.config.request_timeout = timeout;
+        self
+    }
+
+    pub fn send_timeout(mut self, timeout: Duration) -> BadgeBuilder {
+        self.config.send_timeout = timeout;
+        self
+    }
+
+    pub fn receive_timeout(mut self, timeout: Duration) -> BadgeBuilder {
+        self.config.receive_timeout = timeout;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> BadgeBuilder {
+        self.config.retry = retry;
+        self
+    }
+
+    pub fn pipelining_depth(mut self, depth: Option<usize>) -> BadgeBuilder {
+        self.config.pipelining_depth = depth;
+        self
+    }
+
+    pub fn output_capacity(mut self, capacity: usize) -> BadgeBuilder {
+        self.config.output_capacity = capacity;
+        self
+    }
+
+    pub fn via_repl(mut self, via_repl: bool) -> BadgeBuilder {
+        self.config.via_repl = via_repl;
+        self
+    }
+
+    /// Rounded up to a multiple of the USB packet size
+    pub fn receive_buffer_size(mut self, size: usize) -> BadgeBuilder {
+        self.config.receive_buffer_size = size.max(1).div_ceil(PACKET_SIZE) * PACKET_SIZE;
+        self
+    }
+
+    pub fn rate_limit(mut self, rate: Option<u64>) -> BadgeBuilder {
+        self.config.rate_limit = rate;
+        self
+    }
+
+    pub fn debug_requests(mut self, after: Option<Duration>) -> BadgeBuilder {
+        self.config.debug_requests = after;
+        self
+    }
+
+    pub fn latency_trace(mut self, trace: Option<Arc<LatencyTrace>>) -> BadgeBuilder {
+        self.config.latency_trace = trace;
+        self
+    }
+
+    pub fn build(self, device: Device) -> Badge {
+        let config = self.config;
         Badge {
             device,
-            abort: AtomicBool::new(false),
+            abort: Arc::new(AtomicBool::new(false)),
             data: Mutex::new(BadgeData {
-                wakers: HashMap::new(),
+                pending: HashMap::new(),
                 last_message_id: 0,
             }),
+            stats: BadgeStats::new(),
+            heartbeats: HeartbeatTracker::new(),
+            output: Mutex::new(Some(broadcast::channel(config.output_capacity).0)),
+            pipeline: config.pipelining_depth.map(Semaphore::new),
+            pacer: config.rate_limit.map(|rate| Mutex::new(Pacer::new(rate))),
+            config,
+            capabilities: Mutex::new(Capabilities::default()),
+            threads: Mutex::new(Vec::new()),
+            writes: RwLock::new(()),
         }
     }
+}
 
+impl Badge {
+    pub fn new(device: Device) -> Badge {
+        Badge::builder().build(device)
+    }
+
+    pub fn builder() -> BadgeBuilder {
+        BadgeBuilder {
+            config: BadgeConfig::default(),
+        }
+    }
+
+    /// Closes the connection. Pending commands fail with `BadgeError::Closed` and subscribers to the
+    /// output receive `RecvError::Closed`. Blocks until the IO threads have exited, which can take
+    /// up to the receive timeout.
     pub fn close(&self) {
         self.abort.store(true, Ordering::Relaxed);
+        self.output
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        // Dropping the senders fails the pending requests with `BadgeError::Closed`
+        self.data().pending.clear();
+
+        let threads = self.threads.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for handle in threads {
+            // close() can end up being called from an IO thread when it drops the last reference
+            if handle.thread().id() != thread::current().id() && handle.join().is_err() {
+                warn!("IO thread panicked");
+            }
+        }
+    }
+
+    /// A panic while the data was locked doesn't leave it inconsistent, as every change to it is a
+    /// single insert or removal, so the lock is recovered instead of failing every later request
+    fn data(&self) -> MutexGuard<'_, BadgeData> {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
-    fn send(&self, message_id: u32, command: Command) -> Result<(), Box<dyn Error>> {
+    /// Subscribes to everything the badge prints. Each subscriber receives its own copy of the output.
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        match &*self.output.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(sender) => sender.subscribe(),
+            // Already closed, so hand out a receiver that reports that right away
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Sends a command, and returns how long it took to encode and write it
+    fn send(
+        &self,
+        message_id: u32,
+        command: Command,
+    ) -> Result<Phases, Box<dyn Error + Send + Sync>> {
         trace!("Requesting {:?} with message id {}", command, message_id);
 
-        let bytes = command.to_bytes();
-        let size = bytes.len() as u32;
-        let mut packet = Vec::new();
-        packet.write(&command.command().to_le_bytes())?;
-        packet.write(&size.to_le_bytes())?;
-        packet.write(&[0xde, 0xad])?;
-        packet.write(&message_id.to_le_bytes())?;
-        packet.write(&bytes)?;
+        let start = Instant::now();
+        let size = command.payload_len();
+        let mut packet = Vec::with_capacity(cz2020_protocol::HEADER_LEN + size);
+        cz2020_protocol::write_header(&mut packet, command.command(), size as u32, message_id);
+        command.write_payload(&mut packet);
+        let encoded = Instant::now();
+
+        match &self.pacer {
+            Some(pacer) => {
+                let mut pacer = pacer.lock().unwrap();
+                for piece in packet.chunks(pacer.piece_size()) {
+                    pacer.wait(piece.len());
+                    self.device.send(piece, self.config.send_timeout)?;
+                }
+            }
+            None => self.device.send(&packet, self.config.send_timeout)?,
+        }
+        self.stats.record_sent(packet.len());
+        trace!(message_id, bytes = packet.len(), "Sent {}", command.name());
 
-        self.device.send(&packet)?;
+        Ok(Phases {
+            encode: encoded - start,
+            write: encoded.elapsed(),
+            ..Phases::default()
+        })
+    }
 
-        Ok(())
+    pub fn cmd_once(&self, command: Command) -> Result<BadgeRequest, Box<dyn Error + Send + Sync>> {
+        let request = self.request(command)?;
+        self.stats.record_command_sent();
+
+        Ok(request)
     }
 
-    pub fn cmd_once(&self, command: Command) -> Result<BadgeRequest, Box<dyn Error>> {
-        let mut data = self.data.lock().unwrap();
+    /// Sends `command` without counting it in the stats
+    fn request(&self, command: Command) -> Result<BadgeRequest, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data();
+        if self.abort.load(Ordering::Relaxed) {
+            Err(BadgeError::Closed)?
+        }
+
         data.last_message_id += 1;
         let message_id = data.last_message_id;
         trace!("Requesting {:?} with message id {}", command, message_id);
-        let request_data = Arc::new(Mutex::new(BadgeRequestData {
-            waker: None,
-            response: None,
-            at: Instant::now(),
-        }));
-        data.wakers.insert(message_id, request_data.clone());
+        let (sender, receiver) = oneshot::channel();
+        data.pending.insert(
+            message_id,
+            PendingRequest {
+                sender,
+                command: command.name(),
+                at: Instant::now(),
+                heartbeat: false,
+                phases: Phases::default(),
+            },
+        );
+        // Sending can take a while with a rate limit, so don't keep responses from being received
+        drop(data);
+
+        let name = command.name();
+        let phases = self.send(message_id, command)?;
+        // The badge can't respond before it received the entire command
+        let at = Instant::now();
+        if let Some(pending) = self.data().pending.get_mut(&message_id) {
+            pending.at = at;
+            pending.phases = phases;
+        }
 
-        self.send(message_id, command)?;
+        Ok(BadgeRequest {
+            receiver,
+            command: name,
+            message_id,
+            at,
+            closed: self.abort.clone(),
+        })
+    }
 
-        Ok(BadgeRequest { data: request_data })
+    /// Sends a heartbeat without waiting for the response, which is recorded for `health`
+    fn send_heartbeat(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut data = self.data();
+        data.last_message_id += 1;
+        let message_id = data.last_message_id;
+        data.pending.insert(
+            message_id,
+            PendingRequest {
+                // Nobody waits for the response
+                sender: oneshot::channel().0,
+                command: Command::Heartbeat.name(),
+                at: Instant::now(),
+                heartbeat: true,
+                phases: Phases::default(),
+            },
+        );
+        drop(data);
+
+        self.send(message_id, Command::Heartbeat)?;
+        Ok(())
+    }
+
+    /// Sends `command` and waits for the response, retrying according to the retry policy
+    pub async fn cmd(
+        &self,
+        command: Command,
+    ) -> Result<ResponseData, Box<dyn Error + Send + Sync>> {
+        let span = debug_span!(
+            "cmd",
+            command = command.name(),
+            bytes = command.payload_len(),
+            attempts = field::Empty
+        );
+        self.cmd_with_retries(command).instrument(span).await
     }
 
-    pub async fn cmd(&self, command: Command) -> Result<ResponseData, Box<dyn Error>> {
-        let mut i: i32 = 0;
+    async fn cmd_with_retries(
+        &self,
+        command: Command,
+    ) -> Result<ResponseData, Box<dyn Error + Send + Sync>> {
+        // Waits until writes are no longer paused
+        let _writing = if command.is_write() {
+            Some(self.writes.read().await)
+        } else {
+            None
+        };
+        // Only bulk commands count towards the pipelining depth, so typing in the shell stays
+        // responsive during a big transfer. A packet that is being paced still goes out first.
+        let _permit = match &self.pipeline {
+            Some(pipeline) if command.priority() == Priority::Bulk => {
+                Some(pipeline.acquire().await)
+            }
+            _ => None,
+        };
+
+        let retry = &self.config.retry;
+        let start = Instant::now();
+        let mut i: u32 = 0;
         loop {
             trace!("Attempt {}", i);
             let result = self.cmd_once(command.clone())?;
-            if i > 1 {
-                std::thread::sleep(Duration::from_millis(500));
+            if i >= retry.wake_after {
+                tokio::time::delay_for(retry.backoff.delay(i - retry.wake_after)).await;
                 // Send some serial input to wake up the device
-                self.cmd_once(Command::SerialIn {
-                    data: "\r\n\r\n\r\n\r\n".as_bytes().into(),
-                })?
-                .await;
+                let _ = self
+                    .request(Command::SerialIn {
+                        data: Bytes::from_static(b"\r\n\r\n\r\n\r\n"),
+                    })?
+                    .await;
             }
-            let result = result.await;
+            match result.await {
+                Err(BadgeError::Timeout { message_id, .. }) => {
+                    i += 1;
+                    if retry.max_retries.map(|max| i > max).unwrap_or(false) {
+                        self.stats
+                            .record_command(command.name(), i - 1, start.elapsed());
+                        Err(BadgeError::Timeout {
+                            command: command.name(),
+                            message_id,
+                            attempts: i,
+                            elapsed: start.elapsed(),
+                        })?
+                    }
 
-            if let ResponseData::Timeout = result {
-                i += 1;
-                if i % 3 == 0 {
-                    self.device.reset().unwrap();
+                    if i.is_multiple_of(retry.reset_every) {
+                        self.device.reset().unwrap();
+                    }
+                }
+                Err(e) => Err(e)?,
+                Ok(result) => {
+                    Span::current().record("attempts", i + 1);
+                    self.stats
+                        .record_command(command.name(), i, start.elapsed());
+                    return Ok(result);
                 }
-
-                continue;
-            } else {
-                return Ok(result);
             }
         }
     }
 
-    pub async fn fetch_dir<S: Into<String>>(
+    /// Lists `dir`. The firmware sends the whole listing in one response and can't be asked for
+    /// part of it; the response is reassembled from as many USB transfers as it takes. If the badge
+    /// doesn't answer, the directory is listed in parts through the Python shell instead, if it is
+    /// idle.
+    pub async fn fetch_dir<S: AsRef<str>>(
         &self,
         dir: S,
-    ) -> Result<DirectoryListingResponse, Box<dyn Error>> {
-        let response = self.cmd(Command::FetchDir { path: dir.into() }).await?;
+    ) -> Result<DirectoryListingResponse, Box<dyn Error + Send + Sync>> {
+        let path = BadgePath::new(dir)?;
+        if self.via_repl() {
+            return repl_fs::fetch_dir(self, &path).await;
+        }
+
+        let response = match self.cmd(Command::FetchDir { path: path.clone() }).await {
+            Ok(response) => response,
+            Err(e) if matches!(e.downcast_ref(), Some(BadgeError::Timeout { .. })) => {
+                if self.capabilities().python_shell && capabilities::shell_is_idle(self).await {
+                    warn!("{} Listing {} through the Python shell", e, path);
+                    return repl_fs::fetch_dir(self, &path).await;
+                }
+                Err(BadgeError::ListingFailed(path, e.to_string()))?
+            }
+            Err(e) => return Err(e),
+        };
         if let ResponseData::DirectoryListing(listing) = response {
             Ok(listing)
         } else {
@@ -225,8 +934,16 @@ impl Badge {
         }
     }
 
-    pub async fn fetch_file<S: Into<String>>(&self, file: S) -> Result<Vec<u8>, Box<dyn Error>> {
-        let response = self.cmd(Command::FetchFile { path: file.into() }).await?;
+    pub async fn fetch_file<S: AsRef<str>>(
+        &self,
+        file: S,
+    ) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+        let path = BadgePath::new(file)?;
+        if self.via_repl() {
+            return Ok(repl_fs::fetch_file(self, &path).await?.into());
+        }
+
+        let response = self.cmd(Command::FetchFile { path }).await?;
         if let ResponseData::FileContents(data) = response {
             Ok(data)
         } else {
@@ -234,7 +951,22 @@ impl Badge {
         }
     }
 
-    pub async fn ensure_ok(&self, cmd: Command) -> Result<(), Box<dyn Error>> {
+    /// Holds off writes until the returned pause is dropped, after those that are running finished,
+    /// so what is read in the meantime is consistent. With a badge that another process shares,
+    /// that process holds off its own writes and those of its other clients too.
+    pub async fn pause_writes(&self) -> Result<WritePause<'_>, Box<dyn Error + Send + Sync>> {
+        let guard = self.writes.write().await;
+        if self.device.is_shared() {
+            self.ensure_ok(Command::PauseWrites { paused: true })
+                .await?;
+        }
+        Ok(WritePause {
+            badge: self,
+            _guard: guard,
+        })
+    }
+
+    pub async fn ensure_ok(&self, cmd: Command) -> Result<(), Box<dyn Error + Send + Sync>> {
         let response = self.cmd(cmd).await?;
         if let ResponseData::Ok = response {
             Ok(())
@@ -245,142 +977,443 @@ impl Badge {
         }
     }
 
-    pub async fn create_dir<S: Into<String>>(&self, path: S) -> Result<(), Box<dyn Error>> {
-        self.ensure_ok(Command::CreateDir { path: path.into() })
-            .await
+    pub async fn create_dir<S: AsRef<str>>(
+        &self,
+        path: S,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = BadgePath::new(path)?;
+        if self.via_repl() {
+            return repl_fs::create_dir(self, &path).await;
+        }
+
+        self.ensure_ok(Command::CreateDir { path }).await
     }
 
-    pub async fn create_file<S: Into<String>>(&self, path: S) -> Result<(), Box<dyn Error>> {
-        self.ensure_ok(Command::CreateFile { path: path.into() })
-            .await
+    pub async fn create_file<S: AsRef<str>>(
+        &self,
+        path: S,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = BadgePath::new(path)?;
+        if self.via_repl() {
+            return repl_fs::create_file(self, &path).await;
+        }
+
+        self.ensure_ok(Command::CreateFile { path }).await
     }
 
-    pub async fn copy_file<S1: Into<String>, S2: Into<String>>(
+    pub async fn copy_file<S1: AsRef<str>, S2: AsRef<str>>(
         &self,
         from: S1,
         to: S2,
-    ) -> Result<(), Box<dyn Error>> {
-        self.ensure_ok(Command::CopyFile {
-            from: from.into(),
-            to: to.into(),
-        })
-        .await
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (from, to) = (BadgePath::new(from)?, BadgePath::new(to)?);
+        if self.via_repl() {
+            return repl_fs::copy_file(self, &from, &to).await;
+        }
+
+        self.ensure_ok(Command::CopyFile { from, to }).await
     }
 
-    pub async fn move_file<S1: Into<String>, S2: Into<String>>(
+    pub async fn move_file<S1: AsRef<str>, S2: AsRef<str>>(
         &self,
         from: S1,
         to: S2,
-    ) -> Result<(), Box<dyn Error>> {
-        self.ensure_ok(Command::MoveFile {
-            from: from.into(),
-            to: to.into(),
-        })
-        .await
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (from, to) = (BadgePath::new(from)?, BadgePath::new(to)?);
+        if self.via_repl() {
+            return repl_fs::move_file(self, &from, &to).await;
+        }
+
+        self.ensure_ok(Command::MoveFile { from, to }).await
     }
 
-    pub async fn write_file<S: Into<String>, B: AsRef<[u8]>>(
+    pub async fn write_file<S: AsRef<str>, B: AsRef<[u8]>>(
         &self,
         path: S,
         data: B,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = BadgePath::new(path)?;
+        if self.via_repl() {
+            return repl_fs::write_file(self, &path, data.as_ref()).await;
+        }
+
         self.ensure_ok(Command::WriteFile {
-            path: path.into(),
-            data: data.as_ref().into(),
+            path,
+            data: Bytes::copy_from_slice(data.as_ref()),
         })
         .await
     }
 
-    pub async fn run_file<S: Into<String>>(&self, path: S) -> Result<(), Box<dyn Error>> {
-        self.ensure_ok(Command::RunFile { path: path.into() }).await
+    pub async fn run_file<S: AsRef<str>>(
+        &self,
+        path: S,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.ensure_ok(Command::RunFile {
+            path: BadgePath::new(path)?.run_path()?,
+        })
+        .await
     }
 
-    pub async fn delete_path<S: Into<String>>(&self, path: S) -> Result<(), Box<dyn Error>> {
-        self.ensure_ok(Command::DeletePath { path: path.into() })
-            .await
+    pub async fn delete_path<S: AsRef<str>>(
+        &self,
+        path: S,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = BadgePath::new(path)?;
+        if self.via_repl() {
+            return repl_fs::delete_path(self, &path).await;
+        }
+
+        self.ensure_ok(Command::DeletePath { path }).await
     }
 
-    pub async fn serial_in<S: AsRef<[u8]>>(&self, data: S) -> Result<(), Box<dyn Error>> {
+    pub async fn serial_in<S: AsRef<[u8]>>(
+        &self,
+        data: S,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.ensure_ok(Command::SerialIn {
-            data: data.as_ref().into(),
+            data: Bytes::copy_from_slice(data.as_ref()),
         })
         .await
     }
 
-    pub async fn heartbeat(&self) -> Result<(), Box<dyn Error>> {
+    pub async fn heartbeat(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.ensure_ok(Command::Heartbeat).await
     }
 
-    pub fn run<F: Fn(String)>(self: Arc<Self>, stdout: F) {
-        crossbeam::scope(|scope| {
-            let me = self.clone();
-            let t = scope.spawn(move |_| {
-                while !me.abort.load(Ordering::Relaxed) {
-                    me.send(0, Command::Heartbeat).unwrap();
-                    std::thread::sleep(Duration::from_millis(250));
+    /// Reboots the badge by interrupting the running program and calling `machine.reset()` from the Python shell
+    pub async fn reboot(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.serial_in("\u{003}").await?;
+        self.serial_in("import machine\r\nmachine.reset()\r\n")
+            .await
+    }
+
+    /// What the firmware supports. Everything is assumed to be supported until `probe` is called.
+    pub fn capabilities(&self) -> Capabilities {
+        *self.capabilities.lock().unwrap()
+    }
+
+    /// Tries out what the firmware supports, so the right code paths are used for it
+    pub async fn probe(&self) -> Capabilities {
+        let capabilities = capabilities::probe(self).await;
+        *self.capabilities.lock().unwrap() = capabilities;
+        capabilities
+    }
+
+    /// Whether file operations go through the Python shell instead of the file commands
+    fn via_repl(&self) -> bool {
+        self.config.via_repl || !self.capabilities().file_commands
+    }
+
+    pub fn device_info(&self) -> &DeviceInfo {
+        self.device.info()
+    }
+
+    pub fn stats(&self) -> &BadgeStats {
+        &self.stats
+    }
+
+    /// A snapshot of the traffic, latencies and retries so far
+    pub fn metrics(&self) -> Metrics {
+        self.stats.metrics()
+    }
+
+    /// How well the connection is doing, judged by the heartbeats
+    pub fn health(&self) -> Health {
+        self.heartbeats.health()
+    }
+
+    /// The requests that are waiting for a response, oldest first
+    pub fn outstanding(&self) -> Vec<Outstanding> {
+        let mut outstanding = self
+            .data()
+            .pending
+            .iter()
+            .map(|(&message_id, pending)| Outstanding {
+                message_id,
+                command: pending.command,
+                pending: pending.at.elapsed(),
+            })
+            .collect::<Vec<_>>();
+        outstanding.sort_by_key(|request| request.message_id);
+        outstanding
+    }
+
+    /// Starts the threads that send heartbeats, receive responses and time out requests. They keep
+    /// running until `close()` is called or the last reference to the badge is dropped.
+    pub fn start(self) -> Arc<Badge> {
+        let badge = Arc::new(self);
+        if let Some(after) = badge.config.debug_requests {
+            let badge = Arc::downgrade(&badge);
+            // Not joined on close, as it only prints
+            thread::spawn(move || Badge::debug_requests_loop(badge, after));
+        }
+        let heartbeat = {
+            let badge = Arc::downgrade(&badge);
+            thread::spawn(move || Badge::heartbeat_loop(badge))
+        };
+        let receive = {
+            let badge = Arc::downgrade(&badge);
+            thread::spawn(move || Badge::receive_loop(badge))
+        };
+        let expire = {
+            let badge = Arc::downgrade(&badge);
+            thread::spawn(move || Badge::expire_loop(badge))
+        };
+
+        badge
+            .threads
+            .lock()
+            .unwrap()
+            .extend(vec![heartbeat, receive, expire]);
+        badge
+    }
+
+    // The IO threads only hold a weak reference in between iterations, so that dropping the badge shuts them down
+    fn heartbeat_loop(badge: Weak<Badge>) {
+        while let Some(badge) = badge.upgrade() {
+            if badge.abort.load(Ordering::Relaxed) {
+                break;
+            }
+
+            if let Err(e) = badge.send_heartbeat() {
+                error!("Sending a heartbeat failed: {}", e);
+                break;
+            }
+
+            let interval = badge.config.heartbeat_interval;
+            drop(badge);
+            thread::sleep(interval);
+        }
+    }
+
+    fn debug_requests_loop(badge: Weak<Badge>, after: Duration) {
+        while let Some(badge) = badge.upgrade() {
+            if badge.abort.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let stuck = badge
+                .outstanding()
+                .into_iter()
+                .filter(|request| request.pending >= after)
+                .collect::<Vec<_>>();
+            if !stuck.is_empty() {
+                // Carriage returns, as the terminal may be in raw mode for the shell
+                let mut report = String::from("[Outstanding requests]\r\n");
+                for request in stuck {
+                    report.push_str(&format!("  {}\r\n", request));
                 }
-            });
-
-            let mut input = Buffer::new_ringbuf();
-            let mut buf = [0u8; 256];
-            while !self.abort.load(Ordering::Relaxed) {
-                let device = &self.device;
-                match device.receive(&mut buf) {
-                    Ok(len) => {
-                        self.data.lock().unwrap().wakers.retain(|_, value| {
-                            let mut waker = value.lock().unwrap();
-
-                            if waker.at < Instant::now() - Duration::from_secs(10) {
-                                waker.response = Some(Response {
-                                    message_id: 0,
-                                    data: ResponseData::Timeout,
-                                });
-                                if let Some(waker) = waker.waker.take() {
-                                    waker.wake();
-                                }
-
-                                false
-                            } else {
-                                true
-                            }
-                        });
-
-                        trace!("Received {} bytes: {:?}", len, &buf[0..len]);
-                        input.push_bytes(&buf[0..len]);
-
-                        while let Some(response) = Response::try_read(&mut input).unwrap() {
-                            let mut data = self.data.lock().unwrap();
-                            if let Some(waker) = data.wakers.remove(&response.message_id) {
-                                let mut waker = waker.lock().unwrap();
-                                waker.response = Some(response);
-                                if let Some(waker) = waker.waker.take() {
-                                    waker.wake();
-                                }
-                            } else if let Response {
-                                data: ResponseData::Log { text },
-                                message_id: 0,
-                            } = response
-                            {
-                                stdout(text);
-                            } else {
-                                warn!("Unhandled message: {:?}", response.data);
-                            }
-                        }
-
-                        if input.len() > 0 {
-                            warn!("Leftover input bytes: {}", input.len());
-                            trace!("Leftover bytes: {:?}", input.buf())
-                        }
-                    }
-                    Err(e) => {
-                        println!("Error: {}", e);
-                        break;
-                    }
+                eprint!("{}", report);
+            }
+
+            drop(badge);
+            thread::sleep(after);
+        }
+    }
+
+    /// Times out requests on a timer of its own, so they expire on time even while the receive
+    /// thread is busy with a large response
+    fn expire_loop(badge: Weak<Badge>) {
+        while let Some(badge) = badge.upgrade() {
+            if badge.abort.load(Ordering::Relaxed) {
+                break;
+            }
+
+            badge.expire();
+            drop(badge);
+            thread::sleep(EXPIRE_INTERVAL);
+        }
+    }
+
+    fn expire(&self) {
+        // A heartbeat that takes longer than a few intervals counts as missed
+        let heartbeat_timeout = (self.config.heartbeat_interval * 4).max(Duration::from_secs(1));
+        self.data().pending.retain(|_, pending| {
+            if pending.heartbeat && pending.at.elapsed() > heartbeat_timeout {
+                self.heartbeats.record_miss();
+                false
+            } else if pending.at.elapsed() > self.config.request_timeout {
+                // Dropping the sender times out the request
+                self.stats.record_timeout();
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn receive_loop(badge: Weak<Badge>) {
+        let mut input = Buffer::new_ringbuf();
+        let mut buf = match badge.upgrade() {
+            Some(badge) => vec![0u8; badge.config.receive_buffer_size],
+            None => return,
+        };
+        // When the first byte of the response in `input` arrived
+        let mut first_byte = None;
+        while let Some(badge) = badge.upgrade() {
+            if badge.abort.load(Ordering::Relaxed) {
+                break;
+            }
+
+            if let Err(e) = badge.receive(&mut input, &mut buf, &mut first_byte) {
+                // Most likely the badge was disconnected or reset. Closing lets pending commands
+                // and subscribers know.
+                error!("Receiving from the badge failed: {}", e);
+                badge.close();
+                break;
+            }
+        }
+    }
+
+    fn receive(
+        &self,
+        input: &mut Buffer,
+        buf: &mut [u8],
+        first_byte: &mut Option<Instant>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let len = self
+            .device
+            .receive(buf, self.config.receive_timeout.min(RECEIVE_SLICE))?;
+        let received = Instant::now();
+        if len > 0 && input.is_empty() {
+            *first_byte = Some(received);
+        }
+
+        trace!(bytes = len, "Received {:?}", &buf[0..len]);
+        input.push_bytes(&buf[0..len]);
+        self.stats.record_received(len);
+
+        loop {
+            let parse_start = Instant::now();
+            let response = match Response::try_read(input) {
+                Some(response) => response,
+                None => break,
+            };
+            let parsed = parse_start.elapsed();
+            // What is left arrived with this read, at the latest
+            let started = first_byte.take().unwrap_or(received);
+            if !input.is_empty() {
+                *first_byte = Some(received);
+            }
+
+            let mut data = self.data();
+            if let Some(pending) = data.pending.remove(&response.message_id) {
+                trace!(message_id = response.message_id, latency = ?pending.at.elapsed(), "Response");
+                if pending.heartbeat {
+                    self.heartbeats.record_answer(pending.at.elapsed());
+                    continue;
                 }
+                self.stats.record_response(pending.at.elapsed());
+                if let Some(trace) = &self.config.latency_trace {
+                    let phases = Phases {
+                        processing: started.saturating_duration_since(pending.at),
+                        read: received - started,
+                        parse: parsed,
+                        ..pending.phases
+                    };
+                    trace.record(pending.command, response.message_id, pending.at, &phases);
+                }
+                // Only fails if the request was dropped, so nobody needs the response anymore
+                let _ = pending.sender.send(response.data);
+            } else if let Response {
+                data: ResponseData::Log { text },
+                message_id: 0,
+            } = response
+            {
+                if let Some(output) = &*self.output.lock().unwrap_or_else(PoisonError::into_inner) {
+                    // Sending only fails if there are no subscribers, in which case the output is dropped
+                    let _ = output.send(text);
+                }
+            } else {
+                warn!("Unhandled message: {:?}", response.data);
             }
+        }
 
-            t.join().unwrap();
-        })
-        .unwrap();
+        reserve_rest(input);
+
+        Ok(())
+    }
+}
+
+/// Makes room for the rest of a response that spans several reads. Making room for all of it at
+/// once keeps the buffer from being grown and copied a read at a time during big downloads.
+fn reserve_rest(input: &mut Buffer) {
+    if let Next::Incomplete {
+        needed: Some(needed),
+    } = cz2020_protocol::next_frame(input.buf())
+    {
+        let missing = needed - input.len();
+        trace!("Waiting for {} more bytes of a response", missing);
+        if input.usable_space() < missing {
+            input.reserve(missing.min(MAX_RESERVE));
+        }
+    }
+}
+
+impl Drop for Badge {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cz2020_protocol::{command, write_header, HEADER_LEN};
+
+    fn header(len: u32) -> Vec<u8> {
+        let mut input = Vec::new();
+        write_header(&mut input, command::FETCH_FILE, len, 1);
+        input
+    }
+
+    #[test]
+    fn reserves_the_rest_of_a_response() {
+        let mut input = Buffer::new_ringbuf();
+        input.push_bytes(&header(200_000));
+        reserve_rest(&mut input);
+        assert!(input.usable_space() >= 200_000);
+    }
+
+    #[test]
+    fn caps_the_reservation() {
+        let mut input = Buffer::new_ringbuf();
+        input.push_bytes(&header(u32::MAX));
+        reserve_rest(&mut input);
+        assert!(input.usable_space() >= MAX_RESERVE);
+        assert!(input.capacity() < 2 * MAX_RESERVE + HEADER_LEN);
+    }
+
+    #[test]
+    fn receives_a_response_longer_than_the_reservation() {
+        let len = 3 * MAX_RESERVE;
+        let mut response = header(len as u32);
+        response.resize(HEADER_LEN + len, b'x');
+
+        let mut input = Buffer::new_ringbuf();
+        let mut received = Vec::new();
+        for read in response.chunks(64 * 1024) {
+            input.push_bytes(read);
+            while let Some(response) = Response::try_read(&mut input) {
+                received.push(response);
+            }
+            reserve_rest(&mut input);
+        }
+
+        assert_eq!(received.len(), 1);
+        match &received[0].data {
+            ResponseData::FileContents(data) => assert_eq!(data.len(), len),
+            data => panic!("Expected the file, got {:?}", data),
+        }
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn leaves_a_complete_header_alone() {
+        let mut input = Buffer::new_ringbuf();
+        input.push_bytes(&header(0));
+        let capacity = input.capacity();
+        reserve_rest(&mut input);
+        assert_eq!(input.capacity(), capacity);
     }
 }
@@ -0,0 +1,141 @@
+//! A line diff, printed like `diff -u`, to show what is about to change on the badge
+
+/// A line of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Above this many lines times lines, the changed part is shown as removed and added as a whole
+/// instead of computing the smallest diff
+const MAX_TABLE: usize = 4_000_000;
+
+/// The lines of `old` and `new`, with the lines that only one of them has marked
+pub fn lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut result = old[..prefix]
+        .iter()
+        .map(|line| Line::Same(line))
+        .collect::<Vec<_>>();
+    if a.len() * b.len() > MAX_TABLE {
+        result.extend(a.iter().map(|line| Line::Removed(line)));
+        result.extend(b.iter().map(|line| Line::Added(line)));
+    } else {
+        // The length of the longest common subsequence of a[i..] and b[j..]
+        let width = b.len() + 1;
+        let mut table = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i * width + j] = if a[i] == b[j] {
+                    table[(i + 1) * width + j + 1] + 1
+                } else {
+                    table[(i + 1) * width + j].max(table[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                result.push(Line::Same(a[i]));
+                i += 1;
+                j += 1;
+            } else if j == b.len()
+                || (i < a.len() && table[(i + 1) * width + j] >= table[i * width + j + 1])
+            {
+                result.push(Line::Removed(a[i]));
+                i += 1;
+            } else {
+                result.push(Line::Added(b[j]));
+                j += 1;
+            }
+        }
+    }
+    result.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| Line::Same(line)),
+    );
+
+    result
+}
+
+/// The diff in the unified format, with `context` unchanged lines around each change, or an empty
+/// string if nothing changed
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str, context: usize) -> String {
+    let lines = lines(old, new);
+    let changes = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Line::Same(_)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // Changes that are close together share a hunk
+    let mut hunks = Vec::<(usize, usize)>::new();
+    for &i in changes.iter() {
+        let start = i.saturating_sub(context);
+        let end = (i + 1 + context).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for (start, end) in hunks {
+        let before = &lines[..start];
+        let hunk = &lines[start..end];
+        let count = |lines: &[Line], old: bool| {
+            lines
+                .iter()
+                .filter(|line| match line {
+                    Line::Same(_) => true,
+                    Line::Removed(_) => old,
+                    Line::Added(_) => !old,
+                })
+                .count()
+        };
+        let (old_len, new_len) = (count(hunk, true), count(hunk, false));
+        // Like diff, an empty range starts at the line before it
+        let old_start = count(before, true) + if old_len > 0 { 1 } else { 0 };
+        let new_start = count(before, false) + if new_len > 0 { 1 } else { 0 };
+
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_len, new_start, new_len
+        ));
+        for line in hunk {
+            match line {
+                Line::Same(text) => out.push_str(&format!(" {}\n", text)),
+                Line::Removed(text) => out.push_str(&format!("-{}\n", text)),
+                Line::Added(text) => out.push_str(&format!("+{}\n", text)),
+            }
+        }
+    }
+
+    out
+}
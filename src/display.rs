@@ -0,0 +1,99 @@
+//! Test patterns and solid colors on the display, to check the hardware of a badge. The drawing
+//! runs in the Python shell with the `display` module of the firmware.
+
+use crate::{device::Badge, repl};
+use std::{error::Error, str::FromStr, time::Duration};
+use structopt::StructOpt;
+use thiserror::Error;
+
+/// How long drawing may take, on top of the time the patterns are shown
+const REPL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+#[error("Unknown color {:?}. Use a name like red, or hex like ff8000.", .0)]
+pub struct ColorError(String);
+
+/// A color as `0xrrggbb`, like the `display` module expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(u32);
+
+impl FromStr for Color {
+    type Err = ColorError;
+
+    fn from_str(s: &str) -> Result<Color, ColorError> {
+        let rgb = match s.to_ascii_lowercase().as_str() {
+            "black" | "off" => 0x000000,
+            "white" => 0xffffff,
+            "red" => 0xff0000,
+            "green" => 0x00ff00,
+            "blue" => 0x0000ff,
+            "yellow" => 0xffff00,
+            "cyan" => 0x00ffff,
+            "magenta" => 0xff00ff,
+            hex => {
+                let hex = hex.trim_start_matches('#').trim_start_matches("0x");
+                match u32::from_str_radix(hex, 16) {
+                    Ok(rgb) if hex.len() == 6 => rgb,
+                    _ => return Err(ColorError(s.to_owned())),
+                }
+            }
+        };
+
+        Ok(Color(rgb))
+    }
+}
+
+#[derive(StructOpt, Clone)]
+pub enum DisplayCommand {
+    #[structopt(
+        about = "Shows red, green, blue and white on the whole display and then a checkerboard, to find dead pixels"
+    )]
+    Test {
+        #[structopt(
+            long,
+            default_value = "1000",
+            help = "Milliseconds to show each pattern"
+        )]
+        step: u64,
+    },
+
+    #[structopt(about = "Fills the display with a color, like red or ff8000")]
+    Fill { color: Color },
+}
+
+fn fill(color: Color) -> String {
+    format!("display.drawFill(0x{:06x})\ndisplay.flush()\n", color.0)
+}
+
+/// Runs a `display` subcommand
+pub async fn command(
+    badge: &Badge,
+    command: DisplayCommand,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (code, duration) = match command {
+        DisplayCommand::Test { step } => {
+            let mut code = String::from("import display, time\n");
+            for color in [0xff0000, 0x00ff00, 0x0000ff, 0xffffff].iter() {
+                code.push_str(&fill(Color(*color)));
+                code.push_str(&format!("time.sleep_ms({})\n", step));
+            }
+            code.push_str(&format!(
+                "for y in range(display.height()):
+ for x in range(display.width()):
+  display.drawPixel(x, y, 0xffffff if (x + y) % 2 else 0)
+display.flush()
+time.sleep_ms({})
+{}",
+                step,
+                fill(Color(0))
+            ));
+            (code, Duration::from_millis(step * 5))
+        }
+        DisplayCommand::Fill { color } => {
+            (format!("import display\n{}", fill(color)), Duration::ZERO)
+        }
+    };
+
+    repl::exec(badge, &code, duration + REPL_TIMEOUT).await?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use crate::device::{BadgeBuilder, Device, ENDPOINT_IN, ENDPOINT_OUT, PRODUCT_ID, VENDOR_ID};
+use rusb::{Context, UsbContext};
+use std::time::{Duration, Instant};
+use tokio::{runtime::Runtime, time::timeout};
+
+/// The outcome of a single check: a description of what was found, or the problem and a suggested fix
+type Check = Result<String, (String, String)>;
+
+fn report(name: &str, check: Check) -> bool {
+    match check {
+        Ok(detail) => {
+            println!("[ OK ] {}: {}", name, detail);
+            true
+        }
+        Err((problem, fix)) => {
+            println!("[FAIL] {}: {}", name, problem);
+            println!("       fix: {}", fix);
+            false
+        }
+    }
+}
+
+fn fail<S: Into<String>, T: Into<String>>(problem: S, fix: T) -> Check {
+    Err((problem.into(), fix.into()))
+}
+
+/// Finds the interface that has both bulk endpoints used by the protocol
+fn find_interface(device: &rusb::Device<Context>) -> Result<u8, rusb::Error> {
+    let config = device.active_config_descriptor()?;
+    for interface in config.interfaces() {
+        for descriptor in interface.descriptors() {
+            let addresses = descriptor
+                .endpoint_descriptors()
+                .map(|endpoint| endpoint.address())
+                .collect::<Vec<_>>();
+            if addresses.contains(&ENDPOINT_IN) && addresses.contains(&ENDPOINT_OUT) {
+                return Ok(descriptor.interface_number());
+            }
+        }
+    }
+
+    Err(rusb::Error::NotFound)
+}
+
+/// Checks every step needed to talk to the badge and prints a diagnosis. Stops at the first
+/// failure, because later checks depend on it. Returns whether all checks passed.
+pub fn doctor(context: &Context, builder: BadgeBuilder) -> bool {
+    let devices = context
+        .devices()
+        .map(|devices| {
+            devices
+                .iter()
+                .filter(|device| {
+                    device
+                        .device_descriptor()
+                        .map(|desc| {
+                            desc.vendor_id() == VENDOR_ID && desc.product_id() == PRODUCT_ID
+                        })
+                        .unwrap_or(false)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let count = devices.len();
+    let usb_device = match devices.into_iter().next() {
+        Some(device) => device,
+        None => {
+            return report(
+                "device present",
+                fail(
+                    format!("no device with id {:04x}:{:04x} found", VENDOR_ID, PRODUCT_ID),
+                    "Turn the badge on and connect it with a USB cable that supports data, not just charging",
+                ),
+            )
+        }
+    };
+    report(
+        "device present",
+        Ok(format!(
+            "found {} badge(s), checking bus {:03} device {:03}",
+            count,
+            usb_device.bus_number(),
+            usb_device.address()
+        )),
+    );
+
+    let check = match usb_device.open() {
+        Ok(_) => Ok("the device can be opened".to_owned()),
+        Err(rusb::Error::Access) => fail(
+            "permission denied while opening the device",
+            format!(
+                "Add a udev rule such as SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"0666\" to /etc/udev/rules.d/ and reconnect the badge, or run as root",
+                VENDOR_ID, PRODUCT_ID
+            ),
+        ),
+        Err(e) => fail(
+            format!("unable to open the device: {}", e),
+            "Unplug the badge and plug it back in",
+        ),
+    };
+    if !report("permissions", check) {
+        return false;
+    }
+
+    let interface = find_interface(&usb_device);
+    let check = match &interface {
+        Ok(interface) => Ok(format!(
+            "{:#04x} and {:#04x} found on interface {}",
+            ENDPOINT_OUT, ENDPOINT_IN, interface
+        )),
+        Err(e) => fail(
+            format!(
+                "no interface with bulk endpoints {:#04x} and {:#04x}: {}",
+                ENDPOINT_OUT, ENDPOINT_IN, e
+            ),
+            "The badge may be running firmware without USB support. Update the firmware.",
+        ),
+    };
+    if !report("endpoints", check) {
+        return false;
+    }
+    let interface = interface.unwrap();
+
+    let check = match usb_device
+        .open()
+        .and_then(|handle| handle.kernel_driver_active(interface))
+    {
+        Ok(false) => Ok("no kernel driver is bound to the interface".to_owned()),
+        Ok(true) => fail(
+            format!("a kernel driver is bound to interface {}", interface),
+            "Another driver or program is using the badge. Close other instances of this tool and unbind the driver through /sys/bus/usb/drivers",
+        ),
+        Err(rusb::Error::NotSupported) => Ok("can't be checked on this platform".to_owned()),
+        Err(e) => fail(
+            format!("unable to query the kernel driver: {}", e),
+            "Unplug the badge and plug it back in",
+        ),
+    };
+    if !report("kernel driver", check) {
+        return false;
+    }
+
+    let device = match Device::open(&usb_device) {
+        Ok(device) => device,
+        Err(e) => {
+            return report(
+                "heartbeat",
+                fail(
+                    format!("unable to open the device: {}", e),
+                    "Unplug the badge and plug it back in",
+                ),
+            )
+        }
+    };
+
+    let badge = builder.build(device).start();
+    let mut rt = Runtime::new().unwrap();
+    let passed = rt.block_on(async {
+        let start = Instant::now();
+        let check = match timeout(Duration::from_secs(5), badge.heartbeat()).await {
+            Ok(Ok(())) => Ok(format!("round trip took {:?}", start.elapsed())),
+            Ok(Err(e)) => fail(
+                format!("the heartbeat failed: {}", e),
+                "Press the reset button on the badge, or unplug it and plug it back in",
+            ),
+            Err(_) => fail(
+                "no response within 5 seconds",
+                "Press the reset button on the badge, or unplug it and plug it back in",
+            ),
+        };
+        if !report("heartbeat", check) {
+            return false;
+        }
+
+        let check = match timeout(Duration::from_secs(10), badge.fetch_dir("/flash")).await {
+            Ok(Ok(_)) => Ok("the firmware answers filesystem commands".to_owned()),
+            Ok(Err(e)) => fail(
+                format!("listing /flash failed: {}", e),
+                "Reboot the badge. If this keeps happening, reflash the firmware.",
+            ),
+            Err(_) => fail(
+                "the heartbeat works, but listing /flash didn't finish within 10 seconds",
+                "An app may be keeping the firmware busy. Open `shell` and press Ctrl-C, or reboot the badge.",
+            ),
+        };
+        report("firmware", check)
+    });
+
+    badge.close();
+    passed
+}
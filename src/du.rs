@@ -0,0 +1,136 @@
+//! Sums up the sizes of the files in each directory, to find out what is filling up the flash.
+//! The Python shell can stat files on the badge itself; without it, every file has to be fetched
+//! to learn its size.
+
+use crate::{
+    client::{BadgeFs, ClientError},
+    cmds::FsEntry,
+    device::Badge,
+    output::Output,
+    path::BadgePath,
+    repl::{self, python_literal},
+};
+use std::{collections::BTreeMap, error::Error, time::Duration};
+use tracing::info;
+
+/// Walking a large tree takes a while
+const REPL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The total size of `path` and of every directory below it, with directories after their
+/// contents, using `uos.stat` on the badge
+async fn sizes_via_repl(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<Vec<(u64, BadgePath)>, Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import uos
+def du(p):
+ if uos.stat(p)[0] & 0x4000 == 0:
+  t = uos.stat(p)[6]
+ else:
+  t = 0
+  for e in uos.ilistdir(p):
+   t += du(p + '/' + e[0]) if e[1] == 0x4000 else uos.stat(p + '/' + e[0])[6]
+ print('%d\\t%s' % (t, p))
+ return t
+du({})",
+        python_literal(path.as_str())
+    );
+
+    repl::exec(badge, &code, REPL_TIMEOUT)
+        .await?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.trim_end_matches('\r').splitn(2, '\t');
+            let size = fields.next().unwrap_or_default().parse()?;
+            let path = BadgePath::new(fields.next().unwrap_or_default())?;
+            Ok((size, path))
+        })
+        .collect()
+}
+
+/// Like `sizes_via_repl`, but by fetching every file
+async fn sizes_via_files(
+    client: &BadgeFs,
+    path: &BadgePath,
+) -> Result<Vec<(u64, BadgePath)>, Box<dyn Error + Send + Sync>> {
+    let metadata = client.metadata(path.clone()).await?;
+    if metadata.is_file() {
+        return Ok(vec![(metadata.len, path.clone())]);
+    }
+
+    // Directories in the order they were found, which puts every directory before its contents
+    let mut dirs = vec![path.clone()];
+    let mut totals = BTreeMap::new();
+    let mut stack = vec![path.clone()];
+    while let Some(dir) = stack.pop() {
+        let mut entries = client.read_dir(dir.clone());
+        loop {
+            let entry = match entries.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) if e.downcast_ref::<ClientError>().is_some() => break,
+                Err(e) => return Err(e),
+            };
+
+            let child = dir.join(entry.name())?;
+            match entry {
+                // Takes up no space that can be measured
+                FsEntry::Other(..) => {}
+                FsEntry::Directory(_) => {
+                    dirs.push(child.clone());
+                    stack.push(child);
+                }
+                FsEntry::File(_) => {
+                    let len = client.open(child.clone()).len().await?;
+                    // Count the file in each directory it is in, up to `path`
+                    let mut ancestor = child.parent();
+                    while let Some(dir) = ancestor {
+                        *totals.entry(dir.clone()).or_insert(0) += len;
+                        if dir == *path {
+                            break;
+                        }
+                        ancestor = dir.parent();
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(dirs
+        .into_iter()
+        .rev()
+        .map(|dir| (totals.get(&dir).copied().unwrap_or(0), dir))
+        .collect())
+}
+
+/// Prints the total size of `path` and of the directories below it, down to `max_depth` levels
+/// below `path`
+pub async fn du(
+    badge: &Badge,
+    client: &BadgeFs,
+    path: &BadgePath,
+    max_depth: Option<usize>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sizes = if badge.capabilities().python_shell {
+        sizes_via_repl(badge, path).await?
+    } else {
+        info!("The Python shell isn't available, so every file is fetched to learn its size");
+        sizes_via_files(client, path).await?
+    };
+
+    let depth = |other: &BadgePath| other.as_str().matches('/').count();
+    for (size, dir) in sizes.iter() {
+        let shown = match max_depth {
+            Some(max) => depth(dir).saturating_sub(depth(path)) <= max,
+            None => true,
+        };
+        if shown {
+            output.usage(*size, dir);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use std::{num::ParseIntError, time::Duration};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DurationError {
+    #[error("Invalid number in duration: {}", .0)]
+    InvalidNumber(#[from] ParseIntError),
+
+    #[error("Unknown unit in duration: {:?}. Use ms, s, m or h.", .0)]
+    UnknownUnit(String),
+
+    #[error("Duration too long: {}", .0)]
+    TooLong(String),
+}
+
+/// Parses durations like `500ms`, `30s`, `5m` and `1h`. A number without unit is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, DurationError> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number = number.parse::<u64>()?;
+    let seconds = |factor: u64| {
+        number
+            .checked_mul(factor)
+            .map(Duration::from_secs)
+            .ok_or_else(|| DurationError::TooLong(s.to_owned()))
+    };
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => seconds(1),
+        "m" => seconds(60),
+        "h" => seconds(60 * 60),
+        unit => Err(DurationError::UnknownUnit(unit.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(matches!(
+            parse_duration("s"),
+            Err(DurationError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            parse_duration("2d"),
+            Err(DurationError::UnknownUnit(unit)) if unit == "d"
+        ));
+        assert!(matches!(
+            parse_duration("18446744073709551615h"),
+            Err(DurationError::TooLong(_))
+        ));
+    }
+}
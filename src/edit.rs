@@ -0,0 +1,104 @@
+//! `edit` fetches a file to a temporary file, opens it in `$VISUAL` or `$EDITOR`, and after the
+//! editor exits shows what changed and writes the file back atomically once that is confirmed.
+
+use crate::{atomic, cache::MetadataCache, client::BadgeFs, device::Badge, diff, path::BadgePath};
+use std::{
+    error::Error,
+    fs,
+    io::{self, BufRead, Write},
+    process::{self, Command},
+};
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum EditError {
+    #[error("The editor exited with {}, so the file wasn't uploaded", .0)]
+    EditorFailed(process::ExitStatus),
+
+    #[error("Only text files can be edited")]
+    Binary,
+}
+
+/// The editor the user chose, or `vi`
+fn editor() -> String {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_owned())
+}
+
+/// Asks on the terminal whether to continue
+fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Edits `path`, which is created if it doesn't exist. With `yes`, the changes are uploaded
+/// without asking.
+pub async fn edit(
+    badge: &Badge,
+    client: &BadgeFs,
+    cache: &MetadataCache,
+    path: &BadgePath,
+    yes: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let original = if client.exists(path).await? {
+        client.open(path.clone()).read_to_end().await?.to_vec()
+    } else {
+        info!("{} doesn't exist yet, starting with an empty file", path);
+        Vec::new()
+    };
+    let original = String::from_utf8(original).map_err(|_| EditError::Binary)?;
+
+    // The file name is kept, so the editor can tell the type of the file
+    let dir = std::env::temp_dir().join(format!("cz2020-usbtool-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let local = dir.join(path.file_name());
+    fs::write(&local, &original)?;
+
+    let result = async {
+        // Through the shell, so the editor can have arguments like `code --wait`
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor()))
+            .arg("sh")
+            .arg(&local)
+            .status()?;
+        if !status.success() {
+            Err(EditError::EditorFailed(status))?
+        }
+
+        let edited = fs::read(&local)?;
+        let edited_text = String::from_utf8(edited.clone()).map_err(|_| EditError::Binary)?;
+        let diff = diff::unified(path.as_str(), path.as_str(), &original, &edited_text, 3);
+        if diff.is_empty() && edited == original.as_bytes() {
+            eprintln!("No changes");
+            fs::remove_file(&local)?;
+            return Ok(());
+        }
+
+        eprint!("{}", diff);
+        if !yes && !confirm(&format!("Upload the changes to {}?", path))? {
+            eprintln!("Not uploaded. Your changes are in {:?}.", local);
+            return Ok(());
+        }
+
+        atomic::write_file(badge, path, &edited).await?;
+        cache.record_file(path, &edited);
+        fs::remove_file(&local)?;
+        Ok(())
+    }
+    .await;
+
+    // The edited file is only removed once it was uploaded, so a failure doesn't lose the changes
+    if result.is_err() && local.exists() {
+        eprintln!("Your changes are in {:?}", local);
+    }
+    let _ = fs::remove_dir(&dir);
+    result
+}
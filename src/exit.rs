@@ -0,0 +1,186 @@
+use crate::{
+    client::ClientError,
+    device::{BadgeError, LibUsbError},
+    expect::{ExpectError, ScriptError},
+    fleet::FleetError,
+    git::GitError,
+    hooks::HookError,
+    install::InstallError,
+    lint::LintError,
+    manifest::ManifestError,
+    mirror::MirrorError,
+    output::Output,
+    path::PathError,
+    repl::ReplError,
+    run::RunError,
+    scaffold::ScaffoldError,
+    send::EscapeError,
+    snapshot::SnapshotError,
+    sourcemap::SourceMapError,
+    testrun::TestError,
+};
+use std::{error::Error, io};
+use thiserror::Error;
+
+/// Exit codes for failures, so scripts can tell them apart. Documented in the README; don't renumber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Failure = 1,
+    Usage = 2,
+    DeviceNotFound = 3,
+    Timeout = 4,
+    RemoteError = 5,
+    NotFound = 6,
+    LocalIo = 7,
+}
+
+impl ExitCode {
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+
+    /// The exit code with `code` as number, e.g. of a child process
+    pub fn from_code(code: i32) -> ExitCode {
+        match code {
+            2 => ExitCode::Usage,
+            3 => ExitCode::DeviceNotFound,
+            4 => ExitCode::Timeout,
+            5 => ExitCode::RemoteError,
+            6 => ExitCode::NotFound,
+            7 => ExitCode::LocalIo,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+/// Some of the files of a command failed. Each failure has been reported already; `code` is the
+/// exit code of the first one.
+#[derive(Error, Debug)]
+#[error("{} of {} files failed", .failed, .total)]
+pub struct PartialFailure {
+    pub failed: usize,
+    pub total: usize,
+    pub code: ExitCode,
+}
+
+fn classify(e: &(dyn Error + 'static)) -> Option<ExitCode> {
+    if let Some(e) = e.downcast_ref::<LibUsbError>() {
+        match e {
+            LibUsbError::NoDeviceFound | LibUsbError::NoSuchDevice(_) => {
+                return Some(ExitCode::DeviceNotFound)
+            }
+            // Classified by the error they wrap
+            LibUsbError::Open(_) | LibUsbError::Locked(_) => {}
+        }
+    }
+
+    if let Some(e) = e.downcast_ref::<rusb::Error>() {
+        return Some(match e {
+            rusb::Error::NoDevice | rusb::Error::NotFound => ExitCode::DeviceNotFound,
+            rusb::Error::Timeout => ExitCode::Timeout,
+            _ => ExitCode::Failure,
+        });
+    }
+
+    if let Some(e) = e.downcast_ref::<BadgeError>() {
+        return Some(match e {
+            BadgeError::Timeout { .. } | BadgeError::ListingFailed(..) => ExitCode::Timeout,
+            BadgeError::InvalidResponse(_) | BadgeError::CommandFailed => ExitCode::RemoteError,
+            BadgeError::Closed => ExitCode::Failure,
+        });
+    }
+
+    if let Some(e) = e.downcast_ref::<ReplError>() {
+        return Some(match e {
+            ReplError::Timeout => ExitCode::Timeout,
+            ReplError::Exception(_) => ExitCode::RemoteError,
+            ReplError::Closed => ExitCode::Failure,
+        });
+    }
+
+    if let Some(e) = e.downcast_ref::<ExpectError>() {
+        return Some(match e {
+            ExpectError::Timeout { .. } => ExitCode::Timeout,
+            ExpectError::Closed => ExitCode::Failure,
+        });
+    }
+
+    if e.is::<RunError>() {
+        return Some(ExitCode::RemoteError);
+    }
+
+    if let Some(e) = e.downcast_ref::<TestError>() {
+        return Some(match e {
+            TestError::InvalidName(_) => ExitCode::Usage,
+            TestError::Timeout(_) => ExitCode::Timeout,
+            TestError::Failed { .. } | TestError::Closed => ExitCode::Failure,
+        });
+    }
+
+    if let Some(e) = e.downcast_ref::<PartialFailure>() {
+        return Some(e.code);
+    }
+
+    if let Some(e) = e.downcast_ref::<FleetError>() {
+        return Some(match e {
+            FleetError::Unsupported(_) => ExitCode::Usage,
+            FleetError::Failed { code, .. } => *code,
+        });
+    }
+
+    if let Some(e) = e.downcast_ref::<MirrorError>() {
+        return Some(match e {
+            MirrorError::Invalid(_) | MirrorError::UnsupportedVersion(_) => ExitCode::Usage,
+            MirrorError::Mismatch(_) => ExitCode::Failure,
+        });
+    }
+
+    if e.is::<LintError>() {
+        return Some(ExitCode::Failure);
+    }
+
+    if e.is::<ClientError>() || e.is::<SourceMapError>() {
+        return Some(ExitCode::NotFound);
+    }
+
+    if e.is::<PathError>()
+        || e.is::<InstallError>()
+        || e.is::<ManifestError>()
+        || e.is::<serde_json::Error>()
+        || e.is::<EscapeError>()
+        || e.is::<ScriptError>()
+        || e.is::<HookError>()
+        || e.is::<SnapshotError>()
+        || e.is::<GitError>()
+        || e.is::<ScaffoldError>()
+    {
+        return Some(ExitCode::Usage);
+    }
+
+    if e.is::<io::Error>() {
+        return Some(ExitCode::LocalIo);
+    }
+
+    None
+}
+
+/// The exit code for `e`, based on the first error in its chain of sources that is recognized
+pub fn exit_code(e: &(dyn Error + 'static)) -> ExitCode {
+    let mut current = Some(e);
+    while let Some(e) = current {
+        if let Some(code) = classify(e) {
+            return code;
+        }
+
+        current = e.source();
+    }
+
+    ExitCode::Failure
+}
+
+/// Prints `e` and exits with the matching exit code
+pub fn fail(output: Output, e: &(dyn Error + 'static)) -> ! {
+    let code = exit_code(e);
+    output.error(code, e);
+    code.exit()
+}
@@ -0,0 +1,282 @@
+//! Scripted conversations with the badge over its serial connection, like `expect`, to go through
+//! on-badge menus and first-boot wizards without a person at the keyboard. A script has one
+//! command per line:
+//!
+//! ```text
+//! # Comments and empty lines are ignored
+//! timeout 20s          # How long each following `expect` may wait, 10s by default
+//! expect Nickname:     # Waits until the output matches a regular expression
+//! send Jos             # Types text followed by Enter
+//! send-raw \x1b[B      # Types text without Enter
+//! sleep 500ms
+//! ```
+//!
+//! Escape sequences like `\n`, `\e` and `\xHH` are interpreted in the text of `send` and
+//! `send-raw`, as with `send -e`.
+
+use crate::{
+    device::Badge,
+    duration::{parse_duration, DurationError},
+    render::{strip_escapes, Renderer},
+    send::{self, EscapeError},
+};
+use regex::Regex;
+use std::{error::Error, fs, path::Path, time::Duration};
+use thiserror::Error;
+use tokio::{
+    sync::broadcast::{self, RecvError},
+    time::{timeout_at, Instant},
+};
+use tracing::{debug, warn};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("Line {}: unknown command {:?}", .0, .1)]
+    UnknownCommand(usize, String),
+
+    #[error("Line {}: {} needs an argument", .0, .1)]
+    MissingArgument(usize, &'static str),
+
+    #[error("Line {}: {}", .0, .1)]
+    InvalidPattern(usize, regex::Error),
+
+    #[error("Line {}: {}", .0, .1)]
+    InvalidDuration(usize, DurationError),
+
+    #[error("Line {}: {}", .0, .1)]
+    InvalidEscape(usize, EscapeError),
+}
+
+#[derive(Error, Debug)]
+pub enum ExpectError {
+    #[error("Line {}: {:?} didn't appear within {:?}", .line, .pattern, .timeout)]
+    Timeout {
+        line: usize,
+        pattern: String,
+        timeout: Duration,
+    },
+
+    #[error("The connection to the badge was closed")]
+    Closed,
+}
+
+#[derive(Debug)]
+enum Step {
+    Expect(Regex),
+    Send { text: Vec<u8>, newline: bool },
+    Timeout(Duration),
+    Sleep(Duration),
+}
+
+/// A parsed script, with the line number of each step
+#[derive(Debug)]
+pub struct Script {
+    steps: Vec<(usize, Step)>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, ScriptError> {
+        let mut steps = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (command, argument) = match line.split_once(' ') {
+                Some((command, argument)) => (command, argument),
+                None => (line, ""),
+            };
+            let required = |name: &'static str| match argument {
+                "" => Err(ScriptError::MissingArgument(number, name)),
+                argument => Ok(argument),
+            };
+            let duration = |name| {
+                parse_duration(required(name)?.trim())
+                    .map_err(|e| ScriptError::InvalidDuration(number, e))
+            };
+            let text =
+                |text| send::unescape(text).map_err(|e| ScriptError::InvalidEscape(number, e));
+
+            let step = match command {
+                "expect" => Step::Expect(
+                    Regex::new(required("expect")?)
+                        .map_err(|e| ScriptError::InvalidPattern(number, e))?,
+                ),
+                // Sending only Enter is fine
+                "send" => Step::Send {
+                    text: text(argument)?,
+                    newline: true,
+                },
+                "send-raw" => Step::Send {
+                    text: text(required("send-raw")?)?,
+                    newline: false,
+                },
+                "timeout" => Step::Timeout(duration("timeout")?),
+                "sleep" => Step::Sleep(duration("sleep")?),
+                other => return Err(ScriptError::UnknownCommand(number, other.to_owned())),
+            };
+            steps.push((number, step));
+        }
+
+        Ok(Script { steps })
+    }
+
+    pub fn load(path: &Path) -> Result<Script, Box<dyn Error + Send + Sync>> {
+        Ok(Script::parse(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Waits until the output since the last match matches `pattern`, and drops the output up to the
+/// end of the match
+async fn expect(
+    output: &mut broadcast::Receiver<String>,
+    renderer: &mut Option<Renderer>,
+    received: &mut String,
+    pattern: &Regex,
+    deadline: Instant,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    loop {
+        let stripped = strip_escapes(received);
+        if let Some(found) = pattern.find(&stripped) {
+            debug!("Matched {:?}", found.as_str());
+            *received = stripped[found.end()..].to_owned();
+            return Ok(true);
+        }
+
+        let text = match timeout_at(deadline, output.recv()).await {
+            Ok(Ok(text)) => text,
+            Ok(Err(RecvError::Lagged(n))) => {
+                warn!("Dropped {} messages of badge output", n);
+                continue;
+            }
+            Ok(Err(RecvError::Closed)) => Err(ExpectError::Closed)?,
+            Err(_) => return Ok(false),
+        };
+        if let Some(renderer) = renderer {
+            renderer.write(&text)?;
+        }
+        received.push_str(&text);
+    }
+}
+
+/// Runs `script` against the output of the badge from now on. The output is printed unless `quiet`
+/// is set.
+pub async fn run(
+    badge: &Badge,
+    script: &Script,
+    quiet: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut output = badge.subscribe();
+    let mut renderer = if quiet { None } else { Some(Renderer::new()) };
+    let mut received = String::new();
+    let mut limit = DEFAULT_TIMEOUT;
+
+    for (line, step) in script.steps.iter() {
+        match step {
+            Step::Expect(pattern) => {
+                let deadline = Instant::now() + limit;
+                if !expect(&mut output, &mut renderer, &mut received, pattern, deadline).await? {
+                    Err(ExpectError::Timeout {
+                        line: *line,
+                        pattern: pattern.as_str().to_owned(),
+                        timeout: limit,
+                    })?
+                }
+            }
+            Step::Send { text, newline } => send::send_text(badge, text, *newline).await?,
+            Step::Timeout(duration) => limit = *duration,
+            Step::Sleep(duration) => std::thread::sleep(*duration),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The steps of `script` as text, as `Step` can't be compared
+    fn steps(script: &str) -> Vec<String> {
+        Script::parse(script)
+            .unwrap()
+            .steps
+            .iter()
+            .map(|(line, step)| match step {
+                Step::Expect(pattern) => format!("{} expect {}", line, pattern),
+                Step::Send { text, newline } => {
+                    format!("{} send {:?} {}", line, text, newline)
+                }
+                Step::Timeout(duration) => format!("{} timeout {:?}", line, duration),
+                Step::Sleep(duration) => format!("{} sleep {:?}", line, duration),
+            })
+            .collect()
+    }
+
+    fn error(script: &str) -> String {
+        Script::parse(script).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_each_command() {
+        let script = "# Setup\n\
+                      \n\
+                      timeout 20s\n  \
+                      expect Nick(name)?:\n\
+                      send Jos\n\
+                      send\n\
+                      send-raw \\e[B\\x41\n\
+                      sleep 500ms\n";
+        assert_eq!(
+            steps(script),
+            [
+                "3 timeout 20s",
+                "4 expect Nick(name)?:",
+                "5 send [74, 111, 115] true",
+                "6 send [] true",
+                "7 send [27, 91, 66, 65] false",
+                "8 sleep 500ms",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_spaces_in_arguments() {
+        assert_eq!(
+            steps("expect a  b \nsend  x"),
+            ["1 expect a  b ", "2 send [32, 120] true"]
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let cases = [
+            ("send x\nexplode now", "Line 2: unknown command \"explode\""),
+            ("expect", "Line 1: expect needs an argument"),
+            ("\n\nsend-raw", "Line 3: send-raw needs an argument"),
+            ("sleep", "Line 1: sleep needs an argument"),
+            ("timeout 5d", "Line 1: Unknown unit"),
+            ("sleep soon", "Line 1: Invalid number"),
+            ("sleep 99999999999999999999h", "Line 1: Invalid number"),
+            ("timeout 18446744073709551615m", "Line 1: Duration too long"),
+            ("expect (", "Line 1: regex parse error"),
+            ("send \\q", "Line 1: "),
+            ("# \\x\nsend-raw \\x4", "Line 2: "),
+            ("send trailing\\", "Line 1: "),
+        ];
+        for (script, expected) in cases.iter() {
+            let error = error(script);
+            assert!(error.starts_with(expected), "{:?}: {}", script, error);
+        }
+    }
+
+    #[test]
+    fn accepts_an_empty_script() {
+        assert!(steps("").is_empty());
+        assert!(steps("# nothing\n\n   \n").is_empty());
+    }
+}
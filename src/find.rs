@@ -0,0 +1,122 @@
+//! Searches the filesystem of the badge by name, type and size. The badge can only list
+//! directories, so the filters are applied here while walking the tree.
+
+use crate::{
+    client::{BadgeFs, ClientError, FileKind},
+    cmds::FsEntry,
+    device::Badge,
+    ignore::glob,
+    output::Output,
+    path::BadgePath,
+    repl,
+};
+use std::error::Error;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("Unknown file type {:?}. Use f for files or d for directories.", .0)]
+pub struct KindError(String);
+
+/// Parses the argument of `find --type`
+pub fn parse_kind(s: &str) -> Result<FileKind, KindError> {
+    match s {
+        "f" | "file" => Ok(FileKind::File),
+        "d" | "dir" | "directory" => Ok(FileKind::Directory),
+        _ => Err(KindError(s.to_owned())),
+    }
+}
+
+/// Which entries `find` prints. Every filter that is set has to match.
+#[derive(Debug)]
+pub struct Filter {
+    /// A glob with `*` and `?`, matched against the file name
+    pub name: Option<String>,
+    pub kind: Option<FileKind>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl Filter {
+    fn has_size(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some()
+    }
+
+    /// Whether an entry matches, apart from its size
+    fn matches(&self, kind: FileKind, path: &BadgePath) -> bool {
+        if matches!(self.kind, Some(wanted) if wanted != kind) {
+            return false;
+        }
+        // Directories have no size of their own
+        if self.has_size() && kind == FileKind::Directory {
+            return false;
+        }
+
+        match &self.name {
+            Some(pattern) => glob(pattern.as_bytes(), path.file_name().as_bytes()),
+            None => true,
+        }
+    }
+
+    fn matches_size(&self, len: u64) -> bool {
+        !matches!(self.min_size, Some(min) if len < min)
+            && !matches!(self.max_size, Some(max) if len > max)
+    }
+}
+
+/// The size of a file, without fetching it if the Python shell is available
+async fn file_len(
+    badge: &Badge,
+    client: &BadgeFs,
+    path: &BadgePath,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    if badge.capabilities().python_shell {
+        repl::file_len(badge, path).await
+    } else {
+        client.open(path.clone()).len().await
+    }
+}
+
+/// Prints `path` and everything below it that matches `filter`, in order of their names
+pub async fn find(
+    badge: &Badge,
+    client: &BadgeFs,
+    path: &BadgePath,
+    filter: &Filter,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let kind = client.metadata(path.clone()).await?.kind;
+    let mut stack = vec![(path.clone(), kind)];
+    while let Some((path, kind)) = stack.pop() {
+        if filter.matches(kind, &path)
+            && (!filter.has_size() || filter.matches_size(file_len(badge, client, &path).await?))
+        {
+            output.entry(kind, path.as_str());
+        }
+
+        if kind == FileKind::Directory {
+            let mut children = Vec::new();
+            let mut entries = client.read_dir(path.clone());
+            loop {
+                match entries.next_entry().await {
+                    Ok(Some(FsEntry::File(name))) => {
+                        children.push((path.join(name)?, FileKind::File))
+                    }
+                    Ok(Some(FsEntry::Directory(name))) => {
+                        children.push((path.join(name)?, FileKind::Directory))
+                    }
+                    Ok(Some(FsEntry::Other(kind, name))) => {
+                        children.push((path.join(name)?, FileKind::Other(kind)))
+                    }
+                    Ok(None) => break,
+                    Err(e) if e.downcast_ref::<ClientError>().is_some() => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            children.sort_by(|(a, _), (b, _)| b.file_name().cmp(a.file_name()));
+            stack.extend(children);
+        }
+    }
+
+    Ok(())
+}
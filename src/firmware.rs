@@ -0,0 +1,95 @@
+//! Updates the firmware over the air, with the OTA updater of the firmware. The badge restarts
+//! into the updater, which downloads the new firmware over WiFi and prints its progress.
+
+use crate::{
+    device::Badge,
+    duration::parse_duration,
+    logdump,
+    repl::{self, python_literal},
+};
+use regex::Regex;
+use std::{error::Error, time::Duration};
+use structopt::StructOpt;
+use thiserror::Error;
+use tokio::time::timeout;
+use tracing::info;
+
+const REPL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lines of the updater that mean it is done, or that it failed
+const DONE: &str = r"(?i)(update (complete|successful|done)|up to date)";
+const FAILED: &str = r"(?i)(update|ota|download) (failed|error)";
+
+#[derive(Error, Debug)]
+pub enum FirmwareError {
+    #[error("The update failed: {}", .0)]
+    Failed(String),
+
+    #[error("The update didn't finish within {:?}", .0)]
+    Timeout(Duration),
+}
+
+#[derive(StructOpt, Clone)]
+pub enum FirmwareCommand {
+    #[structopt(
+        about = "Restarts the badge into the OTA updater and prints its progress until it is done"
+    )]
+    Update {
+        #[structopt(
+            long,
+            help = "Download the firmware from this URL instead of the default server. Stored in the system/ota.url setting."
+        )]
+        url: Option<String>,
+
+        #[structopt(
+            long,
+            default_value = "10m",
+            parse(try_from_str = parse_duration),
+            help = "How long the update may take, like 90s or 10m"
+        )]
+        timeout: Duration,
+    },
+}
+
+/// Starts an update and prints the output of the badge until the updater reports it is done
+async fn update(
+    badge: &Badge,
+    url: Option<&str>,
+    limit: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(url) = url {
+        let code = format!(
+            "import machine\nmachine.nvs_setstr('system', 'ota.url', {})",
+            python_literal(url)
+        );
+        repl::exec(badge, &code, REPL_TIMEOUT).await?;
+    }
+
+    let output = badge.subscribe();
+    info!("Restarting the badge into the updater");
+    // Interrupt whatever is running, so the shell is ready for input. `system.ota()` doesn't
+    // return, so it can't go through `repl::exec`.
+    badge.serial_in("\u{003}").await?;
+    badge.serial_in("import system\r\nsystem.ota()\r\n").await?;
+
+    let until = Regex::new(&format!("{}|{}", DONE, FAILED)).unwrap();
+    let line = match timeout(limit, logdump::capture(output, Some(&until))).await {
+        Ok(line) => line?,
+        Err(_) => Err(FirmwareError::Timeout(limit))?,
+    };
+    if Regex::new(FAILED).unwrap().is_match(&line) {
+        Err(FirmwareError::Failed(line.trim().to_owned()))?
+    }
+
+    Ok(())
+}
+
+/// Runs a `firmware` subcommand
+pub async fn command(
+    badge: &Badge,
+    command: FirmwareCommand,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match command {
+        FirmwareCommand::Update { url, timeout } => update(badge, url.as_deref(), timeout).await,
+    }
+}
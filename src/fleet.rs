@@ -0,0 +1,113 @@
+//! Runs a command against every connected badge at the same time. Each badge gets its own instance
+//! of the tool, started with `--device`, and every line it prints is labeled with the badge's id.
+
+use crate::{
+    device::{Device, LibUsbError},
+    exit::ExitCode,
+    output::Output,
+};
+use rusb::Context;
+use std::{
+    error::Error,
+    ffi::OsString,
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{Command, Stdio},
+    thread,
+};
+use thiserror::Error;
+use tracing::debug;
+
+pub const ALL_DEVICES_FLAG: &str = "--all-devices";
+
+#[derive(Error, Debug)]
+pub enum FleetError {
+    #[error("{} can't be used with --all-devices", .0)]
+    Unsupported(&'static str),
+
+    #[error("{} of {} badges failed", .failed, .total)]
+    Failed {
+        failed: usize,
+        total: usize,
+        code: ExitCode,
+    },
+}
+
+/// Copies the lines of `from` to `to`, each prefixed with `label`
+fn label_lines<R: Read, W: Write>(from: R, mut to: impl FnMut() -> W, label: &str) {
+    for line in BufReader::new(from).split(b'\n') {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        // One write per line, so lines of different badges don't get mixed up
+        let mut labeled = label.as_bytes().to_vec();
+        labeled.extend_from_slice(&line);
+        labeled.push(b'\n');
+        let _ = to().write_all(&labeled);
+    }
+}
+
+/// Runs this tool with the same arguments once for every connected badge, in parallel
+pub fn run_all(context: &Context, output: Output) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let badges = Device::list(context)?;
+    if badges.is_empty() {
+        Err(LibUsbError::NoDeviceFound)?;
+    }
+
+    let exe = std::env::current_exe()?;
+    let args = std::env::args_os()
+        .skip(1)
+        .filter(|arg| arg != ALL_DEVICES_FLAG)
+        .collect::<Vec<OsString>>();
+
+    let mut children = Vec::new();
+    for badge in badges.iter() {
+        debug!("Starting for badge {} at {}", badge.id(), badge.port());
+        let mut child = Command::new(&exe)
+            .arg("--device")
+            .arg(badge.port())
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let label = match output {
+            Output::Human => format!("[{}] ", badge.id()),
+            Output::Porcelain => format!("{}\t", badge.id()),
+        };
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let label2 = label.clone();
+        let threads = vec![
+            thread::spawn(move || label_lines(stdout, io::stdout, &label)),
+            thread::spawn(move || label_lines(stderr, io::stderr, &label2)),
+        ];
+        children.push((child, threads));
+    }
+
+    let total = children.len();
+    let mut failed = 0;
+    let mut first_code = None;
+    for (mut child, threads) in children {
+        let status = child.wait()?;
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        if !status.success() {
+            failed += 1;
+            first_code.get_or_insert(status.code().map_or(ExitCode::Failure, ExitCode::from_code));
+        }
+    }
+
+    match first_code {
+        Some(code) => Err(FleetError::Failed {
+            failed,
+            total,
+            code,
+        })?,
+        None => Ok(()),
+    }
+}
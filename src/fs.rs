@@ -1,34 +1,133 @@
 use crate::{
+    cache, capabilities,
+    client::{BadgeFile, BadgeFs},
     cmds::{DirectoryListingResponse, FsEntry},
     device::Badge,
+    path::BadgePath,
+    run,
+    space::{self, Usage},
     stream::Stream,
 };
 use buf_redux::Buffer;
-use fuse::{FileAttr, FileType, Filesystem};
-use libc::{EAGAIN, EIO, ENOENT, ENOSYS};
-use log::{debug, error, info};
+use fuse::{
+    consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE},
+    FileAttr, FileType, Filesystem,
+};
+use libc::{EAGAIN, EBADF, EINVAL, EIO, ENOENT, ENOSYS, ENOTDIR, O_APPEND, O_NONBLOCK};
 use nix::unistd::{getegid, geteuid};
 use std::{
-    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    future::Future,
     ops::Add,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 use time::Timespec;
 use tokio::runtime::Runtime;
+use tracing::{debug, error, info, info_span, trace, warn, Instrument, Span};
 
 // ! WARNING: Garbage ahead. Beware of the shitty code.
 
-type Node = Arc<RefCell<Ino>>;
+type Node = Arc<tokio::sync::Mutex<Ino>>;
 
-pub struct AppFS<'a> {
+/// All FUSE callbacks are dispatched onto `rt` and reply once the badge has answered, so a slow
+/// transfer doesn't stall unrelated filesystem operations. Individual nodes are protected by an
+/// async mutex, which serializes operations on the same node.
+#[derive(Clone)]
+pub struct AppFS {
     app: Arc<Badge>,
-    io: &'a Stream,
-    nodes: Vec<Node>,
-    rt: Arc<RefCell<Runtime>>,
+    client: BadgeFs,
+    io: Arc<Stream>,
+    nodes: Arc<Mutex<Vec<Node>>>,
+    dir_handles: Arc<Mutex<HashMap<u64, Vec<DirListingEntry>>>>,
+    file_handles: Arc<Mutex<HashMap<u64, FileHandle>>>,
+    next_fh: Arc<AtomicU64>,
+    /// The hash of each file when it was last opened, to tell whether the pages the kernel cached
+    /// of it are still valid
+    opened: Arc<Mutex<HashMap<u64, String>>>,
+    /// The last `statfs` result of each filesystem, and when it was fetched
+    usage: Arc<Mutex<HashMap<&'static str, (Instant, Usage)>>>,
+    rt: Arc<Runtime>,
+}
+
+/// An open file. Writes to a file are kept in its handle until the handle is flushed or released,
+/// so other handles of the file don't see them before that. They are then applied to the local
+/// copy of the node, which is shared by all handles, and sent to the badge.
+struct FileHandle {
+    flags: u32,
+    writes: WriteBuffer,
+}
+
+/// The writes through a handle that haven't been flushed yet, in the order they were made
+#[derive(Clone, Default)]
+struct WriteBuffer {
+    /// The offset of each write, or `None` for the end of the file with O_APPEND, and its data
+    writes: Vec<(Option<u64>, Vec<u8>)>,
+}
+
+impl WriteBuffer {
+    fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    fn push(&mut self, offset: Option<u64>, data: &[u8]) {
+        // Sequential writes, like those of `cp`, are merged
+        if let Some((last_offset, last_data)) = self.writes.last_mut() {
+            let follows = match (*last_offset, offset) {
+                (None, None) => true,
+                (Some(last_offset), Some(offset)) => last_offset + last_data.len() as u64 == offset,
+                _ => false,
+            };
+            if follows {
+                last_data.extend_from_slice(data);
+                return;
+            }
+        }
+
+        self.writes.push((offset, data.to_vec()));
+    }
+
+    /// Applies the writes to `contents`, growing it and filling any gap with zeroes if needed
+    fn apply(&self, contents: &mut Vec<u8>) {
+        for (offset, data) in self.writes.iter() {
+            let start = offset.map_or(contents.len(), |offset| offset as usize);
+            let end = start + data.len();
+            contents.resize(end.max(contents.len()), 0);
+            contents[start..end].copy_from_slice(data);
+        }
+    }
+
+    /// Applies the writes to the local copy of `file`. They are sent to the badge when it is
+    /// flushed.
+    async fn write_to(self, file: &mut BadgeFile) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for (offset, data) in self.writes {
+            // The offset the kernel picks for O_APPEND is based on a size that may be outdated
+            match offset {
+                Some(offset) => file.write_at(offset, &data).await?,
+                None => file.append(&data).await?,
+            };
+        }
+
+        Ok(())
+    }
+}
+
+struct DirListingEntry {
+    ino: u64,
+    kind: FileType,
+    name: String,
 }
 
 const TTL: Timespec = Timespec { sec: 10, nsec: 0 }; // 10 seconds
+const SERIAL_READ_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long `statfs` results are reused, as `df` and file managers call it often
+const STATFS_TTL: Duration = Duration::from_secs(10);
+/// How long `statfs` waits for the Python shell, so `df` doesn't hang on a slow badge
+const STATFS_TIMEOUT: Duration = Duration::from_secs(2);
 const CREATE_TIME: Timespec = Timespec {
     sec: 1381237736,
     nsec: 0,
@@ -55,115 +154,180 @@ fn default_attr() -> FileAttr {
     }
 }
 
+fn new_node(ino: Ino) -> Node {
+    Arc::new(tokio::sync::Mutex::new(ino))
+}
+
 #[derive(Debug)]
 enum InoData {
-    File { contents: Option<Vec<u8>> },
+    File { file: BadgeFile },
     Directory { children: Option<Vec<Node>> },
-    Serial { pending_data: Buffer },
+    SerialOut { pending_data: Buffer },
+    SerialIn,
     Run,
+    Reset,
+    Info,
+    Stats,
 }
 
 #[derive(Debug)]
 struct Ino {
     ino: u64,
-    path: String,
+    path: BadgePath,
     name: String,
     last_update: Instant,
     data: InoData,
 }
 
 impl Ino {
-    pub fn dir<P: Into<String>>(path: P, ino: u64) -> Ino {
+    pub fn dir(path: BadgePath, ino: u64) -> Ino {
         Ino {
             ino,
-            path: path.into(),
+            path,
             name: String::new(),
             data: InoData::Directory { children: None },
             last_update: Instant::now(),
         }
     }
 
-    pub fn ensure_data<'a>(&mut self, appfs: &mut AppFS) {
+    pub async fn ensure_data(&mut self, appfs: &AppFS) -> Result<(), Box<dyn Error + Send + Sync>> {
         let path = self.path.clone();
         match &mut self.data {
-            InoData::File { contents } => {
-                if contents.is_some() && self.last_update > Instant::now() - Duration::from_secs(30)
+            InoData::File { file } => {
+                if file.is_dirty()
+                    || file.cached_len().is_some()
+                        && self.last_update > Instant::now() - Duration::from_secs(30)
                 {
-                    // Cache file contents for 30 seconds
-                    return;
+                    // Cache file contents for 30 seconds, and until writes have been flushed
+                    appfs.app.stats().record_cache_hit();
+                    return Ok(());
                 }
 
-                println!("Loading info for {:?}", path);
-                *contents = Some(
-                    appfs
-                        .rt
-                        .borrow_mut()
-                        .block_on(async { appfs.app.fetch_file(path).await.unwrap() }),
-                );
+                appfs.app.stats().record_cache_miss();
+                debug!("Loading info for {}", path);
+                file.invalidate();
+                file.len().await?;
                 self.last_update = Instant::now();
             }
             InoData::Directory { children } => {
                 if children.is_some() && self.last_update > Instant::now() - Duration::from_secs(15)
                 {
                     // Cache directory listings for 15 seconds
-                    return;
+                    appfs.app.stats().record_cache_hit();
+                    return Ok(());
                 }
 
-                println!("Loading info for {:?}", path);
+                appfs.app.stats().record_cache_miss();
+                debug!("Loading info for {}", path);
                 if let DirectoryListingResponse::Found {
                     requested: _,
                     entries,
-                } = appfs
-                    .rt
-                    .borrow_mut()
-                    .block_on(async { appfs.app.fetch_dir(path).await.unwrap() })
+                } = appfs.app.fetch_dir(&path).await?
                 {
+                    let mut nodes = appfs.nodes.lock().unwrap();
                     let mut v = Vec::new();
                     for entry in entries.iter() {
-                        let child_ino = appfs.nodes.len() as u64;
-                        let ino_entry = Arc::new(RefCell::new(Ino {
+                        if let FsEntry::Other(kind, name) = &entry {
+                            // There is no way to tell what to show it as
+                            debug!("Skipping {:?} in {}, which has type {:?}", name, path, kind);
+                            continue;
+                        }
+                        let child_path = match self.path.join(entry.name()) {
+                            Ok(child_path) => child_path,
+                            Err(e) => {
+                                warn!("Skipping entry {:?} in {}: {}", entry.name(), path, e);
+                                continue;
+                            }
+                        };
+                        let child_ino = nodes.len() as u64;
+                        let ino_entry = new_node(Ino {
                             data: match entry {
-                                FsEntry::File(_) => InoData::File { contents: None },
+                                FsEntry::File(_) => InoData::File {
+                                    file: appfs.client.open(child_path.clone()),
+                                },
                                 FsEntry::Directory(_) => InoData::Directory { children: None },
+                                FsEntry::Other(..) => unreachable!("Skipped above"),
                             },
-                            path: if self.path == "/" {
-                                format!("/{}", entry.name())
-                            } else {
-                                format!("{}/{}", &self.path, entry.name())
-                            },
+                            path: child_path,
                             name: entry.name().to_owned(),
                             ino: child_ino,
                             last_update: Instant::now(),
-                        }));
+                        });
 
-                        appfs.nodes.push(ino_entry.clone());
+                        nodes.push(ino_entry.clone());
                         v.push(ino_entry);
                     }
 
                     *children = Some(v);
                     self.last_update = Instant::now();
-                    println!("{:?}", children);
+                    trace!("Children of {}: {:?}", path, children);
                 } else {
                     *children = None;
                 }
             }
-            InoData::Serial { pending_data } => {
+            InoData::SerialOut { pending_data } => {
                 let mut buf = [0u8; 4096];
                 let len = appfs.io.read(&mut buf);
                 pending_data.push_bytes(&buf[0..len]);
             }
-            InoData::Run => {}
+            InoData::SerialIn | InoData::Run | InoData::Reset | InoData::Info | InoData::Stats => {}
+        }
+
+        Ok(())
+    }
+
+    /// Contents of the read-only text nodes, which are generated on every access
+    fn text_contents(&self, appfs: &AppFS) -> Vec<u8> {
+        match &self.data {
+            InoData::Info => {
+                format!("{}{}", appfs.app.device_info(), appfs.app.capabilities()).into_bytes()
+            }
+            InoData::Stats => format!(
+                "{}serial_dropped_bytes: {}\n{}",
+                appfs.app.stats(),
+                appfs.io.dropped(),
+                appfs.app.health()
+            )
+            .into_bytes(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self.data {
+            InoData::Directory { children: _ } => FileType::Directory,
+            InoData::File { file: _ }
+            | InoData::SerialOut { pending_data: _ }
+            | InoData::SerialIn
+            | InoData::Run
+            | InoData::Reset
+            | InoData::Info
+            | InoData::Stats => FileType::RegularFile,
+        }
+    }
+
+    /// Flags for the reply to open(). The kernel may not cache or coalesce reads and writes of
+    /// the special nodes, because every access has side effects or returns fresh data.
+    pub fn open_flags(&self) -> u32 {
+        match self.data {
+            InoData::File { file: _ } | InoData::Directory { children: _ } => 0,
+            InoData::SerialOut { pending_data: _ }
+            | InoData::SerialIn
+            | InoData::Run
+            | InoData::Reset
+            | InoData::Info
+            | InoData::Stats => FOPEN_DIRECT_IO,
         }
     }
 
-    pub fn attr(&self) -> FileAttr {
+    pub fn attr(&self, appfs: &AppFS) -> FileAttr {
         match &self.data {
-            InoData::File { contents } => FileAttr {
+            InoData::File { file } => FileAttr {
                 ino: self.ino,
                 kind: FileType::RegularFile,
                 nlink: 1,
-                size: contents.as_ref().map(|x| x.len() as u64).unwrap_or(0),
-                blocks: contents.as_ref().map(|x| x.len() as u64).unwrap_or(0) / 4096,
+                size: file.cached_len().unwrap_or(0),
+                blocks: file.cached_len().unwrap_or(0) / 4096,
                 ..default_attr()
             },
             InoData::Directory { children } => FileAttr {
@@ -173,42 +337,108 @@ impl Ino {
                 nlink: children.as_ref().map(|x| x.len()).unwrap_or(0) as u32 + 1,
                 ..default_attr()
             },
-            InoData::Serial { pending_data: _ } => FileAttr {
+            InoData::SerialOut { pending_data: _ } => FileAttr {
                 ino: self.ino,
                 kind: FileType::RegularFile,
+                perm: 0o444,
                 nlink: 1,
                 // Fake file size to make sure minicom and/or tail -f keep reading even though we're not returning full output
                 size: 0xffffffff,
                 ..default_attr()
             },
+            InoData::SerialIn | InoData::Reset => FileAttr {
+                ino: self.ino,
+                kind: FileType::RegularFile,
+                perm: 0o222,
+                nlink: 1,
+                ..default_attr()
+            },
             InoData::Run => FileAttr {
                 ino: self.ino,
                 kind: FileType::RegularFile,
                 nlink: 1,
                 ..default_attr()
             },
+            InoData::Info | InoData::Stats => FileAttr {
+                ino: self.ino,
+                kind: FileType::RegularFile,
+                perm: 0o444,
+                nlink: 1,
+                size: self.text_contents(appfs).len() as u64,
+                ..default_attr()
+            },
         }
     }
 
-    pub fn read(&mut self, offset: usize, size: usize, reply: fuse::ReplyData, _appfs: &mut AppFS) {
+    pub async fn read(
+        &mut self,
+        fh: u64,
+        offset: usize,
+        size: usize,
+        reply: fuse::ReplyData,
+        appfs: &AppFS,
+    ) {
         match &mut self.data {
-            InoData::File {
-                contents: Some(contents),
-            } => {
-                let start = offset as usize;
-                let end = (start + size as usize).min(contents.len());
-                reply.data(&contents[start..end])
-            }
-            InoData::File { contents: _ } => {
-                panic!("Called read() on an unloaded file node");
+            InoData::File { file } => {
+                let writes = appfs
+                    .file_handles
+                    .lock()
+                    .unwrap()
+                    .get(&fh)
+                    .filter(|handle| !handle.writes.is_empty())
+                    .map(|handle| handle.writes.clone());
+                if let Some(writes) = writes {
+                    // The handle reads back what it wrote, on top of the local copy
+                    match file.read_to_end().await {
+                        Ok(contents) => {
+                            let mut contents = contents.to_vec();
+                            writes.apply(&mut contents);
+                            let start = offset.min(contents.len());
+                            let end = (start + size).min(contents.len());
+                            reply.data(&contents[start..end]);
+                        }
+                        Err(e) => {
+                            error!("Error reading file: {}", e);
+                            reply.error(EIO);
+                        }
+                    }
+                    return;
+                }
+
+                let mut buf = vec![0u8; size];
+                match file.read_at(offset as u64, &mut buf).await {
+                    Ok(len) => reply.data(&buf[..len]),
+                    Err(e) => {
+                        error!("Error reading file: {}", e);
+                        reply.error(EIO);
+                    }
+                }
             }
             InoData::Directory { children: _ } => {
                 error!("Trying to read from a directory");
                 reply.error(EIO);
             }
-            InoData::Serial { pending_data } => {
+            InoData::SerialOut { pending_data } => {
                 let mut buf = vec![0u8; size];
-                let len = pending_data.copy_to_slice(&mut buf);
+                let mut len = pending_data.copy_to_slice(&mut buf);
+                let nonblocking = match appfs.file_handles.lock().unwrap().get(&fh) {
+                    Some(handle) => handle.flags as i32 & O_NONBLOCK != 0,
+                    None => false,
+                };
+                if len == 0 && !nonblocking {
+                    // The fuse crate has no support for poll(), so instead of returning EAGAIN right away we
+                    // hold on to the read for a bit to give readers like tail -f a chance to see new output.
+                    // Readers that opened the node with O_NONBLOCK get EAGAIN right away.
+                    let io = appfs.io.clone();
+                    let (waited_buf, waited_len) = tokio::task::spawn_blocking(move || {
+                        let len = io.read_timeout(&mut buf, SERIAL_READ_TIMEOUT);
+                        (buf, len)
+                    })
+                    .await
+                    .unwrap();
+                    buf = waited_buf;
+                    len = waited_len;
+                }
                 debug!(
                     "Read bytes from serial input: {:?}",
                     std::str::from_utf8(&buf[0..len])
@@ -219,68 +449,73 @@ impl Ino {
                     reply.data(&buf[0..len]);
                 }
             }
+            InoData::SerialIn | InoData::Reset => {
+                error!("Trying to read from a write-only node");
+                reply.error(EBADF);
+            }
             InoData::Run => reply.data(&[]),
+            InoData::Info | InoData::Stats => {
+                let contents = self.text_contents(appfs);
+                let start = offset.min(contents.len());
+                let end = (start + size).min(contents.len());
+                reply.data(&contents[start..end])
+            }
         }
     }
 
-    pub fn write(&mut self, offset: usize, data: &[u8], appfs: &mut AppFS) -> Option<usize> {
+    pub async fn write(
+        &mut self,
+        fh: u64,
+        offset: usize,
+        data: &[u8],
+        append: bool,
+        appfs: &AppFS,
+    ) -> Option<usize> {
         match &mut self.data {
-            InoData::File {
-                contents: Some(contents),
-            } => {
-                let start = offset as usize;
-                let size = contents.len();
-                let end = start + data.len();
-
-                let mut new_data = contents.clone();
-
-                new_data.resize(end.max(size), 0);
-                new_data[start..end].copy_from_slice(data);
-
-                let path = self.path.clone();
-
-                match appfs
-                    .rt
-                    .borrow_mut()
-                    .block_on(async { appfs.app.write_file(&path, &new_data).await })
-                {
-                    Ok(_) => {
-                        *contents = new_data;
-                        Some(data.len())
-                    }
-                    Err(e) => {
-                        error!("Error writing file: {}", e);
-                        None
-                    }
-                }
-            }
-            InoData::File { contents: _ } => {
-                panic!("Called read() on an unloaded file node");
+            InoData::File { file: _ } => {
+                let mut handles = appfs.file_handles.lock().unwrap();
+                let handle = handles.get_mut(&fh)?;
+                let offset = if append { None } else { Some(offset as u64) };
+                handle.writes.push(offset, data);
+                Some(data.len())
             }
             InoData::Directory { children: _ } => {
-                error!("Trying to read from a directory");
+                error!("Trying to write to a directory");
                 None
             }
-            InoData::Serial { pending_data: _ } => match appfs
-                .rt
-                .borrow_mut()
-                .block_on(async { appfs.app.serial_in(&data).await })
-            {
+            InoData::SerialOut { pending_data: _ } | InoData::Info | InoData::Stats => {
+                error!("Trying to write to a read-only node");
+                None
+            }
+            InoData::SerialIn => match appfs.app.serial_in(&data).await {
                 Ok(_) => Some(data.len()),
                 Err(e) => {
                     error!("Error writing to serial: {}", e);
                     None
                 }
             },
-            InoData::Run => match appfs.rt.borrow_mut().block_on(async {
-                appfs
-                    .app
-                    .run_file(String::from_utf8(data.into()).unwrap().trim_end())
-                    .await
-            }) {
+            InoData::Run => {
+                // The path, optionally followed by arguments separated by whitespace
+                let line = String::from_utf8_lossy(data);
+                let mut words = line.split_whitespace().map(str::to_owned);
+                let result = match words.next().map(BadgePath::new) {
+                    Some(Ok(path)) => run::run(&appfs.app, &path, &words.collect::<Vec<_>>()).await,
+                    Some(Err(e)) => Err(e.into()),
+                    None => Ok(()),
+                };
+
+                match result {
+                    Ok(_) => Some(data.len()),
+                    Err(e) => {
+                        error!("Error running app: {}", e);
+                        None
+                    }
+                }
+            }
+            InoData::Reset => match appfs.app.reboot().await {
                 Ok(_) => Some(data.len()),
                 Err(e) => {
-                    error!("Error running app: {}", e);
+                    error!("Error resetting badge: {}", e);
                     None
                 }
             },
@@ -288,71 +523,234 @@ impl Ino {
     }
 }
 
-impl<'a> AppFS<'a> {
-    pub fn new(badge: Arc<Badge>, io: &'a Stream) -> AppFS<'a> {
-        let flash = Arc::new(RefCell::new(Ino {
+impl AppFS {
+    pub fn new(badge: Arc<Badge>, io: Arc<Stream>) -> AppFS {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(badge.probe());
+
+        let flash = new_node(Ino {
             ino: 2,
             last_update: Instant::now(),
             name: "flash".to_owned(),
-            path: "/flash".to_owned(),
+            path: BadgePath::new("/flash").unwrap(),
             data: InoData::Directory { children: None },
-        }));
-        let sdcard = Arc::new(RefCell::new(Ino {
+        });
+        let sdcard = new_node(Ino {
             ino: 3,
             last_update: Instant::now(),
             name: "sdcard".to_owned(),
-            path: "/sdcard".to_owned(),
+            path: BadgePath::new("/sdcard").unwrap(),
             data: InoData::Directory { children: None },
-        }));
+        });
 
-        let serial = Arc::new(RefCell::new(Ino {
+        let serial_out = new_node(Ino {
             ino: 4,
             last_update: Instant::now(),
-            name: "serial".to_owned(),
-            path: "/serial".to_owned(),
-            data: InoData::Serial {
+            name: "serial_out".to_owned(),
+            path: BadgePath::new("/serial_out").unwrap(),
+            data: InoData::SerialOut {
                 pending_data: Buffer::new(),
             },
-        }));
+        });
 
-        let run = Arc::new(RefCell::new(Ino {
+        let serial_in = new_node(Ino {
             ino: 5,
             last_update: Instant::now(),
+            name: "serial_in".to_owned(),
+            path: BadgePath::new("/serial_in").unwrap(),
+            data: InoData::SerialIn,
+        });
+
+        let run = new_node(Ino {
+            ino: 6,
+            last_update: Instant::now(),
             name: "run".to_owned(),
-            path: "/run".to_owned(),
+            path: BadgePath::new("/run").unwrap(),
             data: InoData::Run,
-        }));
+        });
+
+        let reset = new_node(Ino {
+            ino: 7,
+            last_update: Instant::now(),
+            name: "reset".to_owned(),
+            path: BadgePath::new("/reset").unwrap(),
+            data: InoData::Reset,
+        });
+
+        let info = new_node(Ino {
+            ino: 8,
+            last_update: Instant::now(),
+            name: "info".to_owned(),
+            path: BadgePath::new("/info").unwrap(),
+            data: InoData::Info,
+        });
+
+        let stats = new_node(Ino {
+            ino: 9,
+            last_update: Instant::now(),
+            name: "stats".to_owned(),
+            path: BadgePath::new("/stats").unwrap(),
+            data: InoData::Stats,
+        });
 
         AppFS {
+            client: BadgeFs::new(badge.clone()),
             app: badge,
             io,
-            nodes: vec![
-                Arc::new(RefCell::new(Ino::dir("ERROR", 1))),
-                Arc::new(RefCell::new(Ino {
+            nodes: Arc::new(Mutex::new(vec![
+                new_node(Ino::dir(BadgePath::new("/ERROR").unwrap(), 1)),
+                new_node(Ino {
                     ino: 1,
                     last_update: Instant::now().add(Duration::from_secs(0xffff_ffff)),
                     name: "".to_owned(),
-                    path: "/".to_owned(),
+                    path: BadgePath::root(),
                     data: InoData::Directory {
                         children: Some(vec![
                             flash.clone(),
                             sdcard.clone(),
-                            serial.clone(),
+                            serial_out.clone(),
+                            serial_in.clone(),
                             run.clone(),
+                            reset.clone(),
+                            info.clone(),
+                            stats.clone(),
                         ]),
                     },
-                })),
+                }),
                 flash,
                 sdcard,
-                serial,
+                serial_out,
+                serial_in,
                 run,
-            ],
-            rt: Arc::new(RefCell::new(Runtime::new().unwrap())),
+                reset,
+                info,
+                stats,
+            ])),
+            dir_handles: Arc::new(Mutex::new(HashMap::new())),
+            file_handles: Arc::new(Mutex::new(HashMap::new())),
+            next_fh: Arc::new(AtomicU64::new(1)),
+            opened: Arc::new(Mutex::new(HashMap::new())),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            rt: Arc::new(rt),
         }
     }
+
+    fn node(&self, ino: u64) -> Option<Node> {
+        self.nodes.lock().unwrap().get(ino as usize).cloned()
+    }
+
+    fn push_node(&self, f: impl FnOnce(u64) -> Ino) -> Node {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = new_node(f(nodes.len() as u64));
+        nodes.push(node.clone());
+
+        node
+    }
+
+    /// Whether the kernel may keep the pages it cached of the file `ino`, because its contents are
+    /// the same as when it was last opened
+    async fn keep_cache(&self, ino: u64, file: &mut BadgeFile) -> bool {
+        let hash = match file.read_to_end().await {
+            Ok(contents) => cache::hash(&contents),
+            Err(e) => {
+                debug!("Unable to load {} to compare it: {}", ino, e);
+                self.opened.lock().unwrap().remove(&ino);
+                return false;
+            }
+        };
+
+        self.opened.lock().unwrap().insert(ino, hash.clone()) == Some(hash)
+    }
+
+    /// The writes through `fh` that haven't been flushed, which are taken out of the handle
+    fn take_writes(&self, fh: u64) -> WriteBuffer {
+        self.file_handles
+            .lock()
+            .unwrap()
+            .get_mut(&fh)
+            .map(|handle| std::mem::take(&mut handle.writes))
+            .unwrap_or_default()
+    }
+
+    /// Sends `writes` and any other unflushed writes to `ino` to the badge
+    async fn flush(&self, ino: u64, writes: WriteBuffer, reply: fuse::ReplyEmpty) {
+        if let Some(entry) = self.node(ino) {
+            let mut entry = entry.lock().await;
+            let path = entry.path.clone();
+            if let InoData::File { file } = &mut entry.data {
+                let result = match writes.write_to(file).await {
+                    Ok(()) => file.flush().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("Error writing {}: {}", path, e);
+                    reply.error(EIO);
+                    return;
+                }
+            }
+
+            reply.ok();
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    /// The space on the filesystem `path` is on, asked from the Python shell at most every
+    /// `STATFS_TTL`. While an app is running the shell can't be asked, so the last known values are
+    /// reported, or zeros. The root and the special nodes report /flash.
+    async fn usage(&self, path: &BadgePath) -> Usage {
+        let filesystem = if path.as_str() == "/sdcard" || path.as_str().starts_with("/sdcard/") {
+            "/sdcard"
+        } else {
+            "/flash"
+        };
+
+        let cached = self.usage.lock().unwrap().get(filesystem).copied();
+        if let Some((fetched, usage)) = cached {
+            if fetched.elapsed() < STATFS_TTL {
+                return usage;
+            }
+        }
+
+        let known = cached.map(|(_, usage)| usage).unwrap_or_default();
+        // The code would be typed into a running app instead
+        let usage = if !self.app.capabilities().python_shell
+            || !capabilities::shell_is_idle(&self.app).await
+        {
+            debug!(
+                "The Python shell is busy, not updating the size of {}",
+                filesystem
+            );
+            known
+        } else {
+            match space::usage(&self.app, filesystem, &[], STATFS_TIMEOUT).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    warn!("Unable to get the size of {}: {}", filesystem, e);
+                    known
+                }
+            }
+        };
+        self.usage
+            .lock()
+            .unwrap()
+            .insert(filesystem, (Instant::now(), usage));
+
+        usage
+    }
+
+    /// Runs `f` on the runtime with a clone of this filesystem. The FUSE callback returns
+    /// immediately, `f` is responsible for sending the reply.
+    fn spawn<F, Fut>(&self, span: Span, f: F)
+    where
+        F: FnOnce(AppFS) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.rt.spawn(f(self.clone()).instrument(span));
+    }
 }
 
-impl<'a> Filesystem for AppFS<'a> {
+impl Filesystem for AppFS {
     fn lookup(
         &mut self,
         _req: &fuse::Request,
@@ -360,41 +758,53 @@ impl<'a> Filesystem for AppFS<'a> {
         name: &std::ffi::OsStr,
         reply: fuse::ReplyEntry,
     ) {
-        info!("lookup({}, {:?})", parent, name);
-        if let Some(entry) = self.nodes.get(parent as usize) {
-            let entry = entry.clone();
-            let entry = entry.borrow();
-            match &entry.data {
-                InoData::Directory {
-                    children: Some(children),
-                } => {
-                    if let Some(child) = children
-                        .iter()
-                        .filter(|n| n.borrow().name.as_str() == name)
-                        .next()
-                    {
-                        child.borrow_mut().ensure_data(self);
-                        let child = child.borrow();
-                        let result = child.attr();
-                        debug!("Attr result: {:?}", result);
-                        reply.entry(&TTL, &result, 0);
-                    } else {
-                        debug!("ENOENT: Node not found in children");
+        let span = info_span!("lookup", parent, ?name);
+        let name = name.to_owned();
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(parent) {
+                let entry = entry.lock().await;
+                match &entry.data {
+                    InoData::Directory {
+                        children: Some(children),
+                    } => {
+                        let mut found = None;
+                        for child in children.iter() {
+                            if child.lock().await.name.as_str() == name {
+                                found = Some(child.clone());
+                                break;
+                            }
+                        }
+                        drop(entry);
+
+                        if let Some(child) = found {
+                            let mut child = child.lock().await;
+                            if let Err(e) = child.ensure_data(&fs).await {
+                                error!("Error loading {}: {}", child.path, e);
+                                reply.error(EIO);
+                                return;
+                            }
+                            let result = child.attr(&fs);
+                            debug!("Attr result: {:?}", result);
+                            reply.entry(&TTL, &result, 0);
+                        } else {
+                            debug!("ENOENT: Node not found in children");
+                            reply.error(ENOENT);
+                        }
+                    }
+                    InoData::Directory { children: None } => {
+                        error!("Tried to lookup file in directory which was not loaded.");
+                        reply.error(EIO);
+                    }
+                    _ => {
+                        error!("Tried to load children of a non-directory");
                         reply.error(ENOENT);
                     }
                 }
-                InoData::Directory { children: None } => {
-                    panic!("Tried to lookup file in directory which was not loaded.");
-                }
-                _ => {
-                    error!("Tried to load children of a non-directory");
-                    reply.error(ENOENT);
-                }
+            } else {
+                debug!("ENOENT: Unknown ino");
+                reply.error(ENOENT);
             }
-        } else {
-            debug!("ENOENT: Unknown ino");
-            reply.error(ENOENT);
-        }
+        });
     }
 
     fn forget(&mut self, _req: &fuse::Request, _ino: u64, _nlookup: u64) {
@@ -402,14 +812,20 @@ impl<'a> Filesystem for AppFS<'a> {
     }
 
     fn getattr(&mut self, _req: &fuse::Request, ino: u64, reply: fuse::ReplyAttr) {
-        info!("getattr({})", ino);
-        if let Some(entry) = self.nodes.get(ino as usize) {
-            let entry = entry.clone();
-            entry.borrow_mut().ensure_data(self);
-            reply.attr(&TTL, &entry.borrow().attr());
-        } else {
-            reply.error(ENOENT);
-        }
+        let span = info_span!("getattr", ino);
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(ino) {
+                let mut entry = entry.lock().await;
+                if let Err(e) = entry.ensure_data(&fs).await {
+                    error!("Error loading {}: {}", entry.path, e);
+                    reply.error(EIO);
+                } else {
+                    reply.attr(&TTL, &entry.attr(&fs));
+                }
+            } else {
+                reply.error(ENOENT);
+            }
+        });
     }
 
     fn mknod(
@@ -421,26 +837,28 @@ impl<'a> Filesystem for AppFS<'a> {
         _rdev: u32,
         reply: fuse::ReplyEntry,
     ) {
-        info!("mknod({}, {})", parent, name.to_str().unwrap());
-        if let Some(entry) = self.nodes.get(parent as usize) {
-            let name = name.to_str().unwrap();
-            let path = format!("{}/{}", entry.borrow().path, name);
-            match &mut entry.clone().borrow_mut().data {
-                InoData::Directory { children } => {
-                    let new_node = Arc::new(RefCell::new(Ino {
-                        ino: self.nodes.len() as u64,
-                        path: path.clone(),
-                        name: name.to_owned(),
-                        data: InoData::File { contents: None },
-                        last_update: Instant::now(),
-                    }));
-
-                    match self
-                        .rt
-                        .borrow_mut()
-                        .block_on(async { self.app.create_file(path).await })
-                    {
+        let span = info_span!("mknod", parent, ?name);
+        let name = name.to_str().unwrap().to_owned();
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(parent) {
+                let mut entry = entry.lock().await;
+                let path = match entry.path.join(&name) {
+                    Ok(path) => path,
+                    Err(_) => return reply.error(EINVAL),
+                };
+                match &mut entry.data {
+                    InoData::Directory { children } => match fs.app.create_file(&path).await {
                         Ok(_) => {
+                            let new_node = fs.push_node(|ino| Ino {
+                                ino,
+                                path: path.clone(),
+                                name: name.clone(),
+                                data: InoData::File {
+                                    file: fs.client.open(path.clone()),
+                                },
+                                last_update: Instant::now(),
+                            });
+
                             if let Some(children) = children {
                                 children.push(new_node.clone());
                             }
@@ -448,30 +866,28 @@ impl<'a> Filesystem for AppFS<'a> {
                             reply.entry(
                                 &TTL,
                                 &FileAttr {
-                                    ino: new_node.borrow().ino,
+                                    ino: new_node.lock().await.ino,
                                     kind: FileType::RegularFile,
                                     nlink: 1,
                                     ..default_attr()
                                 },
                                 0,
                             );
-
-                            self.nodes.push(new_node.clone());
                         }
                         Err(e) => {
                             error!("Error creating file: {}", e);
                             reply.error(EIO);
                         }
+                    },
+                    _ => {
+                        error!("Tried to mknod on a non-directory");
+                        reply.error(ENOENT)
                     }
                 }
-                _ => {
-                    error!("Tried to mknod on a non-directory");
-                    reply.error(ENOENT)
-                }
+            } else {
+                reply.error(ENOENT);
             }
-        } else {
-            reply.error(ENOENT);
-        }
+        });
     }
 
     fn mkdir(
@@ -482,49 +898,48 @@ impl<'a> Filesystem for AppFS<'a> {
         _mode: u32,
         reply: fuse::ReplyEntry,
     ) {
-        info!("mkdir({}, {})", parent, name.to_str().unwrap());
-        if let Some(entry) = self.nodes.get(parent as usize) {
-            let name = name.to_str().unwrap();
-            let path = format!("{}/{}", entry.borrow().path, name);
-            match &mut entry.clone().borrow_mut().data {
-                InoData::Directory { children } => {
-                    let new_node = Arc::new(RefCell::new(Ino {
-                        ino: self.nodes.len() as u64,
-                        path: path.clone(),
-                        name: name.to_owned(),
-                        last_update: Instant::now(),
-                        data: InoData::Directory {
-                            children: Some(Vec::new()),
-                        },
-                    }));
-
-                    match self
-                        .rt
-                        .borrow_mut()
-                        .block_on(async { self.app.create_dir(path).await })
-                    {
+        let span = info_span!("mkdir", parent, ?name);
+        let name = name.to_str().unwrap().to_owned();
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(parent) {
+                let mut entry = entry.lock().await;
+                let path = match entry.path.join(&name) {
+                    Ok(path) => path,
+                    Err(_) => return reply.error(EINVAL),
+                };
+                match &mut entry.data {
+                    InoData::Directory { children } => match fs.app.create_dir(&path).await {
                         Ok(_) => {
+                            let new_node = fs.push_node(|ino| Ino {
+                                ino,
+                                path: path.clone(),
+                                name: name.clone(),
+                                last_update: Instant::now(),
+                                data: InoData::Directory {
+                                    children: Some(Vec::new()),
+                                },
+                            });
+
                             if let Some(children) = children {
                                 children.push(new_node.clone());
                             }
 
-                            reply.entry(&TTL, &new_node.borrow().attr(), 0);
-                            self.nodes.push(new_node.clone());
+                            reply.entry(&TTL, &new_node.lock().await.attr(&fs), 0);
                         }
                         Err(e) => {
                             error!("Error creating directory: {}", e);
                             reply.error(EIO);
                         }
+                    },
+                    _ => {
+                        error!("mkdir on a non-directory");
+                        reply.error(ENOENT);
                     }
                 }
-                _ => {
-                    error!("mkdir on a non-directory");
-                    reply.error(ENOENT);
-                }
+            } else {
+                reply.error(ENOENT);
             }
-        } else {
-            reply.error(ENOENT);
-        }
+        });
     }
 
     fn unlink(
@@ -534,20 +949,21 @@ impl<'a> Filesystem for AppFS<'a> {
         name: &std::ffi::OsStr,
         reply: fuse::ReplyEmpty,
     ) {
-        info!("unlink({}, {})", parent, name.to_str().unwrap());
-        if let Some(entry) = self.nodes.get(parent as usize) {
-            let path = format!("{}/{}", entry.borrow().path, name.to_str().unwrap());
-            info!("Unlinking {}", path);
-            match &mut entry.borrow_mut().data {
-                InoData::Directory { children } => {
-                    match self
-                        .rt
-                        .borrow_mut()
-                        .block_on(async { self.app.delete_path(&path).await })
-                    {
+        let span = info_span!("unlink", parent, ?name);
+        let name = name.to_str().unwrap().to_owned();
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(parent) {
+                let mut entry = entry.lock().await;
+                let path = match entry.path.join(&name) {
+                    Ok(path) => path,
+                    Err(_) => return reply.error(EINVAL),
+                };
+                info!("Unlinking {}", path);
+                match &mut entry.data {
+                    InoData::Directory { children } => match fs.app.delete_path(&path).await {
                         Ok(_) => {
                             if let Some(children) = children {
-                                children.retain(|item| item.borrow().path != path);
+                                remove_child(children, &path).await;
                             }
 
                             reply.ok()
@@ -556,16 +972,16 @@ impl<'a> Filesystem for AppFS<'a> {
                             error!("Error deleting file: {}", e);
                             reply.error(EIO);
                         }
+                    },
+                    _ => {
+                        error!("Tried to unlink a file inside a non-directory");
+                        reply.error(ENOENT);
                     }
                 }
-                _ => {
-                    error!("Tried to unlink a file inside a non-directory");
-                    reply.error(ENOENT);
-                }
+            } else {
+                reply.error(ENOENT);
             }
-        } else {
-            reply.error(ENOENT);
-        }
+        });
     }
 
     fn rmdir(
@@ -575,19 +991,20 @@ impl<'a> Filesystem for AppFS<'a> {
         name: &std::ffi::OsStr,
         reply: fuse::ReplyEmpty,
     ) {
-        info!("rmdir({}, {})", parent, name.to_str().unwrap());
-        if let Some(entry) = self.nodes.get(parent as usize) {
-            let path = format!("{}/{}", entry.borrow().path, name.to_str().unwrap());
-            match &mut entry.borrow_mut().data {
-                InoData::Directory { children } => {
-                    match self
-                        .rt
-                        .borrow_mut()
-                        .block_on(async { self.app.delete_path(&path).await })
-                    {
+        let span = info_span!("rmdir", parent, ?name);
+        let name = name.to_str().unwrap().to_owned();
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(parent) {
+                let mut entry = entry.lock().await;
+                let path = match entry.path.join(&name) {
+                    Ok(path) => path,
+                    Err(_) => return reply.error(EINVAL),
+                };
+                match &mut entry.data {
+                    InoData::Directory { children } => match fs.app.delete_path(&path).await {
                         Ok(_) => {
                             if let Some(children) = children {
-                                children.retain(|item| item.borrow().path != path);
+                                remove_child(children, &path).await;
                             }
                             reply.ok()
                         }
@@ -595,16 +1012,16 @@ impl<'a> Filesystem for AppFS<'a> {
                             error!("Error deleting directory: {}", e);
                             reply.error(EIO);
                         }
+                    },
+                    _ => {
+                        error!("rmdir on a non-directory");
+                        reply.error(ENOENT);
                     }
                 }
-                _ => {
-                    error!("rmdir on a non-directory");
-                    reply.error(ENOENT);
-                }
+            } else {
+                reply.error(ENOENT);
             }
-        } else {
-            reply.error(ENOENT);
-        }
+        });
     }
 
     fn rename(
@@ -616,222 +1033,350 @@ impl<'a> Filesystem for AppFS<'a> {
         newname: &std::ffi::OsStr,
         reply: fuse::ReplyEmpty,
     ) {
-        info!("rename({}, {})", parent, name.to_str().unwrap());
-        if let (Some(from), Some(to)) = (
-            self.nodes.get(parent as usize),
-            self.nodes.get(newparent as usize),
-        ) {
-            let from_path = format!("{}/{}", from.borrow().path, name.to_str().unwrap());
-            let to_path = format!("{}/{}", to.borrow().path, newname.to_str().unwrap());
-            match (&mut from.borrow_mut().data, &mut to.borrow_mut().data) {
-                (
-                    InoData::Directory {
-                        children: from_children,
-                    },
-                    InoData::Directory {
-                        children: to_children,
-                    },
-                ) => {
-                    match self
-                        .rt
-                        .borrow_mut()
-                        .block_on(async { self.app.move_file(&from_path, &to_path).await })
-                    {
-                        Ok(_) => {
-                            if let Some(from_children) = from_children {
-                                if let Some(to_children) = to_children {
-                                    let item = from_children
-                                        .iter()
-                                        .filter(|item| item.borrow().path == from_path)
-                                        .next()
-                                        .unwrap()
-                                        .clone();
-                                    item.borrow_mut().path = to_path.clone();
-                                    item.borrow_mut().name = newname.to_str().unwrap().to_owned();
-                                    to_children.push(item);
+        let span = info_span!("rename", parent, ?name, newparent, ?newname);
+        let name = name.to_str().unwrap().to_owned();
+        let newname = newname.to_str().unwrap().to_owned();
+        self.spawn(span, |fs| async move {
+            if let (Some(from), Some(to)) = (fs.node(parent), fs.node(newparent)) {
+                // Always lock the parent with the lowest ino first, so two renames in opposite
+                // directions can't deadlock.
+                let (mut from_entry, mut to_entry) = if parent == newparent {
+                    (from.lock().await, None)
+                } else if parent < newparent {
+                    let from_entry = from.lock().await;
+                    (from_entry, Some(to.lock().await))
+                } else {
+                    let to_entry = to.lock().await;
+                    (from.lock().await, Some(to_entry))
+                };
+
+                let from_path = from_entry.path.join(&name);
+                let to_path = to_entry
+                    .as_ref()
+                    .map(|x| &x.path)
+                    .unwrap_or(&from_entry.path)
+                    .join(&newname);
+                let (from_path, to_path) = match (from_path, to_path) {
+                    (Ok(from_path), Ok(to_path)) => (from_path, to_path),
+                    _ => return reply.error(EINVAL),
+                };
+
+                if from_entry.file_type() != FileType::Directory
+                    || to_entry.as_ref().map(|x| x.file_type()) == Some(FileType::RegularFile)
+                {
+                    error!("Rename where one of the parents isn't a directory");
+                    reply.error(ENOENT);
+                    return;
+                }
+
+                match fs.app.move_file(&from_path, &to_path).await {
+                    Ok(_) => {
+                        let item = if let InoData::Directory {
+                            children: Some(from_children),
+                        } = &mut from_entry.data
+                        {
+                            let mut item = None;
+                            for child in from_children.iter() {
+                                // The new parent might be a child of the old parent, and we're already holding its lock
+                                if Arc::ptr_eq(child, &to) {
+                                    continue;
                                 }
 
-                                from_children.retain(|item| item.borrow().path != from_path);
+                                if child.lock().await.path == from_path {
+                                    item = Some(child.clone());
+                                    break;
+                                }
                             }
 
-                            reply.ok()
-                        }
-                        Err(e) => {
-                            error!("Error deleting file: {}", e);
-                            reply.error(EIO);
+                            if let Some(item) = &item {
+                                from_children.retain(|child| !Arc::ptr_eq(child, item));
+                            }
+
+                            item
+                        } else {
+                            None
+                        };
+
+                        if let Some(item) = item {
+                            {
+                                let mut item = item.lock().await;
+                                item.path = to_path.clone();
+                                item.name = newname.clone();
+                                if let InoData::File { file } = &mut item.data {
+                                    file.set_path(to_path.clone());
+                                }
+                            }
+
+                            let to_data = match &mut to_entry {
+                                Some(to_entry) => &mut to_entry.data,
+                                None => &mut from_entry.data,
+                            };
+                            if let InoData::Directory {
+                                children: Some(to_children),
+                            } = to_data
+                            {
+                                to_children.push(item);
+                            }
                         }
+
+                        reply.ok()
+                    }
+                    Err(e) => {
+                        error!("Error moving file: {}", e);
+                        reply.error(EIO);
                     }
                 }
-                _ => {
-                    error!("Rename where one of the parents isn't a directory");
-                }
+            } else {
+                reply.error(ENOENT);
             }
-        } else {
-            reply.error(ENOENT);
-        }
+        });
     }
 
-    fn open(&mut self, _req: &fuse::Request, ino: u64, _flags: u32, reply: fuse::ReplyOpen) {
-        info!("open()");
-        if let Some(_) = self.nodes.get(ino as usize) {
-            reply.opened(0, 0);
-        } else {
-            reply.error(ENOENT);
-        }
+    fn open(&mut self, _req: &fuse::Request, ino: u64, flags: u32, reply: fuse::ReplyOpen) {
+        let span = info_span!("open", ino, flags);
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(ino) {
+                let mut entry = entry.lock().await;
+                let mut open_flags = entry.open_flags();
+                if let InoData::File { file: _ } = entry.data {
+                    // Refreshes the local copy if it is outdated
+                    if let Err(e) = entry.ensure_data(&fs).await {
+                        debug!("Unable to load {}: {}", entry.path, e);
+                    }
+                }
+                if let InoData::File { file } = &mut entry.data {
+                    if fs.keep_cache(ino, file).await {
+                        open_flags |= FOPEN_KEEP_CACHE;
+                    }
+                }
+                let fh = fs.next_fh.fetch_add(1, Ordering::Relaxed);
+                fs.file_handles.lock().unwrap().insert(
+                    fh,
+                    FileHandle {
+                        flags,
+                        writes: WriteBuffer::default(),
+                    },
+                );
+                debug!("Opened handle {}", fh);
+                reply.opened(fh, open_flags);
+            } else {
+                reply.error(ENOENT);
+            }
+        });
     }
 
     fn read(
         &mut self,
         _req: &fuse::Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         reply: fuse::ReplyData,
     ) {
-        info!("read({}, .., {}, {})", ino, offset, size);
-        if let Some(entry) = self.nodes.get(ino as usize) {
-            let entry = entry.clone();
-            let mut entry = entry.borrow_mut();
-            entry.ensure_data(self);
-            entry.read(offset as usize, size as usize, reply, self);
-        } else {
-            reply.error(ENOENT);
-        }
+        let span = info_span!("read", ino, fh, offset, size);
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(ino) {
+                let mut entry = entry.lock().await;
+                if let Err(e) = entry.ensure_data(&fs).await {
+                    error!("Error loading {}: {}", entry.path, e);
+                    reply.error(EIO);
+                    return;
+                }
+
+                entry
+                    .read(fh, offset as usize, size as usize, reply, &fs)
+                    .await;
+            } else {
+                reply.error(ENOENT);
+            }
+        });
     }
 
     fn write(
         &mut self,
         _req: &fuse::Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _flags: u32,
         reply: fuse::ReplyWrite,
     ) {
-        info!("write({}, {}, {:?})", ino, offset, data);
-        if let Some(entry) = self.nodes.get(ino as usize) {
-            let entry = entry.clone();
-            let mut entry = entry.borrow_mut();
-            entry.ensure_data(self);
-
-            if let Some(size) = entry.write(offset as usize, data, self) {
-                reply.written(size as u32);
+        let span = info_span!("write", ino, fh, offset, bytes = data.len());
+        let data = data.to_vec();
+        self.spawn(span, |fs| async move {
+            let flags = match fs.file_handles.lock().unwrap().get(&fh) {
+                Some(handle) => handle.flags,
+                None => {
+                    error!("write() on unknown file handle {}", fh);
+                    reply.error(EBADF);
+                    return;
+                }
+            };
+
+            if let Some(entry) = fs.node(ino) {
+                let mut entry = entry.lock().await;
+                if let Err(e) = entry.ensure_data(&fs).await {
+                    error!("Error loading {}: {}", entry.path, e);
+                    reply.error(EIO);
+                    return;
+                }
+
+                let append = flags as i32 & O_APPEND != 0;
+                if let Some(size) = entry.write(fh, offset as usize, &data, append, &fs).await {
+                    reply.written(size as u32);
+                } else {
+                    error!("Error writing file!");
+                    reply.error(EIO);
+                }
             } else {
-                error!("Error writing file!");
-                reply.error(EIO);
+                reply.error(ENOENT);
             }
-        } else {
-            reply.error(ENOENT);
-        }
+        });
     }
 
     fn flush(
         &mut self,
         _req: &fuse::Request,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _lock_owner: u64,
         reply: fuse::ReplyEmpty,
     ) {
-        info!("flush()");
-        reply.error(ENOSYS);
+        let span = info_span!("flush", ino, fh);
+        self.spawn(span, |fs| async move {
+            let writes = fs.take_writes(fh);
+            fs.flush(ino, writes, reply).await
+        });
     }
 
     fn release(
         &mut self,
         _req: &fuse::Request,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _flags: u32,
         _lock_owner: u64,
         _flush: bool,
         reply: fuse::ReplyEmpty,
     ) {
-        info!("release()");
-        reply.ok();
+        let span = info_span!("release", ino, fh);
+        self.spawn(span, |fs| async move {
+            let writes = match fs.file_handles.lock().unwrap().remove(&fh) {
+                Some(handle) => handle.writes,
+                None => {
+                    warn!("release() on unknown file handle {}", fh);
+                    WriteBuffer::default()
+                }
+            };
+
+            fs.flush(ino, writes, reply).await
+        });
     }
 
     fn fsync(
         &mut self,
         _req: &fuse::Request,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _datasync: bool,
         reply: fuse::ReplyEmpty,
     ) {
-        info!("fsync()");
-        reply.error(ENOSYS);
+        let span = info_span!("fsync", ino, fh);
+        self.spawn(span, |fs| async move {
+            let writes = fs.take_writes(fh);
+            fs.flush(ino, writes, reply).await
+        });
     }
 
     fn opendir(&mut self, _req: &fuse::Request, ino: u64, _flags: u32, reply: fuse::ReplyOpen) {
-        info!(
-            "opendir({} = {:?})",
-            ino,
-            self.nodes
-                .get(ino as usize)
-                .map(|n| n.borrow().path.clone())
-                .unwrap_or("<unknown>".to_owned())
-        );
-        reply.opened(0, 0);
+        let span = info_span!("opendir", ino);
+        self.spawn(span, |fs| async move {
+            if let Some(entry) = fs.node(ino) {
+                let mut entry = entry.lock().await;
+                if let Err(e) = entry.ensure_data(&fs).await {
+                    error!("Error loading {}: {}", entry.path, e);
+                    reply.error(EIO);
+                    return;
+                }
+
+                match &entry.data {
+                    InoData::Directory {
+                        children: Some(children),
+                    } => {
+                        // Snapshot the listing, so that offsets stay valid for every readdir() on this handle
+                        // even if the directory is reloaded in between.
+                        let mut listing = vec![
+                            DirListingEntry {
+                                ino,
+                                kind: FileType::Directory,
+                                name: ".".to_owned(),
+                            },
+                            DirListingEntry {
+                                ino,
+                                kind: FileType::Directory,
+                                name: "..".to_owned(),
+                            },
+                        ];
+                        for child in children.iter() {
+                            let child = child.lock().await;
+                            listing.push(DirListingEntry {
+                                ino: child.ino,
+                                kind: child.file_type(),
+                                name: child.name.clone(),
+                            });
+                        }
+
+                        debug!(
+                            "opendir({} = {:?}): {} entries",
+                            ino,
+                            entry.path,
+                            listing.len()
+                        );
+                        let fh = fs.next_fh.fetch_add(1, Ordering::Relaxed);
+                        fs.dir_handles.lock().unwrap().insert(fh, listing);
+                        reply.opened(fh, 0);
+                    }
+                    InoData::Directory { children: None } => reply.error(ENOENT),
+                    _ => {
+                        error!("Tried to opendir() on a non-directory");
+                        reply.error(ENOTDIR);
+                    }
+                }
+            } else {
+                reply.error(ENOENT);
+            }
+        });
     }
 
     fn readdir(
         &mut self,
         _req: &fuse::Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: fuse::ReplyDirectory,
     ) {
-        info!("readdir(.., {}, .., {})", ino, offset);
-        if let Some(parent_entry) = self.nodes.get(ino as usize) {
-            let parent_entry = parent_entry.borrow();
-            match &parent_entry.data {
-                InoData::Directory { children } => {
-                    if let Some(children) = &children {
-                        if offset < 1 {
-                            reply.add(ino, 1, FileType::Directory, ".");
-                        }
-                        if offset < 2 {
-                            reply.add(ino, 2, FileType::Directory, "..");
-                        }
-
-                        for (offset, entry) in children
-                            .iter()
-                            .enumerate()
-                            .skip(offset.checked_sub(2).unwrap_or(0) as usize)
-                            .map(|(x, e)| (x as i64 + 3, e))
-                        {
-                            let entry = entry.borrow();
-                            debug!("Adding child {} to response", entry.path);
-                            // ! TODO: Duplicate FileType mapping
-                            if reply.add(
-                                entry.ino,
-                                offset,
-                                match entry.data {
-                                    InoData::File { contents: _ } => FileType::RegularFile,
-                                    InoData::Directory { children: _ } => FileType::Directory,
-                                    InoData::Serial { pending_data: _ } => FileType::RegularFile,
-                                    InoData::Run => FileType::RegularFile,
-                                },
-                                &entry.name,
-                            ) {
-                                break;
-                            }
-                        }
-
-                        reply.ok()
-                    } else {
-                        reply.error(ENOENT)
-                    }
-                }
-                _ => {
-                    error!("Tried to readdir() on a non-directory");
-                    reply.error(ENOENT);
+        info!("readdir(.., {}, {}, {})", ino, fh, offset);
+        if let Some(listing) = self.dir_handles.lock().unwrap().get(&fh) {
+            // The offset passed to reply.add() is the offset of the *next* entry
+            for (offset, entry) in listing
+                .iter()
+                .enumerate()
+                .skip(offset.max(0) as usize)
+                .map(|(x, e)| (x as i64 + 1, e))
+            {
+                debug!("Adding child {} to response", entry.name);
+                if reply.add(entry.ino, offset, entry.kind, &entry.name) {
+                    break;
                 }
             }
+
+            reply.ok()
+        } else {
+            error!("readdir() on unknown directory handle {}", fh);
+            reply.error(EBADF);
         }
     }
 
@@ -844,12 +1389,35 @@ impl<'a> Filesystem for AppFS<'a> {
         reply: fuse::ReplyEmpty,
     ) {
         info!("releasedir({}, {})", ino, fh);
+        self.dir_handles.lock().unwrap().remove(&fh);
         reply.ok();
     }
 
-    fn statfs(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyStatfs) {
-        info!("statfs()");
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+    fn statfs(&mut self, _req: &fuse::Request, ino: u64, reply: fuse::ReplyStatfs) {
+        let span = info_span!("statfs", ino);
+        self.spawn(span, |fs| async move {
+            let path = match fs.node(ino) {
+                Some(entry) => entry.lock().await.path.clone(),
+                None => return reply.error(ENOENT),
+            };
+            let usage = fs.usage(&path).await;
+            // Zero while unknown
+            let block_size = if usage.block_size == 0 {
+                512
+            } else {
+                usage.block_size as u32
+            };
+            reply.statfs(
+                usage.blocks,
+                usage.free_blocks,
+                usage.available_blocks,
+                0,
+                0,
+                block_size,
+                255,
+                block_size,
+            )
+        });
     }
 
     fn setxattr(
@@ -934,60 +1502,50 @@ impl<'a> Filesystem for AppFS<'a> {
         _flags: Option<u32>,
         reply: fuse::ReplyAttr,
     ) {
-        info!("setattr({}, .., size={:?})", ino, size);
-        if let Some(node) = self.nodes.get(ino as usize) {
-            let node = node.clone();
-            let mut node = node.borrow_mut();
-            let path = node.path.clone();
-            node.ensure_data(self);
-            match &mut node.data {
-                InoData::File {
-                    contents: Some(contents),
-                } => {
-                    if let Some(new_size) = size {
-                        let result = self
-                            .rt
-                            .borrow_mut()
-                            .block_on(async {
-                                self.app
-                                    .write_file(path, &contents[0..new_size as usize])
-                                    .await
-                            })
-                            .map(|x| x);
-                        match result {
-                            Ok(_) => {
-                                contents.resize(new_size as usize, 0);
-                                drop(contents);
-                                reply.attr(&TTL, &node.attr());
-                            }
-                            Err(e) => {
-                                error!("Error deleting directory: {}", e);
-                                reply.error(EIO);
+        let span = info_span!("setattr", ino, ?size);
+        self.spawn(span, |fs| async move {
+            if let Some(node) = fs.node(ino) {
+                let mut node = node.lock().await;
+                let path = node.path.clone();
+                if let Err(e) = node.ensure_data(&fs).await {
+                    error!("Error loading {}: {}", path, e);
+                    reply.error(EIO);
+                    return;
+                }
+
+                match &mut node.data {
+                    InoData::File { file } => {
+                        if let Some(new_size) = size {
+                            match file.set_len(new_size).await {
+                                Ok(_) => {
+                                    reply.attr(&TTL, &node.attr(&fs));
+                                }
+                                Err(e) => {
+                                    error!("Error resizing file: {}", e);
+                                    reply.error(EIO);
+                                }
                             }
+                        } else {
+                            reply.attr(&TTL, &node.attr(&fs));
                         }
-                    } else {
-                        reply.attr(&TTL, &node.attr());
+                    }
+                    InoData::Directory { children: _ } => {
+                        info!("setattr on directory ignored");
+                        reply.attr(&TTL, &node.attr(&fs));
+                    }
+                    InoData::SerialOut { pending_data: _ } | InoData::SerialIn => {
+                        info!("setattr on serial ignored");
+                        reply.attr(&TTL, &node.attr(&fs));
+                    }
+                    InoData::Run | InoData::Reset | InoData::Info | InoData::Stats => {
+                        info!("setattr on control file ignored");
+                        reply.attr(&TTL, &node.attr(&fs));
                     }
                 }
-                InoData::File { contents: _ } => {
-                    unreachable!();
-                }
-                InoData::Directory { children: _ } => {
-                    info!("setattr on directory ignored");
-                    reply.attr(&TTL, &node.attr());
-                }
-                InoData::Serial { pending_data: _ } => {
-                    info!("setattr on serial ignored");
-                    reply.attr(&TTL, &node.attr());
-                }
-                InoData::Run => {
-                    info!("setattr on run ignored");
-                    reply.attr(&TTL, &node.attr());
-                }
+            } else {
+                reply.error(ENOENT);
             }
-        } else {
-            reply.error(ENOENT);
-        }
+        });
     }
 
     fn readlink(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyData) {
@@ -1076,3 +1634,53 @@ impl<'a> Filesystem for AppFS<'a> {
         reply.error(ENOSYS);
     }
 }
+
+async fn remove_child(children: &mut Vec<Node>, path: &BadgePath) {
+    let mut remaining = Vec::with_capacity(children.len());
+    for child in children.drain(..) {
+        if &child.lock().await.path != path {
+            remaining.push(child);
+        }
+    }
+
+    *children = remaining;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writes(writes: &[(Option<u64>, &[u8])]) -> WriteBuffer {
+        let mut buffer = WriteBuffer::default();
+        for (offset, data) in writes {
+            buffer.push(*offset, data);
+        }
+        buffer
+    }
+
+    #[test]
+    fn merges_sequential_writes() {
+        let buffer = writes(&[
+            (Some(0), b"ab"),
+            (Some(2), b"cd"),
+            (Some(1), b"x"),
+            (None, b"e"),
+            (None, b"f"),
+        ]);
+        assert_eq!(
+            buffer.writes,
+            [
+                (Some(0), b"abcd".to_vec()),
+                (Some(1), b"x".to_vec()),
+                (None, b"ef".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_writes_in_order() {
+        let mut contents = b"hello".to_vec();
+        writes(&[(Some(1), b"EL"), (None, b"!"), (Some(8), b"?")]).apply(&mut contents);
+        assert_eq!(contents, b"hELlo!\0\0?");
+    }
+}
@@ -0,0 +1,79 @@
+//! Asks git which files of a directory changed since a commit, for `push --since`, so only those
+//! are uploaded. Runs the `git` command, so it understands every kind of ref git does.
+
+use std::{collections::BTreeSet, io, path::Path, process::Command};
+use thiserror::Error;
+use tracing::debug;
+
+#[derive(Error, Debug)]
+pub enum GitError {
+    #[error("Unable to run git: {}", .0)]
+    Spawn(#[from] io::Error),
+
+    #[error("git {} failed: {}", .0, .1)]
+    Failed(String, String),
+}
+
+/// The files that differ from a commit, relative to the directory that was compared
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// Added, changed and untracked files
+    pub changed: BTreeSet<String>,
+    pub deleted: BTreeSet<String>,
+}
+
+/// Runs git in `dir` and returns the NUL-separated fields it printed
+fn run(dir: &Path, args: &[&str]) -> Result<Vec<String>, GitError> {
+    debug!("Running git {}", args.join(" "));
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+        return Err(GitError::Failed(args.join(" "), message));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter(|field| !field.is_empty())
+        .map(|field| field.to_owned())
+        .collect())
+}
+
+/// The files in `dir` that differ between `since` and the working tree, including those that
+/// aren't committed or staged yet, with paths relative to `dir`
+pub fn changes_since(dir: &Path, since: &str) -> Result<Changes, GitError> {
+    let mut changes = Changes::default();
+
+    // Status and path alternate. Renames show up as a deletion and an addition.
+    let status = run(
+        dir,
+        &[
+            "diff",
+            "--name-status",
+            "--no-renames",
+            "--relative",
+            "-z",
+            since,
+            "--",
+        ],
+    )?;
+    for pair in status.chunks_exact(2) {
+        if pair[0] == "D" {
+            changes.deleted.insert(pair[1].clone());
+        } else {
+            changes.changed.insert(pair[1].clone());
+        }
+    }
+
+    changes.changed.extend(run(
+        dir,
+        &["ls-files", "--others", "--exclude-standard", "-z"],
+    )?);
+
+    debug!(
+        "{} files changed and {} deleted since {}",
+        changes.changed.len(),
+        changes.deleted.len(),
+        since
+    );
+    Ok(changes)
+}
@@ -0,0 +1,192 @@
+//! Searches the contents of files on the badge. The badge can't search itself, so every file is
+//! fetched and searched here. With `--cached`, fetched files are kept in
+//! `~/.cache/cz2020-usbtool/<serial>.contents/`, named after their SHA-256, and a file is only
+//! fetched again when the hash the badge computes for it doesn't match a kept copy.
+
+use crate::{
+    cache::{self, cache_dir},
+    client::{BadgeFs, ClientError, FileKind},
+    cmds::FsEntry,
+    device::Badge,
+    ignore::glob,
+    output::Output,
+    path::BadgePath,
+    repl,
+};
+use bytes::Bytes;
+use regex::{Regex, RegexBuilder};
+use std::{error::Error, fs, path::PathBuf};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+/// Like `grep`, finding nothing is a failure, so scripts can tell
+#[derive(Error, Debug)]
+#[error("No lines matched")]
+pub struct NoMatches;
+
+/// What `grep` searches for, and in which files
+pub struct Search {
+    pub pattern: Regex,
+    /// A glob with `*` and `?`, matched against the file name
+    pub include: Option<String>,
+}
+
+impl Search {
+    pub fn new(
+        pattern: &str,
+        ignore_case: bool,
+        include: Option<String>,
+    ) -> Result<Search, regex::Error> {
+        Ok(Search {
+            pattern: RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()?,
+            include,
+        })
+    }
+}
+
+/// Copies of files that were fetched before, by their hash
+struct Contents {
+    dir: PathBuf,
+}
+
+impl Contents {
+    fn open(id: &str) -> Option<Contents> {
+        let dir = cache_dir()?.join(format!("{}.contents", id));
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!(
+                "Unable to create {:?}, not keeping fetched files: {}",
+                dir, e
+            );
+            return None;
+        }
+
+        Some(Contents { dir })
+    }
+
+    fn get(&self, hash: &str) -> Option<Vec<u8>> {
+        fs::read(self.dir.join(hash)).ok()
+    }
+
+    fn put(&self, data: &[u8]) {
+        if let Err(e) = fs::write(self.dir.join(cache::hash(data)), data) {
+            warn!("Unable to keep a copy of a fetched file: {}", e);
+        }
+    }
+}
+
+/// The files below `path` whose name matches `include`, in order of their paths
+async fn files(
+    client: &BadgeFs,
+    path: &BadgePath,
+    include: Option<&str>,
+) -> Result<Vec<BadgePath>, Box<dyn Error + Send + Sync>> {
+    let included = |path: &BadgePath| match include {
+        Some(pattern) => glob(pattern.as_bytes(), path.file_name().as_bytes()),
+        None => true,
+    };
+
+    if client.metadata(path.clone()).await?.kind == FileKind::File {
+        return Ok(if included(path) {
+            vec![path.clone()]
+        } else {
+            Vec::new()
+        });
+    }
+
+    let mut files = Vec::new();
+    let mut stack = vec![path.clone()];
+    while let Some(dir) = stack.pop() {
+        let mut entries = client.read_dir(dir.clone());
+        loop {
+            match entries.next_entry().await {
+                Ok(Some(FsEntry::File(name))) => {
+                    let file = dir.join(name)?;
+                    if included(&file) {
+                        files.push(file);
+                    }
+                }
+                Ok(Some(FsEntry::Directory(name))) => stack.push(dir.join(name)?),
+                // Neither a file nor a directory, so there is nothing to search
+                Ok(Some(FsEntry::Other(..))) => {}
+                Ok(None) => break,
+                Err(e) if e.downcast_ref::<ClientError>().is_some() => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// The contents of `path`, from `contents` if the badge has the same version of it
+async fn fetch(
+    badge: &Badge,
+    client: &BadgeFs,
+    contents: Option<&Contents>,
+    path: &BadgePath,
+) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+    let contents = match contents {
+        Some(contents) => contents,
+        None => return client.open(path.clone()).read_to_end().await,
+    };
+
+    if let Some(hash) = repl::file_hash(badge, path).await? {
+        if let Some(data) = contents.get(&hash) {
+            debug!(
+                "{} is unchanged, using the copy that was fetched before",
+                path
+            );
+            return Ok(data.into());
+        }
+    }
+
+    let data = client.open(path.clone()).read_to_end().await?;
+    contents.put(&data);
+    Ok(data)
+}
+
+/// Prints the lines of the files below `path` that match `search`
+pub async fn grep(
+    badge: &Badge,
+    client: &BadgeFs,
+    path: &BadgePath,
+    search: &Search,
+    cached: bool,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let contents = if cached && badge.capabilities().python_shell {
+        Contents::open(&badge.device_info().id())
+    } else {
+        if cached {
+            info!("The Python shell isn't available to hash files, so every file is fetched");
+        }
+        None
+    };
+
+    let mut matches = 0;
+    for file in files(client, path, search.include.as_deref()).await? {
+        let data = fetch(badge, client, contents.as_ref(), &file).await?;
+        if data.contains(&0) {
+            debug!("Skipping binary file {}", file);
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&data);
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if search.pattern.is_match(line) {
+                output.grep_match(&file, i + 1, line);
+                matches += 1;
+            }
+        }
+    }
+
+    if matches == 0 {
+        Err(NoMatches)?
+    }
+
+    Ok(())
+}
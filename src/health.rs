@@ -0,0 +1,139 @@
+//! How well the connection to the badge is doing, judged by the heartbeats that are sent every
+//! quarter second, so a slow or flaky connection shows before commands start to time out.
+
+use std::{collections::VecDeque, fmt, sync::Mutex, time::Duration};
+
+/// How many of the last heartbeats count towards the status
+const WINDOW: usize = 20;
+
+/// A round trip slower than this on average means the connection is degraded
+const SLOW_ROUND_TRIP: Duration = Duration::from_millis(200);
+
+/// This many missed heartbeats in a row means the badge is probably gone
+const LOST_AFTER: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Good,
+    /// Heartbeats were missed recently, or are slow
+    Degraded,
+    /// The last few heartbeats weren't answered
+    Lost,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Good => "good",
+            Status::Degraded => "degraded",
+            Status::Lost => "lost",
+        })
+    }
+}
+
+/// A snapshot of the health of the connection
+#[derive(Debug, Clone)]
+pub struct Health {
+    pub status: Status,
+    /// Of the last heartbeat that was answered
+    pub last_round_trip: Option<Duration>,
+    /// Of the answered heartbeats among the last few
+    pub average_round_trip: Option<Duration>,
+    pub missed_in_a_row: u32,
+    pub answered: u64,
+    pub missed: u64,
+}
+
+fn millis(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => format!("{:.3}", duration.as_secs_f64() * 1000.0),
+        None => "-".to_owned(),
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "health: {}", self.status)?;
+        writeln!(f, "heartbeat_last_ms: {}", millis(self.last_round_trip))?;
+        writeln!(
+            f,
+            "heartbeat_average_ms: {}",
+            millis(self.average_round_trip)
+        )?;
+        writeln!(f, "heartbeats_answered: {}", self.answered)?;
+        writeln!(f, "heartbeats_missed: {}", self.missed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Beats {
+    /// The round trip of each of the last heartbeats, or `None` if it was missed
+    recent: VecDeque<Option<Duration>>,
+    missed_in_a_row: u32,
+    answered: u64,
+    missed: u64,
+}
+
+impl Beats {
+    fn push(&mut self, beat: Option<Duration>) {
+        if self.recent.len() == WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(beat);
+    }
+}
+
+/// Collects the outcome of every heartbeat
+#[derive(Debug, Default)]
+pub struct HeartbeatTracker {
+    beats: Mutex<Beats>,
+}
+
+impl HeartbeatTracker {
+    pub fn new() -> HeartbeatTracker {
+        Default::default()
+    }
+
+    pub fn record_answer(&self, round_trip: Duration) {
+        let mut beats = self.beats.lock().unwrap();
+        beats.push(Some(round_trip));
+        beats.missed_in_a_row = 0;
+        beats.answered += 1;
+    }
+
+    pub fn record_miss(&self) {
+        let mut beats = self.beats.lock().unwrap();
+        beats.push(None);
+        beats.missed_in_a_row += 1;
+        beats.missed += 1;
+    }
+
+    pub fn health(&self) -> Health {
+        let beats = self.beats.lock().unwrap();
+        let answered = beats.recent.iter().flatten().collect::<Vec<_>>();
+        let average_round_trip = if answered.is_empty() {
+            None
+        } else {
+            Some(answered.iter().copied().sum::<Duration>() / answered.len() as u32)
+        };
+
+        let status = if beats.missed_in_a_row >= LOST_AFTER {
+            Status::Lost
+        } else if beats.recent.iter().any(Option::is_none)
+            || matches!(average_round_trip, Some(average) if average > SLOW_ROUND_TRIP)
+        {
+            Status::Degraded
+        } else {
+            Status::Good
+        };
+
+        Health {
+            status,
+            last_round_trip: beats.recent.iter().rev().flatten().next().copied(),
+            average_round_trip,
+            missed_in_a_row: beats.missed_in_a_row,
+            answered: beats.answered,
+            missed: beats.missed,
+        }
+    }
+}
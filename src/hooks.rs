@@ -0,0 +1,302 @@
+//! Hooks run a command on this computer when a line of output of the badge matches a pattern, for
+//! example to send a notification when an app crashes during a long `monitor` session. Hooks are
+//! given on the command line or in a file:
+//!
+//! ```toml
+//! [[hook]]
+//! pattern = "^Traceback "
+//! command = "notify-send 'The badge crashed' \"$CZ2020_LINE\""
+//! # Don't run again within a minute, 10s by default
+//! cooldown = "1m"
+//! ```
+
+use crate::{
+    duration::{parse_duration, DurationError},
+    manifest::{self, Document, ManifestError},
+    render::strip_escapes,
+};
+use regex::Regex;
+use std::{
+    error::Error,
+    fs,
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Matches the first line of a MicroPython traceback
+pub const TRACEBACK: &str = r"^Traceback \(most recent call last\):";
+
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum HookError {
+    #[error("Invalid pattern {:?}: {}", .0, .1)]
+    InvalidPattern(String, regex::Error),
+
+    #[error("Invalid cooldown: {}", .0)]
+    InvalidCooldown(#[from] DurationError),
+}
+
+/// A command that runs when a line matches `pattern`, at most once per `cooldown`
+pub struct Hook {
+    pattern: Regex,
+    command: String,
+    cooldown: Duration,
+    last_run: Option<Instant>,
+}
+
+impl Hook {
+    pub fn new(pattern: &str, command: &str) -> Result<Hook, HookError> {
+        Ok(Hook {
+            pattern: Regex::new(pattern)
+                .map_err(|e| HookError::InvalidPattern(pattern.to_owned(), e))?,
+            command: command.to_owned(),
+            cooldown: DEFAULT_COOLDOWN,
+            last_run: None,
+        })
+    }
+
+    pub fn cooldown(mut self, cooldown: Duration) -> Hook {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Whether the cooldown since the last run is over. If so, it starts again.
+    fn due(&mut self) -> bool {
+        if let Some(last_run) = self.last_run {
+            if last_run.elapsed() < self.cooldown {
+                return false;
+            }
+        }
+        self.last_run = Some(Instant::now());
+        true
+    }
+
+    /// Starts the command in the background, with the line in `$CZ2020_LINE`
+    fn run(&mut self, line: &str) {
+        if !self.due() {
+            return;
+        }
+
+        info!("Running hook {:?}", self.command);
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("CZ2020_LINE", line)
+            .env("CZ2020_PATTERN", self.pattern.as_str())
+            .spawn();
+        match child {
+            // Waited for on another thread, so the output keeps flowing and no zombies are left
+            Ok(mut child) => {
+                std::thread::spawn(move || match child.wait() {
+                    Ok(status) if !status.success() => warn!("The hook exited with {}", status),
+                    Ok(_) => {}
+                    Err(e) => warn!("Unable to wait for the hook: {}", e),
+                });
+            }
+            Err(e) => warn!("Unable to run the hook: {}", e),
+        }
+    }
+}
+
+/// Reads the `[[hook]]` tables of a hooks file
+pub fn load(path: &Path) -> Result<Vec<Hook>, Box<dyn Error + Send + Sync>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+fn parse(text: &str) -> Result<Vec<Hook>, Box<dyn Error + Send + Sync>> {
+    let document = Document::parse(text)?;
+    if let Some(key) = document.root.keys().next() {
+        Err(ManifestError::Unknown(key.clone()))?
+    }
+    if let Some(name) = document.tables.keys().next() {
+        Err(ManifestError::Unknown(format!("[{}]", name)))?
+    }
+
+    let mut hooks = Vec::new();
+    for (name, tables) in document.arrays.iter() {
+        if name != "hook" {
+            Err(ManifestError::Unknown(format!("[[{}]]", name)))?
+        }
+
+        for table in tables {
+            if let Some(key) = table
+                .keys()
+                .find(|key| !["pattern", "command", "cooldown"].contains(&key.as_str()))
+            {
+                Err(ManifestError::Unknown(format!("hook.{}", key)))?
+            }
+
+            let mut hook = Hook::new(
+                manifest::require_str(table, "pattern", "hook")?,
+                manifest::require_str(table, "command", "hook")?,
+            )?;
+            if let Some(cooldown) = manifest::get_str(table, "cooldown", "hook")? {
+                hook = hook.cooldown(parse_duration(cooldown).map_err(HookError::from)?);
+            }
+            hooks.push(hook);
+        }
+    }
+
+    Ok(hooks)
+}
+
+/// Runs hooks for every complete line of output. Lines are held back until they are complete.
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    partial: String,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Hook>) -> Hooks {
+        Hooks {
+            hooks,
+            partial: String::new(),
+        }
+    }
+
+    /// The index of every hook that matches a line completed by `text`, with that line
+    fn matches(&mut self, text: &str) -> Vec<(usize, String)> {
+        self.partial.push_str(text);
+        let mut matches = Vec::new();
+        while let Some(i) = self.partial.find('\n') {
+            let line = self.partial.drain(..=i).collect::<String>();
+            let line = strip_escapes(&line);
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            for (index, hook) in self.hooks.iter().enumerate() {
+                if hook.pattern.is_match(line) {
+                    matches.push((index, line.to_owned()));
+                }
+            }
+        }
+
+        matches
+    }
+
+    pub fn feed(&mut self, text: &str) {
+        for (index, line) in self.matches(text) {
+            self.hooks[index].run(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hooks(patterns: &[&str]) -> Hooks {
+        Hooks::new(
+            patterns
+                .iter()
+                .map(|pattern| Hook::new(pattern, "true").unwrap())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn matches_complete_lines() {
+        let mut hooks = hooks(&[TRACEBACK, "wifi"]);
+        assert_eq!(
+            hooks.matches("I (1) wifi: up\r\nTraceback (most recent call last):\r\n  File"),
+            [
+                (1, "I (1) wifi: up".to_owned()),
+                (0, "Traceback (most recent call last):".to_owned()),
+            ]
+        );
+        assert!(hooks.matches(" \"main.py\", wifi\r").is_empty());
+        assert_eq!(
+            hooks.matches("\n"),
+            [(1, "  File \"main.py\", wifi".to_owned())]
+        );
+    }
+
+    #[test]
+    fn matches_a_pattern_split_across_outputs() {
+        let mut hooks = hooks(&[TRACEBACK]);
+        assert!(hooks.matches(">>> run()\r\nTrace").is_empty());
+        assert!(hooks.matches("back (most recent").is_empty());
+        assert_eq!(
+            hooks.matches(" call last):\r\n"),
+            [(0, "Traceback (most recent call last):".to_owned())]
+        );
+    }
+
+    #[test]
+    fn matches_without_escapes() {
+        let mut hooks = hooks(&["^E \\(", "^crashed$"]);
+        assert_eq!(
+            hooks.matches("\u{1b}[31mE (5) app: failed\u{1b}[0m\r\ncrash\u{1b}[0med\n"),
+            [
+                (0, "E (5) app: failed".to_owned()),
+                (1, "crashed".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_for_the_cooldown() {
+        let mut hook = Hook::new("x", "true").unwrap();
+        assert!(hook.due());
+        assert!(!hook.due());
+
+        let mut hook = hook.cooldown(Duration::from_secs(0));
+        assert!(hook.due());
+        assert!(hook.due());
+    }
+
+    #[test]
+    fn loads_hooks() {
+        let hooks = parse(
+            "# Crashes\n\
+             [[hook]]\n\
+             pattern = \"^Traceback \"\n\
+             command = 'notify-send crashed \"$CZ2020_LINE\"'\n\
+             cooldown = \"1m\"\n\
+             \n\
+             [[hook]]\n\
+             pattern = \"wifi\"\n\
+             command = \"true\"\n",
+        )
+        .unwrap();
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0].pattern.as_str(), "^Traceback ");
+        assert_eq!(hooks[0].command, "notify-send crashed \"$CZ2020_LINE\"");
+        assert_eq!(hooks[0].cooldown, Duration::from_secs(60));
+        assert_eq!(hooks[1].cooldown, DEFAULT_COOLDOWN);
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_hook_files() {
+        let cases = [
+            ("pattern = \"x\"", "Unknown key or table pattern"),
+            ("[hook]", "Unknown key or table [hook]"),
+            ("[[hooks]]", "Unknown key or table [[hooks]]"),
+            (
+                "[[hook]]\npattern = \"x\"\ncommand = \"y\"\nwhen = 1",
+                "Unknown key or table hook.when",
+            ),
+            ("[[hook]]\ncommand = \"y\"", "hook.pattern is missing"),
+            (
+                "[[hook]]\npattern = 1\ncommand = \"y\"",
+                "hook.pattern must be a string",
+            ),
+            (
+                "[[hook]]\npattern = \"(\"\ncommand = \"y\"",
+                "Invalid pattern \"(\"",
+            ),
+            (
+                "[[hook]]\npattern = \"x\"\ncommand = \"y\"\ncooldown = \"soon\"",
+                "Invalid cooldown",
+            ),
+            ("[[hook]]\npattern = ", "Line 2: "),
+        ];
+        for (text, expected) in cases.iter() {
+            let error = parse(text).err().unwrap().to_string();
+            assert!(error.starts_with(expected), "{:?}: {}", text, error);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{fs, io, path::Path};
+
+/// Patterns that are excluded unless a rule negates them with `!`
+const DEFAULT_RULES: &[&str] = &[
+    ".git/",
+    ".hg/",
+    ".svn/",
+    "__pycache__/",
+    "*.pyc",
+    ".*.swp",
+    "*~",
+    ".DS_Store",
+    ".badgeignore",
+];
+
+pub const IGNORE_FILE: &str = ".badgeignore";
+
+struct Rule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    /// Patterns with a slash are matched against the whole relative path, others against the file name
+    anchored: bool,
+}
+
+/// Matches `text` against a glob with `*`, `?` and `**`. Only `**` matches across slashes.
+pub fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => match rest[1..].strip_prefix(b"/") {
+            // `**/` matches zero or more whole directories
+            Some(rest) => {
+                (0..=text.len()).any(|i| (i == 0 || text[i - 1] == b'/') && glob(rest, &text[i..]))
+            }
+            None => (0..=text.len()).any(|i| glob(&rest[1..], &text[i..])),
+        },
+        Some((b'*', rest)) => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob(rest, &text[i..])),
+        Some((b'?', rest)) => match text.split_first() {
+            Some((c, text)) if *c != b'/' => glob(rest, text),
+            _ => false,
+        },
+        Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+/// gitignore-style exclude rules. Later rules take precedence over earlier ones.
+pub struct ExcludeRules {
+    rules: Vec<Rule>,
+}
+
+impl Default for ExcludeRules {
+    fn default() -> ExcludeRules {
+        ExcludeRules::new()
+    }
+}
+
+impl ExcludeRules {
+    pub fn new() -> ExcludeRules {
+        let mut rules = ExcludeRules { rules: Vec::new() };
+        for rule in DEFAULT_RULES {
+            rules.add(rule);
+        }
+
+        rules
+    }
+
+    /// Adds a single rule. Blank lines and lines starting with `#` are ignored.
+    pub fn add(&mut self, line: &str) {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+
+        self.rules.push(Rule {
+            pattern: line.trim_start_matches('/').to_owned(),
+            negated,
+            dir_only,
+            anchored: line.contains('/'),
+        });
+    }
+
+    /// Adds the rules in `path`, if it exists
+    pub fn add_file(&mut self, path: &Path) -> io::Result<()> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    self.add(line);
+                }
+
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `relative`, a slash-separated path relative to the root being transferred, is excluded.
+    /// Contents of excluded directories should not be visited at all.
+    pub fn is_excluded(&self, relative: &str, is_dir: bool) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        let mut excluded = false;
+        for rule in self.rules.iter() {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+
+            let text = if rule.anchored { relative } else { name };
+            if glob(rule.pattern.as_bytes(), text.as_bytes()) {
+                excluded = !rule.negated;
+            }
+        }
+
+        excluded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"*.py", b"main.py"));
+        assert!(!glob(b"*.py", b"lib/main.py"));
+        assert!(glob(b"?.txt", b"a.txt"));
+        assert!(!glob(b"?.txt", b"ab.txt"));
+        assert!(glob(b"**/test.py", b"test.py"));
+        assert!(glob(b"**/test.py", b"lib/sub/test.py"));
+        assert!(glob(b"lib/**", b"lib/sub/main.py"));
+    }
+
+    #[test]
+    fn excludes_defaults() {
+        let rules = ExcludeRules::new();
+        assert!(rules.is_excluded(".git", true));
+        assert!(rules.is_excluded("lib/__pycache__", true));
+        assert!(rules.is_excluded("lib/main.pyc", false));
+        assert!(!rules.is_excluded("lib/main.py", false));
+    }
+
+    #[test]
+    fn directory_rules_only_match_directories() {
+        let mut rules = ExcludeRules::new();
+        rules.add("build/");
+        assert!(rules.is_excluded("build", true));
+        assert!(!rules.is_excluded("build", false));
+    }
+
+    #[test]
+    fn rules_with_a_slash_match_the_whole_path() {
+        let mut rules = ExcludeRules::new();
+        rules.add("/docs/*.md");
+        assert!(rules.is_excluded("docs/readme.md", false));
+        assert!(!rules.is_excluded("lib/docs/readme.md", false));
+        assert!(!rules.is_excluded("readme.md", false));
+    }
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let mut rules = ExcludeRules::new();
+        rules.add("*.json");
+        rules.add("!metadata.json");
+        rules.add("# a comment");
+        rules.add("");
+        assert!(rules.is_excluded("settings.json", false));
+        assert!(!rules.is_excluded("metadata.json", false));
+    }
+}
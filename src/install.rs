@@ -0,0 +1,160 @@
+use crate::{
+    cache::MetadataCache, device::Badge, ignore::ExcludeRules, output::Output, path::BadgePath,
+};
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Component, Path},
+};
+use thiserror::Error;
+use tracing::debug;
+
+/// Files every app needs
+pub const REQUIRED_FILES: &[&str] = &["__init__.py", "metadata.json"];
+
+#[derive(Error, Debug)]
+pub enum InstallError {
+    #[error("The archive doesn't contain {}", .0)]
+    MissingFile(&'static str),
+
+    #[error("Invalid app name {:?}, pass one with --name", .0)]
+    InvalidName(String),
+
+    #[error("The archive contains an unsafe path: {:?}", .0)]
+    UnsafePath(String),
+}
+
+/// The path and contents of each file in an archive
+type ArchiveFiles = Vec<(String, Vec<u8>)>;
+
+/// Reads all regular files from a tar archive, which may be gzipped
+fn read_archive(file: &Path) -> Result<ArchiveFiles, Box<dyn Error + Send + Sync>> {
+    let reader = BufReader::new(File::open(file)?);
+    let name = file.to_string_lossy();
+    let reader: Box<dyn Read> = if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(libflate::gzip::Decoder::new(reader)?)
+    } else {
+        Box::new(reader)
+    };
+
+    let mut files = Vec::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => components.push(part.to_string_lossy().into_owned()),
+                Component::CurDir => {}
+                _ => Err(InstallError::UnsafePath(
+                    path.to_string_lossy().into_owned(),
+                ))?,
+            }
+        }
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.push((components.join("/"), data));
+    }
+
+    Ok(files)
+}
+
+/// The directory all files are in, if there is exactly one. Archives are usually created from the
+/// app directory itself.
+fn common_directory(files: &[(String, Vec<u8>)]) -> Option<String> {
+    let first = files.first()?.0.split('/').next()?;
+    if files
+        .iter()
+        .all(|(path, _)| path.starts_with(first) && path[first.len()..].starts_with('/'))
+    {
+        Some(first.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Unpacks the archive `file` to `/flash/apps/<name>`. The name defaults to the directory inside the
+/// archive, or the name of the archive itself.
+pub async fn install(
+    badge: &Badge,
+    cache: &MetadataCache,
+    file: &Path,
+    name: Option<String>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut files = read_archive(file)?;
+    let prefix = common_directory(&files);
+    if let Some(prefix) = &prefix {
+        for (path, _) in files.iter_mut() {
+            *path = path[prefix.len() + 1..].to_owned();
+        }
+    }
+
+    let name = name.or(prefix).unwrap_or_else(|| {
+        let stem = file.file_name().unwrap().to_string_lossy();
+        stem.split('.').next().unwrap().to_owned()
+    });
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        Err(InstallError::InvalidName(name.clone()))?
+    }
+
+    for required in REQUIRED_FILES {
+        if !files.iter().any(|(path, _)| path == required) {
+            Err(InstallError::MissingFile(required))?
+        }
+    }
+
+    let rules = ExcludeRules::new();
+    files.retain(|(path, _)| {
+        // Check every parent directory as well, like a walk over the directory would
+        let mut parent = String::new();
+        for part in path.split('/').collect::<Vec<_>>().split_last().unwrap().1 {
+            parent = if parent.is_empty() {
+                part.to_string()
+            } else {
+                format!("{}/{}", parent, part)
+            };
+            if rules.is_excluded(&parent, true) {
+                return false;
+            }
+        }
+
+        !rules.is_excluded(path, false)
+    });
+
+    let root = BadgePath::new("/flash/apps")?.join(&name)?;
+    let mut dirs = BTreeSet::new();
+    dirs.insert(root.clone());
+    for (path, _) in files.iter() {
+        let mut dir = root.join(path)?.parent().unwrap();
+        while dir != root {
+            dirs.insert(dir.clone());
+            dir = dir.parent().unwrap();
+        }
+    }
+
+    // BTreeSet iterates parents before their children
+    for dir in dirs {
+        if let Err(e) = badge.create_dir(&dir).await {
+            // Most likely the directory exists already
+            debug!("Unable to create {}: {}", dir, e);
+        }
+    }
+
+    for (path, data) in files {
+        let target = root.join(&path)?;
+        badge.write_file(&target, &data).await?;
+        cache.record_file(&target, &data);
+        output.transferred(&target, data.len());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Records where the time of every command goes with `--latency-trace <file>`: building the
+//! packet, writing it to USB, the badge working on it until the first byte of the response
+//! arrives, reading the rest of the response, and parsing it. Written in the Chrome trace format,
+//! which chrome://tracing, the Perfetto UI and speedscope open, with each command as a slice and
+//! its phases nested below it.
+//!
+//! Events are appended as commands finish, in the JSON array form of the format that may lack the
+//! closing bracket, so the trace is usable even if the process is killed.
+
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+static TRACE: Mutex<Option<Arc<LatencyTrace>>> = Mutex::new(None);
+
+/// How long each phase of a command took
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Phases {
+    /// Building the packet
+    pub encode: Duration,
+    /// Sending the packet, including waiting for the rate limit
+    pub write: Duration,
+    /// From the end of the write until the first byte of the response arrived
+    pub processing: Duration,
+    /// From the first until the last byte of the response
+    pub read: Duration,
+    pub parse: Duration,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    /// When the last command on each lane ended. Commands that overlap, because they are
+    /// pipelined, go on separate lanes, so their phases don't get mixed up.
+    lanes: Vec<Instant>,
+}
+
+#[derive(Debug)]
+pub struct LatencyTrace {
+    /// Timestamps in the trace count from here
+    epoch: Instant,
+    state: Mutex<State>,
+}
+
+/// A complete event in microseconds since `epoch`, on `lane`
+fn slice(name: &str, epoch: Instant, start: Instant, duration: Duration, lane: usize) -> String {
+    json!({
+        "name": name,
+        "ph": "X",
+        "ts": start.saturating_duration_since(epoch).as_secs_f64() * 1e6,
+        "dur": duration.as_secs_f64() * 1e6,
+        "pid": 1,
+        "tid": lane,
+    })
+    .to_string()
+}
+
+impl LatencyTrace {
+    /// Creates or truncates `path`
+    pub fn create(path: &Path) -> io::Result<LatencyTrace> {
+        let mut file = File::create(path)?;
+        file.write_all(b"[\n")?;
+        Ok(LatencyTrace {
+            epoch: Instant::now(),
+            state: Mutex::new(State {
+                file,
+                lanes: Vec::new(),
+            }),
+        })
+    }
+
+    /// Records a command that finished writing at `sent`
+    pub fn record(&self, command: &str, message_id: u32, sent: Instant, phases: &Phases) {
+        let start = sent
+            .checked_sub(phases.encode + phases.write)
+            .unwrap_or(sent);
+        let total = phases.encode + phases.write + phases.processing + phases.read + phases.parse;
+
+        let mut state = self.state.lock().unwrap();
+        let lane = match state.lanes.iter().position(|end| *end <= start) {
+            Some(lane) => lane,
+            None => {
+                state.lanes.push(start);
+                state.lanes.len() - 1
+            }
+        };
+        state.lanes[lane] = start + total;
+
+        let mut events = vec![slice(
+            &format!("{} #{}", command, message_id),
+            self.epoch,
+            start,
+            total,
+            lane,
+        )];
+        let mut at = start;
+        for (name, duration) in [
+            ("encode", phases.encode),
+            ("write", phases.write),
+            ("processing", phases.processing),
+            ("read", phases.read),
+            ("parse", phases.parse),
+        ] {
+            events.push(slice(name, self.epoch, at, duration, lane));
+            at += duration;
+        }
+
+        let mut text = events.join(",\n");
+        text.push_str(",\n");
+        if let Err(e) = state.file.write_all(text.as_bytes()) {
+            debug!("Unable to write the latency trace: {}", e);
+        }
+    }
+}
+
+/// Starts recording the commands of every badge this process opens into `path`
+pub fn start(path: &Path) -> io::Result<()> {
+    *TRACE.lock().unwrap() = Some(Arc::new(LatencyTrace::create(path)?));
+    Ok(())
+}
+
+/// The trace that `start` created, if any
+pub fn trace() -> Option<Arc<LatencyTrace>> {
+    TRACE.lock().unwrap().clone()
+}
@@ -0,0 +1,76 @@
+//! The protocol of the CampZone 2020 badge and everything built on it: the connection to the
+//! badge, filesystem access, the Python shell and the commands of the command line tool.
+
+pub mod archive;
+pub mod atomic;
+pub mod backoff;
+pub mod bench;
+pub mod cache;
+pub mod capabilities;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod client;
+pub mod cmds;
+pub mod config;
+pub mod delta;
+pub mod device;
+pub mod diff;
+pub mod display;
+pub mod doctor;
+pub mod du;
+pub mod duration;
+pub mod edit;
+pub mod exit;
+pub mod expect;
+pub mod find;
+pub mod firmware;
+pub mod fleet;
+pub mod fs;
+pub mod git;
+pub mod grep;
+pub mod health;
+pub mod hooks;
+pub mod ignore;
+pub mod install;
+pub mod latency;
+pub mod lint;
+pub mod listing;
+pub mod lock;
+pub mod logdump;
+pub mod loglevel;
+pub mod manifest;
+pub mod mirror;
+pub mod mpremote;
+pub mod mux;
+pub mod notify;
+pub mod output;
+pub mod overwrite;
+pub mod pacing;
+pub mod paste;
+pub mod path;
+pub mod provision;
+pub mod push;
+#[cfg(feature = "python")]
+mod python;
+pub mod ranged;
+pub mod render;
+pub mod repl;
+pub mod repl_fs;
+pub mod resume;
+pub mod run;
+pub mod scaffold;
+pub mod screenshot;
+pub mod send;
+pub mod share;
+pub mod shell;
+pub mod size;
+pub mod snapshot;
+pub mod sourcemap;
+pub mod space;
+pub mod stats;
+pub mod status;
+pub mod stream;
+pub mod testrun;
+pub mod text;
+pub mod traceback;
+pub mod trash;
@@ -0,0 +1,226 @@
+//! `lint` checks a local app directory before it is uploaded, for what `install` and the launcher
+//! expect: the required files, the fields of `metadata.json` and the icon. It also looks in
+//! `/flash/apps` for an installed app with the same name, which the upload would overwrite.
+
+use crate::{
+    cmds::DirectoryListingResponse,
+    device::Badge,
+    install::REQUIRED_FILES,
+    output::Output,
+    path::BadgePath,
+    scaffold::{self, ICON_SIZE},
+    sourcemap::SourceMap,
+};
+use serde_json::Value;
+use std::{convert::TryInto, error::Error, fmt, fs, path::Path};
+use thiserror::Error;
+use tracing::{debug, info};
+
+/// The fields of `metadata.json`, and whether they hold a number instead of a string
+const METADATA_FIELDS: &[(&str, bool)] = &[
+    ("name", false),
+    ("description", false),
+    ("category", false),
+    ("author", false),
+    ("revision", true),
+];
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Error, Debug)]
+pub enum LintError {
+    #[error("The app has {} problem(s) that need to be fixed", .0)]
+    Failed(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Keeps the app from being installed or started
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+struct Problem {
+    severity: Severity,
+    /// The file of the app, or the path on the badge, that the problem is about
+    file: String,
+    message: String,
+}
+
+impl Problem {
+    fn error(file: &str, message: String) -> Problem {
+        Problem {
+            severity: Severity::Error,
+            file: file.to_owned(),
+            message,
+        }
+    }
+
+    fn warning(file: &str, message: String) -> Problem {
+        Problem {
+            severity: Severity::Warning,
+            file: file.to_owned(),
+            message,
+        }
+    }
+}
+
+fn check_metadata(dir: &Path, problems: &mut Vec<Problem>) {
+    const FILE: &str = "metadata.json";
+    let text = match fs::read_to_string(dir.join(FILE)) {
+        Ok(text) => text,
+        // Reported with the other required files
+        Err(_) => return,
+    };
+    let metadata = match serde_json::from_str::<Value>(&text) {
+        Ok(Value::Object(metadata)) => metadata,
+        Ok(_) => return problems.push(Problem::error(FILE, "isn't a JSON object".to_owned())),
+        Err(e) => return problems.push(Problem::error(FILE, format!("invalid JSON: {}", e))),
+    };
+
+    for (field, number) in METADATA_FIELDS.iter().copied() {
+        match metadata.get(field) {
+            None => problems.push(Problem::error(FILE, format!("{} is missing", field))),
+            Some(value) if number && value.as_u64().is_none() => problems.push(Problem::error(
+                FILE,
+                format!("{} must be a whole number", field),
+            )),
+            Some(value) if !number && !value.is_string() => {
+                problems.push(Problem::error(FILE, format!("{} must be a string", field)))
+            }
+            Some(Value::String(value)) if value.trim().is_empty() => {
+                problems.push(Problem::warning(FILE, format!("{} is empty", field)))
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+/// The width and height of a PNG image, from its header
+fn png_size(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(PNG_SIGNATURE) || data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let number = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    Some((number(16)?, number(20)?))
+}
+
+fn check_icon(dir: &Path, problems: &mut Vec<Problem>) {
+    const FILE: &str = "icon.png";
+    let data = match fs::read(dir.join(FILE)) {
+        Ok(data) => data,
+        Err(_) => {
+            return problems.push(Problem::warning(
+                FILE,
+                "is missing, so the app has no icon".to_owned(),
+            ))
+        }
+    };
+
+    match png_size(&data) {
+        None => problems.push(Problem::error(FILE, "isn't a PNG image".to_owned())),
+        Some((width, height)) if (width as usize, height as usize) != (ICON_SIZE, ICON_SIZE) => {
+            problems.push(Problem::warning(
+                FILE,
+                format!(
+                    "is {}x{}, but icons are {}x{}",
+                    width, height, ICON_SIZE, ICON_SIZE
+                ),
+            ))
+        }
+        Some(_) => {}
+    }
+}
+
+/// Fails if another app is installed as `name`. The same app uploaded earlier from `dir` is fine,
+/// which the source map tells.
+async fn check_installed(
+    badge: &Badge,
+    dir: &Path,
+    name: &str,
+    problems: &mut Vec<Problem>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let apps = BadgePath::new("/flash/apps")?;
+    let entries = match badge.fetch_dir(&apps).await? {
+        DirectoryListingResponse::Found { entries, .. } => entries,
+        DirectoryListingResponse::DirectoryNotFound => return Ok(()),
+    };
+    if !entries.iter().any(|entry| entry.name() == name) {
+        return Ok(());
+    }
+
+    let installed = apps.join(name)?;
+    let init = installed.join("__init__.py")?;
+    let local = dir.canonicalize()?.join("__init__.py");
+    if SourceMap::load().local_path(&init) == Some(local.as_path()) {
+        debug!("{} was uploaded from {:?} before", installed, dir);
+    } else {
+        problems.push(Problem::error(
+            installed.as_str(),
+            "another app is installed with this name, which uploading would overwrite".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the app in `dir`, which will be installed as `name`, or the name of the directory
+pub async fn lint(
+    badge: &Badge,
+    dir: &Path,
+    name: Option<String>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let name = name.unwrap_or_else(|| {
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+        dir.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+
+    // Fails if it isn't a directory
+    fs::read_dir(dir)?;
+
+    let mut problems = Vec::new();
+    for required in REQUIRED_FILES {
+        if !dir.join(required).is_file() {
+            problems.push(Problem::error(required, "is missing".to_owned()));
+        }
+    }
+    check_metadata(dir, &mut problems);
+    check_icon(dir, &mut problems);
+    if scaffold::is_valid_name(&name) {
+        check_installed(badge, dir, &name, &mut problems).await?;
+    } else {
+        problems.push(Problem::error(
+            ".",
+            format!(
+                "{:?} can't be the name of an app, pass one with --name",
+                name
+            ),
+        ));
+    }
+
+    for problem in problems.iter() {
+        output.lint_problem(problem.severity, &problem.message, &problem.file);
+    }
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        Err(LintError::Failed(errors))?
+    }
+    info!("No problems found in {:?}", dir);
+
+    Ok(())
+}
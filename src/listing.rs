@@ -0,0 +1,142 @@
+//! Prints `ls` and `tree` for people: names colored by type like `ls --color` does, and the tree
+//! drawn with box characters. Porcelain output stays one plain record per line.
+
+use crate::{client::FileKind, output::Output, path::BadgePath};
+
+const RESET: &str = "\u{1b}[0m";
+
+/// Colors by file type, as SGR parameters like `01;34`
+struct Colors {
+    directory: String,
+    /// By lowercase extension. Earlier entries take precedence.
+    extensions: Vec<(String, String)>,
+}
+
+impl Colors {
+    /// The defaults, overridden by the `di` and `*.ext` entries of `$LS_COLORS`
+    fn from_env() -> Colors {
+        let mut colors = Colors {
+            directory: "01;34".to_owned(),
+            extensions: Vec::new(),
+        };
+        let defaults = [
+            ("32", &["py", "mpy"][..]),
+            (
+                "35",
+                &[
+                    "png", "jpg", "jpeg", "gif", "bmp", "wav", "mp3", "ogg", "mod",
+                ][..],
+            ),
+            ("31", &["tar", "gz", "tgz", "zip"][..]),
+        ];
+        for (color, extensions) in defaults.iter() {
+            for extension in extensions.iter() {
+                colors
+                    .extensions
+                    .push((extension.to_string(), color.to_string()));
+            }
+        }
+
+        if let Ok(ls_colors) = std::env::var("LS_COLORS") {
+            for item in ls_colors.split(':') {
+                let (key, value) = match item.split_once('=') {
+                    Some(pair) => pair,
+                    None => continue,
+                };
+                if key == "di" {
+                    colors.directory = value.to_owned();
+                } else if let Some(extension) = key.strip_prefix("*.") {
+                    colors
+                        .extensions
+                        .insert(0, (extension.to_ascii_lowercase(), value.to_owned()));
+                }
+            }
+        }
+
+        colors
+    }
+
+    fn get(&self, kind: FileKind, name: &str) -> Option<&str> {
+        if kind == FileKind::Directory {
+            return Some(&self.directory);
+        }
+
+        let extension = name.rsplit_once('.')?.1.to_ascii_lowercase();
+        self.extensions
+            .iter()
+            .find(|(other, _)| *other == extension)
+            .map(|(_, color)| color.as_str())
+    }
+}
+
+pub(crate) fn stdout_is_tty() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+pub struct Lister {
+    output: Output,
+    colors: Option<Colors>,
+}
+
+impl Lister {
+    /// Colors are used when stdout is a terminal, unless `no_color` or `$NO_COLOR` is set
+    pub fn new(output: Output, no_color: bool) -> Lister {
+        let colors = output == Output::Human
+            && !no_color
+            && std::env::var_os("NO_COLOR").is_none()
+            && stdout_is_tty();
+        Lister {
+            output,
+            colors: if colors {
+                Some(Colors::from_env())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Entries of a type this tool doesn't know are flagged with the type
+    fn paint(&self, kind: FileKind, name: &str) -> String {
+        let painted = match self
+            .colors
+            .as_ref()
+            .and_then(|colors| colors.get(kind, name))
+        {
+            Some(color) => format!("\u{1b}[{}m{}{}", color, name, RESET),
+            None => name.to_owned(),
+        };
+        match kind {
+            FileKind::Other(kind) => format!("{} [unknown type {:?}]", painted, kind),
+            _ => painted,
+        }
+    }
+
+    /// An entry of `ls`
+    pub fn entry(&self, kind: FileKind, name: &str) {
+        match self.output {
+            Output::Human => println!("{}", self.paint(kind, name)),
+            Output::Porcelain => self.output.entry(kind, name),
+        }
+    }
+
+    /// An entry of `tree`. `last` tells for `path` and each of its ancestors below the root
+    /// whether it is the last entry of its directory, which decides the lines that are drawn.
+    pub fn tree_entry(&self, kind: FileKind, path: &BadgePath, last: &[bool]) {
+        if self.output == Output::Porcelain {
+            return self.output.entry(kind, path.as_str());
+        }
+
+        let (own, ancestors) = match last.split_last() {
+            Some(split) => split,
+            None => return println!("{}", self.paint(kind, path.as_str())),
+        };
+
+        let mut line = String::new();
+        for &ancestor in ancestors {
+            line.push_str(if ancestor { "    " } else { "│   " });
+        }
+        line.push_str(if *own { "└── " } else { "├── " });
+        line.push_str(&self.paint(kind, path.file_name()));
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,137 @@
+//! Makes sure only one process opens a badge at a time, and tells which process has it otherwise,
+//! instead of failing with a libusb error. Opening a badge takes an exclusive `flock` on
+//! `<port>.lock` next to the sockets of `share`, and writes the PID and command line of the process
+//! into it. The kernel releases the lock when the process exits, even if it crashes, so a lock is
+//! never left behind.
+
+use crate::{backoff::Backoff, share};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// How often to check whether the badge was released
+const POLL_BACKOFF: Backoff = Backoff::new(Duration::from_millis(200), Duration::from_secs(2));
+
+/// How long the process that has the badge gets to exit after `--steal`
+const STEAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do when another process has the badge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Fail,
+    /// Wait until the other process releases it
+    Wait,
+    /// Ask the other process to exit with SIGTERM, and take over
+    Steal,
+}
+
+static POLICY: Mutex<Policy> = Mutex::new(Policy::Fail);
+
+/// Sets the policy for every badge this process opens
+pub fn set_policy(policy: Policy) {
+    *POLICY.lock().unwrap() = policy;
+}
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("The badge is in use by process {} ({}). Stop it, wait for it with --wait-for-badge or stop it with --steal.", .pid, .command)]
+    Busy { pid: i32, command: String },
+
+    #[error("Process {} didn't release the badge within {:?}", .0, STEAL_TIMEOUT)]
+    NotReleased(i32),
+
+    #[error("Unable to lock the badge: {}", .0)]
+    Io(#[from] io::Error),
+}
+
+/// The exclusive lock on a badge. Dropping it releases the badge.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: File,
+}
+
+/// Takes the lock without waiting, or returns `false` if another process has it
+fn try_lock(file: &File) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+/// The PID and command line that the process that has the lock wrote
+fn holder(path: &Path) -> (i32, String) {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    let (pid, command) = contents
+        .trim_end()
+        .split_once('\t')
+        .unwrap_or(("0", "unknown"));
+    (pid.parse().unwrap_or(0), command.to_owned())
+}
+
+/// Locks the badge at `port`, as `<bus>-<address>`, following the policy set with `set_policy`
+pub fn acquire(port: &str) -> Result<DeviceLock, LockError> {
+    let path = share::create_socket_dir()?.join(format!("{}.lock", port));
+    // Not truncated, the process that has the lock might still be writing to it
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    let policy = *POLICY.lock().unwrap();
+    let mut stolen_at = None;
+    let mut waiting = false;
+    let mut attempt = 0;
+    while !try_lock(&file)? {
+        let (pid, command) = holder(&path);
+        match policy {
+            Policy::Fail => return Err(LockError::Busy { pid, command }),
+            Policy::Wait if !waiting => {
+                info!(
+                    "Waiting for process {} ({}) to release the badge",
+                    pid, command
+                );
+                waiting = true;
+            }
+            Policy::Wait => {}
+            Policy::Steal => match stolen_at {
+                None if pid > 0 => {
+                    warn!(
+                        "Stopping process {} ({}) to take over the badge",
+                        pid, command
+                    );
+                    unsafe { libc::kill(pid, libc::SIGTERM) };
+                    stolen_at = Some(Instant::now());
+                }
+                None => return Err(LockError::Busy { pid, command }),
+                Some(at) if at.elapsed() > STEAL_TIMEOUT => {
+                    return Err(LockError::NotReleased(pid))
+                }
+                Some(_) => {}
+            },
+        }
+        std::thread::sleep(POLL_BACKOFF.delay(attempt));
+        attempt += 1;
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    let command = std::env::args().collect::<Vec<_>>().join(" ");
+    writeln!(file, "{}\t{}", std::process::id(), command)?;
+
+    Ok(DeviceLock { _file: file })
+}
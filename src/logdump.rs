@@ -0,0 +1,90 @@
+use crate::{
+    device::Badge,
+    render::{strip_escapes, Renderer},
+};
+use regex::Regex;
+use std::{error::Error, time::Duration};
+use thiserror::Error;
+use tokio::{
+    sync::broadcast::{self, RecvError},
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+#[derive(Error, Debug)]
+pub enum LogdumpError {
+    #[error("The output didn't match {} within {:?}", .0, .1)]
+    NotMatched(Regex, Duration),
+
+    #[error("The connection to the badge was closed")]
+    Closed,
+}
+
+/// Prints the output of the badge until a line matches `until`, or forever without it. Returns
+/// the line that matched.
+pub async fn capture(
+    mut output: broadcast::Receiver<String>,
+    until: Option<&Regex>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut renderer = Renderer::new();
+    let mut line = String::new();
+    loop {
+        let text = match output.recv().await {
+            Ok(text) => text,
+            Err(RecvError::Lagged(n)) => {
+                warn!("Dropped {} messages of badge output", n);
+                continue;
+            }
+            Err(RecvError::Closed) => Err(LogdumpError::Closed)?,
+        };
+        renderer.write(&text)?;
+
+        let until = match until {
+            Some(until) => until,
+            None => continue,
+        };
+        for c in text.chars() {
+            if c == '\n' {
+                let stripped = strip_escapes(&line);
+                if until.is_match(&stripped) {
+                    return Ok(stripped);
+                }
+                line.clear();
+            } else {
+                line.push(c);
+            }
+        }
+
+        // Also match lines that haven't been finished, like prompts
+        let stripped = strip_escapes(&line);
+        if !line.is_empty() && until.is_match(&stripped) {
+            return Ok(stripped);
+        }
+    }
+}
+
+/// Prints the output of the badge for `duration`, or until a line matches `until`. Fails if `until`
+/// doesn't match in time. Without `duration`, waits for `until` forever.
+pub async fn logdump(
+    badge: &Badge,
+    duration: Option<Duration>,
+    until: Option<Regex>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let output = badge.subscribe();
+    badge.heartbeat().await?;
+
+    let capture = capture(output, until.as_ref());
+    match duration {
+        Some(duration) => match timeout(duration, capture).await {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => match until {
+                Some(until) => Err(LogdumpError::NotMatched(until, duration))?,
+                None => {
+                    debug!("Captured output for {:?}", duration);
+                    Ok(())
+                }
+            },
+        },
+        None => capture.await.map(|_| ()),
+    }
+}
@@ -0,0 +1,208 @@
+//! Recognizes the log lines of the firmware and apps, which start with a level and usually a tag:
+//! `W (1234) wifi: message` from ESP-IDF and `WARNING:module:message` from the `logging` module.
+
+use regex::Regex;
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Verbose,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown log level {:?}. Use error, warning, info, debug or verbose.", .0)]
+pub struct LevelError(String);
+
+impl FromStr for Level {
+    type Err = LevelError;
+
+    fn from_str(s: &str) -> Result<Level, LevelError> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "e" | "error" | "critical" => Level::Error,
+            "w" | "warn" | "warning" => Level::Warning,
+            "i" | "info" => Level::Info,
+            "d" | "debug" => Level::Debug,
+            "v" | "verbose" | "trace" => Level::Verbose,
+            _ => return Err(LevelError(s.to_owned())),
+        })
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Verbose => "verbose",
+        })
+    }
+}
+
+impl Level {
+    /// The SGR sequence to color lines of this level with, if any
+    pub fn color(self) -> Option<&'static str> {
+        match self {
+            Level::Error => Some("\u{1b}[31m"),
+            Level::Warning => Some("\u{1b}[33m"),
+            Level::Debug | Level::Verbose => Some("\u{1b}[2m"),
+            Level::Info => None,
+        }
+    }
+}
+
+/// A line of output, split into its parts if it is a log line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: Option<Level>,
+    pub module: Option<String>,
+    pub message: String,
+}
+
+pub struct LogParser {
+    esp_idf: Regex,
+    logging: Regex,
+}
+
+impl Default for LogParser {
+    fn default() -> LogParser {
+        LogParser::new()
+    }
+}
+
+impl LogParser {
+    pub fn new() -> LogParser {
+        LogParser {
+            esp_idf: Regex::new(r"^([EWIDV]) \(\d+\) ([^:]+): (.*)$").unwrap(),
+            logging: Regex::new(r"^(CRITICAL|ERROR|WARNING|INFO|DEBUG):([^:]*):(.*)$").unwrap(),
+        }
+    }
+
+    /// Parses a line without escape sequences or line ending
+    pub fn parse(&self, line: &str) -> LogRecord {
+        for pattern in [&self.esp_idf, &self.logging].iter() {
+            if let Some(captures) = pattern.captures(line) {
+                return LogRecord {
+                    level: captures[1].parse().ok(),
+                    module: Some(captures[2].to_owned()).filter(|module| !module.is_empty()),
+                    message: captures[3].to_owned(),
+                };
+            }
+        }
+
+        LogRecord {
+            level: None,
+            module: None,
+            message: line.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(level: Option<Level>, module: Option<&str>, message: &str) -> LogRecord {
+        LogRecord {
+            level,
+            module: module.map(str::to_owned),
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let cases = [
+            ("E", Level::Error),
+            ("critical", Level::Error),
+            ("ERROR", Level::Error),
+            ("w", Level::Warning),
+            ("warn", Level::Warning),
+            ("WARNING", Level::Warning),
+            ("I", Level::Info),
+            ("info", Level::Info),
+            ("D", Level::Debug),
+            ("debug", Level::Debug),
+            ("V", Level::Verbose),
+            ("trace", Level::Verbose),
+        ];
+        for (name, level) in cases.iter() {
+            assert_eq!(name.parse::<Level>().unwrap(), *level, "{}", name);
+        }
+        assert!("loud".parse::<Level>().is_err());
+        assert!(Level::Error < Level::Warning && Level::Debug < Level::Verbose);
+    }
+
+    #[test]
+    fn esp_idf_lines() {
+        let parser = LogParser::new();
+        let cases = [
+            ("E (12) wifi: failed", Level::Error, "wifi", "failed"),
+            ("W (1234) wifi: slow: 2", Level::Warning, "wifi", "slow: 2"),
+            (
+                "I (5) boot: ESP-IDF v4.0",
+                Level::Info,
+                "boot",
+                "ESP-IDF v4.0",
+            ),
+            ("D (0) nvs: opened", Level::Debug, "nvs", "opened"),
+            ("V (99) spi: sent", Level::Verbose, "spi", "sent"),
+        ];
+        for (line, level, module, message) in cases.iter() {
+            assert_eq!(
+                parser.parse(line),
+                record(Some(*level), Some(module), message),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn logging_lines() {
+        let parser = LogParser::new();
+        let cases = [
+            ("CRITICAL:app:down", Level::Error, Some("app"), "down"),
+            ("ERROR:app:failed", Level::Error, Some("app"), "failed"),
+            (
+                "WARNING:net:retry: 2",
+                Level::Warning,
+                Some("net"),
+                "retry: 2",
+            ),
+            ("INFO:root:started", Level::Info, Some("root"), "started"),
+            ("DEBUG::no module", Level::Debug, None, "no module"),
+        ];
+        for (line, level, module, message) in cases.iter() {
+            assert_eq!(
+                parser.parse(line),
+                record(Some(*level), *module, message),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn lines_without_a_level() {
+        let parser = LogParser::new();
+        for line in [
+            "",
+            "hello",
+            ">>> print('W (1) x: y')",
+            "X (12) wifi: unknown level",
+            "W (abc) wifi: no timestamp",
+            "Info:app:wrong case",
+        ]
+        .iter()
+        {
+            assert_eq!(parser.parse(line), record(None, None, line), "{}", line);
+        }
+    }
+}
@@ -1,221 +1,1875 @@
-use cmds::{DirectoryListingResponse, FsEntry};
+use backoff::Backoff;
+use cache::MetadataCache;
+use client::{BadgeFs, ClientError, FileKind};
+use cmds::FsEntry;
 use crossbeam::scope;
-use device::{Badge, Device};
+use cz2020_usbtool::{
+    archive, atomic, backoff, bench, cache, client, cmds, config, delta, device, display, doctor,
+    du, duration, edit, exit, expect, find, firmware, fleet, fs, git, grep, hooks, ignore, install,
+    latency, lint, listing, lock, logdump, loglevel, mirror, mpremote, mux, notify, output,
+    overwrite, pacing, paste, path, provision, push, ranged, render, repl, resume, run, scaffold,
+    screenshot, send, share, shell, size, snapshot, sourcemap, space, status, stream, testrun,
+    text, traceback, trash,
+};
+use device::{Badge, BadgeBuilder, Device, RetryPolicy};
+use exit::{ExitCode, PartialFailure};
+use fleet::FleetError;
 use fs::AppFS;
-use log::{info, warn};
+use ignore::ExcludeRules;
+use listing::Lister;
+use output::Output;
+use overwrite::Overwrite;
+use path::BadgePath;
+use regex::Regex;
+use render::{LineFilter, Renderer};
+use resume::Journal;
 use std::{
+    collections::HashSet,
     error::Error,
-    io::{Read, Write},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use stream::{OverflowPolicy, Stream};
+use structopt::StructOpt;
+use tokio::{
+    runtime::Runtime,
+    sync::broadcast::{self, RecvError},
+};
+use tracing::{debug, error, info, warn, Level};
+use tracing_subscriber::{
+    fmt::{self, format::FmtSpan, writer::MakeWriterExt},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter,
+};
+
+#[derive(StructOpt, Clone)]
+#[structopt(
+    name = "cz2020-usbtool",
+    about = "Communicate with the CampZone 2020 badge without using Chrome."
+)]
+struct Opts {
+    #[structopt(
+        long,
+        default_value = "250",
+        help = "Milliseconds between heartbeats sent to the badge"
+    )]
+    heartbeat_interval: u64,
+
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "Seconds to wait for a response before a command is retried"
+    )]
+    timeout: u64,
+
+    #[structopt(
+        long,
+        help = "Give up after retrying a command this many times, instead of retrying forever"
+    )]
+    retries: Option<u32>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = duration::parse_duration),
+        default_value = "8s",
+        help = "Longest wait between retries of a command, which starts at half a second and doubles with every retry"
+    )]
+    max_retry_delay: Duration,
+
+    #[structopt(
+        long,
+        help = "Maximum number of commands that can be waiting for a response at the same time"
+    )]
+    pipelining_depth: Option<usize>,
+
+    #[structopt(
+        long,
+        default_value = "16384",
+        help = "Maximum number of bytes to read from the badge at once. Larger reads make big downloads faster."
+    )]
+    receive_buffer: usize,
+
+    #[structopt(
+        long,
+        parse(try_from_str = pacing::parse_rate),
+        help = "Send at most this many bytes per second, like 20k or 1m, so transfers don't starve the app running on the badge"
+    )]
+    limit_rate: Option<u64>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Write a JSON trace of every command and filesystem operation to this file"
+    )]
+    trace_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Print tab-separated output with a stable format for scripts, and errors as error<TAB>code<TAB>message"
+    )]
+    porcelain: bool,
+
+    #[structopt(
+        long,
+        help = "Perform file operations with Python code in the shell on the badge instead of the USB file commands, to tell firmware bugs from bugs in this tool. Much slower, and no app may be running."
+    )]
+    via_repl: bool,
+
+    #[structopt(
+        long,
+        help = "Print the traffic, latency and retries of each kind of command to stderr when done"
+    )]
+    stats: bool,
+
+    #[structopt(
+        long,
+        help = "Print the commands that have been waiting for a response for a second or more to stderr, every second, to find out where a transfer is stuck"
+    )]
+    debug_requests: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with = "all-devices",
+        help = "Record how long encoding, writing, the badge, reading and parsing took for every command, as a Chrome trace that Perfetto and chrome://tracing open"
+    )]
+    latency_trace: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with = "all-devices",
+        help = "Use the badge with this serial number or <bus>-<address> (like 001-014) instead of the first badge"
+    )]
+    device: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Run the command on every connected badge in parallel, labeling each line of output with the badge"
+    )]
+    all_devices: bool,
+
+    #[structopt(
+        long,
+        help = "If another process is using the badge, wait until it is done instead of failing"
+    )]
+    wait_for_badge: bool,
+
+    #[structopt(
+        long,
+        conflicts_with = "wait-for-badge",
+        help = "If another process is using the badge, stop it and take over"
+    )]
+    steal: bool,
+
+    #[structopt(
+        long,
+        help = "Show desktop notifications when a badge is plugged in or unplugged during mount --watch, a push finishes or an app crashes"
+    )]
+    notify: bool,
+
+    #[structopt(subcommand)]
+    cmd: Args,
+}
+
+impl Opts {
+    fn output(&self) -> Output {
+        if self.porcelain {
+            Output::Porcelain
+        } else {
+            Output::Human
+        }
+    }
+
+    fn lock_policy(&self) -> lock::Policy {
+        if self.steal {
+            lock::Policy::Steal
+        } else if self.wait_for_badge {
+            lock::Policy::Wait
+        } else {
+            lock::Policy::Fail
+        }
+    }
+
+    fn badge_builder(&self) -> BadgeBuilder {
+        Badge::builder()
+            .heartbeat_interval(Duration::from_millis(self.heartbeat_interval))
+            .request_timeout(Duration::from_secs(self.timeout))
+            .retry(RetryPolicy {
+                max_retries: self.retries,
+                backoff: Backoff::new(Duration::from_millis(500), self.max_retry_delay),
+                ..RetryPolicy::default()
+            })
+            .pipelining_depth(self.pipelining_depth)
+            .receive_buffer_size(self.receive_buffer)
+            .rate_limit(self.limit_rate)
+            .via_repl(self.via_repl)
+            .debug_requests(if self.debug_requests {
+                Some(Duration::from_secs(1))
+            } else {
+                None
+            })
+            .latency_trace(latency::trace())
+    }
+}
+
+/// How often `mount --watch` looks for badges that were plugged in
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+const TEXT_HELP: &str =
+    "Convert CRLF line endings to LF, because stray carriage returns can break MicroPython";
+const TIMESTAMPS_HELP: &str = "Prefix every line of output with the time it was received";
+const NO_COLOR_HELP: &str =
+    "Don't color names by type. Colors are only used on a terminal, and not if $NO_COLOR is set.";
+const RESUME_HELP: &str = "Transfer in chunks through the Python shell and record the progress, so an interrupted transfer continues where it stopped when run again with --resume. Slower, and no app may be running.";
+const ATOMIC_HELP: &str = "Write to a temporary file first and then move it over the target, so an interrupted transfer never leaves a half-written file";
+const FORCE_SPACE_HELP: &str =
+    "Upload even if the badge doesn't seem to have enough free space, instead of refusing";
+const NO_CLOBBER_HELP: &str = "Do nothing if the destination exists";
+const FORCE_HELP: &str =
+    "Replace the destination if it exists. Without this, an existing destination is an error.";
+const CACHED_HELP: &str = "Answer from the local metadata cache, which is updated whenever the badge is accessed, instead of asking the badge";
+
+#[derive(StructOpt, Clone)]
+enum Args {
+    #[structopt(about = "Lists all files available on the badge one-by-one")]
+    Tree {
+        #[structopt(long, help = CACHED_HELP)]
+        cached: bool,
+
+        #[structopt(long, help = NO_COLOR_HELP)]
+        no_color: bool,
+    },
+
+    #[structopt(about = "Lists all files in the specified directory")]
+    Ls {
+        path: BadgePath,
+
+        #[structopt(long, help = CACHED_HELP)]
+        cached: bool,
+
+        #[structopt(long, help = NO_COLOR_HELP)]
+        no_color: bool,
+    },
+
+    #[structopt(
+        about = "Fetches the specified files. Files that can't be fetched are reported and skipped."
+    )]
+    Get {
+        #[structopt(required = true)]
+        paths: Vec<BadgePath>,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Save the files in this directory instead of writing them to stdout"
+        )]
+        output_dir: Option<PathBuf>,
+
+        #[structopt(long, help = "Convert LF line endings to CRLF")]
+        crlf: bool,
+
+        #[structopt(long, requires = "output-dir", help = RESUME_HELP)]
+        resume: bool,
+
+        #[structopt(
+            long,
+            conflicts_with_all = &["output-dir", "crlf"],
+            help = "Write the files and directories to stdout as a tar archive, like `get /flash/apps --tar | tar x`"
+        )]
+        tar: bool,
+    },
+
+    #[structopt(
+        about = "Copies a directory of the badge to a new local directory, while writes to the badge are paused so the copy is consistent"
+    )]
+    Snapshot {
+        path: BadgePath,
+
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+    },
+
+    #[structopt(
+        about = "Opens a file of the badge in $VISUAL or $EDITOR, and uploads it atomically after showing the changes"
+    )]
+    Edit {
+        path: BadgePath,
+
+        #[structopt(short, long, help = "Upload the changes without asking")]
+        yes: bool,
+    },
+
+    #[structopt(about = "Prints how much space each directory takes up, including subdirectories")]
+    Du {
+        #[structopt(default_value = "/flash")]
+        path: BadgePath,
+
+        #[structopt(
+            short = "d",
+            long,
+            help = "Only prints directories up to this many levels below the path"
+        )]
+        max_depth: Option<usize>,
+    },
+
+    #[structopt(about = "Finds files and directories by name, type and size")]
+    Find {
+        #[structopt(default_value = "/flash")]
+        path: BadgePath,
+
+        #[structopt(
+            long,
+            help = "Only print entries whose name matches this glob, like '*.py'"
+        )]
+        name: Option<String>,
+
+        #[structopt(
+            long = "type",
+            parse(try_from_str = find::parse_kind),
+            help = "Only print files (f) or directories (d)"
+        )]
+        kind: Option<FileKind>,
+
+        #[structopt(
+            long,
+            parse(try_from_str = size::parse_size),
+            help = "Only print files of at least this size, like 512, 10k or 1m"
+        )]
+        min_size: Option<u64>,
+
+        #[structopt(
+            long,
+            parse(try_from_str = size::parse_size),
+            help = "Only print files of at most this size, like 512, 10k or 1m"
+        )]
+        max_size: Option<u64>,
+    },
+
+    #[structopt(
+        about = "Prints the lines of files that match a regular expression, as path:line:text. Fails if nothing matched."
+    )]
+    Grep {
+        pattern: String,
+
+        #[structopt(default_value = "/flash")]
+        path: BadgePath,
+
+        #[structopt(
+            long,
+            help = "Only search files whose name matches this glob, like '*.py'"
+        )]
+        include: Option<String>,
+
+        #[structopt(short, long, help = "Ignore the case of letters")]
+        ignore_case: bool,
+
+        #[structopt(
+            long,
+            help = "Keep fetched files in the cache directory, and only fetch files again if their hash changed"
+        )]
+        cached: bool,
+    },
+
+    #[structopt(about = "Updates the firmware of the badge over WiFi")]
+    Firmware {
+        #[structopt(subcommand)]
+        command: firmware::FirmwareCommand,
+    },
+
+    #[structopt(about = "Prints the first lines of a file, without fetching all of it")]
+    Head {
+        path: BadgePath,
+
+        #[structopt(short = "n", long, default_value = "10")]
+        lines: usize,
+    },
+
+    #[structopt(about = "Prints the last lines of a file, without fetching all of it")]
+    Tail {
+        path: BadgePath,
+
+        #[structopt(short = "n", long, default_value = "10")]
+        lines: usize,
+    },
+
+    #[structopt(about = "Writes stdin to the specified file")]
+    Set {
+        path: BadgePath,
+
+        #[structopt(
+            long,
+            help = "Only send the parts of the file that changed. Uses the Python shell, so no app may be running."
+        )]
+        delta: bool,
+
+        #[structopt(long, help = TEXT_HELP)]
+        text: bool,
+
+        #[structopt(
+            long,
+            conflicts_with = "delta",
+            help = "Add stdin to the end of the file instead of replacing it. Uses the Python shell, so no app may be running."
+        )]
+        append: bool,
+
+        #[structopt(long, conflicts_with_all = &["delta", "append"], help = ATOMIC_HELP)]
+        atomic: bool,
+
+        #[structopt(long, help = FORCE_SPACE_HELP)]
+        force: bool,
+    },
+
+    #[structopt(about = "Uploads a local directory to the badge", alias = "deploy")]
+    Push {
+        #[structopt(parse(from_os_str))]
+        local: PathBuf,
+
+        remote: BadgePath,
+
+        #[structopt(
+            long,
+            number_of_values = 1,
+            help = "Skip files matching this gitignore-style pattern, in addition to those in .badgeignore"
+        )]
+        exclude: Vec<String>,
+
+        #[structopt(
+            long,
+            help = "Convert CRLF line endings to LF in every file that looks like text"
+        )]
+        text: bool,
+
+        #[structopt(long, help = RESUME_HELP)]
+        resume: bool,
+
+        #[structopt(long, conflicts_with = "resume", help = ATOMIC_HELP)]
+        atomic: bool,
+
+        #[structopt(long, help = FORCE_SPACE_HELP)]
+        force: bool,
+
+        #[structopt(
+            long,
+            help = "Only upload the files that changed since this git commit, branch or tag, and delete those that were deleted since then"
+        )]
+        since: Option<String>,
+    },
+
+    #[structopt(
+        about = "Applies a manifest that lists files to upload, apps to install, settings and code to run. Steps that are already done are skipped."
+    )]
+    Provision {
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+    },
+
+    #[structopt(
+        about = "Creates a local directory with a minimal app: __init__.py, metadata.json and a placeholder icon"
+    )]
+    New {
+        name: String,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Create the app in this directory instead of ./<name>"
+        )]
+        dir: Option<PathBuf>,
+
+        #[structopt(long, help = "Upload the app to /flash/apps/<name> right away")]
+        push: bool,
+    },
+
+    #[structopt(
+        about = "Checks a local app directory for problems before it is uploaded, like missing metadata or an app that is installed with the same name"
+    )]
+    Lint {
+        #[structopt(parse(from_os_str))]
+        local: PathBuf,
+
+        #[structopt(
+            long,
+            help = "The name the app will be installed as. Defaults to the name of the directory."
+        )]
+        name: Option<String>,
+    },
+
+    #[structopt(
+        about = "Uploads an app with tests to /flash/apps/<name>, runs it and checks the TAP or unittest results it prints"
+    )]
+    Test {
+        #[structopt(parse(from_os_str))]
+        local: PathBuf,
+
+        #[structopt(
+            long,
+            help = "The name of the app. Defaults to the name of the directory."
+        )]
+        name: Option<String>,
+
+        #[structopt(
+            long,
+            parse(try_from_str = duration::parse_duration),
+            default_value = "60s",
+            help = "How long to wait for the tests to finish"
+        )]
+        timeout: Duration,
+    },
+
+    #[structopt(
+        about = "Prints a JSON manifest with the path, size and SHA-256 of every file below a directory"
+    )]
+    Manifest {
+        #[structopt(default_value = "/flash")]
+        path: BadgePath,
+
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Write the manifest to this file instead of stdout"
+        )]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(
+        about = "Checks that the files on the badge match a manifest written by `manifest`, and prints the ones that don't"
+    )]
+    Verify {
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+
+        #[structopt(long, help = "Don't report files that aren't in the manifest")]
+        allow_extra: bool,
+    },
+
+    #[structopt(about = "Installs an app from a .tar or .tar.gz archive to /flash/apps/<name>")]
+    Install {
+        #[structopt(long, parse(from_os_str))]
+        file: PathBuf,
+
+        #[structopt(
+            long,
+            help = "The name of the app. Defaults to the directory in the archive, or the archive's file name."
+        )]
+        name: Option<String>,
+    },
+
+    #[structopt(about = "Creates a new file")]
+    CreateFile { path: BadgePath },
+
+    #[structopt(about = "Creates a new directory")]
+    CreateDir { path: BadgePath },
+
+    #[structopt(about = "Deletes the specified path")]
+    Rm {
+        path: BadgePath,
+
+        #[structopt(
+            long,
+            help = "Move the path to /flash/.trash instead, so it can be restored with `trash restore`"
+        )]
+        trash: bool,
+    },
+
+    #[structopt(
+        about = "Lists, restores or deletes the paths that `rm --trash` moved to the trash"
+    )]
+    Trash {
+        #[structopt(subcommand)]
+        command: trash::TrashCommand,
+    },
+
+    #[structopt(about = "Copies a file to another file")]
+    Cp {
+        from: BadgePath,
+
+        to: BadgePath,
+
+        #[structopt(short = "n", long, help = NO_CLOBBER_HELP)]
+        no_clobber: bool,
+
+        #[structopt(short, long, conflicts_with = "no-clobber", help = FORCE_HELP)]
+        force: bool,
+    },
+
+    #[structopt(about = "Moves a file from one location to another")]
+    Mv {
+        #[structopt(help = "The original file location")]
+        from: BadgePath,
+
+        #[structopt(about = "The new file location. The filename itself must be included.")]
+        to: BadgePath,
+
+        #[structopt(short = "n", long, help = NO_CLOBBER_HELP)]
+        no_clobber: bool,
+
+        #[structopt(short, long, conflicts_with = "no-clobber", help = FORCE_HELP)]
+        force: bool,
+    },
+
+    #[structopt(
+        about = "Lists, prints, copies and deletes files like `mpremote fs`, with ':' in front of paths on the badge"
+    )]
+    Fs {
+        #[structopt(subcommand)]
+        command: mpremote::FsCommand,
+    },
+
+    #[structopt(
+        about = "Reads and changes the settings of the firmware, like the nickname and WiFi"
+    )]
+    Config {
+        #[structopt(subcommand)]
+        command: config::ConfigCommand,
+    },
+
+    #[structopt(about = "Shows test patterns or a color on the display, to check the hardware")]
+    Display {
+        #[structopt(subcommand)]
+        command: display::DisplayCommand,
+    },
+
+    #[structopt(about = "Saves what the display shows as a PNG image")]
+    Screenshot {
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+
+        #[structopt(
+            long,
+            default_value = "8",
+            help = "Draw every pixel of the display as a square of this size"
+        )]
+        scale: usize,
+    },
+
+    #[structopt(
+        about = "Prints whether an app is running, the battery voltage and other sensor readings"
+    )]
+    Status,
+
+    #[structopt(about = "Stops the running app with a KeyboardInterrupt")]
+    Stop {
+        #[structopt(
+            long,
+            help = "Soft reset the badge after the interrupt, which returns to the launcher even if the app ignores it"
+        )]
+        reset: bool,
+    },
+
+    #[structopt(
+        about = "Runs an app",
+        setting = structopt::clap::AppSettings::TrailingVarArg
+    )]
+    Run {
+        #[structopt(
+            about = "The path to the Python file or app directory, e.g. /apps/synthesizer. The /flash prefix is optional."
+        )]
+        path: BadgePath,
+
+        #[structopt(
+            allow_hyphen_values = true,
+            help = "Arguments for the app, passed in sys.argv. Apps with arguments are run from the Python shell instead of by the firmware."
+        )]
+        args: Vec<String>,
+    },
+
+    #[structopt(
+        about = "Runs the Python code on the clipboard on the badge and prints its output until it finishes"
+    )]
+    PasteRun {
+        #[structopt(
+            long,
+            parse(try_from_str = duration::parse_duration),
+            help = "Give up if the code hasn't finished after this long, like 30s"
+        )]
+        timeout: Option<Duration>,
+    },
+
+    #[structopt(
+        about = "Interrupts the running app, runs Python code in the shell on the badge and prints its output, like `mpremote exec`"
+    )]
+    Exec {
+        code: String,
+
+        #[structopt(
+            long,
+            parse(try_from_str = duration::parse_duration),
+            default_value = "60s",
+            help = "How long the code may run"
+        )]
+        timeout: Duration,
+    },
+
+    #[structopt(
+        about = "Runs a script that types into the serial input of the badge and waits for its output, to go through menus and wizards"
+    )]
+    Expect {
+        #[structopt(parse(from_os_str))]
+        script: PathBuf,
+
+        #[structopt(short, long, help = "Don't print the output of the badge")]
+        quiet: bool,
+    },
+
+    #[structopt(
+        about = "Prints the local files that a path on the badge, or the files below it, were uploaded from"
+    )]
+    Which { path: BadgePath },
+
+    #[structopt(
+        about = "Prints the paths on the badge that a local file, or the files below it, were uploaded to"
+    )]
+    Where {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
     },
-    time::Duration,
-};
-use stream::Stream;
-use structopt::StructOpt;
-use termios::{tcsetattr, Termios, ECHO, ICANON, TCSANOW};
-use tokio::runtime::Runtime;
 
-mod cmds;
-mod device;
-mod fs;
-mod stream;
+    #[structopt(
+        about = "Types text into the serial input of the badge, followed by Enter, to answer the prompts of a running app"
+    )]
+    Send {
+        #[structopt(required_unless = "file")]
+        text: Option<String>,
 
-#[derive(StructOpt, Clone)]
-#[structopt(
-    name = "cz2020-usbtool",
-    about = "Communicate with the CampZone 2020 badge without using Chrome."
-)]
-enum Args {
-    #[structopt(about = "Lists all files available on the badge one-by-one")]
-    Tree,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            conflicts_with = "text",
+            help = "Send the bytes of this file as they are instead"
+        )]
+        file: Option<PathBuf>,
 
-    #[structopt(about = "Lists all files in the specified directory")]
-    Ls { path: String },
+        #[structopt(short = "n", long, help = "Don't press Enter after the text")]
+        no_newline: bool,
 
-    #[structopt(about = "Fetches the specified file")]
-    Get { path: String },
+        #[structopt(
+            short,
+            long,
+            help = "Interpret \\n, \\r, \\t, \\e, \\\\ and \\xHH in the text, like `echo -e`"
+        )]
+        escapes: bool,
+    },
 
-    #[structopt(about = "Writes stdin to the specified file")]
-    Set { path: String },
+    #[structopt(
+        about = "Opens the serial connection for the Python shell on the badge. Input from standard in is written to the device.",
+        alias = "repl"
+    )]
+    Shell {
+        #[structopt(long, help = TIMESTAMPS_HELP)]
+        timestamps: bool,
 
-    #[structopt(about = "Creates a new file")]
-    CreateFile { path: String },
+        #[structopt(
+            long,
+            default_value = "!",
+            help = "Lines that start with this prefix are run as commands of this tool, e.g. '!ls /flash/apps'. An empty prefix disables this."
+        )]
+        command_prefix: String,
 
-    #[structopt(about = "Creates a new directory")]
-    CreateDir { path: String },
+        #[structopt(
+            long,
+            help = "Exit the shell with Ctrl-C instead of sending a KeyboardInterrupt to the badge"
+        )]
+        no_forward_interrupt: bool,
 
-    #[structopt(about = "Deletes the specified path")]
-    Rm { path: String },
+        #[structopt(
+            long,
+            help = "Python code to run at the prompt of the badge when the terminal is resized, with {cols} and {rows} replaced by its size"
+        )]
+        resize_hook: Option<String>,
 
-    #[structopt(about = "Copies a file to another file")]
-    Cp { from: String, to: String },
+        #[structopt(
+            long,
+            help = "Show the local file that each frame of a traceback came from, for files uploaded from this computer"
+        )]
+        sources: bool,
+    },
 
-    #[structopt(about = "Moves a file from one location to another")]
-    Mv {
-        #[structopt(help = "The original file location")]
-        from: String,
+    #[structopt(
+        about = "Prints the output of the badge without interrupting or sending input to the running app"
+    )]
+    Monitor {
+        #[structopt(long, help = TIMESTAMPS_HELP)]
+        timestamps: bool,
 
-        #[structopt(about = "The new file location. The filename itself must be included.")]
-        to: String,
+        #[structopt(long, help = "Only print lines that match this regular expression")]
+        grep: Option<Regex>,
+
+        #[structopt(long, help = "Don't print lines that match this regular expression")]
+        exclude: Option<Regex>,
+
+        #[structopt(
+            long,
+            help = "Only print log lines of this level or more severe: error, warning, info, debug or verbose. Lines without a level count as info."
+        )]
+        level: Option<loglevel::Level>,
+
+        #[structopt(long, help = "Color log lines by their level")]
+        color_levels: bool,
+
+        #[structopt(
+            long,
+            help = "Print every line as its level, module and message, separated by tabs, with - for what's unknown"
+        )]
+        structured: bool,
+
+        #[structopt(
+            long,
+            help = "Write all output to this file, including lines that aren't printed"
+        )]
+        log: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            conflicts_with_all = &["grep", "exclude", "level", "color-levels", "structured"],
+            help = "Print the output as a hex dump, for apps that write binary data"
+        )]
+        hex: bool,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Share the connection on a Unix socket at this path, so other clients can use `attach` while monitoring continues"
+        )]
+        listen: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "Run this shell command when a line matches --on-output-match, with the line in $CZ2020_LINE"
+        )]
+        on_output: Option<String>,
+
+        #[structopt(
+            long,
+            requires = "on-output",
+            default_value = hooks::TRACEBACK,
+            help = "The regular expression for --on-output; by default the start of a traceback"
+        )]
+        on_output_match: String,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Run the commands of the [[hook]] tables in this file when lines match their patterns"
+        )]
+        hooks: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "Show the local file that each frame of a traceback came from, for files uploaded from this computer"
+        )]
+        sources: bool,
     },
 
-    #[structopt(about = "Runs an app")]
-    Run {
+    #[structopt(
+        about = "Prints the output of the badge for a while, or until it matches a pattern. Fails if the pattern didn't appear in time."
+    )]
+    Logdump {
         #[structopt(
-            about = "The path to the __init__.py file. Don't prefix the path with /flash."
+            long,
+            parse(try_from_str = duration::parse_duration),
+            required_unless = "until",
+            help = "How long to capture output, e.g. 500ms, 30s or 5m"
         )]
-        path: String,
+        duration: Option<Duration>,
+
+        #[structopt(
+            long,
+            help = "Stop successfully once a line matches this regular expression"
+        )]
+        until: Option<Regex>,
     },
 
     #[structopt(
-        about = "Opens the serial connection for the Python shell on the badge. Input from standard in is written to the device."
+        about = "Connects to the Python shell of a badge that another instance shares with `monitor --listen`"
     )]
-    Shell,
+    Attach {
+        #[structopt(parse(from_os_str))]
+        socket: PathBuf,
+    },
+
+    #[structopt(about = "Measures upload and download throughput and latency")]
+    Bench {
+        #[structopt(
+            long,
+            default_value = "/flash/bench.tmp",
+            help = "The file to use for the benchmark. It is overwritten and deleted afterwards."
+        )]
+        path: BadgePath,
+
+        #[structopt(
+            long,
+            use_delimiter = true,
+            default_value = "1024,16384,131072",
+            help = "Comma-separated payload sizes in bytes"
+        )]
+        sizes: Vec<usize>,
+
+        #[structopt(long, default_value = "5", help = "Transfers per size and direction")]
+        iterations: usize,
+    },
+
+    #[structopt(about = "Diagnoses problems with the connection to the badge")]
+    Doctor,
 
     #[structopt(about = "Mounts the filesystem of the badge to a directory using libfuse")]
-    Mount { path: String },
+    Mount {
+        path: String,
+
+        #[structopt(
+            long,
+            help = "Mount every connected badge in its own subdirectory of <path>, named after its serial number"
+        )]
+        all: bool,
+
+        #[structopt(
+            long,
+            help = "Keep running, mounting badges when they are plugged in and unmounting them when they are unplugged"
+        )]
+        watch: bool,
+
+        #[structopt(
+            long,
+            default_value = "1048576",
+            help = "Maximum number of bytes of serial output that are buffered until serial_out is read"
+        )]
+        serial_buffer: usize,
+
+        #[structopt(
+            long,
+            default_value = "drop-oldest",
+            help = "What to do when the serial output buffer is full: drop-oldest or block"
+        )]
+        serial_overflow: OverflowPolicy,
+    },
+}
+
+fn entry_kind(entry: &FsEntry) -> FileKind {
+    match entry {
+        FsEntry::File(_) => FileKind::File,
+        FsEntry::Directory(_) => FileKind::Directory,
+        FsEntry::Other(kind, _) => FileKind::Other(*kind),
+    }
+}
+
+/// Pushes `children` onto a depth-first `stack`, so they are visited in order of their names.
+/// Each one gets the `last` flags of its parent, followed by its own.
+fn push_children(
+    stack: &mut Vec<(BadgePath, FileKind, Vec<bool>)>,
+    mut children: Vec<(BadgePath, FileKind)>,
+    last: &[bool],
+) {
+    children.sort_by(|(a, _), (b, _)| a.file_name().cmp(b.file_name()));
+    let count = children.len();
+    for (i, (path, kind)) in children.into_iter().enumerate().rev() {
+        let mut last = last.to_vec();
+        last.push(i + 1 == count);
+        stack.push((path, kind, last));
+    }
 }
 
-pub async fn tree(badge: &Badge) -> Result<(), Box<dyn Error>> {
+pub async fn tree(client: &BadgeFs, lister: &Lister) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut stack = vec![
-        ("".to_owned(), FsEntry::Directory("flash".to_owned())),
-        ("".to_owned(), FsEntry::Directory("sd".to_owned())),
+        (BadgePath::new("/sd")?, FileKind::Directory, Vec::new()),
+        (BadgePath::new("/flash")?, FileKind::Directory, Vec::new()),
     ];
 
-    while let Some((base, entry)) = stack.pop() {
-        let new_base = format!("{}/{}", base, entry.name());
-        println!("{}", new_base);
-        match entry {
-            FsEntry::Directory(_) => {
-                let items = badge.fetch_dir(&new_base).await?;
-
-                if let DirectoryListingResponse::Found {
-                    requested: _,
-                    entries,
-                } = items
-                {
-                    stack.extend(entries.into_iter().map(|x| (new_base.clone(), x)));
+    while let Some((path, kind, last)) = stack.pop() {
+        lister.tree_entry(kind, &path, &last);
+        if kind == FileKind::Directory {
+            let mut children = Vec::new();
+            let mut entries = client.read_dir(path.clone());
+            loop {
+                match entries.next_entry().await {
+                    Ok(Some(entry)) => {
+                        children.push((path.join(entry.name())?, entry_kind(&entry)))
+                    }
+                    Ok(None) => break,
+                    Err(e) if e.downcast_ref::<ClientError>().is_some() => break,
+                    Err(e) => return Err(e),
                 }
             }
-            _ => {}
+            push_children(&mut stack, children, &last);
         }
     }
 
     Ok(())
 }
 
-static PRINT_STDOUT: AtomicBool = AtomicBool::new(false);
+/// Fetches `path` and writes it to stdout, or to a file in `output_dir`
+async fn get(
+    client: &BadgeFs,
+    badge: &Badge,
+    path: &BadgePath,
+    output_dir: Option<&Path>,
+    crlf: bool,
+    journal: Option<&Journal>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let (Some(dir), Some(journal)) = (output_dir, journal) {
+        let dest = dir.join(path.file_name());
+        let mut data = resume::download(badge, journal, path, &dest).await?;
+        if crlf {
+            data = text::to_crlf(&data);
+            std::fs::write(&dest, &data)?;
+        }
+        output.transferred(path, data.len());
+        return Ok(());
+    }
 
-fn main() {
-    env_logger::init();
+    let mut data = client.open(path.clone()).read_to_end().await?;
+    if crlf {
+        data = text::to_crlf(&data).into();
+    }
+
+    match output_dir {
+        Some(dir) => {
+            std::fs::write(dir.join(path.file_name()), &data)?;
+            output.transferred(path, data.len());
+        }
+        None => std::io::stdout().write_all(&data)?,
+    }
+
+    Ok(())
+}
 
-    let context = rusb::Context::new().unwrap();
-    let device = Device::new(&context).unwrap();
+/// Prints the last-known tree, without contacting the badge
+fn cached_tree(cache: &MetadataCache, lister: &Lister) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stack = vec![
+        (BadgePath::new("/sd")?, FileKind::Directory, Vec::new()),
+        (BadgePath::new("/flash")?, FileKind::Directory, Vec::new()),
+    ];
+    while let Some((path, kind, last)) = stack.pop() {
+        lister.tree_entry(kind, &path, &last);
+        let children = cache
+            .children(&path)
+            .into_iter()
+            .map(|(path, entry)| (path, entry.kind))
+            .collect();
+        push_children(&mut stack, children, &last);
+    }
 
-    let badge = Arc::new(Badge::new(device));
-    let b2 = badge.clone();
-    let b3 = badge.clone();
-    let io = Stream::new();
-    let ioref = &io;
+    Ok(())
+}
 
-    scope(|s| {
-        let j = s.spawn(move |_| {
-            b2.run(|text| {
+/// Copies everything the badge prints to `io`, until the badge is closed
+async fn forward_output(mut output: broadcast::Receiver<String>, io: Arc<Stream>) {
+    loop {
+        match output.recv().await {
+            Ok(text) => {
                 // replace().replace() to fix missing '\r's from some of the output, but not all
-                ioref.write(text.replace("\r\n", "\n").replace("\n", "\r\n").as_bytes());
+                io.write(text.replace("\r\n", "\n").replace("\n", "\r\n").as_bytes());
+            }
+            Err(RecvError::Lagged(n)) => warn!("Dropped {} messages of badge output", n),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Lets other invocations use the badge while it is mounted. Failing to do so doesn't stop the mount.
+fn share(badge: &Arc<Badge>) -> Option<share::Share> {
+    share::Share::start(badge.clone())
+        .map_err(|e| warn!("Unable to share the badge with other commands: {}", e))
+        .ok()
+}
+
+/// Mounts `badge` at `dir` until it is unplugged, or closed otherwise
+fn mount_until_unplugged(badge: Arc<Badge>, io: Arc<Stream>, dir: &Path) -> std::io::Result<()> {
+    let _share = share(&badge);
+    let mut output = badge.subscribe();
+    let session = unsafe { fuse::spawn_mount(AppFS::new(badge.clone(), io), &dir, &[])? };
+    info!("Mounted badge {} at {:?}", badge.device_info().id(), dir);
+    notify::notify(
+        "Badge connected",
+        &format!("Mounted {} at {}", badge.device_info().id(), dir.display()),
+    );
+
+    // The output only closes when the badge goes away
+    let mut rt = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .build()
+        .unwrap();
+    while !matches!(rt.block_on(output.recv()), Err(RecvError::Closed)) {}
+
+    drop(session);
+    info!(
+        "Unmounted badge {} from {:?}",
+        badge.device_info().id(),
+        dir
+    );
+    notify::notify(
+        "Badge disconnected",
+        &format!(
+            "Unmounted {} from {}",
+            badge.device_info().id(),
+            dir.display()
+        ),
+    );
+    Ok(())
+}
+
+/// `mount --watch`: mounts badges as they are plugged in, at `path` or with `all` in a subdirectory
+/// per badge, and unmounts each one when it is unplugged. Runs until the process is stopped.
+fn watch_mounts(
+    context: &rusb::Context,
+    opts: &Opts,
+    path: &Path,
+    all: bool,
+    io: impl Fn() -> Stream,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // By port, as the badge may not have a serial number
+    let mounted = Arc::new(Mutex::new(HashSet::new()));
+    loop {
+        let connected = Device::list(context).unwrap_or_else(|e| {
+            debug!("Unable to list badges: {}", e);
+            Vec::new()
+        });
+        for info in connected {
+            let port = info.port();
+            let busy = {
+                let mounted = mounted.lock().unwrap();
+                // Without `all`, only one badge is mounted at a time
+                mounted.contains(&port) || (!all && !mounted.is_empty())
+            };
+            if busy {
+                continue;
+            }
+
+            let device = match Device::find(context, Some(&port)) {
+                Ok(device) => device,
+                Err(e) => {
+                    // Most likely still starting up, or in use by another process
+                    debug!("Not mounting badge {} yet: {}", port, e);
+                    continue;
+                }
+            };
+            let dir = if all {
+                path.join(info.id())
+            } else {
+                path.to_owned()
+            };
+            std::fs::create_dir_all(&dir)?;
 
-                if PRINT_STDOUT.load(Ordering::Relaxed) {
-                    print!("{}", text);
-                    std::io::stdout().flush().unwrap();
+            mounted.lock().unwrap().insert(port.clone());
+            let (builder, io, mounted) = (opts.badge_builder(), io(), mounted.clone());
+            std::thread::spawn(move || {
+                let result = with_badge(builder, device, io, |badge, io| {
+                    mount_until_unplugged(badge, io, &dir)
+                });
+                if let Err(e) = result {
+                    error!("Unable to mount badge {} at {:?}: {}", port, dir, e);
                 }
+                mounted.lock().unwrap().remove(&port);
             });
-        });
+        }
+
+        std::thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Traceback highlighting for `shell` and `monitor`, with the local files of frames if `sources`
+fn tracebacks(sources: bool) -> traceback::Tracebacks {
+    let tracebacks = traceback::Tracebacks::new();
+    if sources {
+        tracebacks.sources(sourcemap::SourceMap::load())
+    } else {
+        tracebacks
+    }
+}
+
+/// Connects to `device` for as long as `f` is running, then shuts the connection down
+fn with_badge<R, F: FnOnce(Arc<Badge>, Arc<Stream>) -> R>(
+    builder: BadgeBuilder,
+    device: Device,
+    io: Stream,
+    f: F,
+) -> R {
+    let badge = builder.build(device).start();
+    let io = Arc::new(io);
+    let output = badge.subscribe();
+    let io2 = io.clone();
+
+    let k = std::thread::spawn(move || {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(forward_output(output, io2));
+    });
+
+    let result = f(badge.clone(), io.clone());
+
+    info!("Terminating threads...");
+    badge.close();
+    io.close();
+    k.join().unwrap();
+
+    result
+}
+
+/// Logs to stderr, filtered by `RUST_LOG`, and optionally writes spans and events as JSON to
+/// `trace_file`. Without `RUST_LOG`, only errors are printed but the trace still includes debug events.
+fn init_tracing(trace_file: Option<&Path>) -> std::io::Result<()> {
+    let (filter, stderr_level) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, Level::TRACE),
+        Err(_) if trace_file.is_some() => {
+            (EnvFilter::new("error,cz2020_usbtool=debug"), Level::ERROR)
+        }
+        Err(_) => (EnvFilter::new("error"), Level::ERROR),
+    };
+    let json = match trace_file {
+        Some(path) => Some(
+            fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(Arc::new(File::create(path)?)),
+        ),
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr.with_max_level(stderr_level)))
+        .with(json)
+        .init();
+
+    Ok(())
+}
+
+fn main() {
+    let opts = match Opts::from_iter_safe(std::env::args_os()) {
+        Ok(opts) => opts,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            ExitCode::Usage.exit()
+        }
+        // --help and --version
+        Err(e) => e.exit(),
+    };
+    let output = opts.output();
+    if let Err(e) = init_tracing(opts.trace_file.as_deref()) {
+        exit::fail(output, &e);
+    }
+
+    lock::set_policy(opts.lock_policy());
+    if opts.notify {
+        notify::enable();
+    }
+    if let Some(path) = &opts.latency_trace {
+        if let Err(e) = latency::start(path) {
+            exit::fail(output, &e);
+        }
+    }
+    let context = rusb::Context::new().unwrap_or_else(|e| exit::fail(output, &e));
+    if opts.all_devices {
+        let result = match &opts.cmd {
+            // These need the terminal, or use a single badge by design
+            Args::Mount { .. } => Err(FleetError::Unsupported("mount").into()),
+            Args::Shell { .. } => Err(FleetError::Unsupported("shell").into()),
+            Args::Monitor { .. } => Err(FleetError::Unsupported("monitor").into()),
+            Args::Attach { .. } => Err(FleetError::Unsupported("attach").into()),
+            Args::Set { .. } => Err(FleetError::Unsupported("set").into()),
+            Args::Edit { .. } => Err(FleetError::Unsupported("edit").into()),
+            Args::Snapshot { .. } => Err(FleetError::Unsupported("snapshot").into()),
+            Args::New { .. } => Err(FleetError::Unsupported("new").into()),
+            Args::Doctor => Err(FleetError::Unsupported("doctor").into()),
+            Args::Which { .. } => Err(FleetError::Unsupported("which").into()),
+            Args::Where { .. } => Err(FleetError::Unsupported("where").into()),
+            _ => fleet::run_all(&context, output),
+        };
+        if let Err(e) = result {
+            exit::fail(output, &*e);
+        }
+        return;
+    }
+
+    let device = opts.device.as_deref();
+    let result = match opts.cmd.clone() {
+        Args::Mount {
+            path,
+            all,
+            watch: true,
+            serial_buffer,
+            serial_overflow,
+        } => watch_mounts(&context, &opts, Path::new(&path), all, || {
+            Stream::with_capacity(serial_buffer, serial_overflow)
+        }),
+        Args::Mount {
+            path,
+            all: true,
+            watch: false,
+            serial_buffer,
+            serial_overflow,
+        } => {
+            let devices = Device::all(&context).unwrap_or_else(|e| exit::fail(output, &e));
 
-        let args = Args::from_args();
-        match args {
-            Args::Mount { path } => {
-                fuse::mount(AppFS::new(badge, &io), &path, &[]).unwrap();
+            scope(|s| {
+                let mut threads = Vec::new();
+                for device in devices {
+                    let builder = opts.badge_builder();
+                    let dir = Path::new(&path).join(device.info().id());
+                    info!("Mounting badge {} at {:?}", device.info().id(), dir);
+                    std::fs::create_dir_all(&dir)?;
+
+                    threads.push(s.spawn(move |_| {
+                        let io = Stream::with_capacity(serial_buffer, serial_overflow);
+                        with_badge(builder, device, io, |badge, io| {
+                            let _share = share(&badge);
+                            fuse::mount(AppFS::new(badge, io), &dir, &[])
+                        })
+                    }));
+                }
+
+                threads
+                    .into_iter()
+                    .try_for_each(|thread| thread.join().unwrap())
+            })
+            .unwrap()
+            .map_err(|e| e.into())
+        }
+        Args::Mount {
+            path,
+            all: false,
+            watch: false,
+            serial_buffer,
+            serial_overflow,
+        } => {
+            let io = Stream::with_capacity(serial_buffer, serial_overflow);
+            with_badge(
+                opts.badge_builder(),
+                Device::find(&context, device).unwrap_or_else(|e| exit::fail(output, &e)),
+                io,
+                |badge, io| {
+                    let _share = share(&badge);
+                    fuse::mount(AppFS::new(badge, io), &path, &[])
+                },
+            )
+            .map_err(|e| e.into())
+        }
+        Args::Shell {
+            timestamps,
+            command_prefix,
+            no_forward_interrupt,
+            resize_hook,
+            sources,
+        } => {
+            let mut renderer = Renderer::new().tracebacks(tracebacks(sources));
+            if timestamps {
+                renderer = renderer.timestamps();
+            }
+            let interactive = shell::Interactive {
+                commands: Some(shell::LocalCommands {
+                    prefix: command_prefix,
+                    run: Box::new(move |words, badge| {
+                        Box::pin(local_command(words, badge, output))
+                    }),
+                }),
+                forward_interrupt: !no_forward_interrupt,
+                resize_hook,
+            };
+
+            shell::shell(
+                &context,
+                device,
+                opts.badge_builder(),
+                Some(interactive),
+                renderer,
+                None,
+            )
+        }
+        Args::Monitor {
+            timestamps,
+            grep,
+            exclude,
+            level,
+            color_levels,
+            structured,
+            log,
+            hex,
+            listen,
+            on_output,
+            on_output_match,
+            hooks: hooks_file,
+            sources,
+        } => {
+            let mut renderer = Renderer::new();
+            if let Some(log) = log {
+                renderer =
+                    renderer.log(File::create(&log).unwrap_or_else(|e| exit::fail(output, &e)));
+            }
+            let mut all_hooks = match hooks_file {
+                Some(file) => hooks::load(&file).unwrap_or_else(|e| exit::fail(output, &*e)),
+                None => Vec::new(),
+            };
+            if let Some(command) = on_output {
+                all_hooks.push(
+                    hooks::Hook::new(&on_output_match, &command)
+                        .unwrap_or_else(|e| exit::fail(output, &e)),
+                );
+            }
+            if !all_hooks.is_empty() {
+                renderer = renderer.hooks(hooks::Hooks::new(all_hooks));
+            }
+            if hex {
+                renderer = renderer.hex();
+            } else if grep.is_some()
+                || exclude.is_some()
+                || level.is_some()
+                || color_levels
+                || structured
+            {
+                let mut filter = LineFilter::new(grep, exclude).max_level(level);
+                if color_levels {
+                    filter = filter.colors();
+                }
+                if structured {
+                    filter = filter.structured();
+                }
+                renderer = renderer.filter(filter);
+            }
+            if !hex && !structured {
+                renderer = renderer.tracebacks(tracebacks(sources));
+            }
+            if timestamps {
+                renderer = renderer.timestamps();
             }
-            args => {
+
+            shell::shell(
+                &context,
+                device,
+                opts.badge_builder(),
+                None,
+                renderer,
+                listen.as_deref(),
+            )
+        }
+        Args::Attach { socket } => mux::attach(&socket),
+        Args::New {
+            name,
+            dir,
+            push: false,
+        } => scaffold::create(&name, dir.as_deref()).map(|_| ()),
+        Args::Which { path } => sourcemap::uploaded_from(&path),
+        Args::Where { path } => sourcemap::uploaded_to(&path),
+        Args::Logdump { duration, until } => with_badge(
+            opts.badge_builder(),
+            Device::find(&context, device).unwrap_or_else(|e| exit::fail(output, &e)),
+            Stream::new(),
+            |badge, _| {
                 let mut rt = Runtime::new().unwrap();
-                rt.block_on(async {
-                    run(args, badge).await.unwrap();
-                });
+                let result = rt.block_on(logdump::logdump(&badge, duration, until));
+                if opts.stats {
+                    eprint!("{}", badge.metrics());
+                }
+                result
+            },
+        ),
+        Args::Doctor => {
+            if !doctor::doctor(&context, opts.badge_builder()) {
+                ExitCode::Failure.exit();
             }
+
+            Ok(())
         }
+        args => with_badge(
+            opts.badge_builder(),
+            Device::find(&context, device).unwrap_or_else(|e| exit::fail(output, &e)),
+            Stream::new(),
+            |badge, _| {
+                let mut rt = Runtime::new().unwrap();
+                let result = rt.block_on(run(args, badge.clone(), output));
+                if opts.stats {
+                    eprint!("{}", badge.metrics());
+                }
+                result
+            },
+        ),
+    };
 
-        info!("Terminating threads...");
-        b3.close();
-        j.join().unwrap();
-    })
-    .unwrap();
+    if let Err(e) = result {
+        exit::fail(output, &*e);
+    }
 }
 
-async fn run<'a>(args: Args, badge: Arc<Badge>) -> Result<(), Box<dyn Error>> {
+async fn run(
+    args: Args,
+    badge: Arc<Badge>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     badge.heartbeat().await?;
-
+    badge.probe().await;
     std::thread::sleep(Duration::from_millis(500));
 
+    command(args, badge, output).await
+}
+
+/// Runs a command typed in the shell, which already has a connection to the badge
+async fn local_command(
+    words: Vec<String>,
+    badge: Arc<Badge>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let name = words[0].clone();
+    match Args::from_iter_safe(std::iter::once(String::new()).chain(words))? {
+        // These need the terminal or stdin, which the shell is using
+        Args::Mount { .. }
+        | Args::Doctor
+        | Args::Shell { .. }
+        | Args::Monitor { .. }
+        | Args::Attach { .. }
+        | Args::Logdump { .. }
+        | Args::Set { .. }
+        | Args::Edit { .. } => Err(shell::ShellError::UnsupportedCommand(name))?,
+        args => command(args, badge, output).await,
+    }
+}
+
+async fn command(
+    args: Args,
+    badge: Arc<Badge>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cache = Arc::new(MetadataCache::load(&badge.device_info().id()));
+    let client = BadgeFs::new(badge.clone()).with_cache(cache.clone());
+
     match args {
-        Args::Ls { path } => {
-            let entries = badge.fetch_dir(path).await?;
-            if let DirectoryListingResponse::Found {
-                requested: _,
-                entries,
-            } = entries
-            {
-                for entry in entries {
-                    println!("{}", entry.name());
+        Args::Ls {
+            path,
+            cached: true,
+            no_color,
+        } => {
+            let lister = Lister::new(output, no_color);
+            let children = cache.children(&path);
+            for (child, entry) in children.iter() {
+                lister.entry(entry.kind, child.file_name());
+            }
+
+            if children.is_empty() {
+                match cache.get(&path) {
+                    Some(entry) if entry.kind == FileKind::File => {
+                        lister.entry(entry.kind, path.as_str())
+                    }
+                    Some(_) => {}
+                    None => Err(ClientError::NotFound(path))?,
                 }
-            } else {
-                println!("Unable to load directory");
             }
         }
-        Args::Tree => tree(&badge).await?,
-        Args::Get { path } => std::io::stdout().write_all(&badge.fetch_file(path).await?)?,
-        Args::Set { path } => {
-            let mut data = Vec::new();
-            std::io::stdin().lock().read_to_end(&mut data)?;
-            badge.write_file(path, data).await?;
+        Args::Ls {
+            path,
+            cached: false,
+            no_color,
+        } => {
+            let lister = Lister::new(output, no_color);
+            let mut entries = client.read_dir(path.clone());
+            loop {
+                match entries.next_entry().await {
+                    Ok(Some(entry)) => lister.entry(entry_kind(&entry), entry.name()),
+                    Ok(None) => break,
+                    Err(e) => {
+                        match client.metadata(path.clone()).await {
+                            Ok(metadata) if metadata.is_file() => {
+                                lister.entry(FileKind::File, path.as_str())
+                            }
+                            _ => return Err(e),
+                        }
+
+                        break;
+                    }
+                }
+            }
         }
-        Args::CreateFile { path } => badge.create_file(path).await?,
-        Args::CreateDir { path } => badge.create_dir(path).await?,
-        Args::Rm { path } => badge.delete_path(path).await?,
-        Args::Cp { from, to } => badge.copy_file(from, to).await?,
-        Args::Mv { from, to } => badge.move_file(from, to).await?,
-        Args::Run { path } => {
-            if path.starts_with("/flash") {
-                warn!("You should use the run command without `/flash` prefix. I.e. instead of `run /flash/apps/synthesizer/__init__.py` do `run /apps/synthesizer/__init__.py`");
+        Args::Tree {
+            cached: true,
+            no_color,
+        } => cached_tree(&cache, &Lister::new(output, no_color))?,
+        Args::Tree {
+            cached: false,
+            no_color,
+        } => tree(&client, &Lister::new(output, no_color)).await?,
+        Args::Get {
+            paths, tar: true, ..
+        } => archive::write_tar(&client, &paths, BufWriter::new(std::io::stdout())).await?,
+        Args::Get {
+            paths,
+            output_dir,
+            crlf,
+            resume,
+            tar: false,
+        } => {
+            let journal = resume.then(|| Journal::load(&badge.device_info().id()));
+            let mut first_error = None;
+            let mut failed = 0;
+            for path in paths.iter() {
+                let result = get(
+                    &client,
+                    &badge,
+                    path,
+                    output_dir.as_deref(),
+                    crlf,
+                    journal.as_ref(),
+                    output,
+                )
+                .await;
+                if let Err(e) = result {
+                    let code = exit::exit_code(&*e);
+                    output.file_error(path, code, &*e);
+                    first_error.get_or_insert(code);
+                    failed += 1;
+                }
             }
 
-            badge.run_file(path).await?
+            if let Some(code) = first_error {
+                Err(PartialFailure {
+                    failed,
+                    total: paths.len(),
+                    code,
+                })?
+            }
         }
-        Args::Shell => {
-            PRINT_STDOUT.store(true, Ordering::Relaxed);
-
-            // Send a Control + C to terminate any previous command that might have been running
-            badge.serial_in("\u{003}".as_bytes()).await?;
+        Args::Edit { path, yes } => edit::edit(&badge, &client, &cache, &path, yes).await?,
+        Args::Snapshot { path, out_dir } => {
+            snapshot::snapshot(&badge, &client, &path, &out_dir, output).await?
+        }
+        Args::Du { path, max_depth } => du::du(&badge, &client, &path, max_depth, output).await?,
+        Args::Find {
+            path,
+            name,
+            kind,
+            min_size,
+            max_size,
+        } => {
+            let filter = find::Filter {
+                name,
+                kind,
+                min_size,
+                max_size,
+            };
+            find::find(&badge, &client, &path, &filter, output).await?
+        }
+        Args::Grep {
+            pattern,
+            path,
+            include,
+            ignore_case,
+            cached,
+        } => {
+            let search = grep::Search::new(&pattern, ignore_case, include)?;
+            grep::grep(&badge, &client, &path, &search, cached, output).await?
+        }
+        Args::Firmware { command } => firmware::command(&badge, command).await?,
+        Args::Head { path, lines } => {
+            std::io::stdout().write_all(&ranged::head(&badge, &path, lines).await?)?
+        }
+        Args::Tail { path, lines } => {
+            std::io::stdout().write_all(&ranged::tail(&badge, &path, lines).await?)?
+        }
+        Args::Set {
+            path,
+            delta,
+            text,
+            append,
+            atomic,
+            force,
+        } => {
+            let mut data = Vec::new();
+            std::io::stdin().lock().read_to_end(&mut data)?;
+            if text {
+                data = text::to_lf(&data);
+            }
 
-            let mut buf = [0u8; 1];
-            let stdin = libc::STDIN_FILENO;
+            space::check(&badge, &[(path.clone(), data.len() as u64)], !append, force).await?;
 
-            let mut termios = Termios::from_fd(stdin).unwrap();
-            // Make sure the terminal doesn't print keys and that we can read keys one-by-one
-            termios.c_lflag &= !(ICANON | ECHO);
-            tcsetattr(stdin, TCSANOW, &mut termios).unwrap();
-            let mut reader = std::io::stdin();
+            if append {
+                repl::append(&badge, &path, &data).await?;
+                cache.remove(&path);
+            } else if delta {
+                delta::write_file(&badge, &path, &data).await?;
+                cache.record_file(&path, &data);
+            } else if atomic {
+                atomic::write_file(&badge, &path, &data).await?;
+                cache.record_file(&path, &data);
+            } else {
+                badge.write_file(&path, &data).await?;
+                cache.record_file(&path, &data);
+            }
+        }
+        Args::Push {
+            local,
+            remote,
+            exclude,
+            text,
+            resume,
+            atomic,
+            force,
+            since,
+        } => {
+            let changes = since
+                .map(|since| git::changes_since(&local, &since))
+                .transpose()?;
+            let mut rules = ExcludeRules::new();
+            rules.add_file(&local.join(ignore::IGNORE_FILE))?;
+            for pattern in exclude.iter() {
+                rules.add(pattern);
+            }
 
-            while let Ok(_) = reader.read_exact(&mut buf) {
-                if buf[0] == '\n' as u8 {
-                    badge.serial_in("\r\n".as_bytes()).await?;
-                } else {
-                    badge.serial_in(&buf).await?;
+            // Sizes before converting line endings, which only makes files smaller
+            let mut entries = push::walk(&local, &rules)?;
+            if let Some(changes) = &changes {
+                push::retain_changed(&mut entries, changes);
+            }
+            let mut files = Vec::new();
+            for entry in entries {
+                if !entry.is_dir {
+                    let len = entry.path.metadata()?.len();
+                    files.push((remote.join(&entry.relative)?, len));
                 }
             }
+            space::check(&badge, &files, true, force).await?;
+
+            let journal = resume.then(|| Journal::load(&badge.device_info().id()));
+            push::push(
+                &badge,
+                &cache,
+                &local,
+                &remote,
+                &rules,
+                text,
+                journal.as_ref(),
+                atomic,
+                changes.as_ref(),
+                output,
+            )
+            .await?;
+        }
+        Args::Manifest { path, output: file } => {
+            let manifest = mirror::manifest(&badge, &client, &path).await?;
+            match file {
+                Some(file) => std::fs::write(file, manifest)?,
+                None => print!("{}", manifest),
+            }
+        }
+        Args::Verify {
+            manifest,
+            allow_extra,
+        } => mirror::verify(&badge, &client, &manifest, allow_extra, output).await?,
+        Args::Install { file, name } => {
+            install::install(&badge, &cache, &file, name, output).await?
+        }
+        Args::New { name, dir, push } => {
+            let dir = scaffold::create(&name, dir.as_deref())?;
+            if push {
+                let remote = BadgePath::new("/flash/apps")?.join(&name)?;
+                let rules = ExcludeRules::new();
+                push::push(
+                    &badge, &cache, &dir, &remote, &rules, false, None, false, None, output,
+                )
+                .await?;
+            }
         }
-        Args::Mount { path: _ } => unreachable!("Handled in main()"),
+        Args::Lint { local, name } => lint::lint(&badge, &local, name, output).await?,
+        Args::Provision { manifest } => {
+            let manifest = provision::Manifest::load(&manifest)?;
+            provision::provision(&badge, &cache, &manifest, output).await?
+        }
+        Args::Test {
+            local,
+            name,
+            timeout,
+        } => testrun::test(&badge, &cache, &local, name, timeout, output).await?,
+        Args::CreateFile { path } => badge.create_file(path).await?,
+        Args::CreateDir { path } => badge.create_dir(path).await?,
+        Args::Rm { path, trash: false } => {
+            badge.delete_path(&path).await?;
+            cache.remove(&path);
+        }
+        Args::Rm { path, trash: true } => {
+            trash::trash(&badge, &client, &path).await?;
+            cache.remove(&path);
+        }
+        Args::Trash { command } => trash::command(&badge, &client, &cache, command, output).await?,
+        Args::Cp {
+            from,
+            to,
+            no_clobber,
+            force,
+        } => {
+            let overwrite = Overwrite::from_flags(no_clobber, force);
+            if overwrite::prepare(&badge, &client, &to, overwrite).await? {
+                badge.copy_file(&from, &to).await?;
+                cache.remove(&to);
+            }
+        }
+        Args::Mv {
+            from,
+            to,
+            no_clobber,
+            force,
+        } => {
+            let overwrite = Overwrite::from_flags(no_clobber, force);
+            if overwrite::prepare(&badge, &client, &to, overwrite).await? {
+                badge.move_file(&from, &to).await?;
+                cache.remove(&from);
+                cache.remove(&to);
+            }
+        }
+        Args::Fs { command } => mpremote::command(&badge, &client, &cache, command, output).await?,
+        Args::Config { command } => config::command(&badge, command, output).await?,
+        Args::Display { command } => display::command(&badge, command).await?,
+        Args::Screenshot {
+            output: file,
+            scale,
+        } => {
+            let screen = screenshot::capture(&badge).await?;
+            std::fs::write(&file, screen.to_png(scale.max(1))?)?;
+        }
+        Args::Status => status::status(&badge, output).await?,
+        Args::Stop { reset } => status::stop(&badge, reset).await?,
+        Args::Run { path, args } => run::run(&badge, &path, &args).await?,
+        Args::Send {
+            file: Some(file), ..
+        } => badge.serial_in(std::fs::read(file)?).await?,
+        Args::Send {
+            text: Some(text),
+            no_newline,
+            escapes,
+            ..
+        } => {
+            let text = if escapes {
+                send::unescape(&text)?
+            } else {
+                text.into_bytes()
+            };
+            send::send_text(&badge, &text, !no_newline).await?
+        }
+        Args::Send { .. } => unreachable!("Either text or --file is required"),
+        Args::Expect { script, quiet } => {
+            expect::run(&badge, &expect::Script::load(&script)?, quiet).await?
+        }
+        Args::PasteRun { timeout } => paste::paste_run(&badge, timeout).await?,
+        Args::Exec { code, timeout } => mpremote::exec(&badge, &code, timeout).await?,
+        Args::Bench {
+            path,
+            sizes,
+            iterations,
+        } => bench::bench(&badge, &path, &sizes, iterations, output).await?,
+        Args::Which { path } => sourcemap::uploaded_from(&path)?,
+        Args::Where { path } => sourcemap::uploaded_to(&path)?,
+        Args::Mount { .. }
+        | Args::Doctor
+        | Args::Shell { .. }
+        | Args::Monitor { .. }
+        | Args::Attach { .. }
+        | Args::Logdump { .. } => {
+            unreachable!("Handled in main() and local_command()")
+        }
+    }
+
+    if let Err(e) = cache.save() {
+        warn!("Unable to save the metadata cache: {}", e);
     }
 
     Ok(())
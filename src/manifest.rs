@@ -0,0 +1,254 @@
+//! A parser for the subset of TOML used by manifests: `key = value` pairs with strings, integers,
+//! booleans and single-line arrays, `[table]` and `[a.b]` headers and `[[array]]` tables.
+//! Dotted keys and inline tables aren't supported.
+
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("Line {}: {}", .0, .1)]
+    Syntax(usize, String),
+
+    #[error("{} must be {}", .0, .1)]
+    InvalidValue(String, &'static str),
+
+    #[error("{} is missing", .0)]
+    Missing(String),
+
+    #[error("Unknown key or table {}", .0)]
+    Unknown(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+pub type Table = BTreeMap<String, Value>;
+
+/// A parsed document. Tables are stored by their full header, e.g. `settings.owner`.
+#[derive(Debug, Default)]
+pub struct Document {
+    pub root: Table,
+    pub tables: BTreeMap<String, Table>,
+    pub arrays: BTreeMap<String, Vec<Table>>,
+}
+
+enum Section {
+    Root,
+    Table(String),
+    Array(String),
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Parses a header name like `settings.owner`, where parts may be quoted
+fn parse_header(header: &str) -> Option<String> {
+    let parts = header
+        .split('.')
+        .map(|part| {
+            let part = part.trim();
+            if is_bare_key(part) {
+                Some(part.to_owned())
+            } else {
+                match parse_string(part) {
+                    Some((key, "")) => Some(key),
+                    _ => None,
+                }
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(parts.join("."))
+}
+
+/// Parses a string at the start of `s`, returning it and the rest of `s`
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.char_indices();
+    let quote = match chars.next()? {
+        (_, c @ '"') | (_, c @ '\'') => c,
+        _ => return None,
+    };
+
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Some((value, &s[i + 1..])),
+            // Literal strings don't have escapes
+            '\\' if quote == '"' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                '\\' => value.push('\\'),
+                '"' => value.push('"'),
+                _ => return None,
+            },
+            c => value.push(c),
+        }
+    }
+
+    None
+}
+
+/// Parses a value at the start of `s`, returning it and the rest of `s`
+fn parse_value(s: &str) -> Option<(Value, &str)> {
+    let s = s.trim_start();
+    if s.starts_with('"') || s.starts_with('\'') {
+        let (string, rest) = parse_string(s)?;
+        return Some((Value::String(string), rest));
+    }
+
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Some((Value::Array(values), rest));
+            }
+
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return None;
+            }
+        }
+    }
+
+    let end = s
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let value = match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        word => Value::Integer(word.replace('_', "").parse().ok()?),
+    };
+
+    Some((value, rest))
+}
+
+/// Parses a `key = value` line
+fn parse_pair(line: &str) -> Option<(String, Value)> {
+    let (key, rest) = if line.starts_with('"') || line.starts_with('\'') {
+        parse_string(line)?
+    } else {
+        let end = line.find(|c: char| c == '=' || c.is_whitespace())?;
+        let key = &line[..end];
+        if !is_bare_key(key) {
+            return None;
+        }
+        (key.to_owned(), &line[end..])
+    };
+
+    let rest = rest.trim_start().strip_prefix('=')?;
+    let (value, rest) = parse_value(rest)?;
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return None;
+    }
+
+    Some((key, value))
+}
+
+impl Document {
+    pub fn parse(text: &str) -> Result<Document, ManifestError> {
+        let mut document = Document::default();
+        let mut section = Section::Root;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: &str| ManifestError::Syntax(index + 1, message.to_owned());
+            if let Some(header) = line.strip_prefix("[[") {
+                let name = header
+                    .split("]]")
+                    .next()
+                    .and_then(parse_header)
+                    .ok_or_else(|| error("invalid array table header"))?;
+                document
+                    .arrays
+                    .entry(name.clone())
+                    .or_default()
+                    .push(Table::new());
+                section = Section::Array(name);
+            } else if let Some(header) = line.strip_prefix('[') {
+                let name = header
+                    .split(']')
+                    .next()
+                    .and_then(parse_header)
+                    .ok_or_else(|| error("invalid table header"))?;
+                document.tables.entry(name.clone()).or_default();
+                section = Section::Table(name);
+            } else {
+                let (key, value) = parse_pair(line).ok_or_else(|| {
+                    error("expected key = value, with a string, number, boolean or array")
+                })?;
+                let table = match &section {
+                    Section::Root => &mut document.root,
+                    Section::Table(name) => document.tables.get_mut(name).unwrap(),
+                    Section::Array(name) => document
+                        .arrays
+                        .get_mut(name)
+                        .and_then(|tables| tables.last_mut())
+                        .unwrap(),
+                };
+                if table.insert(key.clone(), value).is_some() {
+                    return Err(error(&format!("duplicate key {}", key)));
+                }
+            }
+        }
+
+        Ok(document)
+    }
+
+    /// The tables whose header starts with `prefix.`, by the rest of their header
+    pub fn subtables(&self, prefix: &str) -> Vec<(&str, &Table)> {
+        self.tables
+            .iter()
+            .filter_map(|(name, table)| {
+                name.strip_prefix(prefix)
+                    .and_then(|name| name.strip_prefix('.'))
+                    .map(|name| (name, table))
+            })
+            .collect()
+    }
+}
+
+/// The string `key` of `table`, where `context` describes the table in errors
+pub fn get_str<'a>(
+    table: &'a Table,
+    key: &str,
+    context: &str,
+) -> Result<Option<&'a str>, ManifestError> {
+    match table.get(key) {
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(ManifestError::InvalidValue(
+            format!("{}.{}", context, key),
+            "a string",
+        )),
+        None => Ok(None),
+    }
+}
+
+pub fn require_str<'a>(
+    table: &'a Table,
+    key: &str,
+    context: &str,
+) -> Result<&'a str, ManifestError> {
+    get_str(table, key, context)?
+        .ok_or_else(|| ManifestError::Missing(format!("{}.{}", context, key)))
+}
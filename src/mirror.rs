@@ -0,0 +1,374 @@
+//! `manifest` records the path, size and SHA-256 of every file below a directory as JSON, and
+//! `verify` checks a badge against such a record, to make sure a provisioned badge still matches
+//! the golden image:
+//!
+//! ```json
+//! {"version": 1, "root": "/flash", "files": [{"path": "/flash/boot.py", "size": 139, "sha256": "..."}]}
+//! ```
+
+use crate::{
+    cache,
+    client::{BadgeFs, ClientError},
+    cmds::FsEntry,
+    device::Badge,
+    output::Output,
+    path::BadgePath,
+    repl::{self, python_literal},
+};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, error::Error, path::Path, time::Duration};
+use thiserror::Error;
+use tracing::info;
+
+const VERSION: u64 = 1;
+
+/// Hashing every file of a full flash takes a while
+const REPL_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Error, Debug)]
+pub enum MirrorError {
+    #[error("Invalid manifest: {}", .0)]
+    Invalid(String),
+
+    #[error("Manifest version {} isn't supported", .0)]
+    UnsupportedVersion(u64),
+
+    #[error("{} files don't match the manifest", .0)]
+    Mismatch(usize),
+}
+
+/// The size and hash of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The files below `root`, hashed on the badge itself
+async fn files_via_repl(
+    badge: &Badge,
+    root: &BadgePath,
+) -> Result<BTreeMap<BadgePath, FileInfo>, Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import uos, uhashlib, ubinascii
+def h(p):
+ x = uhashlib.sha256()
+ f = open(p, 'rb')
+ while True:
+  b = f.read(4096)
+  if not b:
+   break
+  x.update(b)
+ f.close()
+ return ubinascii.hexlify(x.digest()).decode()
+def p(c):
+ print('%d\\t%s\\t%s' % (uos.stat(c)[6], h(c), c))
+def w(d):
+ for e in uos.ilistdir(d):
+  if e[1] == 0x4000:
+   w(d + '/' + e[0])
+  else:
+   p(d + '/' + e[0])
+r = {}
+if uos.stat(r)[0] & 0x4000:
+ w(r)
+else:
+ p(r)",
+        python_literal(root.as_str())
+    );
+
+    repl::exec(badge, &code, REPL_TIMEOUT)
+        .await?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.trim_end_matches('\r').splitn(3, '\t');
+            let size = fields.next().unwrap_or_default().parse()?;
+            let sha256 = fields.next().unwrap_or_default().to_owned();
+            let path = BadgePath::new(fields.next().unwrap_or_default())?;
+            Ok((path, FileInfo { size, sha256 }))
+        })
+        .collect()
+}
+
+/// Like `files_via_repl`, but by fetching every file
+async fn files_via_fetch(
+    client: &BadgeFs,
+    root: &BadgePath,
+) -> Result<BTreeMap<BadgePath, FileInfo>, Box<dyn Error + Send + Sync>> {
+    let mut paths = Vec::new();
+    if client.metadata(root.clone()).await?.is_file() {
+        paths.push(root.clone());
+    } else {
+        let mut stack = vec![root.clone()];
+        while let Some(dir) = stack.pop() {
+            let mut entries = client.read_dir(dir.clone());
+            loop {
+                match entries.next_entry().await {
+                    Ok(Some(FsEntry::File(name))) => paths.push(dir.join(name)?),
+                    Ok(Some(FsEntry::Directory(name))) => stack.push(dir.join(name)?),
+                    // Can't be downloaded, and the directory of the mirror has no equivalent
+                    Ok(Some(FsEntry::Other(..))) => {}
+                    Ok(None) => break,
+                    Err(e) if e.downcast_ref::<ClientError>().is_some() => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    let mut files = BTreeMap::new();
+    for path in paths {
+        let data = client.open(path.clone()).read_to_end().await?;
+        let info = FileInfo {
+            size: data.len() as u64,
+            sha256: cache::hash(&data),
+        };
+        files.insert(path, info);
+    }
+
+    Ok(files)
+}
+
+/// The size and hash of every file below `root`
+pub async fn snapshot(
+    badge: &Badge,
+    client: &BadgeFs,
+    root: &BadgePath,
+) -> Result<BTreeMap<BadgePath, FileInfo>, Box<dyn Error + Send + Sync>> {
+    if badge.capabilities().python_shell {
+        files_via_repl(badge, root).await
+    } else {
+        info!("The Python shell isn't available, so every file is fetched to hash it");
+        files_via_fetch(client, root).await
+    }
+}
+
+/// The manifest of the files below `root`, as pretty-printed JSON
+pub async fn manifest(
+    badge: &Badge,
+    client: &BadgeFs,
+    root: &BadgePath,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let files = snapshot(badge, client, root).await?;
+    Ok(render(root, &files)?)
+}
+
+/// Writes a manifest of `files`, the reverse of `parse`
+fn render(
+    root: &BadgePath,
+    files: &BTreeMap<BadgePath, FileInfo>,
+) -> Result<String, serde_json::Error> {
+    let files = files
+        .iter()
+        .map(
+            |(path, info)| json!({"path": path.as_str(), "size": info.size, "sha256": info.sha256}),
+        )
+        .collect::<Vec<_>>();
+    let manifest = json!({"version": VERSION, "root": root.as_str(), "files": files});
+
+    Ok(serde_json::to_string_pretty(&manifest)? + "\n")
+}
+
+/// Reads the root and the files of a manifest
+fn parse(
+    text: &str,
+) -> Result<(BadgePath, BTreeMap<BadgePath, FileInfo>), Box<dyn Error + Send + Sync>> {
+    let invalid = |what: &str| MirrorError::Invalid(what.to_owned());
+    let manifest: Value = serde_json::from_str(text)?;
+
+    let version = manifest["version"]
+        .as_u64()
+        .ok_or_else(|| invalid("version must be a number"))?;
+    if version != VERSION {
+        Err(MirrorError::UnsupportedVersion(version))?
+    }
+
+    let root = manifest["root"]
+        .as_str()
+        .ok_or_else(|| invalid("root must be a string"))?;
+    let mut files = BTreeMap::new();
+    for file in manifest["files"]
+        .as_array()
+        .ok_or_else(|| invalid("files must be an array"))?
+    {
+        let path = file["path"]
+            .as_str()
+            .ok_or_else(|| invalid("each file needs a path"))?;
+        let size = file["size"]
+            .as_u64()
+            .ok_or_else(|| invalid("each file needs a size"))?;
+        let sha256 = file["sha256"]
+            .as_str()
+            .ok_or_else(|| invalid("each file needs a sha256"))?;
+        files.insert(
+            BadgePath::new(path)?,
+            FileInfo {
+                size,
+                sha256: sha256.to_ascii_lowercase(),
+            },
+        );
+    }
+
+    Ok((BadgePath::new(root)?, files))
+}
+
+/// The files that are `missing`, `changed`, or, unless `allow_extra` is set, `extra` on the badge
+fn compare<'a>(
+    expected: &'a BTreeMap<BadgePath, FileInfo>,
+    actual: &'a BTreeMap<BadgePath, FileInfo>,
+    allow_extra: bool,
+) -> Vec<(&'static str, &'a BadgePath)> {
+    let mut mismatches = Vec::new();
+    for (path, info) in expected.iter() {
+        match actual.get(path) {
+            None => mismatches.push(("missing", path)),
+            Some(actual) if actual != info => mismatches.push(("changed", path)),
+            Some(_) => {}
+        }
+    }
+    if !allow_extra {
+        for path in actual.keys().filter(|path| !expected.contains_key(path)) {
+            mismatches.push(("extra", path));
+        }
+    }
+
+    mismatches
+}
+
+/// Compares the badge with the manifest in `file` and prints every file that is missing, changed,
+/// or, unless `allow_extra` is set, not in the manifest
+pub async fn verify(
+    badge: &Badge,
+    client: &BadgeFs,
+    file: &Path,
+    allow_extra: bool,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (root, expected) = parse(&std::fs::read_to_string(file)?)?;
+    let actual = snapshot(badge, client, &root).await?;
+
+    let mismatches = compare(&expected, &actual, allow_extra);
+    for (status, path) in mismatches.iter() {
+        output.verified(status, path);
+    }
+    if !mismatches.is_empty() {
+        Err(MirrorError::Mismatch(mismatches.len()))?
+    }
+    info!("All {} files match", expected.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(files: &[(&str, u64, &str)]) -> BTreeMap<BadgePath, FileInfo> {
+        files
+            .iter()
+            .map(|(path, size, sha256)| {
+                let info = FileInfo {
+                    size: *size,
+                    sha256: sha256.to_string(),
+                };
+                (BadgePath::new(*path).unwrap(), info)
+            })
+            .collect()
+    }
+
+    fn mismatches(
+        expected: &BTreeMap<BadgePath, FileInfo>,
+        actual: &BTreeMap<BadgePath, FileInfo>,
+        allow_extra: bool,
+    ) -> Vec<String> {
+        compare(expected, actual, allow_extra)
+            .into_iter()
+            .map(|(status, path)| format!("{} {}", status, path))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let root = BadgePath::new("/flash").unwrap();
+        let files = files(&[
+            ("/flash/boot.py", 139, "ab12"),
+            ("/flash/apps/x/__init__.py", 0, "e3b0"),
+        ]);
+
+        let text = render(&root, &files).unwrap();
+        let (parsed_root, parsed) = parse(&text).unwrap();
+        assert_eq!(parsed_root, root);
+        assert_eq!(parsed, files);
+    }
+
+    #[test]
+    fn lowercases_hashes() {
+        let text = r#"{"version": 1, "root": "/flash", "files": [{"path": "/flash/a", "size": 1, "sha256": "AB12"}]}"#;
+        let (_, parsed) = parse(text).unwrap();
+        assert_eq!(parsed, files(&[("/flash/a", 1, "ab12")]));
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        let cases = [
+            ("[]", "Invalid manifest: version must be a number"),
+            (
+                r#"{"version": 2, "root": "/flash", "files": []}"#,
+                "Manifest version 2 isn't supported",
+            ),
+            (
+                r#"{"version": 1, "files": []}"#,
+                "Invalid manifest: root must be a string",
+            ),
+            (
+                r#"{"version": 1, "root": "/flash", "files": {}}"#,
+                "Invalid manifest: files must be an array",
+            ),
+            (
+                r#"{"version": 1, "root": "/flash", "files": [{"path": "/flash/a", "sha256": "ab"}]}"#,
+                "Invalid manifest: each file needs a size",
+            ),
+        ];
+        for (text, expected) in cases.iter() {
+            assert_eq!(parse(text).unwrap_err().to_string(), *expected, "{}", text);
+        }
+        assert!(parse("{").is_err());
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let expected = files(&[
+            ("/flash/a", 1, "aa"),
+            ("/flash/b", 2, "bb"),
+            ("/flash/c", 3, "cc"),
+            ("/flash/d", 4, "dd"),
+        ]);
+        let actual = files(&[
+            ("/flash/a", 1, "aa"),
+            ("/flash/b", 5, "bb"),
+            ("/flash/c", 3, "ff"),
+            ("/flash/e", 6, "ee"),
+        ]);
+
+        assert_eq!(
+            mismatches(&expected, &actual, false),
+            [
+                "changed /flash/b",
+                "changed /flash/c",
+                "missing /flash/d",
+                "extra /flash/e",
+            ]
+        );
+        assert_eq!(
+            mismatches(&expected, &actual, true),
+            ["changed /flash/b", "changed /flash/c", "missing /flash/d"]
+        );
+    }
+
+    #[test]
+    fn matching_files_pass() {
+        let files = files(&[("/flash/a", 1, "aa")]);
+        assert!(compare(&files, &files.clone(), false).is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+//! Commands that follow the conventions of `mpremote`, the command line tool of MicroPython, so
+//! its users and their scripts can use this tool the same way. Paths on the badge start with `:`,
+//! like `fs cp main.py :apps/hello/__init__.py`, and relative paths are relative to /flash.
+
+use crate::{
+    cache::MetadataCache,
+    client::{BadgeFs, FileKind},
+    cmds::FsEntry,
+    device::Badge,
+    output::Output,
+    overwrite::{self, Overwrite},
+    path::{BadgePath, PathError},
+    repl, sourcemap,
+};
+use std::{error::Error, io::Write, path::PathBuf, str::FromStr, time::Duration};
+use structopt::StructOpt;
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+#[error("Either the source or the destination has to be on the badge, written with a leading ':'")]
+pub struct LocalCopyError;
+
+/// A path on the badge as mpremote writes it: with or without a leading `:`, and relative to
+/// /flash unless it is absolute
+pub fn remote_path(s: &str) -> Result<BadgePath, PathError> {
+    let path = s.strip_prefix(':').unwrap_or(s);
+    if path.starts_with('/') {
+        BadgePath::new(path)
+    } else {
+        BadgePath::new(format!("/flash/{}", path))
+    }
+}
+
+/// An argument of `fs cp`, which is on the badge if it starts with `:`
+#[derive(Debug, Clone)]
+pub enum Location {
+    Local(PathBuf),
+    Remote(BadgePath),
+}
+
+impl FromStr for Location {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Location, PathError> {
+        if s.starts_with(':') {
+            Ok(Location::Remote(remote_path(s)?))
+        } else {
+            Ok(Location::Local(PathBuf::from(s)))
+        }
+    }
+}
+
+#[derive(StructOpt, Clone)]
+pub enum FsCommand {
+    #[structopt(about = "Lists a directory, /flash by default")]
+    Ls {
+        #[structopt(default_value = ":", parse(try_from_str = remote_path))]
+        path: BadgePath,
+    },
+
+    #[structopt(about = "Prints files")]
+    Cat {
+        #[structopt(required = true, parse(try_from_str = remote_path))]
+        paths: Vec<BadgePath>,
+    },
+
+    #[structopt(
+        about = "Copies a file. Prefix paths on the badge with ':', like `fs cp :main.py main.py`. Existing files are replaced."
+    )]
+    Cp { from: Location, to: Location },
+
+    #[structopt(about = "Deletes files")]
+    Rm {
+        #[structopt(required = true, parse(try_from_str = remote_path))]
+        paths: Vec<BadgePath>,
+    },
+
+    #[structopt(about = "Creates directories")]
+    Mkdir {
+        #[structopt(required = true, parse(try_from_str = remote_path))]
+        paths: Vec<BadgePath>,
+    },
+
+    #[structopt(about = "Deletes empty directories")]
+    Rmdir {
+        #[structopt(required = true, parse(try_from_str = remote_path))]
+        paths: Vec<BadgePath>,
+    },
+
+    #[structopt(about = "Creates empty files, leaving existing files alone")]
+    Touch {
+        #[structopt(required = true, parse(try_from_str = remote_path))]
+        paths: Vec<BadgePath>,
+    },
+}
+
+/// Where a file named `name` ends up when copied to `to`: in it if it is a directory
+async fn remote_destination(
+    client: &BadgeFs,
+    to: &BadgePath,
+    name: &str,
+) -> Result<BadgePath, Box<dyn Error + Send + Sync>> {
+    match client.metadata(to.clone()).await {
+        Ok(metadata) if metadata.kind == FileKind::Directory => Ok(to.join(name)?),
+        _ => Ok(to.clone()),
+    }
+}
+
+async fn copy(
+    badge: &Badge,
+    client: &BadgeFs,
+    cache: &MetadataCache,
+    from: Location,
+    to: Location,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match (from, to) {
+        (Location::Remote(from), Location::Local(to)) => {
+            let data = badge.fetch_file(&from).await?;
+            let to = if to.is_dir() {
+                to.join(from.file_name())
+            } else {
+                to
+            };
+            std::fs::write(&to, &data)?;
+            info!("Copied {} to {:?}", from, to);
+        }
+        (Location::Local(from), Location::Remote(to)) => {
+            let data = std::fs::read(&from)?;
+            let name = from
+                .file_name()
+                .unwrap_or_else(|| from.as_os_str())
+                .to_string_lossy();
+            let to = remote_destination(client, &to, &name).await?;
+            badge.write_file(&to, &data).await?;
+            cache.record_file(&to, &data);
+            sourcemap::record_all([(&to, from.as_path())]);
+            info!("Copied {:?} to {}", from, to);
+        }
+        (Location::Remote(from), Location::Remote(to)) => {
+            let to = remote_destination(client, &to, from.file_name()).await?;
+            overwrite::prepare(badge, client, &to, Overwrite::Replace).await?;
+            badge.copy_file(&from, &to).await?;
+            cache.remove(&to);
+        }
+        (Location::Local(_), Location::Local(_)) => Err(LocalCopyError)?,
+    }
+
+    Ok(())
+}
+
+/// Runs an `fs` subcommand
+pub async fn command(
+    badge: &Badge,
+    client: &BadgeFs,
+    cache: &MetadataCache,
+    command: FsCommand,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match command {
+        FsCommand::Ls { path } => {
+            let mut entries = client.read_dir(path);
+            while let Some(entry) = entries.next_entry().await? {
+                match entry {
+                    // Like mpremote, which marks directories with a trailing slash
+                    FsEntry::Directory(name) if output == Output::Human => println!("{}/", name),
+                    FsEntry::Directory(name) => output.entry(FileKind::Directory, &name),
+                    FsEntry::File(name) => output.entry(FileKind::File, &name),
+                    FsEntry::Other(kind, name) => output.entry(FileKind::Other(kind), &name),
+                }
+            }
+        }
+        FsCommand::Cat { paths } => {
+            for path in paths.iter() {
+                std::io::stdout().write_all(&badge.fetch_file(path).await?)?;
+            }
+        }
+        FsCommand::Cp { from, to } => copy(badge, client, cache, from, to).await?,
+        FsCommand::Rm { paths } | FsCommand::Rmdir { paths } => {
+            for path in paths.iter() {
+                badge.delete_path(path).await?;
+                cache.remove(path);
+            }
+        }
+        FsCommand::Mkdir { paths } => {
+            for path in paths.iter() {
+                badge.create_dir(path).await?;
+            }
+        }
+        FsCommand::Touch { paths } => {
+            // Creating a file that exists would empty it
+            for path in paths.iter() {
+                if !client.exists(path).await? {
+                    badge.create_file(path).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Interrupts the running app, runs `code` in the Python shell and prints what it printed
+pub async fn exec(
+    badge: &Badge,
+    code: &str,
+    limit: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    badge.serial_in("\u{003}".as_bytes()).await?;
+    std::thread::sleep(Duration::from_millis(200));
+
+    print!("{}", repl::exec(badge, code, limit).await?);
+    std::io::stdout().flush()?;
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! Shares the serial connection of one badge with several clients over a Unix socket. Every client
+//! receives the complete output, and input from all clients is sent to the badge one write at a
+//! time.
+
+use crate::{device::Badge, render::raw_bytes, shell};
+use std::{
+    error::Error,
+    future::Future,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net,
+    sync::{broadcast, broadcast::RecvError, mpsc, Mutex},
+};
+use tracing::{debug, warn};
+
+const ATTACH_ESCAPE_HELP: &str = "[Ctrl-A q: exit, Ctrl-A Ctrl-A: send Ctrl-A]";
+
+/// Messages of output buffered per client
+const CLIENT_BUFFER: usize = 1024;
+
+pub struct Mux {
+    path: PathBuf,
+    output: broadcast::Sender<String>,
+    input: Arc<Mutex<mpsc::UnboundedReceiver<Vec<u8>>>>,
+}
+
+impl Mux {
+    /// Starts accepting clients on a Unix socket at `path`. Must be called from within a runtime.
+    pub fn listen(path: &Path) -> io::Result<Mux> {
+        // A socket left behind by a previous run would make binding fail
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        let mut listener = net::UnixListener::bind(path)?;
+        let (output, _) = broadcast::channel(CLIENT_BUFFER);
+        let (input_tx, input) = mpsc::unbounded_channel();
+        let clients = output.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_client(stream, clients.subscribe(), input_tx.clone()));
+                    }
+                    Err(e) => {
+                        warn!("Unable to accept a client: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Mux {
+            path: path.to_owned(),
+            output,
+            input: Arc::new(Mutex::new(input)),
+        })
+    }
+
+    /// Forwards the output of `badge` to the clients and their input to `badge`, until it is
+    /// closed. The clients stay connected, so they can continue with the next badge.
+    pub fn forward(&self, badge: Arc<Badge>) -> impl Future<Output = ()> {
+        let mut output = badge.subscribe();
+        let clients = self.output.clone();
+        let input = self.input.clone();
+        async move {
+            let mut input = input.lock().await;
+            loop {
+                tokio::select! {
+                    received = output.recv() => match received {
+                        Ok(text) => {
+                            // Fails when no client is attached, which is fine
+                            let _ = clients.send(text);
+                        }
+                        Err(RecvError::Lagged(n)) => warn!("Dropped {} messages of badge output", n),
+                        Err(RecvError::Closed) => break,
+                    },
+                    data = input.recv() => match data {
+                        Some(data) => {
+                            if let Err(e) = badge.serial_in(data).await {
+                                debug!("Sending input of a client failed: {}", e);
+                                break;
+                            }
+                        }
+                        None => break,
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn serve_client(
+    stream: net::UnixStream,
+    mut output: broadcast::Receiver<String>,
+    input: mpsc::UnboundedSender<Vec<u8>>,
+) {
+    debug!("Client attached");
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let read = async {
+        let mut buf = [0u8; 256];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if input.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+    let write = async {
+        loop {
+            match output.recv().await {
+                Ok(text) => {
+                    if writer.write_all(&raw_bytes(&text)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => warn!("A client missed {} messages of output", n),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = read => {}
+        _ = write => {}
+    }
+    debug!("Client detached");
+}
+
+/// Connects the terminal to the badge shared on the socket at `path`, until the socket is closed or
+/// the user presses Control + A, Q
+pub fn attach(path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut writer = stream.try_clone()?;
+    let original = shell::raw_mode()?;
+
+    thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        let mut stdin = io::stdin();
+        let mut escaped = false;
+        while stdin.read_exact(&mut buf).is_ok() {
+            if escaped {
+                escaped = false;
+                match buf[0] {
+                    shell::ESCAPE => {}
+                    b'q' => {
+                        // Ends the loop below
+                        let _ = writer.shutdown(Shutdown::Both);
+                        break;
+                    }
+                    _ => {
+                        eprint!("\r\n{}\r\n", ATTACH_ESCAPE_HELP);
+                        continue;
+                    }
+                }
+            } else if buf[0] == shell::ESCAPE {
+                escaped = true;
+                continue;
+            }
+
+            if writer.write_all(&shell::to_crlf(&buf)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut buf = [0u8; 1024];
+    let mut stdout = io::stdout();
+    let result = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => {
+                if let Err(e) = stdout.write_all(&buf[..n]).and_then(|_| stdout.flush()) {
+                    break Err(e);
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    shell::restore_mode(original)?;
+    eprint!("\r\n[The connection was closed]\r\n");
+
+    Ok(result?)
+}
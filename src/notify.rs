@@ -0,0 +1,60 @@
+//! Desktop notifications with `--notify`, for when the badge is plugged in or unplugged while
+//! `mount --watch` runs, a `push` finished and an app crashed with a traceback, so changes are
+//! noticed without watching the terminal. Shown with `notify-send` on Linux and `osascript` on
+//! macOS; without them, notifications are only logged.
+
+use std::{
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+use tracing::debug;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns notifications on for this process
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn command(summary: &str, body: &str) -> Command {
+    if cfg!(target_os = "macos") {
+        let quote = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification \"{}\" with title \"{}\"",
+            quote(body),
+            quote(summary)
+        ));
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", "cz2020-usbtool", summary, body]);
+        command
+    }
+}
+
+/// Shows a notification if they are enabled, without waiting for it
+pub fn notify(summary: &str, body: &str) {
+    if !enabled() {
+        return;
+    }
+
+    debug!("Notifying: {}: {}", summary, body);
+    let mut command = command(summary, body);
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    match command.spawn() {
+        // Waited for on a thread, so no zombie is left behind
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => debug!("Unable to show a notification: {}", e),
+    }
+}
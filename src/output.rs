@@ -0,0 +1,182 @@
+use crate::{client::FileKind, exit::ExitCode, lint::Severity, path::BadgePath, size::human_size};
+use std::{error::Error, time::Duration};
+
+/// How results are printed. Porcelain output has one record per line with tab-separated fields
+/// and no prose, and its format is kept stable for scripts. The last field of a record may
+/// contain tabs, so paths always come last, except after the lines of files that `grep` prints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Human,
+    Porcelain,
+}
+
+fn kind_field(kind: FileKind) -> char {
+    match kind {
+        FileKind::File => 'f',
+        FileKind::Directory => 'd',
+        FileKind::Other(kind) => kind,
+    }
+}
+
+impl Output {
+    /// An entry of a listing: `<f|d>\t<name>`, or the type the firmware sent for other entries
+    pub fn entry(self, kind: FileKind, name: &str) {
+        match self {
+            Output::Human => println!("{}", name),
+            Output::Porcelain => println!("{}\t{}", kind_field(kind), name),
+        }
+    }
+
+    /// A file that was uploaded or downloaded: `<bytes>\t<path>`
+    pub fn transferred(self, path: &BadgePath, bytes: usize) {
+        match self {
+            Output::Human => println!("{}", path),
+            Output::Porcelain => println!("{}\t{}", bytes, path),
+        }
+    }
+
+    /// The space used by a file or directory: `<bytes>\t<path>`
+    pub fn usage(self, bytes: u64, path: &BadgePath) {
+        match self {
+            Output::Human => println!("{:>6} {}", human_size(bytes), path),
+            Output::Porcelain => println!("{}\t{}", bytes, path),
+        }
+    }
+
+    /// A line of a file that matched: `<path>\t<line number>\t<line>`
+    pub fn grep_match(self, path: &BadgePath, number: usize, line: &str) {
+        match self {
+            Output::Human => println!("{}:{}:{}", path, number, line),
+            Output::Porcelain => println!("{}\t{}\t{}", path, number, line),
+        }
+    }
+
+    /// An entry of the trash and the path it was deleted from: `<entry>\t<path>`
+    pub fn trashed(self, entry: &str, path: &BadgePath) {
+        match self {
+            Output::Human => println!("{} {}", entry, path),
+            Output::Porcelain => println!("{}\t{}", entry, path),
+        }
+    }
+
+    /// Whether an app is running and which one was started last, if known:
+    /// `<running|idle>\t<path>`, with an empty path if unknown
+    pub fn run_status(self, running: bool, app: Option<&BadgePath>) {
+        let state = if running { "running" } else { "idle" };
+        match (self, app) {
+            (Output::Human, Some(app)) if running => println!("running {}", app),
+            (Output::Human, _) => println!("{}", state),
+            (Output::Porcelain, Some(app)) => println!("{}\t{}", state, app),
+            (Output::Porcelain, None) => println!("{}\t", state),
+        }
+    }
+
+    /// A sensor reading of the badge: `<name>\t<value>`
+    pub fn reading(self, name: &str, value: &str) {
+        match self {
+            Output::Human => println!("{}: {}", name.replace('_', " "), value),
+            Output::Porcelain => println!("{}\t{}", name, value),
+        }
+    }
+
+    /// A setting of the firmware: `<key>\t<value>`
+    pub fn setting(self, key: &str, value: &str) {
+        match self {
+            Output::Human => println!("{} = {}", key, value),
+            Output::Porcelain => println!("{}\t{}", key, value),
+        }
+    }
+
+    /// A file that doesn't match a manifest: `<missing|changed|extra>\t<path>`
+    pub fn verified(self, state: &str, path: &BadgePath) {
+        match self {
+            Output::Human => println!("{:<7} {}", state, path),
+            Output::Porcelain => println!("{}\t{}", state, path),
+        }
+    }
+
+    /// A benchmark result: `<bytes>\t<direction>\t<KiB/s>\t<p50 µs>\t<p90 µs>\t<max µs>`
+    pub fn bench_header(self) {
+        if self == Output::Human {
+            println!(
+                "{:>10} {:>9} {:>10} {:>10} {:>10} {:>10}",
+                "bytes", "direction", "KiB/s", "p50", "p90", "max"
+            );
+        }
+    }
+
+    pub fn bench_result(
+        self,
+        size: usize,
+        direction: &str,
+        throughput: f64,
+        percentiles: [Duration; 3],
+    ) {
+        let [p50, p90, max] = percentiles;
+        match self {
+            Output::Human => println!(
+                "{:>10} {:>9} {:>10.1} {:>10.0?} {:>10.0?} {:>10.0?}",
+                size, direction, throughput, p50, p90, max
+            ),
+            Output::Porcelain => println!(
+                "{}\t{}\t{:.1}\t{}\t{}\t{}",
+                size,
+                direction,
+                throughput,
+                p50.as_micros(),
+                p90.as_micros(),
+                max.as_micros()
+            ),
+        }
+    }
+
+    /// A step of provisioning and what it did: `<action>\t<target>`
+    pub fn provisioned(self, action: &str, target: &str) {
+        match self {
+            Output::Human => println!("{} {}", action, target),
+            Output::Porcelain => println!("{}\t{}", action, target),
+        }
+    }
+
+    /// The result of a test that ran on the badge: `<pass|fail>\t<name>`
+    pub fn test_result(self, passed: bool, name: &str) {
+        let result = if passed { "pass" } else { "fail" };
+        match self {
+            Output::Human => println!("{} {}", result.to_uppercase(), name),
+            Output::Porcelain => println!("{}\t{}", result, name),
+        }
+    }
+
+    /// A problem that `lint` found in a file of an app: `<error|warning>\t<message>\t<file>`
+    pub fn lint_problem(self, severity: Severity, message: &str, file: &str) {
+        match self {
+            Output::Human => println!("{}: {}: {}", severity, file, message),
+            Output::Porcelain => println!("{}\t{}\t{}", severity, message, file),
+        }
+    }
+
+    /// An error that only affected `path`, printed to stderr: `error\t<exit code>\t<path>: <message>`
+    pub fn file_error(self, path: &BadgePath, code: ExitCode, e: &dyn Error) {
+        match self {
+            Output::Human => eprintln!("{}: {}", path, e),
+            Output::Porcelain => eprintln!(
+                "error\t{}\t{}: {}",
+                code as i32,
+                path,
+                e.to_string().replace('\n', " ")
+            ),
+        }
+    }
+
+    /// An error, printed to stderr: `error\t<exit code>\t<message>`
+    pub fn error(self, code: ExitCode, e: &dyn Error) {
+        match self {
+            Output::Human => eprintln!("Error: {}", e),
+            Output::Porcelain => eprintln!(
+                "error\t{}\t{}",
+                code as i32,
+                e.to_string().replace('\n', " ")
+            ),
+        }
+    }
+}
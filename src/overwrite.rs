@@ -0,0 +1,57 @@
+//! What `cp` and `mv` do when the destination exists. The firmware doesn't handle that
+//! consistently and only answers with a bare error, so the destination is checked first.
+
+use crate::{client::BadgeFs, device::Badge, path::BadgePath};
+use std::error::Error;
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+#[error("{} already exists. Use --force to replace it or --no-clobber to skip it.", .0)]
+pub struct ExistsError(BadgePath);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Fail if the destination exists
+    Refuse,
+    /// Leave the destination alone and do nothing
+    Skip,
+    /// Delete the destination first
+    Replace,
+}
+
+impl Overwrite {
+    pub fn from_flags(no_clobber: bool, force: bool) -> Overwrite {
+        if no_clobber {
+            Overwrite::Skip
+        } else if force {
+            Overwrite::Replace
+        } else {
+            Overwrite::Refuse
+        }
+    }
+}
+
+/// Makes way for a file to be written to `to`. Returns false if the operation should be skipped.
+pub async fn prepare(
+    badge: &Badge,
+    client: &BadgeFs,
+    to: &BadgePath,
+    overwrite: Overwrite,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    if !client.exists(to).await? {
+        return Ok(true);
+    }
+
+    match overwrite {
+        Overwrite::Refuse => Err(ExistsError(to.clone()))?,
+        Overwrite::Skip => {
+            info!("Not replacing {}", to);
+            Ok(false)
+        }
+        Overwrite::Replace => {
+            badge.delete_path(to).await?;
+            Ok(true)
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::size::{parse_size, SizeError};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RateError {
+    #[error("Invalid rate: {}", .0)]
+    Invalid(#[from] SizeError),
+
+    #[error("The rate must be more than 0")]
+    Zero,
+}
+
+/// Parses rates in bytes per second like `512`, `20k` and `1m`, where `k` and `m` are KiB and MiB
+pub fn parse_rate(s: &str) -> Result<u64, RateError> {
+    match parse_size(s)? {
+        0 => Err(RateError::Zero),
+        rate => Ok(rate),
+    }
+}
+
+/// Spreads writes out over time, so no more than `rate` bytes are sent per second
+pub struct Pacer {
+    rate: u64,
+    next: Instant,
+}
+
+impl Pacer {
+    pub fn new(rate: u64) -> Pacer {
+        Pacer {
+            rate,
+            next: Instant::now(),
+        }
+    }
+
+    /// How many bytes to send at a time, so that the pauses in between are about 50ms
+    pub fn piece_size(&self) -> usize {
+        ((self.rate / 20) as usize).clamp(64, 4096)
+    }
+
+    /// Blocks until `len` more bytes may be sent
+    pub fn wait(&mut self, len: usize) {
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        }
+
+        self.next = self.next.max(now) + Duration::from_secs_f64(len as f64 / self.rate as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("512").unwrap(), 512);
+        assert_eq!(parse_rate("20k").unwrap(), 20 * 1024);
+        assert!(matches!(parse_rate("0"), Err(RateError::Zero)));
+        assert!(matches!(parse_rate("fast"), Err(RateError::Invalid(_))));
+    }
+}
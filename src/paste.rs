@@ -0,0 +1,71 @@
+//! `paste-run` runs the Python code on the clipboard of this computer on the badge, to try out a
+//! snippet from documentation or a chat without saving it to a file first.
+
+use crate::{device::Badge, logdump, path::BadgePath, repl::python_literal, text};
+use regex::Regex;
+use std::{error::Error, time::Duration};
+use thiserror::Error;
+use tokio::time::timeout;
+use tracing::info;
+
+/// Where the snippet is stored on the badge. Replaced by every `paste-run`.
+const SCRIPT: &str = "/flash/.paste.py";
+
+#[derive(Error, Debug)]
+pub enum PasteError {
+    #[error("The clipboard is empty")]
+    Empty,
+
+    #[error("Unable to read the clipboard: {}", .0)]
+    Clipboard(#[from] arboard::Error),
+
+    #[error("The snippet didn't finish within {:?}", .0)]
+    Timeout(Duration),
+}
+
+/// The text on the clipboard
+fn clipboard() -> Result<String, PasteError> {
+    let text = arboard::Clipboard::new()?.get_text()?;
+    if text.trim().is_empty() {
+        Err(PasteError::Empty)?
+    }
+
+    Ok(text)
+}
+
+/// Uploads the snippet on the clipboard, runs it from the Python shell and prints its output until
+/// the shell is back at its prompt, or `limit` has passed
+pub async fn paste_run(
+    badge: &Badge,
+    limit: Option<Duration>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = text::to_lf(clipboard()?.as_bytes());
+    let script = BadgePath::new(SCRIPT)?;
+    badge.write_file(&script, code).await?;
+    info!("Running the snippet from {}", script);
+
+    // Interrupt whatever is running, and wait for its output, so it isn't mistaken for the prompt
+    // that follows the snippet
+    badge.serial_in("\u{003}").await?;
+    std::thread::sleep(Duration::from_millis(200));
+
+    let output = badge.subscribe();
+    badge
+        .serial_in(format!(
+            "exec(open({}).read(), {{'__name__': '__main__'}})\r\n",
+            python_literal(SCRIPT)
+        ))
+        .await?;
+
+    let prompt = Regex::new("^>>> $").unwrap();
+    match limit {
+        Some(limit) => match timeout(limit, logdump::capture(output, Some(&prompt))).await {
+            Ok(result) => result?,
+            Err(_) => Err(PasteError::Timeout(limit))?,
+        },
+        None => logdump::capture(output, Some(&prompt)).await?,
+    };
+    println!();
+
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PathError {
+    #[error("Path contains a NUL byte: {:?}", .0)]
+    ContainsNul(String),
+
+    #[error("Apps can only be run from /flash, copy {} there first", .0)]
+    NotRunnable(BadgePath),
+}
+
+/// An absolute path on the badge, e.g. `/flash/apps/synthesizer/__init__.py`.
+///
+/// Paths are normalized on construction: `.` and empty components are removed, `..` is resolved
+/// and there is never a trailing slash (FetchDir doesn't accept one). Paths given on the command
+/// line are parsed with `from_str`, which also accepts backslashes as separators.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BadgePath(String);
+
+impl BadgePath {
+    pub fn new<S: AsRef<str>>(path: S) -> Result<BadgePath, PathError> {
+        BadgePath::parse(path.as_ref(), &['/'])
+    }
+
+    fn parse(path: &str, separators: &[char]) -> Result<BadgePath, PathError> {
+        if path.contains('\0') {
+            return Err(PathError::ContainsNul(path.to_owned()));
+        }
+
+        let mut components = Vec::new();
+        for component in path.split(separators) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                component => components.push(component),
+            }
+        }
+
+        Ok(BadgePath(format!("/{}", components.join("/"))))
+    }
+
+    pub fn root() -> BadgePath {
+        BadgePath("/".to_owned())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0 == "/"
+    }
+
+    pub fn join<S: AsRef<str>>(&self, name: S) -> Result<BadgePath, PathError> {
+        BadgePath::new(format!("{}/{}", self.0, name.as_ref()))
+    }
+
+    /// The parent directory, or `None` for the root
+    pub fn parent(&self) -> Option<BadgePath> {
+        if self.is_root() {
+            None
+        } else {
+            let index = self.0.rfind('/').unwrap();
+            Some(BadgePath(self.0[..index.max(1)].to_owned()))
+        }
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.0[self.0.rfind('/').unwrap() + 1..]
+    }
+
+    /// Apps are run relative to `/flash`, so the run command expects paths without that prefix
+    pub fn is_on_flash(&self) -> bool {
+        self.0 == "/flash" || self.0.starts_with("/flash/")
+    }
+
+    fn is_on_sdcard(&self) -> bool {
+        ["/sd", "/sdcard"]
+            .iter()
+            .any(|dir| self.0 == *dir || self.0.starts_with(&format!("{}/", dir)))
+    }
+
+    /// The path the run command expects for this file: without the `/flash` prefix, and with
+    /// `__init__.py` added if this looks like an app directory rather than a Python file
+    pub fn run_path(&self) -> Result<BadgePath, PathError> {
+        if self.is_on_sdcard() {
+            return Err(PathError::NotRunnable(self.clone()));
+        }
+
+        let path = if self.is_on_flash() {
+            BadgePath::new(&self.0["/flash".len()..])?
+        } else {
+            self.clone()
+        };
+
+        if path.file_name().ends_with(".py") {
+            Ok(path)
+        } else {
+            path.join("__init__.py")
+        }
+    }
+}
+
+impl FromStr for BadgePath {
+    type Err = PathError;
+
+    /// Parses a path that was typed in, where backslashes are separators too
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BadgePath::parse(s, &['/', '\\'])
+    }
+}
+
+impl AsRef<str> for BadgePath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for BadgePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for BadgePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> String {
+        BadgePath::new(path).unwrap().to_string()
+    }
+
+    #[test]
+    fn normalizes() {
+        assert_eq!(
+            path("/flash//apps/./synthesizer/"),
+            "/flash/apps/synthesizer"
+        );
+        assert_eq!(path("flash/apps"), "/flash/apps");
+        assert_eq!(path(""), "/");
+        assert_eq!(path("/"), "/");
+    }
+
+    #[test]
+    fn resolves_parent_components() {
+        assert_eq!(path("/flash/apps/../lib"), "/flash/lib");
+        assert_eq!(path("/flash/.."), "/");
+        // Can't go above the root
+        assert_eq!(path("/../../flash"), "/flash");
+        assert_eq!(
+            BadgePath::new("/flash/apps").unwrap().join("..").unwrap(),
+            BadgePath::new("/flash").unwrap()
+        );
+    }
+
+    #[test]
+    fn backslashes_only_separate_typed_paths() {
+        assert_eq!(path("/flash/a\\b"), "/flash/a\\b");
+        assert_eq!(
+            BadgePath::new("/flash")
+                .unwrap()
+                .join("a\\b")
+                .unwrap()
+                .file_name(),
+            "a\\b"
+        );
+        assert_eq!(
+            "\\flash\\apps\\".parse::<BadgePath>().unwrap().as_str(),
+            "/flash/apps"
+        );
+    }
+
+    #[test]
+    fn rejects_nul() {
+        assert!(matches!(
+            BadgePath::new("/flash/a\0b"),
+            Err(PathError::ContainsNul(_))
+        ));
+    }
+
+    #[test]
+    fn parent_and_file_name() {
+        let file = BadgePath::new("/flash/apps/main.py").unwrap();
+        assert_eq!(file.file_name(), "main.py");
+        assert_eq!(file.parent().unwrap().as_str(), "/flash/apps");
+        assert_eq!(
+            BadgePath::new("/flash").unwrap().parent(),
+            Some(BadgePath::root())
+        );
+        assert_eq!(BadgePath::root().parent(), None);
+    }
+}
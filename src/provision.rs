@@ -0,0 +1,359 @@
+//! Brings a badge into the state described by a manifest: files to upload, apps to install,
+//! settings to store in NVS and Python code to run. Steps that are already done are skipped, so a
+//! manifest can be applied again to the same badge.
+
+use crate::{
+    cache::{self, MetadataCache},
+    cmds::DirectoryListingResponse,
+    device::Badge,
+    expect::{self, Script},
+    ignore::{self, ExcludeRules},
+    install,
+    manifest::{self, Document, ManifestError, Table, Value},
+    output::Output,
+    path::BadgePath,
+    push,
+    repl::{self, python_literal},
+    sourcemap,
+};
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{debug, info};
+
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Installing from the hatchery downloads the app over WiFi, which can be slow
+const HATCHERY_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub struct Upload {
+    local: PathBuf,
+    remote: BadgePath,
+}
+
+pub enum App {
+    Archive { file: PathBuf, name: Option<String> },
+    Hatchery(String),
+}
+
+pub struct Setting {
+    namespace: String,
+    key: String,
+    value: Value,
+}
+
+/// The desired state of a badge. For example:
+///
+/// ```toml
+/// run = ["import machine; machine.nvs_setint('system', 'brightness', 80)"]
+///
+/// [[file]]
+/// local = "config.json"
+/// remote = "/flash/config.json"
+///
+/// [[app]]
+/// hatchery = "game_of_life"
+///
+/// [settings.owner]
+/// nickname = "Jos"
+///
+/// [[expect]]
+/// script = "wizard.expect"
+/// ```
+pub struct Manifest {
+    uploads: Vec<Upload>,
+    apps: Vec<App>,
+    settings: Vec<Setting>,
+    run: Vec<String>,
+    /// Scripts for `expect::run`
+    expect: Vec<PathBuf>,
+}
+
+impl Manifest {
+    /// Reads a manifest. Local paths in it are relative to the manifest.
+    pub fn load(path: &Path) -> Result<Manifest, Box<dyn Error + Send + Sync>> {
+        let document = Document::parse(&fs::read_to_string(path)?)?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        let manifest = Manifest::from_document(&document, base)?;
+
+        // Mistakes in scripts are found before anything is changed
+        for script in manifest.expect.iter() {
+            Script::load(script)?;
+        }
+
+        Ok(manifest)
+    }
+
+    fn from_document(document: &Document, base: &Path) -> Result<Manifest, ManifestError> {
+        if let Some(key) = document.root.keys().find(|key| *key != "run") {
+            return Err(ManifestError::Unknown(key.clone()));
+        }
+        for name in document.arrays.keys() {
+            if name != "file" && name != "app" && name != "expect" {
+                return Err(ManifestError::Unknown(format!("[[{}]]", name)));
+            }
+        }
+        for name in document.tables.keys() {
+            if !name.starts_with("settings.") {
+                return Err(ManifestError::Unknown(format!("[{}]", name)));
+            }
+        }
+
+        let tables = |name: &str| document.arrays.get(name).cloned().unwrap_or_default();
+        let uploads = tables("file")
+            .iter()
+            .map(|table| {
+                let remote = manifest::require_str(table, "remote", "file")?;
+                Ok(Upload {
+                    local: base.join(manifest::require_str(table, "local", "file")?),
+                    remote: BadgePath::new(remote).map_err(|_| {
+                        ManifestError::InvalidValue("file.remote".to_owned(), "a path on the badge")
+                    })?,
+                })
+            })
+            .collect::<Result<Vec<_>, ManifestError>>()?;
+
+        let apps = tables("app")
+            .iter()
+            .map(|table| app(table, base))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let expect = tables("expect")
+            .iter()
+            .map(|table| Ok(base.join(manifest::require_str(table, "script", "expect")?)))
+            .collect::<Result<Vec<_>, ManifestError>>()?;
+
+        let mut settings = Vec::new();
+        for (namespace, table) in document.subtables("settings") {
+            for (key, value) in table.iter() {
+                match value {
+                    Value::String(_) | Value::Integer(_) | Value::Boolean(_) => {
+                        settings.push(Setting {
+                            namespace: namespace.to_owned(),
+                            key: key.clone(),
+                            value: value.clone(),
+                        })
+                    }
+                    Value::Array(_) => {
+                        return Err(ManifestError::InvalidValue(
+                            format!("settings.{}.{}", namespace, key),
+                            "a string, number or boolean",
+                        ))
+                    }
+                }
+            }
+        }
+
+        let run = match document.root.get("run") {
+            None => Vec::new(),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(code) => Ok(code.clone()),
+                    _ => Err(ManifestError::InvalidValue(
+                        "run".to_owned(),
+                        "an array of strings",
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => {
+                return Err(ManifestError::InvalidValue(
+                    "run".to_owned(),
+                    "an array of strings",
+                ))
+            }
+        };
+
+        Ok(Manifest {
+            uploads,
+            apps,
+            settings,
+            run,
+            expect,
+        })
+    }
+
+    /// Whether applying the manifest needs the Python shell
+    fn needs_shell(&self) -> bool {
+        !self.settings.is_empty()
+            || !self.run.is_empty()
+            || self.apps.iter().any(|app| matches!(app, App::Hatchery(_)))
+    }
+}
+
+fn app(table: &Table, base: &Path) -> Result<App, ManifestError> {
+    let name = manifest::get_str(table, "name", "app")?.map(str::to_owned);
+    match (
+        manifest::get_str(table, "archive", "app")?,
+        manifest::get_str(table, "hatchery", "app")?,
+    ) {
+        (Some(archive), None) => Ok(App::Archive {
+            file: base.join(archive),
+            name,
+        }),
+        (None, Some(hatchery)) => Ok(App::Hatchery(hatchery.to_owned())),
+        _ => Err(ManifestError::InvalidValue(
+            "app".to_owned(),
+            "either an archive or a hatchery app",
+        )),
+    }
+}
+
+/// Whether `path` on the badge already contains `data`
+async fn unchanged(badge: &Badge, path: &BadgePath, data: &[u8]) -> bool {
+    if !badge.capabilities().python_shell {
+        return false;
+    }
+
+    match repl::file_hash(badge, path).await {
+        Ok(hash) => hash == Some(cache::hash(data)),
+        Err(e) => {
+            debug!("Unable to hash {} on the badge: {}", path, e);
+            false
+        }
+    }
+}
+
+async fn apply_upload(
+    badge: &Badge,
+    cache: &MetadataCache,
+    upload: &Upload,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if upload.local.is_dir() {
+        let mut rules = ExcludeRules::new();
+        rules.add_file(&upload.local.join(ignore::IGNORE_FILE))?;
+        return push::push(
+            badge,
+            cache,
+            &upload.local,
+            &upload.remote,
+            &rules,
+            false,
+            None,
+            false,
+            None,
+            output,
+        )
+        .await;
+    }
+
+    let data = fs::read(&upload.local)?;
+    if unchanged(badge, &upload.remote, &data).await {
+        output.provisioned("unchanged", upload.remote.as_str());
+        return Ok(());
+    }
+
+    badge.write_file(&upload.remote, &data).await?;
+    cache.record_file(&upload.remote, &data);
+    sourcemap::record_all([(&upload.remote, upload.local.as_path())]);
+    output.transferred(&upload.remote, data.len());
+
+    Ok(())
+}
+
+/// Lets the badge download and install an app from the hatchery, unless it is installed already
+async fn install_from_hatchery(
+    badge: &Badge,
+    name: &str,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let dir = BadgePath::new("/flash/apps")?.join(name)?;
+    if let DirectoryListingResponse::Found { .. } = badge.fetch_dir(&dir).await? {
+        output.provisioned("unchanged", dir.as_str());
+        return Ok(());
+    }
+
+    info!("Installing {} from the hatchery", name);
+    let code = format!("import woezel\nwoezel.install({})", python_literal(name));
+    repl::exec(badge, &code, HATCHERY_TIMEOUT).await?;
+    output.provisioned("installed", dir.as_str());
+
+    Ok(())
+}
+
+async fn apply_setting(
+    badge: &Badge,
+    setting: &Setting,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (kind, value) = match &setting.value {
+        Value::String(s) => ("str", python_literal(s)),
+        Value::Integer(n) => ("int", n.to_string()),
+        Value::Boolean(b) => ("int", (*b as i64).to_string()),
+        Value::Array(_) => unreachable!("Rejected when loading the manifest"),
+    };
+    let code = format!(
+        "import machine
+try:
+ c = machine.nvs_get{0}({1}, {2})
+except Exception:
+ c = None
+if c == {3}:
+ print('unchanged')
+else:
+ machine.nvs_set{0}({1}, {2}, {3})
+ print('set')",
+        kind,
+        python_literal(&setting.namespace),
+        python_literal(&setting.key),
+        value
+    );
+
+    let result = repl::exec(badge, &code, REPL_TIMEOUT).await?;
+    output.provisioned(
+        result.trim(),
+        &format!("{}/{}", setting.namespace, setting.key),
+    );
+
+    Ok(())
+}
+
+/// Applies `manifest` to `badge`: uploads files, installs apps, stores settings, runs code and runs
+/// expect scripts, in that order
+pub async fn provision(
+    badge: &Badge,
+    cache: &MetadataCache,
+    manifest: &Manifest,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if manifest.needs_shell() {
+        // Interrupt whatever is running, so the shell is ready for input
+        badge.serial_in("\u{003}".as_bytes()).await?;
+    }
+
+    for upload in manifest.uploads.iter() {
+        apply_upload(badge, cache, upload, output).await?;
+    }
+
+    for app in manifest.apps.iter() {
+        match app {
+            App::Archive { file, name } => {
+                install::install(badge, cache, file, name.clone(), output).await?
+            }
+            App::Hatchery(name) => install_from_hatchery(badge, name, output).await?,
+        }
+    }
+
+    for setting in manifest.settings.iter() {
+        apply_setting(badge, setting, output).await?;
+    }
+
+    for code in manifest.run.iter() {
+        let printed = repl::exec(badge, code, REPL_TIMEOUT).await?;
+        if output == Output::Human {
+            print!("{}", printed);
+        }
+        output.provisioned("ran", code.lines().next().unwrap_or_default());
+    }
+
+    for file in manifest.expect.iter() {
+        expect::run(badge, &Script::load(file)?, output != Output::Human).await?;
+        output.provisioned("ran", &file.to_string_lossy());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use crate::{
+    atomic,
+    cache::MetadataCache,
+    device::Badge,
+    git::Changes,
+    ignore::ExcludeRules,
+    notify,
+    output::Output,
+    path::BadgePath,
+    resume::{self, Direction, Journal},
+    sourcemap::{self, SourceMap},
+    text,
+};
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
+
+/// A file or directory to upload, by its path relative to the directory being pushed
+pub struct LocalEntry {
+    pub path: PathBuf,
+    pub relative: String,
+    pub is_dir: bool,
+}
+
+/// Everything in `local` that isn't excluded by `rules`. Directories come before their contents.
+pub fn walk(local: &Path, rules: &ExcludeRules) -> io::Result<Vec<LocalEntry>> {
+    let mut found = Vec::new();
+    let mut stack = vec![(local.to_owned(), String::new())];
+    while let Some((dir, relative)) = stack.pop() {
+        let mut entries = fs::read_dir(&dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let child = if relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", relative, name)
+            };
+            let is_dir = entry.file_type()?.is_dir();
+            if rules.is_excluded(&child, is_dir) {
+                info!("Skipping excluded {}", child);
+                continue;
+            }
+
+            if is_dir {
+                stack.push((entry.path(), child.clone()));
+            }
+            found.push(LocalEntry {
+                path: entry.path(),
+                relative: child,
+                is_dir,
+            });
+        }
+    }
+
+    Ok(found)
+}
+
+/// Keeps only the files in `changes`, and the directories they are in
+pub fn retain_changed(entries: &mut Vec<LocalEntry>, changes: &Changes) {
+    entries.retain(|entry| {
+        if entry.is_dir {
+            let prefix = format!("{}/", entry.relative);
+            changes.changed.iter().any(|path| path.starts_with(&prefix))
+        } else {
+            changes.changed.contains(&entry.relative)
+        }
+    });
+}
+
+/// Deletes the files that were deleted from `local` according to `changes` from the badge. Only
+/// those that the source map says were uploaded from there, so nothing that was put on the badge
+/// in another way is lost.
+async fn delete_removed(
+    badge: &Badge,
+    cache: &MetadataCache,
+    local: &Path,
+    remote: &BadgePath,
+    rules: &ExcludeRules,
+    changes: &Changes,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let local = local.canonicalize()?;
+    let mut map = SourceMap::load();
+    for relative in changes.deleted.iter() {
+        if rules.is_excluded(relative, false) {
+            continue;
+        }
+
+        let target = remote.join(relative)?;
+        if map.local_path(&target) != Some(local.join(relative).as_path()) {
+            debug!("Not deleting {}, which wasn't uploaded from here", target);
+            continue;
+        }
+        match badge.delete_path(&target).await {
+            Ok(()) => {
+                info!("Deleted {}", target);
+                cache.remove(&target);
+                map.remove(&target);
+            }
+            Err(e) => warn!("Unable to delete {}: {}", target, e),
+        }
+    }
+
+    if let Err(e) = map.save() {
+        warn!("Unable to forget the deleted files: {}", e);
+    }
+    Ok(())
+}
+
+async fn create_dir(badge: &Badge, dir: &BadgePath) {
+    if let Err(e) = badge.create_dir(dir).await {
+        // Most likely the directory exists already
+        debug!("Unable to create {}: {}", dir, e);
+    }
+}
+
+/// Uploads the directory `local` to `remote` on the badge, skipping everything that matches `rules`.
+/// With `text`, line endings in text files are converted to LF. With a `journal`, the upload can be
+/// resumed if it is interrupted. With `atomic`, each file is written to a temporary file first, so
+/// files are never left half-written. With `changes`, only the files that changed are uploaded, and
+/// those that were deleted are deleted from the badge.
+#[allow(clippy::too_many_arguments)]
+pub async fn push(
+    badge: &Badge,
+    cache: &MetadataCache,
+    local: &Path,
+    remote: &BadgePath,
+    rules: &ExcludeRules,
+    text: bool,
+    journal: Option<&Journal>,
+    atomic: bool,
+    changes: Option<&Changes>,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    create_dir(badge, remote).await;
+    let mut entries = walk(local, rules)?;
+    if let Some(changes) = changes {
+        retain_changed(&mut entries, changes);
+        delete_removed(badge, cache, local, remote, rules, changes).await?;
+    }
+
+    let mut uploaded = Vec::new();
+    for entry in entries {
+        let target = remote.join(&entry.relative)?;
+        if entry.is_dir {
+            create_dir(badge, &target).await;
+            continue;
+        }
+
+        let mut data = fs::read(&entry.path)?;
+        if text && text::is_text(&data) {
+            data = text::to_lf(&data);
+        }
+
+        match journal {
+            Some(journal) => resume::upload(badge, journal, &target, &data).await?,
+            None if atomic => atomic::write_file(badge, &target, &data).await?,
+            None => badge.write_file(&target, &data).await?,
+        }
+        cache.record_file(&target, &data);
+        output.transferred(&target, data.len());
+        uploaded.push((target, entry.path));
+    }
+    sourcemap::record_all(
+        uploaded
+            .iter()
+            .map(|(remote, local)| (remote, local.as_path())),
+    );
+
+    if let Some(journal) = journal {
+        journal.finish(Direction::Upload, remote)?;
+    }
+
+    notify::notify(
+        "Push finished",
+        &format!("Uploaded {} files to {}", uploaded.len(), remote),
+    );
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Python bindings, built with the `python` feature:
+//!
+//! ```python
+//! import cz2020_usbtool
+//! badge = cz2020_usbtool.Badge()
+//! badge.put("/flash/hello.py", b"print('hello')")
+//! print(badge.ls("/flash"))
+//! ```
+//!
+//! Every method blocks until the badge answered, but releases the GIL meanwhile, so other Python
+//! threads keep running. Errors are raised as `OSError`.
+
+// The macros of this pyo3 version expand to impls inside functions
+#![allow(non_local_definitions)]
+
+use crate::{
+    client::BadgeFs,
+    cmds::FsEntry,
+    device::{self, Device},
+    path::BadgePath,
+    run,
+};
+use pyo3::{exceptions::PyOSError, prelude::*, types::PyBytes};
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::broadcast::{self, RecvError},
+    time::timeout,
+};
+
+fn to_py_err(e: Box<dyn Error + Send + Sync>) -> PyErr {
+    PyOSError::new_err(e.to_string())
+}
+
+fn path(path: &str) -> PyResult<BadgePath> {
+    BadgePath::new(path).map_err(|e| to_py_err(e.into()))
+}
+
+/// A connection to a badge
+#[pyclass]
+struct Badge {
+    badge: Arc<device::Badge>,
+    client: BadgeFs,
+    output: Mutex<broadcast::Receiver<String>>,
+    runtime: Mutex<Runtime>,
+}
+
+impl Badge {
+    fn block_on<T>(
+        &self,
+        future: impl std::future::Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+    ) -> PyResult<T> {
+        self.runtime
+            .lock()
+            .unwrap()
+            .block_on(future)
+            .map_err(to_py_err)
+    }
+}
+
+#[pymethods]
+impl Badge {
+    /// Connects to the badge with the given serial number or `<bus>-<address>`, or to the first
+    /// badge that is found
+    #[new]
+    #[pyo3(signature = (device = None))]
+    fn new(py: Python<'_>, device: Option<&str>) -> PyResult<Badge> {
+        let context = rusb::Context::new().map_err(|e| to_py_err(e.into()))?;
+        let device = Device::find(&context, device).map_err(|e| to_py_err(e.into()))?;
+        let badge = device::Badge::builder().build(device).start();
+        let output = badge.subscribe();
+        let badge = Badge {
+            client: BadgeFs::new(badge.clone()),
+            badge,
+            output: Mutex::new(output),
+            runtime: Mutex::new(Runtime::new()?),
+        };
+
+        py.allow_threads(|| {
+            badge.block_on(async {
+                badge.badge.heartbeat().await?;
+                badge.badge.probe().await;
+                Ok(())
+            })
+        })?;
+        Ok(badge)
+    }
+
+    /// Lists a directory as `(kind, name)` tuples, where kind is `"f"`, `"d"` or the type the
+    /// firmware sent for other entries
+    fn ls(&self, py: Python<'_>, dir: &str) -> PyResult<Vec<(String, String)>> {
+        let mut entries = self.client.read_dir(path(dir)?);
+        py.allow_threads(|| {
+            self.block_on(async {
+                let mut result = Vec::new();
+                while let Some(entry) = entries.next_entry().await? {
+                    let kind = match &entry {
+                        FsEntry::File(_) => 'f',
+                        FsEntry::Directory(_) => 'd',
+                        FsEntry::Other(kind, _) => *kind,
+                    };
+                    result.push((kind.to_string(), entry.name().to_owned()));
+                }
+                Ok(result)
+            })
+        })
+    }
+
+    /// Fetches a file
+    fn get<'py>(&self, py: Python<'py>, file: &str) -> PyResult<&'py PyBytes> {
+        let file = path(file)?;
+        let data = py.allow_threads(|| self.block_on(self.badge.fetch_file(&file)))?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Writes a file, replacing it if it exists
+    fn put(&self, py: Python<'_>, file: &str, data: &[u8]) -> PyResult<()> {
+        let file = path(file)?;
+        py.allow_threads(|| self.block_on(self.badge.write_file(&file, data)))
+    }
+
+    /// Runs an app, and raises if it crashes while starting
+    #[pyo3(signature = (app, args = Vec::new()))]
+    fn run(&self, py: Python<'_>, app: &str, args: Vec<String>) -> PyResult<()> {
+        let app = path(app)?;
+        py.allow_threads(|| self.block_on(run::run(&self.badge, &app, &args)))
+    }
+
+    /// Sends input to the Python shell
+    fn write_serial(&self, py: Python<'_>, data: &[u8]) -> PyResult<()> {
+        py.allow_threads(|| self.block_on(self.badge.serial_in(data)))
+    }
+
+    /// Returns the output of the badge since the last call, waiting up to `wait` seconds for
+    /// output if there is none yet
+    #[pyo3(signature = (wait = 0.0))]
+    fn read_serial<'py>(&self, py: Python<'py>, wait: f64) -> PyResult<&'py PyBytes> {
+        let data = py.allow_threads(|| {
+            let mut output = self.output.lock().unwrap();
+            let mut data = Vec::new();
+            self.block_on(async {
+                let mut limit = Duration::from_secs_f64(wait.max(0.));
+                loop {
+                    match timeout(limit, output.recv()).await {
+                        // One char per byte
+                        Ok(Ok(text)) => data.extend(text.chars().map(|c| c as u8)),
+                        Ok(Err(RecvError::Lagged(_))) => {}
+                        Ok(Err(RecvError::Closed)) | Err(_) => break,
+                    }
+                    // Only wait for the first output, then take what has arrived already
+                    limit = Duration::from_millis(1);
+                }
+                Ok(())
+            })?;
+            Ok::<_, PyErr>(data)
+        })?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Shuts down the connection. The badge can't be used afterwards.
+    fn close(&self, py: Python<'_>) {
+        py.allow_threads(|| self.badge.close());
+    }
+}
+
+#[pymodule]
+fn cz2020_usbtool(_py: Python<'_>, module: &PyModule) -> PyResult<()> {
+    module.add_class::<Badge>()?;
+    Ok(())
+}
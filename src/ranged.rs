@@ -0,0 +1,77 @@
+use crate::{device::Badge, path::BadgePath, repl};
+use std::error::Error;
+
+/// Bytes read per round trip
+const CHUNK_SIZE: usize = 4096;
+
+fn count_lines(data: &[u8]) -> usize {
+    data.iter().filter(|&&b| b == b'\n').count()
+}
+
+/// The first `lines` lines of a file, reading only as much of it as needed
+pub async fn head(
+    badge: &Badge,
+    path: &BadgePath,
+    lines: usize,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    if lines == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut data = Vec::new();
+    while count_lines(&data) < lines {
+        let chunk = repl::read_range(badge, path, data.len() as u64, CHUNK_SIZE).await?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        data.extend_from_slice(&chunk);
+    }
+
+    let end = data
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| b == b'\n')
+        .nth(lines - 1)
+        .map(|(i, _)| i + 1)
+        .unwrap_or(data.len());
+    data.truncate(end);
+
+    Ok(data)
+}
+
+/// The last `lines` lines of a file, reading only as much of it as needed
+pub async fn tail(
+    badge: &Badge,
+    path: &BadgePath,
+    lines: usize,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    if lines == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut start = repl::file_len(badge, path).await?;
+    let mut data = Vec::new();
+
+    // The line starts after the newline before it. A trailing newline ends the last line, so it
+    // doesn't count.
+    let newlines = |data: &[u8]| lines + usize::from(data.ends_with(b"\n"));
+    while start > 0 && count_lines(&data) < newlines(&data) {
+        let len = (CHUNK_SIZE as u64).min(start);
+        start -= len;
+        let mut chunk = repl::read_range(badge, path, start, len as usize).await?;
+        chunk.extend_from_slice(&data);
+        data = chunk;
+    }
+
+    let begin = data
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, &b)| b == b'\n')
+        .nth(newlines(&data) - 1)
+        .map(|(i, _)| i + 1)
+        .unwrap_or(0);
+
+    Ok(data[begin..].to_vec())
+}
@@ -0,0 +1,421 @@
+//! Formatting of the output of the badge for `shell` and `monitor`
+
+use crate::{
+    hooks::Hooks,
+    loglevel::{Level, LogParser},
+    traceback::Tracebacks,
+};
+use regex::Regex;
+use std::{
+    fs::File,
+    io::{self, Write},
+};
+
+pub(crate) const ESC: char = '\u{1b}';
+pub(crate) const RESET: &str = "\u{1b}[0m";
+
+/// Whether `sequence`, which starts with ESC, is complete
+fn escape_complete(sequence: &str) -> bool {
+    let mut chars = sequence.chars().skip(1);
+    match chars.next() {
+        None => false,
+        // CSI sequences end with a byte in the range @ to ~
+        Some('[') => chars.any(|c| ('@'..='~').contains(&c)),
+        Some(_) => true,
+    }
+}
+
+/// Prefixes every line with the time it started arriving on the host. Output arrives in arbitrary
+/// chunks, so lines may be split across calls, and so may ANSI escape sequences. The timestamp is
+/// printed without the colors of the app, which are restored after it.
+pub struct Timestamps {
+    at_line_start: bool,
+    /// An escape sequence that hasn't been completed yet
+    escape: String,
+    /// The last SGR (color) sequence, which is still in effect unless it was a reset
+    sgr: Option<String>,
+}
+
+impl Default for Timestamps {
+    fn default() -> Timestamps {
+        Timestamps::new()
+    }
+}
+
+impl Timestamps {
+    pub fn new() -> Timestamps {
+        Timestamps {
+            at_line_start: true,
+            escape: String::new(),
+            sgr: None,
+        }
+    }
+
+    fn prefix(&self, out: &mut String) {
+        let now = time::now();
+        if self.sgr.is_some() {
+            out.push_str(RESET);
+        }
+
+        out.push_str(&format!(
+            "[{}.{:03}] ",
+            now.strftime("%H:%M:%S").unwrap(),
+            now.tm_nsec / 1_000_000
+        ));
+
+        if let Some(sgr) = &self.sgr {
+            out.push_str(sgr);
+        }
+    }
+
+    pub fn format(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            if !self.escape.is_empty() || c == ESC {
+                self.escape.push(c);
+                if escape_complete(&self.escape) {
+                    if self.escape.starts_with("\u{1b}[") && self.escape.ends_with('m') {
+                        self.sgr = match self.escape.as_str() {
+                            "\u{1b}[m" | RESET => None,
+                            sgr => Some(sgr.to_owned()),
+                        };
+                    }
+
+                    out.push_str(&self.escape);
+                    self.escape.clear();
+                }
+
+                continue;
+            }
+
+            // A line only gets a timestamp once something arrives for it
+            if self.at_line_start && c != '\r' {
+                self.prefix(&mut out);
+                self.at_line_start = false;
+            }
+
+            out.push(c);
+            if c == '\n' {
+                self.at_line_start = true;
+            }
+        }
+
+        out
+    }
+}
+
+/// Removes ANSI escape sequences, so colored output can be matched against patterns
+pub fn strip_escapes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut escape = String::new();
+    for c in text.chars() {
+        if !escape.is_empty() || c == ESC {
+            escape.push(c);
+            if escape_complete(&escape) {
+                escape.clear();
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+/// Only lets through lines that match `grep` and don't match `exclude`, and optionally log lines up
+/// to a level. Lines are held back until they are complete.
+pub struct LineFilter {
+    grep: Option<Regex>,
+    exclude: Option<Regex>,
+    parser: LogParser,
+    /// Lines without a level count as info
+    max_level: Option<Level>,
+    colors: bool,
+    structured: bool,
+    partial: String,
+}
+
+impl LineFilter {
+    pub fn new(grep: Option<Regex>, exclude: Option<Regex>) -> LineFilter {
+        LineFilter {
+            grep,
+            exclude,
+            parser: LogParser::new(),
+            max_level: None,
+            colors: false,
+            structured: false,
+            partial: String::new(),
+        }
+    }
+
+    /// Only lets through lines of `level` and the levels that are more severe
+    pub fn max_level(mut self, level: Option<Level>) -> LineFilter {
+        self.max_level = level;
+        self
+    }
+
+    /// Colors log lines by their level, unless the firmware colored them already
+    pub fn colors(mut self) -> LineFilter {
+        self.colors = true;
+        self
+    }
+
+    /// Prints every line as `level module message`, separated by tabs, with `-` for what's unknown
+    pub fn structured(mut self) -> LineFilter {
+        self.structured = true;
+        self
+    }
+
+    fn matches(&self, line: &str) -> bool {
+        if let Some(grep) = &self.grep {
+            if !grep.is_match(line) {
+                return false;
+            }
+        }
+
+        if let Some(exclude) = &self.exclude {
+            if exclude.is_match(line) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn format(&self, line: &str, out: &mut String) {
+        let plain = strip_escapes(line);
+        if !self.matches(&plain) {
+            return;
+        }
+
+        if self.max_level.is_none() && !self.colors && !self.structured {
+            out.push_str(line);
+            return;
+        }
+
+        let record = self.parser.parse(plain.trim_end_matches(&['\r', '\n'][..]));
+        if let Some(max_level) = self.max_level {
+            if record.level.unwrap_or(Level::Info) > max_level {
+                return;
+            }
+        }
+
+        if self.structured {
+            out.push_str(&format!(
+                "{}\t{}\t{}\n",
+                record
+                    .level
+                    .map(|level| level.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                record.module.as_deref().unwrap_or("-"),
+                record.message
+            ));
+            return;
+        }
+
+        match record.level.and_then(Level::color) {
+            Some(color) if self.colors && !line.contains(ESC) => {
+                let content = line.trim_end_matches(&['\r', '\n'][..]);
+                out.push_str(color);
+                out.push_str(content);
+                out.push_str(RESET);
+                out.push_str(&line[content.len()..]);
+            }
+            _ => out.push_str(line),
+        }
+    }
+
+    pub fn filter(&mut self, text: &str) -> String {
+        self.partial.push_str(text);
+        let mut out = String::new();
+        while let Some(i) = self.partial.find('\n') {
+            let line = self.partial.drain(..=i).collect::<String>();
+            self.format(&line, &mut out);
+        }
+
+        out
+    }
+}
+
+/// The bytes the badge sent. Output is decoded with one char per byte, so this is lossless.
+pub fn raw_bytes(text: &str) -> Vec<u8> {
+    text.chars().map(|c| c as u8).collect()
+}
+
+const HEX_ROW: usize = 16;
+
+/// Renders bytes as rows of an offset, hex and ASCII. Rows are printed once they are complete.
+pub struct HexDump {
+    offset: usize,
+    row: Vec<u8>,
+}
+
+impl Default for HexDump {
+    fn default() -> HexDump {
+        HexDump::new()
+    }
+}
+
+impl HexDump {
+    pub fn new() -> HexDump {
+        HexDump {
+            offset: 0,
+            row: Vec::with_capacity(HEX_ROW),
+        }
+    }
+
+    pub fn format(&mut self, data: &[u8]) -> String {
+        let mut out = String::new();
+        for &b in data {
+            self.row.push(b);
+            if self.row.len() < HEX_ROW {
+                continue;
+            }
+
+            out.push_str(&format!("{:08x} ", self.offset));
+            for (i, b) in self.row.iter().enumerate() {
+                if i % 8 == 0 {
+                    out.push(' ');
+                }
+                out.push_str(&format!("{:02x} ", b));
+            }
+
+            let ascii = self
+                .row
+                .iter()
+                .map(|&b| {
+                    if b == b' ' || b.is_ascii_graphic() {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            out.push_str(&format!(" |{}|\n", ascii));
+
+            self.offset += HEX_ROW;
+            self.row.clear();
+        }
+
+        out
+    }
+}
+
+/// Prints the output of the badge to stdout, optionally filtered and timestamped, or as a hex dump
+pub struct Renderer {
+    log: Option<File>,
+    filter: Option<LineFilter>,
+    hex: Option<HexDump>,
+    timestamps: Option<Timestamps>,
+    hooks: Option<Hooks>,
+    tracebacks: Option<Tracebacks>,
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        Renderer::new()
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer {
+            log: None,
+            filter: None,
+            hex: None,
+            timestamps: None,
+            hooks: None,
+            tracebacks: None,
+        }
+    }
+
+    /// Writes the raw output to `log`, before it is filtered
+    pub fn log(mut self, log: File) -> Renderer {
+        self.log = Some(log);
+        self
+    }
+
+    pub fn filter(mut self, filter: LineFilter) -> Renderer {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn hex(mut self) -> Renderer {
+        self.hex = Some(HexDump::new());
+        self
+    }
+
+    pub fn timestamps(mut self) -> Renderer {
+        self.timestamps = Some(Timestamps::new());
+        self
+    }
+
+    /// Runs `hooks` on the output, before it is filtered
+    pub fn hooks(mut self, hooks: Hooks) -> Renderer {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Highlights tracebacks, after the output is filtered. Ignored for hex dumps.
+    pub fn tracebacks(mut self, tracebacks: Tracebacks) -> Renderer {
+        self.tracebacks = Some(tracebacks);
+        self
+    }
+
+    pub fn write(&mut self, text: &str) -> io::Result<()> {
+        if let Some(log) = &mut self.log {
+            log.write_all(&raw_bytes(text))?;
+        }
+        if let Some(hooks) = &mut self.hooks {
+            hooks.feed(text);
+        }
+
+        let mut text = match (&mut self.hex, &mut self.filter) {
+            (Some(hex), _) => hex.format(&raw_bytes(text)),
+            (None, Some(filter)) => filter.filter(text),
+            (None, None) => text.to_owned(),
+        };
+        if let (None, Some(tracebacks)) = (&self.hex, &mut self.tracebacks) {
+            text = tracebacks.format(&text);
+        }
+        if let Some(timestamps) = &mut self.timestamps {
+            text = timestamps.format(&text);
+        }
+
+        let mut stdout = io::stdout();
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_a_record_split_across_outputs() {
+        let mut filter = LineFilter::new(None, None).max_level(Some(Level::Warning));
+        assert_eq!(filter.filter("I (1) boot: sta"), "");
+        assert_eq!(filter.filter("rted\r\nW (2) wi"), "");
+        assert_eq!(filter.filter("fi: slow\r\n"), "W (2) wifi: slow\r\n");
+    }
+
+    #[test]
+    fn structures_a_record_split_across_outputs() {
+        let mut filter = LineFilter::new(None, None).structured();
+        assert_eq!(filter.filter("WARNING:n"), "");
+        assert_eq!(
+            filter.filter("et:retry\r\nplain\n"),
+            "warning\tnet\tretry\n-\t-\tplain\n"
+        );
+    }
+
+    #[test]
+    fn colors_by_level() {
+        let mut filter = LineFilter::new(None, None).colors();
+        assert_eq!(
+            filter.filter("E (1) app: failed\r\nI (2) app: fine\r\n"),
+            "\u{1b}[31mE (1) app: failed\u{1b}[0m\r\nI (2) app: fine\r\n"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use crate::{device::Badge, path::BadgePath};
+use std::{error::Error, time::Duration};
+use thiserror::Error;
+use tokio::{sync::broadcast::RecvError, time::timeout};
+
+// The REPL echoes the code we send, so the markers are built with '+' to keep them out of the echo
+const START: &str = "<<cz>>";
+const ERROR: &str = "<<cz!>>";
+const END: &str = "<</cz>>";
+
+const RANGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum ReplError {
+    #[error("The Python shell didn't finish running the code in time. Is an app running?")]
+    Timeout,
+
+    #[error("Python raised an exception: {}", .0)]
+    Exception(String),
+
+    #[error("The connection to the badge was closed")]
+    Closed,
+}
+
+/// Formats `s` as a Python string literal
+pub fn python_literal(s: &str) -> String {
+    let mut literal = String::from("'");
+    for c in s.chars() {
+        match c {
+            '\\' => literal.push_str("\\\\"),
+            '\'' => literal.push_str("\\'"),
+            '\n' => literal.push_str("\\n"),
+            c if c == ' ' || c.is_ascii_graphic() => literal.push(c),
+            c => literal.push_str(&format!("\\U{:08x}", c as u32)),
+        }
+    }
+
+    literal.push('\'');
+    literal
+}
+
+fn marker(marker: &str) -> String {
+    format!("'{}'+'{}'", &marker[..2], &marker[2..])
+}
+
+/// Runs `code` in the Python shell on the badge and returns everything it printed. The shell must
+/// be idle, so an app that is running has to be interrupted first.
+pub async fn exec(
+    badge: &Badge,
+    code: &str,
+    limit: Duration,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let wrapped = format!(
+        "print({})\ntry:\n exec({})\nexcept Exception as e:\n print({}+repr(e))\nprint({})",
+        marker(START),
+        python_literal(code),
+        marker(ERROR),
+        marker(END)
+    );
+
+    let mut output = badge.subscribe();
+    badge
+        .serial_in(format!("exec({})\r\n", python_literal(&wrapped)))
+        .await?;
+
+    let mut text = String::new();
+    let result = timeout(limit, async {
+        loop {
+            match output.recv().await {
+                Ok(received) => {
+                    text.push_str(&received);
+                    if text.contains(END) {
+                        return Ok(());
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Err(ReplError::Closed),
+            }
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => Err(e)?,
+        Err(_) => Err(ReplError::Timeout)?,
+    }
+
+    let text = text.replace("\r\n", "\n");
+    let start = text.find(START).map(|i| i + START.len() + 1).unwrap_or(0);
+    let end = text.find(END).unwrap();
+    let printed = &text[start.min(end)..end];
+    match printed.find(ERROR) {
+        Some(i) => Err(ReplError::Exception(
+            printed[i + ERROR.len()..].trim().to_owned(),
+        ))?,
+        None => Ok(printed.to_owned()),
+    }
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, ReplError> {
+    let hex = hex.trim().as_bytes();
+    if !hex.len().is_multiple_of(2) {
+        return Err(ReplError::Exception(format!(
+            "invalid hex output of length {}",
+            hex.len()
+        )));
+    }
+
+    hex.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| ReplError::Exception(format!("invalid hex output {:?}", pair)))
+        })
+        .collect()
+}
+
+/// The size of a file on the badge, without transferring it
+pub async fn file_len(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import uos\nprint(uos.stat({})[6])",
+        python_literal(path.as_str())
+    );
+    Ok(exec(badge, &code, RANGE_TIMEOUT).await?.trim().parse()?)
+}
+
+/// The SHA-256 of a file on the badge, hex-encoded like `cache::hash`, or `None` if it doesn't exist
+pub async fn file_hash(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import uhashlib, ubinascii
+try:
+ f = open({}, 'rb')
+except OSError:
+ f = None
+ print('missing')
+if f:
+ h = uhashlib.sha256()
+ while True:
+  b = f.read(4096)
+  if not b:
+   break
+  h.update(b)
+ f.close()
+ print(ubinascii.hexlify(h.digest()).decode())",
+        python_literal(path.as_str())
+    );
+
+    match exec(badge, &code, RANGE_TIMEOUT).await?.trim() {
+        "missing" => Ok(None),
+        hash => Ok(Some(hash.to_owned())),
+    }
+}
+
+/// Reads up to `len` bytes at `offset` of a file on the badge, without transferring the rest of it
+pub async fn read_range(
+    badge: &Badge,
+    path: &BadgePath,
+    offset: u64,
+    len: usize,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import ubinascii
+f = open({}, 'rb')
+f.seek({})
+print(ubinascii.hexlify(f.read({})).decode())
+f.close()",
+        python_literal(path.as_str()),
+        offset,
+        len
+    );
+
+    Ok(from_hex(&exec(badge, &code, RANGE_TIMEOUT).await?)?)
+}
+
+/// Bytes sent per snippet when writing through the shell. Each byte takes two characters of hex.
+const WRITE_CHUNK_SIZE: usize = 1024;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Appends `data` to a file on the badge, creating it if needed, without transferring what is
+/// already in it
+pub async fn append(
+    badge: &Badge,
+    path: &BadgePath,
+    data: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for chunk in data.chunks(WRITE_CHUNK_SIZE) {
+        let code = format!(
+            "import ubinascii
+f = open({}, 'ab')
+f.write(ubinascii.unhexlify('{}'))
+f.close()",
+            python_literal(path.as_str()),
+            to_hex(chunk)
+        );
+        exec(badge, &code, RANGE_TIMEOUT).await?;
+    }
+
+    Ok(())
+}
+
+/// Writes `data` at `offset` of an existing file on the badge, without transferring the rest of
+/// it. A gap between the end of the file and `offset` is filled with zeroes.
+pub async fn write_range(
+    badge: &Badge,
+    path: &BadgePath,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut offset = offset;
+    for chunk in data.chunks(WRITE_CHUNK_SIZE) {
+        let code = format!(
+            "import ubinascii
+f = open({}, 'r+b')
+n = f.seek(0, 2)
+if n < {1}:
+ f.write(bytes({1} - n))
+f.seek({1})
+f.write(ubinascii.unhexlify('{2}'))
+f.close()",
+            python_literal(path.as_str()),
+            offset,
+            to_hex(chunk)
+        );
+        exec(badge, &code, RANGE_TIMEOUT).await?;
+        offset += chunk.len() as u64;
+    }
+
+    Ok(())
+}
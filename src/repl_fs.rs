@@ -0,0 +1,166 @@
+//! The file operations of `Badge`, implemented with `uos` calls in the Python shell instead of the
+//! file commands of the USB protocol
+
+use crate::{
+    cmds::{DirectoryListingResponse, FsEntry},
+    device::Badge,
+    path::BadgePath,
+    repl::{self, python_literal},
+};
+use std::{error::Error, time::Duration};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes read per round trip
+const CHUNK_SIZE: usize = 4096;
+
+/// Directory entries listed per round trip, so a large directory doesn't have to fit in the memory
+/// of the badge at once
+const LISTING_PAGE: usize = 100;
+
+pub async fn fetch_dir(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<DirectoryListingResponse, Box<dyn Error + Send + Sync>> {
+    let mut entries = Vec::new();
+    loop {
+        let code = format!(
+            "import uos
+try:
+ l = uos.ilistdir({})
+except OSError:
+ l = None
+if l is None:
+ print('missing')
+else:
+ n = 0
+ for e in l:
+  if n >= {}:
+   break
+  if n >= {}:
+   print(('d' if e[1] == 0x4000 else 'f') + e[0])
+  n += 1",
+            python_literal(path.as_str()),
+            entries.len() + LISTING_PAGE,
+            entries.len()
+        );
+
+        let printed = repl::exec(badge, &code, TIMEOUT).await?;
+        if printed.trim() == "missing" {
+            return Ok(DirectoryListingResponse::DirectoryNotFound);
+        }
+
+        let listed = entries.len();
+        entries.extend(
+            printed
+                .lines()
+                .filter_map(|line| match line.chars().next() {
+                    Some('d') => Some(FsEntry::Directory(line[1..].to_owned())),
+                    Some('f') => Some(FsEntry::File(line[1..].to_owned())),
+                    _ => None,
+                }),
+        );
+        if entries.len() - listed < LISTING_PAGE {
+            break;
+        }
+    }
+
+    Ok(DirectoryListingResponse::Found {
+        requested: path.to_string(),
+        entries,
+    })
+}
+
+pub async fn fetch_file(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut data = Vec::new();
+    loop {
+        let chunk = repl::read_range(badge, path, data.len() as u64, CHUNK_SIZE).await?;
+        data.extend_from_slice(&chunk);
+        if chunk.len() < CHUNK_SIZE {
+            return Ok(data);
+        }
+    }
+}
+
+pub async fn create_dir(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = format!("import uos\nuos.mkdir({})", python_literal(path.as_str()));
+    repl::exec(badge, &code, TIMEOUT).await?;
+    Ok(())
+}
+
+pub async fn create_file(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = format!("open({}, 'wb').close()", python_literal(path.as_str()));
+    repl::exec(badge, &code, TIMEOUT).await?;
+    Ok(())
+}
+
+pub async fn copy_file(
+    badge: &Badge,
+    from: &BadgePath,
+    to: &BadgePath,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "f = open({}, 'rb')
+g = open({}, 'wb')
+while True:
+ b = f.read({})
+ if not b:
+  break
+ g.write(b)
+f.close()
+g.close()",
+        python_literal(from.as_str()),
+        python_literal(to.as_str()),
+        CHUNK_SIZE
+    );
+    repl::exec(badge, &code, TIMEOUT).await?;
+    Ok(())
+}
+
+pub async fn move_file(
+    badge: &Badge,
+    from: &BadgePath,
+    to: &BadgePath,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import uos\nuos.rename({}, {})",
+        python_literal(from.as_str()),
+        python_literal(to.as_str())
+    );
+    repl::exec(badge, &code, TIMEOUT).await?;
+    Ok(())
+}
+
+pub async fn write_file(
+    badge: &Badge,
+    path: &BadgePath,
+    data: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    create_file(badge, path).await?;
+    repl::append(badge, path, data).await
+}
+
+pub async fn delete_path(
+    badge: &Badge,
+    path: &BadgePath,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = format!(
+        "import uos
+try:
+ uos.remove({0})
+except OSError:
+ uos.rmdir({0})",
+        python_literal(path.as_str())
+    );
+    repl::exec(badge, &code, TIMEOUT).await?;
+    Ok(())
+}
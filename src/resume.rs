@@ -0,0 +1,316 @@
+//! Transfers that can be resumed after they were interrupted. The file is sent or fetched in chunks
+//! through the Python shell, and after every chunk the progress is recorded in a journal next to the
+//! metadata cache. Running the same transfer again continues where the journal left off, as long as
+//! the file hasn't changed in the meantime.
+
+use crate::{
+    cache::{self, hash},
+    client::ClientError,
+    device::{Badge, BadgeError},
+    path::BadgePath,
+    repl,
+};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::{debug, info, warn};
+
+/// Bytes transferred between updates of the journal, at first. The size adapts to how well the
+/// transfer goes, between `MIN_CHUNK_SIZE` and `MAX_CHUNK_SIZE`.
+const CHUNK_SIZE: usize = 16 * 1024;
+const MIN_CHUNK_SIZE: usize = 1024;
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks in a row that have to succeed before the chunk size grows
+const GROW_AFTER: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Upload => "up",
+            Direction::Download => "down",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Progress {
+    /// The hash of the complete file, to tell whether it changed since the transfer started
+    hash: String,
+    /// The number of bytes transferred
+    offset: u64,
+}
+
+/// The progress of unfinished transfers to and from a badge, persisted between invocations.
+///
+/// Stored as one line per file: `direction hash offset path`, separated by tabs.
+pub struct Journal {
+    file: Option<PathBuf>,
+    entries: Mutex<BTreeMap<(Direction, BadgePath), Progress>>,
+}
+
+fn parse_line(line: &str) -> Option<((Direction, BadgePath), Progress)> {
+    let mut fields = line.splitn(4, '\t');
+    let direction = match fields.next()? {
+        "up" => Direction::Upload,
+        "down" => Direction::Download,
+        _ => return None,
+    };
+    let hash = fields.next()?.to_owned();
+    let offset = fields.next()?.parse().ok()?;
+    let path = BadgePath::new(fields.next()?).ok()?;
+
+    Some(((direction, path), Progress { hash, offset }))
+}
+
+impl Journal {
+    /// Loads the journal of the badge with the given id. A missing journal is empty.
+    pub fn load(id: &str) -> Journal {
+        let file = cache::cache_dir().map(|dir| dir.join(format!("{}.journal", id)));
+        let mut entries = BTreeMap::new();
+        match file.as_ref() {
+            Some(file) => {
+                if let Ok(contents) = fs::read_to_string(file) {
+                    entries.extend(contents.lines().filter_map(parse_line));
+                }
+            }
+            None => warn!(
+                "No cache directory found for the transfer journal, set $XDG_CACHE_HOME or $HOME"
+            ),
+        }
+
+        debug!(
+            "Loaded {} unfinished transfers from {:?}",
+            entries.len(),
+            file
+        );
+        Journal {
+            file,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let mut contents = Vec::new();
+        for ((direction, path), progress) in self.entries.lock().unwrap().iter() {
+            writeln!(
+                contents,
+                "{}\t{}\t{}\t{}",
+                direction, progress.hash, progress.offset, path
+            )?;
+        }
+
+        fs::create_dir_all(file.parent().unwrap())?;
+        let tmp = file.with_extension("journal.tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, file)
+    }
+
+    /// Where the transfer of the file with `hash` left off, or 0 if it didn't start or the file
+    /// changed
+    fn offset(&self, direction: Direction, path: &BadgePath, hash: &str) -> u64 {
+        match self.entries.lock().unwrap().get(&(direction, path.clone())) {
+            Some(progress) if progress.hash == hash => progress.offset,
+            _ => 0,
+        }
+    }
+
+    fn record(
+        &self,
+        direction: Direction,
+        path: &BadgePath,
+        hash: &str,
+        offset: u64,
+    ) -> io::Result<()> {
+        self.entries.lock().unwrap().insert(
+            (direction, path.clone()),
+            Progress {
+                hash: hash.to_owned(),
+                offset,
+            },
+        );
+        self.save()
+    }
+
+    /// Forgets the transfers in `direction` of `path` and everything below it, once they are all
+    /// done
+    pub fn finish(&self, direction: Direction, path: &BadgePath) -> io::Result<()> {
+        let prefix = format!("{}/", path);
+        self.entries.lock().unwrap().retain(|(d, other), _| {
+            *d != direction || (other != path && !other.as_str().starts_with(&prefix))
+        });
+        self.save()
+    }
+}
+
+/// The size of the next chunk. Halves when a chunk times out, and doubles again after a few chunks
+/// went through without trouble, so flaky links still make progress and good ones stay fast.
+struct ChunkSize {
+    size: usize,
+    clean: u32,
+}
+
+impl ChunkSize {
+    fn new() -> ChunkSize {
+        ChunkSize {
+            size: CHUNK_SIZE,
+            clean: 0,
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.clean += 1;
+        if self.clean >= GROW_AFTER && self.size < MAX_CHUNK_SIZE {
+            self.size *= 2;
+            self.clean = 0;
+            debug!("Growing chunks to {} bytes", self.size);
+        }
+    }
+
+    /// Shrinks the chunks after one failed. Returns false if they can't get any smaller.
+    fn failed(&mut self) -> bool {
+        self.clean = 0;
+        if self.size <= MIN_CHUNK_SIZE {
+            return false;
+        }
+
+        self.size /= 2;
+        true
+    }
+}
+
+fn is_timeout(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<repl::ReplError>(),
+        Some(repl::ReplError::Timeout)
+    ) || matches!(
+        e.downcast_ref::<BadgeError>(),
+        Some(BadgeError::Timeout { .. })
+    )
+}
+
+/// Writes `data` to `path`, continuing an earlier upload of the same data if the journal has one.
+/// A file that was uploaded completely before isn't sent again until `finish` is called.
+pub async fn upload(
+    badge: &Badge,
+    journal: &Journal,
+    path: &BadgePath,
+    data: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let hash = hash(data);
+    let mut offset = journal.offset(Direction::Upload, path, &hash);
+    if offset > 0 {
+        // The file may have been changed or removed on the badge since
+        match repl::file_len(badge, path).await {
+            Ok(len) if len >= offset => info!("Resuming upload of {} at {} bytes", path, offset),
+            _ => offset = 0,
+        }
+    }
+
+    if offset == 0 {
+        // Creates or truncates the file
+        let first = &data[..data.len().min(CHUNK_SIZE)];
+        badge.write_file(path, first).await?;
+        offset = first.len() as u64;
+        journal.record(Direction::Upload, path, &hash, offset)?;
+    }
+
+    let mut size = ChunkSize::new();
+    while (offset as usize) < data.len() {
+        let end = data.len().min(offset as usize + size.size);
+        match repl::write_range(badge, path, offset, &data[offset as usize..end]).await {
+            Ok(()) => size.succeeded(),
+            // Writing a range again is harmless, so retry with a smaller chunk
+            Err(e) if is_timeout(&*e) && size.failed() => {
+                warn!(
+                    "Writing {} timed out, retrying with {} byte chunks",
+                    path, size.size
+                );
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        offset = end as u64;
+        journal.record(Direction::Upload, path, &hash, offset)?;
+    }
+
+    Ok(())
+}
+
+/// Reads `path` into `dest`, continuing an earlier download into `dest` if the journal has one.
+/// Until the download is complete, the data is kept in `dest` with `.part` appended.
+pub async fn download(
+    badge: &Badge,
+    journal: &Journal,
+    path: &BadgePath,
+    dest: &Path,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let hash = repl::file_hash(badge, path)
+        .await?
+        .ok_or_else(|| ClientError::NotFound(path.clone()))?;
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let mut data = Vec::new();
+    let offset = journal.offset(Direction::Download, path, &hash);
+    if offset > 0 {
+        if let Ok(existing) = fs::read(&part) {
+            if existing.len() as u64 >= offset {
+                info!("Resuming download of {} at {} bytes", path, offset);
+                data = existing;
+                data.truncate(offset as usize);
+            }
+        }
+    }
+
+    let mut file = fs::File::create(&part)?;
+    file.write_all(&data)?;
+    let mut size = ChunkSize::new();
+    loop {
+        let chunk = match repl::read_range(badge, path, data.len() as u64, size.size).await {
+            Ok(chunk) => {
+                size.succeeded();
+                chunk
+            }
+            Err(e) if is_timeout(&*e) && size.failed() => {
+                warn!(
+                    "Reading {} timed out, retrying with {} byte chunks",
+                    path, size.size
+                );
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if chunk.is_empty() {
+            break;
+        }
+
+        file.write_all(&chunk)?;
+        file.sync_data()?;
+        data.extend_from_slice(&chunk);
+        journal.record(Direction::Download, path, &hash, data.len() as u64)?;
+    }
+
+    fs::rename(&part, dest)?;
+    journal.finish(Direction::Download, path)?;
+
+    Ok(data)
+}
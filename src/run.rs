@@ -0,0 +1,135 @@
+use crate::{cache, device::Badge, path::BadgePath, repl::python_literal};
+use std::{
+    error::Error,
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tokio::{
+    sync::broadcast::{self, RecvError},
+    time::{timeout, Instant},
+};
+use tracing::{debug, warn};
+
+pub const TRACEBACK: &str = "Traceback (most recent call last):";
+
+/// How long to watch the output for a traceback after starting an app. Most apps that fail to
+/// import do so right away.
+const STARTUP_WAIT: Duration = Duration::from_secs(3);
+
+/// A traceback ends when the badge stops printing for this long
+pub const TRACEBACK_IDLE: Duration = Duration::from_millis(250);
+
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("The app failed to start:\n{}", .0)]
+    Failed(String),
+}
+
+/// Where the app that was last started on the badge with the given id is recorded, as its path
+/// and the time it started in seconds since the epoch, separated by a tab
+fn started_file(id: &str) -> Option<PathBuf> {
+    cache::cache_dir().map(|dir| dir.join(format!("{}.running", id)))
+}
+
+fn record_started(id: &str, path: &BadgePath) {
+    let file = match started_file(id) {
+        Some(file) => file,
+        None => return,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let result = fs::create_dir_all(file.parent().unwrap())
+        .and_then(|_| fs::write(&file, format!("{}\t{}\n", path, now)));
+    if let Err(e) = result {
+        warn!("Unable to record the running app in {:?}: {}", file, e);
+    }
+}
+
+/// The app that was last started with `run` on the badge with the given id, and when. It may have
+/// exited since.
+pub fn last_started(id: &str) -> Option<(BadgePath, SystemTime)> {
+    let contents = fs::read_to_string(started_file(id)?).ok()?;
+    let (path, time) = contents.trim_end().split_once('\t')?;
+    let time = UNIX_EPOCH + Duration::from_secs(time.parse().ok()?);
+    Some((BadgePath::new(path).ok()?, time))
+}
+
+/// Forgets the app that was last started, once it is known to have stopped
+pub fn forget_started(id: &str) {
+    if let Some(file) = started_file(id) {
+        let _ = fs::remove_file(file);
+    }
+}
+
+/// Collects the output of the badge for `limit`, or until a traceback has been printed completely
+async fn watch_for_traceback(
+    output: &mut broadcast::Receiver<String>,
+    limit: Duration,
+) -> Option<String> {
+    let deadline = Instant::now() + limit;
+    let mut text = String::new();
+    loop {
+        // Once a traceback starts, only wait for the rest of it
+        let wait = if text.contains(TRACEBACK) {
+            TRACEBACK_IDLE
+        } else {
+            deadline.saturating_duration_since(Instant::now())
+        };
+
+        match timeout(wait, output.recv()).await {
+            Ok(Ok(received)) => text.push_str(&received),
+            Ok(Err(RecvError::Lagged(_))) => {}
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+        }
+    }
+
+    text.find(TRACEBACK)
+        .map(|i| text[i..].replace("\r\n", "\n").trim_end().to_owned())
+}
+
+/// Runs an app and waits a few seconds to see whether it started. Without `args` the run command
+/// of the firmware is used. With `args`, the app is executed from the Python shell instead, with
+/// the arguments in `sys.argv`.
+pub async fn run(
+    badge: &Badge,
+    path: &BadgePath,
+    args: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let path = path.run_path()?;
+    let mut output = badge.subscribe();
+    if args.is_empty() {
+        badge.run_file(&path).await?;
+    } else {
+        let argv = std::iter::once(path.as_str())
+            .chain(args.iter().map(String::as_str))
+            .map(python_literal)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let code = format!(
+            "import sys\nsys.argv[:] = [{}]\nexec(open({}).read(), {{'__name__': '__main__', '__file__': {}}})",
+            argv,
+            python_literal(&format!("/flash{}", path)),
+            python_literal(path.as_str())
+        );
+        debug!("Running {} with {:?} through the Python shell", path, args);
+
+        // Interrupt whatever is running, so the shell is ready for input
+        badge.serial_in("\u{003}".as_bytes()).await?;
+        badge
+            .serial_in(format!("exec({})\r\n", python_literal(&code)))
+            .await?;
+    }
+
+    match watch_for_traceback(&mut output, STARTUP_WAIT).await {
+        Some(traceback) => Err(RunError::Failed(traceback))?,
+        None => {
+            let started = BadgePath::new(format!("/flash{}", path))?;
+            record_started(&badge.device_info().id(), &started);
+            Ok(())
+        }
+    }
+}
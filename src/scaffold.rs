@@ -0,0 +1,96 @@
+//! `new` creates a local directory with a minimal app: `__init__.py`, which runs when the app is
+//! started, `metadata.json` with its name and description, and a placeholder `icon.png`. Those are
+//! what `install` and `lint` expect, so a first app can be written without the web IDE.
+
+use crate::{repl::python_literal, screenshot::Screen};
+use serde_json::json;
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::info;
+
+/// The width and height of the icon of an app
+pub const ICON_SIZE: usize = 32;
+
+const INIT_PY: &str =
+    "# Runs when the app is started, from the launcher or with `cz2020-usbtool run`
+import display
+
+print({greeting})
+display.drawFill(0x0000ff)
+display.flush()
+";
+
+#[derive(Error, Debug)]
+pub enum ScaffoldError {
+    #[error("Invalid app name {:?}, which can't be empty, contain '/' or start with '.'", .0)]
+    InvalidName(String),
+
+    #[error("{:?} already exists", .0)]
+    Exists(PathBuf),
+}
+
+/// Whether `name` can be the name of an app, as a directory in `/flash/apps`
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.starts_with('.')
+}
+
+/// A blue square with a white border, to be replaced by a real icon
+fn placeholder_icon() -> std::io::Result<Vec<u8>> {
+    let edge = |i: usize| i == 0 || i == ICON_SIZE - 1;
+    let pixels = (0..ICON_SIZE * ICON_SIZE)
+        .map(|i| {
+            if edge(i % ICON_SIZE) || edge(i / ICON_SIZE) {
+                [0xff, 0xff, 0xff]
+            } else {
+                [0x00, 0x00, 0xff]
+            }
+        })
+        .collect();
+    Screen {
+        width: ICON_SIZE,
+        height: ICON_SIZE,
+        pixels,
+    }
+    .to_png(1)
+}
+
+/// Creates the app `name` in `dir`, or in `./<name>` without it, and returns the directory
+pub fn create(name: &str, dir: Option<&Path>) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    if !is_valid_name(name) {
+        Err(ScaffoldError::InvalidName(name.to_owned()))?
+    }
+    let dir = dir.map_or_else(|| PathBuf::from(name), Path::to_owned);
+    if dir.exists() {
+        Err(ScaffoldError::Exists(dir.clone()))?
+    }
+
+    let author = std::env::var("USER").unwrap_or_default();
+    let metadata = json!({
+        "name": name,
+        "description": "",
+        "category": "uncategorised",
+        "author": author,
+        "revision": 1,
+    });
+
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join("__init__.py"),
+        INIT_PY.replace(
+            "{greeting}",
+            &python_literal(&format!("Hello from {}!", name)),
+        ),
+    )?;
+    fs::write(
+        dir.join("metadata.json"),
+        serde_json::to_string_pretty(&metadata)? + "\n",
+    )?;
+    fs::write(dir.join("icon.png"), placeholder_icon()?)?;
+    info!("Created the app {} in {:?}", name, dir);
+
+    Ok(dir)
+}
@@ -0,0 +1,113 @@
+//! Captures what the display of the badge shows, as a PNG. The pixels are read from the
+//! framebuffer of the `display` module in the Python shell and encoded on the host.
+
+use crate::{
+    device::Badge,
+    repl::{self, ReplError},
+};
+use libflate::zlib;
+use std::{error::Error, io::Write, time::Duration};
+
+/// Reading the framebuffer pixel by pixel is slow on larger displays
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Prints the width and height, and then one line per row with the pixels as `rrggbb`
+const CAPTURE: &str = "import display
+w, h = display.width(), display.height()
+print(w, h)
+for y in range(h):
+ print(' '.join(['%06x' % (display.getPixel(x, y) & 0xffffff) for x in range(w)]))";
+
+/// The contents of the display as RGB pixels, row by row
+pub struct Screen {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+fn invalid(what: &str) -> ReplError {
+    ReplError::Exception(format!("invalid display output: {}", what))
+}
+
+/// Reads the framebuffer of the badge
+pub async fn capture(badge: &Badge) -> Result<Screen, Box<dyn Error + Send + Sync>> {
+    let output = repl::exec(badge, CAPTURE, REPL_TIMEOUT).await?;
+    let mut lines = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    let size = lines.next().ok_or_else(|| invalid("no size"))?;
+    let (width, height) = size.split_once(' ').ok_or_else(|| invalid(size))?;
+    let (width, height) = (width.parse()?, height.parse()?);
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for line in lines.take(height) {
+        for pixel in line.split(' ').take(width) {
+            let rgb = u32::from_str_radix(pixel, 16)?;
+            pixels.push([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]);
+        }
+    }
+    if pixels.len() != width * height {
+        Err(invalid(&format!(
+            "{} pixels for {}x{}",
+            pixels.len(),
+            width,
+            height
+        )))?
+    }
+
+    Ok(Screen {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&png[start..]);
+    png.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+impl Screen {
+    /// Encodes the screen as an RGB PNG, with every pixel drawn as a `scale` by `scale` square so
+    /// small LED matrices are large enough to see
+    pub fn to_png(&self, scale: usize) -> std::io::Result<Vec<u8>> {
+        let (width, height) = (self.width * scale, self.height * scale);
+
+        // Each row starts with filter type 0, none
+        let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+        for row in self.pixels.chunks(self.width.max(1)) {
+            let start = raw.len();
+            raw.push(0);
+            for pixel in row {
+                for _ in 0..scale {
+                    raw.extend_from_slice(pixel);
+                }
+            }
+            for _ in 1..scale {
+                raw.extend_from_within(start..start + width * 3 + 1);
+            }
+        }
+
+        let mut encoder = zlib::Encoder::new(Vec::new())?;
+        encoder.write_all(&raw)?;
+        let compressed = encoder.finish().into_result()?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        // 8 bits per channel, truecolor, default compression, filtering and no interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"IDAT", &compressed);
+        chunk(&mut png, b"IEND", &[]);
+        Ok(png)
+    }
+}
@@ -0,0 +1,82 @@
+//! `send` writes to the serial input of the badge without opening the shell, so scripts can answer
+//! the prompts of a running app.
+
+use crate::{device::Badge, shell};
+use std::error::Error;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("Invalid escape sequence {:?}. Use \\n, \\r, \\t, \\e, \\\\ or \\xHH.", .0)]
+pub struct EscapeError(String);
+
+/// Replaces the escape sequences `\n`, `\r`, `\t`, `\e` (escape), `\\` and `\xHH` in `text`, like
+/// `echo -e`, so keys like Escape or Ctrl-C (`\x03`) can be sent
+pub fn unescape(text: &str) -> Result<Vec<u8>, EscapeError> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('e') => bytes.push(0x1b),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                // from_str_radix would accept a sign
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                        bytes.push(byte)
+                    }
+                    _ => return Err(EscapeError(format!("\\x{}", hex))),
+                }
+            }
+            Some(other) => return Err(EscapeError(format!("\\{}", other))),
+            None => return Err(EscapeError("\\".to_owned())),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Sends `text` to the badge, followed by Enter unless `newline` is false. Line feeds become
+/// "\r\n", like in the shell.
+pub async fn send_text(
+    badge: &Badge,
+    text: &[u8],
+    newline: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut data = shell::to_crlf(text);
+    if newline {
+        data.extend_from_slice(b"\r\n");
+    }
+
+    badge.serial_in(data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes() {
+        assert_eq!(unescape("plain").unwrap(), b"plain");
+        assert_eq!(unescape("a\\nb\\r\\t").unwrap(), b"a\nb\r\t");
+        assert_eq!(unescape("\\e[A\\\\").unwrap(), b"\x1b[A\\");
+        assert_eq!(unescape("\\x03\\xfF").unwrap(), [0x03, 0xff]);
+        assert_eq!(unescape("é").unwrap(), "é".as_bytes());
+    }
+
+    #[test]
+    fn rejects_invalid_escapes() {
+        for text in &["\\q", "trailing\\", "\\x1", "\\xzz", "\\x+1"] {
+            assert!(unescape(text).is_err(), "{:?}", text);
+        }
+    }
+}
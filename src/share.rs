@@ -0,0 +1,288 @@
+//! Lets other invocations of the tool use a badge while `mount` has it open. Only one process can
+//! claim the USB interface, so `mount` listens on `<runtime dir>/cz2020-usbtool/<port>.sock`, and
+//! `Device::find` connects there before it tries to open the badge itself.
+//!
+//! The socket speaks the protocol of the badge, after a first line with the device info as JSON,
+//! so a shared badge works like one that is connected directly. Commands of each client are sent to
+//! the badge one at a time, in between those of the mount, and every client receives the output.
+//! On top of that, a client can send `PAUSE_WRITES`, which the badge doesn't know, to hold off the
+//! writes of the mount and other clients until it sends it again with 0 or disconnects.
+
+use crate::{
+    cmds::Command,
+    device::{Badge, Device, DeviceInfo, WritePause},
+};
+use cz2020_protocol::{self as protocol, command, Next};
+use serde_json::{json, Value};
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fs,
+    io::{self, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{runtime, sync::broadcast::RecvError};
+use tracing::{debug, info, warn};
+
+/// How long to wait for the device info of a socket, which is sent right away
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pauses writes to a shared badge while the payload is 1, and resumes them when it is 0. Answered
+/// with a status. Far above the commands of the badge, so it never collides with one.
+pub(crate) const PAUSE_WRITES: u16 = 0xf000;
+
+#[derive(Error, Debug)]
+pub enum ShareError {
+    #[error("The process that shares the badge closed the connection")]
+    Closed,
+}
+
+/// `$XDG_RUNTIME_DIR/cz2020-usbtool`, or a directory for this user in the temporary directory
+fn socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("cz2020-usbtool"),
+        _ => std::env::temp_dir().join(format!("cz2020-usbtool-{}", unsafe { libc::getuid() })),
+    }
+}
+
+/// Creates `socket_dir`, which only this user can access
+pub(crate) fn create_socket_dir() -> io::Result<PathBuf> {
+    let dir = socket_dir();
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    Ok(dir)
+}
+
+fn info_to_json(info: &DeviceInfo) -> Value {
+    json!({
+        "bus": info.bus,
+        "address": info.address,
+        "vendor_id": info.vendor_id,
+        "product_id": info.product_id,
+        "firmware_version": info.firmware_version,
+        "manufacturer": info.manufacturer,
+        "product": info.product,
+        "serial_number": info.serial_number,
+    })
+}
+
+fn info_from_json(value: &Value) -> Option<DeviceInfo> {
+    let string = |key: &str| value[key].as_str().map(|s| s.to_owned());
+    Some(DeviceInfo {
+        bus: value["bus"].as_u64()? as u8,
+        address: value["address"].as_u64()? as u8,
+        vendor_id: value["vendor_id"].as_u64()? as u16,
+        product_id: value["product_id"].as_u64()? as u16,
+        firmware_version: string("firmware_version")?,
+        manufacturer: string("manufacturer"),
+        product: string("product"),
+        serial_number: string("serial_number"),
+    })
+}
+
+/// Reads the first line of a shared socket. Byte by byte, so no output that follows is consumed.
+fn handshake(mut stream: &UnixStream) -> Option<DeviceInfo> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok()?;
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while stream.read(&mut byte).ok()? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+
+    info_from_json(&serde_json::from_slice(&line).ok()?)
+}
+
+/// Connects to the shared badge with `id`, its port or serial number, or to any shared badge
+/// without `id`
+pub fn connect(id: Option<&str>) -> Option<Device> {
+    for entry in fs::read_dir(socket_dir()).ok()?.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.extension() != Some(OsStr::new("sock")) {
+            continue;
+        }
+
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Left behind by a process that crashed
+                debug!("Unable to connect to {:?}: {}", path, e);
+                continue;
+            }
+        };
+
+        let info = match handshake(&stream) {
+            Some(info) => info,
+            None => {
+                warn!("{:?} didn't send valid device info", path);
+                continue;
+            }
+        };
+        if id.is_none() || id == Some(info.port().as_str()) || info.serial_number.as_deref() == id {
+            info!("Using the badge {} that another process shares", info.id());
+            return Some(Device::shared(stream, info));
+        }
+    }
+
+    None
+}
+
+fn write_frame(
+    stream: &Mutex<UnixStream>,
+    command: u16,
+    message_id: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(protocol::HEADER_LEN + payload.len());
+    protocol::write_header(&mut frame, command, payload.len() as u32, message_id);
+    frame.extend_from_slice(payload);
+    stream.lock().unwrap().write_all(&frame)
+}
+
+/// Sends everything the badge prints to a client, until either goes away
+fn forward_output(badge: &Badge, stream: &Mutex<UnixStream>) {
+    let mut output = badge.subscribe();
+    let mut rt = runtime::Builder::new().basic_scheduler().build().unwrap();
+    loop {
+        match rt.block_on(output.recv()) {
+            Ok(text) => {
+                let mut payload = Vec::new();
+                protocol::write_latin1(&mut payload, &text);
+                if write_frame(stream, command::LOG, 0, &payload).is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(n)) => warn!("Dropped {} messages of output for a client", n),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Runs the commands of a client on the badge, until the client disconnects
+fn serve(badge: Arc<Badge>, mut stream: UnixStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut handshake = serde_json::to_vec(&info_to_json(badge.device_info()))?;
+    handshake.push(b'\n');
+    stream.write_all(&handshake)?;
+
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    {
+        let badge = badge.clone();
+        let writer = writer.clone();
+        thread::spawn(move || forward_output(&badge, &writer));
+    }
+
+    let mut rt = runtime::Builder::new().basic_scheduler().build().unwrap();
+    // Dropped when the client resumes writes or disconnects
+    let mut pause: Option<WritePause> = None;
+    let mut input = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        input.extend_from_slice(&buf[..len]);
+
+        loop {
+            let frame = match protocol::next_frame(&input) {
+                Next::Frame(frame) => frame,
+                Next::Incomplete { .. } => break,
+                Next::Garbage => {
+                    input.remove(0);
+                    continue;
+                }
+            };
+            let (request, message_id, size) = (frame.command, frame.message_id, frame.size());
+
+            // The mount keeps the badge alive already, so heartbeats only measure the socket
+            if request == command::HEARTBEAT {
+                let mut payload = Vec::new();
+                protocol::write_status(&mut payload, true);
+                write_frame(&writer, request, message_id, &payload)?;
+            } else {
+                match Command::parse(request, frame.payload) {
+                    Some(Command::PauseWrites { paused }) => {
+                        if !paused {
+                            pause = None;
+                        } else if pause.is_none() {
+                            pause = Some(rt.block_on(badge.pause_writes())?);
+                        }
+                        let mut payload = Vec::new();
+                        protocol::write_status(&mut payload, true);
+                        write_frame(&writer, request, message_id, &payload)?;
+                    }
+                    Some(cmd) => {
+                        let response = rt.block_on(badge.cmd(cmd))?;
+                        if let Some((command, payload)) = response.encode(request) {
+                            write_frame(&writer, command, message_id, &payload)?;
+                        }
+                    }
+                    None => {
+                        warn!("A client sent an unknown command {}", request);
+                        let mut payload = Vec::new();
+                        protocol::write_status(&mut payload, false);
+                        write_frame(&writer, request, message_id, &payload)?;
+                    }
+                }
+            }
+            input.drain(..size);
+        }
+    }
+}
+
+/// Shares a badge for as long as it is kept. Dropping it removes the socket.
+pub struct Share {
+    path: PathBuf,
+}
+
+impl Share {
+    /// Starts sharing `badge` with other processes
+    pub fn start(badge: Arc<Badge>) -> io::Result<Share> {
+        let path = create_socket_dir()?.join(format!("{}.sock", badge.device_info().port()));
+
+        // A socket left behind by a process that crashed would make binding fail
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        info!("Sharing the badge on {:?}", path);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let badge = badge.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(badge, stream) {
+                                debug!("Stopped serving a client: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Unable to accept a client: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Share { path })
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
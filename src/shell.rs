@@ -0,0 +1,674 @@
+use crate::{
+    backoff::Backoff,
+    device::{Badge, BadgeBuilder, Device},
+    health::Status,
+    mux::Mux,
+    path::BadgePath,
+    render::Renderer,
+};
+use rusb::Context;
+use std::{
+    error::Error,
+    future::Future,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use termios::{tcsetattr, Termios, ECHO, ICANON, ISIG, TCSANOW};
+use thiserror::Error;
+use tokio::{
+    runtime::Runtime,
+    signal::unix::{signal, Signal, SignalKind},
+    sync::{broadcast::RecvError, mpsc},
+    time::interval,
+};
+use tracing::{debug, warn};
+
+/// How long to wait for the badge to come back after it disconnected, e.g. because an app called
+/// `machine.reset()`
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const RECONNECT_BACKOFF: Backoff = Backoff::new(Duration::from_millis(250), Duration::from_secs(2));
+
+/// How often to check whether the health of the connection changed
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Control + A, which starts a command for the tool instead of input for the badge
+pub const ESCAPE: u8 = 0x01;
+const ESCAPE_HELP: &str =
+    "[Ctrl-A q: exit, Ctrl-A s: send a file to the badge, Ctrl-A Ctrl-A: send Ctrl-A]";
+
+/// Control + C, which raises a KeyboardInterrupt on the badge
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Error, Debug)]
+pub enum ShellError {
+    #[error("{} can't be used inside the shell", .0)]
+    UnsupportedCommand(String),
+}
+
+pub type CommandResult = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error + Send + Sync>>>>>;
+
+/// How the keys typed in the shell are handled
+pub struct Interactive {
+    pub commands: Option<LocalCommands>,
+
+    /// Whether Control + C is sent to the badge. If not, it exits the shell.
+    pub forward_interrupt: bool,
+
+    /// Python code that is run at the prompt when the size of the terminal changes, with `{cols}`
+    /// and `{rows}` replaced by the new size
+    pub resize_hook: Option<String>,
+}
+
+/// The number of columns and rows of the terminal
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_col > 0 && size.ws_row > 0 {
+        Some((size.ws_col, size.ws_row))
+    } else {
+        None
+    }
+}
+
+async fn next_resize(resize: &mut Option<Signal>) -> Option<()> {
+    match resize {
+        Some(resize) => resize.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Commands of the tool that can be typed in the shell: a line that starts with `prefix` is split
+/// into words and passed to `run` instead of being sent to the badge
+pub struct LocalCommands {
+    pub prefix: String,
+    pub run: Box<dyn Fn(Vec<String>, Arc<Badge>) -> CommandResult>,
+}
+
+enum Matched {
+    /// The input so far could still be the prefix, so it is held back
+    Pending,
+    Command,
+    Input(Vec<u8>),
+}
+
+/// Recognizes the prefix of a local command at the start of a line
+struct PrefixMatcher {
+    prefix: Vec<u8>,
+    line_start: bool,
+    pending: Vec<u8>,
+}
+
+impl PrefixMatcher {
+    fn new(prefix: &str) -> PrefixMatcher {
+        PrefixMatcher {
+            prefix: prefix.as_bytes().to_vec(),
+            line_start: true,
+            pending: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Matched {
+        if self.line_start && !self.prefix.is_empty() {
+            self.pending.push(byte);
+            if self.pending == self.prefix {
+                self.pending.clear();
+                return Matched::Command;
+            } else if self.prefix.starts_with(&self.pending) {
+                return Matched::Pending;
+            }
+        } else {
+            self.pending.push(byte);
+        }
+
+        self.line_start = byte == b'\r' || byte == b'\n';
+        Matched::Input(std::mem::take(&mut self.pending))
+    }
+}
+
+/// The terminal sends a bare '\n' for Enter, but the Python shell expects "\r\n"
+pub fn to_crlf(input: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(input.len());
+    for &byte in input {
+        if byte == b'\n' {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+    }
+
+    converted
+}
+
+/// Reads a command after its prefix was typed and runs it
+async fn local_command(
+    badge: &Arc<Badge>,
+    commands: &LocalCommands,
+    input: &mut mpsc::UnboundedReceiver<u8>,
+) {
+    eprint!("\r\n{}", commands.prefix);
+    let words = match read_line(input).await {
+        Some(line) => line
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect::<Vec<_>>(),
+        None => return,
+    };
+    if words.is_empty() {
+        return;
+    }
+
+    if let Err(e) = (commands.run)(words, badge.clone()).await {
+        eprint!("[{}]\r\n", e.to_string().trim_end().replace('\n', "\r\n"));
+    }
+}
+
+/// Terminals put pasted text between these when bracketed paste is enabled
+const ENABLE_BRACKETED_PASTE: &str = "\u{1b}[?2004h";
+const DISABLE_BRACKETED_PASTE: &str = "\u{1b}[?2004l";
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Control + E and Control + D, which start and finish the paste mode of MicroPython. In paste
+/// mode, lines aren't auto-indented.
+const PASTE_MODE: u8 = 0x05;
+const PASTE_FINISH: u8 = 0x04;
+
+fn set_bracketed_paste(enabled: bool) {
+    let mut stdout = std::io::stdout();
+    let sequence = if enabled {
+        ENABLE_BRACKETED_PASTE
+    } else {
+        DISABLE_BRACKETED_PASTE
+    };
+    let _ = stdout
+        .write_all(sequence.as_bytes())
+        .and_then(|_| stdout.flush());
+}
+
+enum Paste {
+    /// The input so far could still be a paste marker, so it is held back
+    Pending,
+    Start,
+    End,
+    Input(Vec<u8>),
+}
+
+/// Recognizes the markers of bracketed paste in the input
+struct PasteDetector {
+    pending: Vec<u8>,
+}
+
+impl PasteDetector {
+    fn push(&mut self, byte: u8) -> Paste {
+        if self.pending.is_empty() && byte != PASTE_START[0] {
+            return Paste::Input(vec![byte]);
+        }
+
+        self.pending.push(byte);
+        if self.pending == PASTE_START {
+            self.pending.clear();
+            Paste::Start
+        } else if self.pending == PASTE_END {
+            self.pending.clear();
+            Paste::End
+        } else if PASTE_START.starts_with(&self.pending) || PASTE_END.starts_with(&self.pending) {
+            Paste::Pending
+        } else {
+            Paste::Input(std::mem::take(&mut self.pending))
+        }
+    }
+}
+
+/// Interprets the keys typed by the user: escapes, local commands and pasted text
+struct Keys<'a> {
+    commands: Option<&'a LocalCommands>,
+    forward_interrupt: bool,
+    escaped: bool,
+    /// Set when the user asked to exit the shell
+    exit: bool,
+    resize_hook: Option<&'a str>,
+    /// Whether the size of the terminal still has to be reported
+    resized: bool,
+    /// Whether the badge printed a prompt and nothing was typed since
+    at_prompt: bool,
+    matcher: PrefixMatcher,
+    paste: PasteDetector,
+    /// The text pasted so far, while a paste is in progress
+    pasted: Option<Vec<u8>>,
+}
+
+impl<'a> Keys<'a> {
+    fn new(interactive: &'a Interactive) -> Keys<'a> {
+        let commands = interactive.commands.as_ref();
+        Keys {
+            commands,
+            forward_interrupt: interactive.forward_interrupt,
+            escaped: false,
+            exit: false,
+            resize_hook: interactive.resize_hook.as_deref(),
+            resized: true,
+            at_prompt: false,
+            matcher: PrefixMatcher::new(commands.map_or("", |commands| &commands.prefix)),
+            paste: PasteDetector {
+                pending: Vec::new(),
+            },
+            pasted: None,
+        }
+    }
+
+    fn output(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.at_prompt = text.ends_with(">>> ");
+        }
+    }
+
+    /// Runs the resize hook once the badge is waiting at the prompt, so it doesn't end up in the
+    /// input of an app or in the middle of a line
+    async fn report_size(
+        &mut self,
+        badge: &Arc<Badge>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let hook = match self.resize_hook {
+            Some(hook) if self.resized && self.at_prompt => hook,
+            _ => return Ok(()),
+        };
+        self.resized = false;
+        self.at_prompt = false;
+
+        let (cols, rows) = match terminal_size() {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        debug!("Reporting a terminal size of {}x{}", cols, rows);
+        let code = hook
+            .replace("{cols}", &cols.to_string())
+            .replace("{rows}", &rows.to_string());
+        badge.serial_in(format!("{}\r\n", code)).await
+    }
+
+    async fn handle(
+        &mut self,
+        badge: &Arc<Badge>,
+        byte: u8,
+        input: &mut mpsc::UnboundedReceiver<u8>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.at_prompt = false;
+        match self.paste.push(byte) {
+            Paste::Pending => Ok(()),
+            Paste::Start => {
+                self.pasted = Some(Vec::new());
+                Ok(())
+            }
+            Paste::End => {
+                let pasted = self.pasted.take().unwrap_or_default();
+                self.matcher.line_start = true;
+                if pasted.contains(&b'\n') {
+                    let mut data = vec![PASTE_MODE];
+                    data.extend(to_crlf(&pasted));
+                    data.push(PASTE_FINISH);
+                    badge.serial_in(data).await
+                } else {
+                    // A single line is typed as is, so it can be pasted into the middle of a line
+                    badge.serial_in(pasted).await
+                }
+            }
+            Paste::Input(bytes) => match &mut self.pasted {
+                Some(pasted) => {
+                    pasted.extend(bytes);
+                    Ok(())
+                }
+                None => {
+                    for byte in bytes {
+                        self.key(badge, byte, input).await?;
+                    }
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    async fn key(
+        &mut self,
+        badge: &Arc<Badge>,
+        byte: u8,
+        input: &mut mpsc::UnboundedReceiver<u8>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.escaped {
+            self.escaped = false;
+            match byte {
+                ESCAPE => badge.serial_in(&[ESCAPE]).await,
+                b'q' => {
+                    self.exit = true;
+                    Ok(())
+                }
+                b's' => {
+                    if let Err(e) = send_file(badge, input).await {
+                        eprint!("[Sending failed: {}]\r\n", e);
+                    }
+                    Ok(())
+                }
+                _ => {
+                    eprint!("\r\n{}\r\n", ESCAPE_HELP);
+                    Ok(())
+                }
+            }
+        } else if byte == ESCAPE {
+            self.escaped = true;
+            Ok(())
+        } else if byte == INTERRUPT && !self.forward_interrupt {
+            self.exit = true;
+            Ok(())
+        } else {
+            match (self.matcher.push(byte), self.commands) {
+                (Matched::Pending, _) => Ok(()),
+                (Matched::Command, Some(commands)) => {
+                    local_command(badge, commands, input).await;
+                    Ok(())
+                }
+                (Matched::Command, None) => unreachable!("No prefix to match"),
+                (Matched::Input(bytes), _) => badge.serial_in(to_crlf(&bytes)).await,
+            }
+        }
+    }
+}
+
+enum SessionEnd {
+    Disconnected,
+    InputClosed,
+    Exited,
+}
+
+/// Makes sure the terminal doesn't print keys, that keys can be read one-by-one and that Control + C
+/// is read instead of ending the tool. Returns the original settings, or `None` if stdin isn't a
+/// terminal.
+pub fn raw_mode() -> Result<Option<Termios>, Box<dyn Error + Send + Sync>> {
+    let original = match Termios::from_fd(libc::STDIN_FILENO) {
+        Ok(original) => original,
+        Err(_) => return Ok(None),
+    };
+
+    let mut termios = original;
+    termios.c_lflag &= !(ICANON | ECHO | ISIG);
+    tcsetattr(libc::STDIN_FILENO, TCSANOW, &termios)?;
+
+    Ok(Some(original))
+}
+
+pub fn restore_mode(original: Option<Termios>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(original) = original {
+        tcsetattr(libc::STDIN_FILENO, TCSANOW, &original)?;
+    }
+
+    Ok(())
+}
+
+/// Reads stdin on a separate thread, so that input isn't lost while reconnecting
+fn spawn_stdin_reader() -> mpsc::UnboundedReceiver<u8> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        let mut reader = std::io::stdin();
+        while reader.read_exact(&mut buf).is_ok() {
+            if tx.send(buf[0]).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+/// Waits for a badge to show up again
+fn reconnect(context: &Context, id: Option<&str>) -> Result<Device, Box<dyn Error + Send + Sync>> {
+    let deadline = Instant::now() + RECONNECT_TIMEOUT;
+    let mut attempt = 0;
+    loop {
+        thread::sleep(RECONNECT_BACKOFF.delay(attempt));
+        attempt += 1;
+        match Device::find(context, id) {
+            Ok(device) => return Ok(device),
+            Err(e) if Instant::now() < deadline => debug!("Not reconnected yet: {}", e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+async fn next_input(input: &mut Option<mpsc::UnboundedReceiver<u8>>) -> Option<u8> {
+    match input {
+        Some(input) => input.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads a line typed by the user, echoing it because the terminal doesn't. Returns `None` if the
+/// user pressed Escape or Control + C, or the input was closed.
+async fn read_line(input: &mut mpsc::UnboundedReceiver<u8>) -> Option<String> {
+    let mut line = Vec::new();
+    let mut stderr = std::io::stderr();
+    loop {
+        match input.recv().await? {
+            b'\n' | b'\r' => {
+                eprint!("\r\n");
+                return Some(String::from_utf8_lossy(&line).trim().to_owned());
+            }
+            0x1b | INTERRUPT => {
+                eprint!("\r\n");
+                return None;
+            }
+            0x7f | 0x08 => {
+                if line.pop().is_some() {
+                    eprint!("\x08 \x08");
+                }
+            }
+            byte => {
+                line.push(byte);
+                let _ = stderr.write_all(&[byte]);
+            }
+        }
+    }
+}
+
+/// Asks for a local file and uploads it to the badge, without leaving the shell
+async fn send_file(
+    badge: &Badge,
+    input: &mut mpsc::UnboundedReceiver<u8>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    eprint!("\r\n[Send file] Local path: ");
+    let local = match read_line(input).await {
+        Some(local) if !local.is_empty() => PathBuf::from(local),
+        _ => {
+            eprint!("[Cancelled]\r\n");
+            return Ok(());
+        }
+    };
+    let data = std::fs::read(&local)?;
+
+    let name = local
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let default = BadgePath::new("/flash")?.join(name)?;
+    eprint!("Remote path [{}]: ", default);
+    let remote = match read_line(input).await {
+        Some(remote) if remote.is_empty() => default,
+        Some(remote) => remote.parse()?,
+        None => {
+            eprint!("[Cancelled]\r\n");
+            return Ok(());
+        }
+    };
+
+    badge.write_file(&remote, &data).await?;
+    eprint!("[Sent {} bytes to {}]\r\n", data.len(), remote);
+
+    Ok(())
+}
+
+/// Forwards input to the badge and prints its output until either side goes away. Only the first
+/// interactive session interrupts the running program; after a reconnect, the badge is left to
+/// start up.
+async fn session(
+    badge: &Arc<Badge>,
+    input: &mut Option<mpsc::UnboundedReceiver<u8>>,
+    renderer: &mut Renderer,
+    interactive: Option<&Interactive>,
+    first: bool,
+) -> Result<SessionEnd, Box<dyn Error + Send + Sync>> {
+    let mut output = badge.subscribe();
+    if let Err(e) = badge.heartbeat().await {
+        if first {
+            return Err(e);
+        }
+
+        // The badge that was found may be the one that is going away
+        debug!("Reconnected badge didn't respond: {}", e);
+        return Ok(SessionEnd::Disconnected);
+    }
+
+    if first && input.is_some() {
+        // Send a Control + C to terminate any previous command that might have been running
+        badge.serial_in("\u{003}".as_bytes()).await?;
+    }
+
+    let mut keys = interactive.map(Keys::new);
+    let mut resize = match interactive {
+        Some(Interactive {
+            resize_hook: Some(_),
+            ..
+        }) => Some(signal(SignalKind::window_change())?),
+        _ => None,
+    };
+    let mut health_check = interval(HEALTH_INTERVAL);
+    let mut health = Status::Good;
+    loop {
+        tokio::select! {
+            received = output.recv() => match received {
+                Ok(text) => {
+                    renderer.write(&text)?;
+                    if let Some(keys) = &mut keys {
+                        keys.output(&text);
+                    }
+                }
+                Err(RecvError::Lagged(n)) => warn!("Dropped {} messages of badge output", n),
+                Err(RecvError::Closed) => return Ok(SessionEnd::Disconnected),
+            },
+            byte = next_input(input) => {
+                let byte = match byte {
+                    Some(byte) => byte,
+                    None => return Ok(SessionEnd::InputClosed),
+                };
+
+                let keys = keys.as_mut().unwrap();
+                let result = keys.handle(badge, byte, input.as_mut().unwrap()).await;
+                if let Err(e) = result {
+                    debug!("Sending input failed: {}", e);
+                    return Ok(SessionEnd::Disconnected);
+                }
+                if keys.exit {
+                    return Ok(SessionEnd::Exited);
+                }
+            }
+            _ = health_check.tick() => {
+                let status = badge.health().status;
+                if status != health {
+                    eprint!("\r\n[Connection {}]\r\n", status);
+                    health = status;
+                }
+            }
+            _ = next_resize(&mut resize) => {
+                if let Some(keys) = &mut keys {
+                    keys.resized = true;
+                }
+            }
+        }
+
+        if let Some(keys) = &mut keys {
+            if let Err(e) = keys.report_size(badge).await {
+                debug!("Reporting the terminal size failed: {}", e);
+                return Ok(SessionEnd::Disconnected);
+            }
+        }
+    }
+}
+
+/// Opens the serial connection for the Python shell. If `interactive`, stdin is sent to the badge
+/// key by key, except for escapes and local commands; otherwise the running program isn't
+/// interrupted either. With `listen`, the connection is shared with other clients on a Unix socket.
+/// When the badge disconnects, the shell waits for it to come back and continues.
+pub fn shell(
+    context: &Context,
+    id: Option<&str>,
+    builder: BadgeBuilder,
+    interactive: Option<Interactive>,
+    mut renderer: Renderer,
+    listen: Option<&Path>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut device = Device::find(context, id)?;
+    // The address of the badge changes when it reconnects, but its serial number doesn't
+    let reconnect_id = id.map(|_| device.info().id());
+    let mut rt = Runtime::new()?;
+    let mux = match listen {
+        Some(path) => Some(rt.enter(|| Mux::listen(path))?),
+        None => None,
+    };
+
+    let original = if interactive.is_some() {
+        raw_mode()?
+    } else {
+        None
+    };
+    if original.is_some() {
+        set_bracketed_paste(true);
+    }
+    let mut input = if interactive.is_some() {
+        Some(spawn_stdin_reader())
+    } else {
+        None
+    };
+    let mut first = true;
+    let result = loop {
+        let badge = builder.clone().build(device).start();
+        if let Some(mux) = &mux {
+            rt.spawn(mux.forward(badge.clone()));
+        }
+        let end = rt.block_on(session(
+            &badge,
+            &mut input,
+            &mut renderer,
+            interactive.as_ref(),
+            first,
+        ));
+        badge.close();
+        first = false;
+
+        match end {
+            Ok(SessionEnd::Disconnected) => {
+                eprint!("\r\n[The badge disconnected, waiting for it to come back]\r\n");
+                match reconnect(context, reconnect_id.as_deref()) {
+                    Ok(reconnected) => {
+                        eprint!("[Reconnected]\r\n");
+                        device = reconnected;
+                    }
+                    Err(e) => break Err(e),
+                }
+            }
+            Ok(SessionEnd::InputClosed) | Ok(SessionEnd::Exited) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    if original.is_some() {
+        set_bracketed_paste(false);
+    }
+    restore_mode(original)?;
+
+    result
+}
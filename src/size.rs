@@ -0,0 +1,73 @@
+use std::num::ParseIntError;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SizeError {
+    #[error("Invalid number: {}", .0)]
+    InvalidNumber(#[from] ParseIntError),
+
+    #[error("Unknown unit: {:?}. Use k or m, for KiB or MiB.", .0)]
+    UnknownUnit(String),
+}
+
+/// Parses sizes in bytes like `512`, `20k` and `1m`, where `k` and `m` are KiB and MiB
+pub fn parse_size(s: &str) -> Result<u64, SizeError> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number = number.parse::<u64>()?;
+    Ok(match unit.trim() {
+        "" => number,
+        "k" | "K" => number * 1024,
+        "m" | "M" => number * 1024 * 1024,
+        unit => return Err(SizeError::UnknownUnit(unit.to_owned())),
+    })
+}
+
+/// Sizes like `du -h` prints them: `512`, `3.5K`, `120K`, `2.0M`
+pub fn human_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+
+    let mut size = bytes as f64;
+    for unit in ["K", "M", "G"].iter() {
+        size /= 1024.;
+        if size < 1024. {
+            return if size < 10. {
+                format!("{:.1}{}", size, unit)
+            } else {
+                format!("{:.0}{}", size, unit)
+            };
+        }
+    }
+
+    format!("{:.0}T", size / 1024.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("20k").unwrap(), 20 * 1024);
+        assert_eq!(parse_size("1M").unwrap(), 1024 * 1024);
+        assert_eq!(parse_size("3 k").unwrap(), 3 * 1024);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(matches!(parse_size("k"), Err(SizeError::InvalidNumber(_))));
+        assert!(matches!(parse_size("-1"), Err(SizeError::InvalidNumber(_))));
+        assert!(matches!(parse_size("2g"), Err(SizeError::UnknownUnit(unit)) if unit == "g"));
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(human_size(512), "512");
+        assert_eq!(human_size(3584), "3.5K");
+        assert_eq!(human_size(120 * 1024), "120K");
+        assert_eq!(human_size(2 * 1024 * 1024), "2.0M");
+    }
+}
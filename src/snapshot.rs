@@ -0,0 +1,112 @@
+//! `snapshot` copies a directory of the badge to a local directory while writes are paused, so the
+//! copy shows the badge as it was at one moment, even while `mount` or another process is writing
+//! to it. Useful to diff what is actually on the badge against a git checkout.
+
+use crate::{client::BadgeFs, cmds::FsEntry, device::Badge, output::Output, path::BadgePath};
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("{:?} already exists and isn't an empty directory", .0)]
+    NotEmpty(PathBuf),
+}
+
+/// Whether `dir` doesn't exist yet, or is an empty directory that can be replaced
+fn is_free(dir: &Path) -> bool {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => !dir.exists(),
+    }
+}
+
+/// The hidden directory next to `out_dir` that the snapshot is written to first
+fn temporary(out_dir: &Path) -> PathBuf {
+    let name = out_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "snapshot".to_owned());
+    out_dir.with_file_name(format!(".{}.snapshot-tmp", name))
+}
+
+/// Copies everything below `root` into `dir`
+async fn copy(
+    client: &BadgeFs,
+    root: &BadgePath,
+    dir: &Path,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // A file ends up in the directory by its name, like `get --output-dir`
+    if client.metadata(root.clone()).await?.is_file() {
+        let data = client.open(root.clone()).read_to_end().await?;
+        fs::write(dir.join(root.file_name()), &data)?;
+        output.transferred(root, data.len());
+        return Ok(());
+    }
+
+    let mut stack = vec![(root.clone(), dir.to_owned())];
+    while let Some((path, local)) = stack.pop() {
+        let mut entries = client.read_dir(path.clone());
+        while let Some(entry) = entries.next_entry().await? {
+            let child = path.join(entry.name())?;
+            let local = local.join(entry.name());
+            match entry {
+                FsEntry::File(_) => {
+                    let data = client.open(child.clone()).read_to_end().await?;
+                    fs::write(&local, &data)?;
+                    output.transferred(&child, data.len());
+                }
+                FsEntry::Directory(_) => {
+                    fs::create_dir(&local)?;
+                    stack.push((child, local));
+                }
+                FsEntry::Other(kind, _) => warn!("Skipping {}, which has type {:?}", child, kind),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies `root` into `out_dir`, which must not exist yet or be empty. Nothing can write to the
+/// badge in the meantime, and `out_dir` only appears once the copy is complete.
+pub async fn snapshot(
+    badge: &Badge,
+    client: &BadgeFs,
+    root: &BadgePath,
+    out_dir: &Path,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !is_free(out_dir) {
+        Err(SnapshotError::NotEmpty(out_dir.to_owned()))?
+    }
+
+    let tmp = temporary(out_dir);
+    // Left behind by a snapshot that was interrupted
+    if tmp.exists() {
+        debug!("Removing {:?}", tmp);
+        fs::remove_dir_all(&tmp)?;
+    }
+    fs::create_dir_all(&tmp)?;
+
+    let pause = badge.pause_writes().await?;
+    info!("Paused writes to the badge while copying {}", root);
+    let result = copy(client, root, &tmp, output).await;
+    drop(pause);
+
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(&tmp);
+        return Err(e);
+    }
+    if out_dir.exists() {
+        fs::remove_dir(out_dir)?;
+    }
+    fs::rename(&tmp, out_dir)?;
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Remembers which local file every file on the badge was uploaded from by `push`, `provision` and
+//! `fs cp`, so paths in tracebacks can be shown as local files, and `which` and `where` can tell
+//! the two apart.
+//!
+//! Stored in `sources.tsv` in the cache directory as one line per file: the path on the badge and
+//! the absolute local path, separated by a tab. A later upload to the same path replaces the
+//! earlier one.
+
+use crate::{cache::cache_dir, path::BadgePath};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+#[derive(Error, Debug)]
+pub enum SourceMapError {
+    #[error("{} wasn't uploaded from this computer", .0)]
+    UnknownRemote(BadgePath),
+
+    #[error("{:?} wasn't uploaded to a badge", .0)]
+    UnknownLocal(PathBuf),
+}
+
+pub struct SourceMap {
+    file: Option<PathBuf>,
+    files: BTreeMap<BadgePath, PathBuf>,
+}
+
+impl SourceMap {
+    /// Loads the map. A missing or unreadable file results in an empty map.
+    pub fn load() -> SourceMap {
+        let file = cache_dir().map(|dir| dir.join("sources.tsv"));
+        let mut files = BTreeMap::new();
+        if let Some(contents) = file.as_ref().and_then(|file| fs::read_to_string(file).ok()) {
+            for line in contents.lines() {
+                if let Some((remote, local)) = line.split_once('\t') {
+                    if let Ok(remote) = BadgePath::new(remote) {
+                        files.insert(remote, PathBuf::from(local));
+                    }
+                }
+            }
+        }
+
+        debug!("Loaded {} source files from {:?}", files.len(), file);
+        SourceMap { file, files }
+    }
+
+    /// A map that isn't backed by a file
+    #[cfg(test)]
+    pub fn in_memory() -> SourceMap {
+        SourceMap {
+            file: None,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Records that `local` was uploaded to `remote`. Call `save` afterwards.
+    pub fn record(&mut self, remote: &BadgePath, local: &Path) {
+        let local = local.canonicalize().unwrap_or_else(|_| local.to_owned());
+        self.files.insert(remote.clone(), local);
+    }
+
+    /// Forgets `remote`, after it was deleted. Call `save` afterwards.
+    pub fn remove(&mut self, remote: &BadgePath) {
+        self.files.remove(remote);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let mut contents = Vec::new();
+        for (remote, local) in self.files.iter() {
+            writeln!(contents, "{}\t{}", remote, local.display())?;
+        }
+        fs::create_dir_all(file.parent().unwrap())?;
+        let tmp = file.with_extension("tsv.tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, file)
+    }
+
+    /// The local file that `remote` was uploaded from
+    pub fn local_path(&self, remote: &BadgePath) -> Option<&Path> {
+        self.files.get(remote).map(|local| local.as_path())
+    }
+
+    /// The uploaded files that are `remote` or below it, with their local files
+    pub fn below_remote(&self, remote: &BadgePath) -> Vec<(&BadgePath, &Path)> {
+        let prefix = format!("{}/", remote.as_str().trim_end_matches('/'));
+        self.files
+            .iter()
+            .filter(|(path, _)| *path == remote || path.as_str().starts_with(&prefix))
+            .map(|(path, local)| (path, local.as_path()))
+            .collect()
+    }
+
+    /// The uploaded files whose local file is `local` or below it
+    pub fn below_local(&self, local: &Path) -> Vec<(&BadgePath, &Path)> {
+        let local = local.canonicalize().unwrap_or_else(|_| local.to_owned());
+        self.files
+            .iter()
+            .filter(|(_, file)| file.starts_with(&local))
+            .map(|(path, file)| (path, file.as_path()))
+            .collect()
+    }
+}
+
+/// Records uploads, and warns instead of failing if they can't be saved, because the upload itself
+/// succeeded
+pub fn record_all<'a>(uploads: impl IntoIterator<Item = (&'a BadgePath, &'a Path)>) {
+    let mut map = SourceMap::load();
+    for (remote, local) in uploads {
+        map.record(remote, local);
+    }
+    if let Err(e) = map.save() {
+        warn!(
+            "Unable to remember where the uploaded files came from: {}",
+            e
+        );
+    }
+}
+
+/// Prints the local files that `remote`, or the files below it, were uploaded from
+pub fn uploaded_from(remote: &BadgePath) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let map = SourceMap::load();
+    let found = map.below_remote(remote);
+    if found.is_empty() {
+        Err(SourceMapError::UnknownRemote(remote.clone()))?
+    }
+
+    for (_, local) in found {
+        println!("{}", local.display());
+    }
+
+    Ok(())
+}
+
+/// Prints the paths on the badge that `local`, or the files below it, were uploaded to
+pub fn uploaded_to(local: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let map = SourceMap::load();
+    let found = map.below_local(local);
+    if found.is_empty() {
+        Err(SourceMapError::UnknownLocal(local.to_owned()))?
+    }
+
+    for (remote, _) in found {
+        println!("{}", remote);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Checks that uploads fit on the badge before they start, because running out of space halfway
+//! leaves partial files behind. Uses the Python shell; without it, uploads aren't checked.
+
+use crate::{
+    device::Badge,
+    path::BadgePath,
+    repl::{self, python_literal},
+    size::human_size,
+};
+use std::{error::Error, time::Duration};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+const REPL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+#[error(
+    "Not enough space on {}: {} needed, but only {} free. Use --force to try anyway.",
+    .filesystem,
+    human_size(*.needed),
+    human_size(*.free)
+)]
+pub struct SpaceError {
+    pub filesystem: String,
+    pub needed: u64,
+    pub free: u64,
+}
+
+/// The space on a filesystem of the badge, as `uos.statvfs` reports it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    /// The size of a block in bytes
+    pub block_size: u64,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    /// The free blocks that can be written to, which can be fewer than `free_blocks`
+    pub available_blocks: u64,
+    /// The bytes taken up by the files that were asked about
+    pub existing: u64,
+}
+
+impl Usage {
+    /// The free space in bytes
+    pub fn available(&self) -> u64 {
+        self.block_size * self.available_blocks
+    }
+}
+
+/// The filesystem `path` is on, like `/flash`
+fn filesystem(path: &BadgePath) -> String {
+    format!("/{}", path.as_str().split('/').nth(1).unwrap_or_default())
+}
+
+/// The space on `filesystem`, and how much of it `files` take up already. Fails if the shell
+/// doesn't answer within `limit`.
+pub(crate) async fn usage(
+    badge: &Badge,
+    filesystem: &str,
+    files: &[&BadgePath],
+    limit: Duration,
+) -> Result<Usage, Box<dyn Error + Send + Sync>> {
+    let paths = files
+        .iter()
+        .map(|path| python_literal(path.as_str()))
+        .collect::<Vec<_>>()
+        .join(",");
+    let code = format!(
+        "import uos
+s = uos.statvfs({})
+print(s[1], s[2], s[3], s[4])
+t = 0
+for p in [{}]:
+ try:
+  t += uos.stat(p)[6]
+ except OSError:
+  pass
+print(t)",
+        python_literal(filesystem),
+        paths
+    );
+
+    let output = repl::exec(badge, &code, limit).await?;
+    let mut numbers = output.split_whitespace();
+    let mut number = || -> Result<u64, Box<dyn Error + Send + Sync>> {
+        Ok(numbers.next().unwrap_or_default().parse()?)
+    };
+    Ok(Usage {
+        block_size: number()?,
+        blocks: number()?,
+        free_blocks: number()?,
+        available_blocks: number()?,
+        existing: number()?,
+    })
+}
+
+/// Fails if writing `files`, given as path and size, would need more space than is free. With
+/// `replacing`, the files replace existing ones, whose space is freed. With `force`, only warns.
+pub async fn check(
+    badge: &Badge,
+    files: &[(BadgePath, u64)],
+    replacing: bool,
+    force: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let filesystem = match files.first() {
+        Some((path, _)) => filesystem(path),
+        None => return Ok(()),
+    };
+    if !badge.capabilities().python_shell {
+        info!("The Python shell isn't available, so the free space isn't checked");
+        return Ok(());
+    }
+
+    let paths = files.iter().map(|(path, _)| path).collect::<Vec<_>>();
+    let usage = usage(badge, &filesystem, &paths, REPL_TIMEOUT).await?;
+    let (free, existing) = (usage.available(), usage.existing);
+    let total = files.iter().map(|(_, len)| len).sum::<u64>();
+    let needed = if replacing {
+        total.saturating_sub(existing)
+    } else {
+        total
+    };
+    debug!(
+        "{} bytes needed on {}, {} bytes free",
+        needed, filesystem, free
+    );
+
+    if needed > free {
+        if !force {
+            Err(SpaceError {
+                filesystem,
+                needed,
+                free,
+            })?
+        } else {
+            warn!(
+                "Uploading {} to {} with only {} free",
+                human_size(needed),
+                filesystem,
+                human_size(free)
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// How one kind of command fared, from the first attempt until the response, including retries
+#[derive(Debug, Clone, Default)]
+pub struct CommandMetrics {
+    pub count: u64,
+    pub retries: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+impl CommandMetrics {
+    pub fn average_time(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_micros(0)
+        } else {
+            self.total_time / self.count as u32
+        }
+    }
+}
+
+/// A snapshot of the statistics of a badge
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub commands_sent: u64,
+    pub responses_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub timeouts: u64,
+    pub retries: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub average_latency: Duration,
+    /// By the name of the command
+    pub commands: BTreeMap<&'static str, CommandMetrics>,
+}
+
+#[derive(Debug, Default)]
+pub struct BadgeStats {
+    commands_sent: AtomicU64,
+    responses_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    timeouts: AtomicU64,
+    retries: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    total_latency_us: AtomicU64,
+    commands: Mutex<BTreeMap<&'static str, CommandMetrics>>,
+}
+
+impl BadgeStats {
+    pub fn new() -> BadgeStats {
+        Default::default()
+    }
+
+    /// Counts a command that was requested, but not heartbeats and other packets the tool sends on
+    /// its own
+    pub fn record_command_sent(&self) {
+        self.commands_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_response(&self, latency: Duration) {
+        self.responses_received.fetch_add(1, Ordering::Relaxed);
+        self.total_latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a command that took `retries` retries and `time` in total
+    pub fn record_command(&self, name: &'static str, retries: u32, time: Duration) {
+        self.retries.fetch_add(retries as u64, Ordering::Relaxed);
+
+        let mut commands = self.commands.lock().unwrap();
+        let command = commands.entry(name).or_default();
+        command.count += 1;
+        command.retries += retries as u64;
+        command.total_time += time;
+        command.max_time = command.max_time.max(time);
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn average_latency(&self) -> Duration {
+        let responses = self.responses_received.load(Ordering::Relaxed);
+        Duration::from_micros(
+            self.total_latency_us
+                .load(Ordering::Relaxed)
+                .checked_div(responses)
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        Metrics {
+            commands_sent: load(&self.commands_sent),
+            responses_received: load(&self.responses_received),
+            bytes_sent: load(&self.bytes_sent),
+            bytes_received: load(&self.bytes_received),
+            timeouts: load(&self.timeouts),
+            retries: load(&self.retries),
+            cache_hits: load(&self.cache_hits),
+            cache_misses: load(&self.cache_misses),
+            average_latency: self.average_latency(),
+            commands: self.commands.lock().unwrap().clone(),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "commands_sent: {}", self.commands_sent)?;
+        writeln!(f, "responses_received: {}", self.responses_received)?;
+        writeln!(f, "bytes_sent: {}", self.bytes_sent)?;
+        writeln!(f, "bytes_received: {}", self.bytes_received)?;
+        writeln!(f, "timeouts: {}", self.timeouts)?;
+        writeln!(f, "retries: {}", self.retries)?;
+        writeln!(f, "cache_hits: {}", self.cache_hits)?;
+        writeln!(f, "cache_misses: {}", self.cache_misses)?;
+        writeln!(f, "average_latency_ms: {:.3}", millis(self.average_latency))?;
+        for (name, command) in self.commands.iter() {
+            writeln!(
+                f,
+                "{}: count={} retries={} average_ms={:.3} max_ms={:.3}",
+                name,
+                command.count,
+                command.retries,
+                millis(command.average_time()),
+                millis(command.max_time)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for BadgeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.metrics().fmt(f)
+    }
+}
@@ -0,0 +1,84 @@
+//! What the badge is running, and stopping it. The protocol can't tell which app is running, so
+//! the app that was last started with `run` is remembered, and the Python shell tells whether
+//! anything is running at all: it only answers when no app is. While it is idle, the battery and
+//! other sensors are read through it as well.
+
+use crate::{device::Badge, output::Output, repl, run};
+use std::{error::Error, thread, time::Duration};
+use thiserror::Error;
+use tracing::info;
+
+const REPL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prints `name<TAB>value` for every reading the firmware provides. Modules differ between
+/// firmware versions, so readings that fail are left out.
+const READINGS: &str = "def r(n, f):
+ try:
+  print('%s\t%s' % (n, f()))
+ except Exception:
+  pass
+r('battery_voltage', lambda: __import__('voltages').battery())
+r('usb_voltage', lambda: __import__('voltages').usb())
+r('charging', lambda: __import__('voltages').charging())
+r('temperature_f', lambda: __import__('esp32').raw_temperature())
+r('hall_sensor', lambda: __import__('esp32').hall_sensor())
+r('free_memory', lambda: __import__('gc').mem_free())
+r('uptime_s', lambda: __import__('time').ticks_ms() // 1000)";
+
+/// How long an app gets to handle the KeyboardInterrupt before the shell is probed again
+const STOP_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum StopError {
+    #[error(
+        "The app is still running after a KeyboardInterrupt. Use --reset to soft reset the badge."
+    )]
+    StillRunning,
+}
+
+/// Prints whether an app is running, which one was started last, and the sensor readings if no
+/// app is running
+pub async fn status(badge: &Badge, output: Output) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let id = badge.device_info().id();
+    let running = !badge.capabilities().python_shell;
+    if !running {
+        run::forget_started(&id);
+    }
+
+    output.run_status(
+        running,
+        run::last_started(&id).map(|(path, _)| path).as_ref(),
+    );
+
+    if running {
+        info!("The Python shell is busy, so the sensors can't be read. Use stop first.");
+        return Ok(());
+    }
+    for line in repl::exec(badge, READINGS, REPL_TIMEOUT).await?.lines() {
+        if let Some((name, value)) = line.trim().split_once('\t') {
+            output.reading(name, value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Interrupts the running app with a KeyboardInterrupt, or soft resets the badge with `reset`
+pub async fn stop(badge: &Badge, reset: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Twice, in case the app catches the first one
+    badge.serial_in("\u{003}").await?;
+    thread::sleep(STOP_WAIT);
+    badge.serial_in("\u{003}").await?;
+    if reset {
+        info!("Soft resetting the badge");
+        badge.serial_in("\u{004}").await?;
+    } else {
+        thread::sleep(STOP_WAIT);
+        if !badge.probe().await.python_shell {
+            Err(StopError::StillRunning)?
+        }
+    }
+
+    run::forget_started(&badge.device_info().id());
+    Ok(())
+}
@@ -1,24 +1,143 @@
 use buf_redux::Buffer;
-use std::sync::Mutex;
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+
+pub const DEFAULT_CAPACITY: usize = 1024 * 1024;
+
+/// What to do when data is written to a full `Stream`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest buffered data to make room
+    DropOldest,
+
+    /// Block the writer until a reader has made room
+    Block,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown overflow policy {:?}, expected drop-oldest or block", .0)]
+pub struct ParseOverflowPolicyError(String);
+
+impl FromStr for OverflowPolicy {
+    type Err = ParseOverflowPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "block" => Ok(OverflowPolicy::Block),
+            other => Err(ParseOverflowPolicyError(other.to_owned())),
+        }
+    }
+}
 
 pub struct Stream {
     data: Mutex<Buffer>,
+    available: Condvar,
+    space: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl Default for Stream {
+    fn default() -> Stream {
+        Stream::new()
+    }
 }
 
 impl Stream {
     pub fn new() -> Stream {
+        Stream::with_capacity(DEFAULT_CAPACITY, OverflowPolicy::DropOldest)
+    }
+
+    pub fn with_capacity(capacity: usize, policy: OverflowPolicy) -> Stream {
         Stream {
             data: Mutex::new(Buffer::new()),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
         }
     }
 
+    /// Wakes up writers blocked on a full buffer. Data written after this is discarded.
+    pub fn close(&self) {
+        let _data = self.data.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        self.space.notify_all();
+    }
+
+    /// Number of bytes that were discarded because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
     pub fn read(&self, buf: &mut [u8]) -> usize {
         let mut data = self.data.lock().unwrap();
-        data.copy_to_slice(buf)
+        let len = data.copy_to_slice(buf);
+        self.space.notify_all();
+
+        len
+    }
+
+    /// Same as `read()`, but blocks for at most `timeout` until data becomes available
+    pub fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> usize {
+        let data = self.data.lock().unwrap();
+        let (mut data, _) = self
+            .available
+            .wait_timeout_while(data, timeout, |data| data.is_empty())
+            .unwrap();
+        let len = data.copy_to_slice(buf);
+        self.space.notify_all();
+
+        len
     }
 
     pub fn write(&self, buf: &[u8]) {
+        let mut buf = buf;
+        if buf.len() > self.capacity {
+            // Even an empty buffer can't hold all of this, so only keep the end
+            let excess = buf.len() - self.capacity;
+            self.dropped.fetch_add(excess as u64, Ordering::Relaxed);
+            buf = &buf[excess..];
+        }
+
         let mut data = self.data.lock().unwrap();
+        match self.policy {
+            OverflowPolicy::DropOldest => {
+                let excess = (data.len() + buf.len()).saturating_sub(self.capacity);
+                if excess > 0 {
+                    data.consume(excess);
+                    self.dropped.fetch_add(excess as u64, Ordering::Relaxed);
+                }
+            }
+            OverflowPolicy::Block => {
+                data = self
+                    .space
+                    .wait_while(data, |data| {
+                        data.len() + buf.len() > self.capacity
+                            && !self.closed.load(Ordering::Relaxed)
+                    })
+                    .unwrap();
+            }
+        }
+
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        data.make_room();
         data.push_bytes(buf);
+        self.available.notify_all();
     }
 }
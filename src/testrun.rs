@@ -0,0 +1,394 @@
+//! Runs the tests of an app on the badge. The app reports its results on the serial output, either
+//! in TAP (`1..N`, `ok N - name`, `not ok N - name`) or in the format of the `unittest` module
+//! (`name ... ok`, `Ran N tests`, `OK` or `FAILED (failures=N)`).
+
+use crate::{
+    cache::MetadataCache,
+    device::Badge,
+    ignore::{self, ExcludeRules},
+    output::Output,
+    path::BadgePath,
+    push,
+    render::{strip_escapes, Renderer},
+    run::{self, RunError},
+};
+use regex::Regex;
+use std::{error::Error, path::Path, time::Duration};
+use thiserror::Error;
+use tokio::{
+    sync::broadcast::{self, RecvError},
+    time::{timeout, Instant},
+};
+
+#[derive(Error, Debug)]
+pub enum TestError {
+    #[error("Invalid app name {:?}, pass one with --name", .0)]
+    InvalidName(String),
+
+    #[error("{} of {} tests failed", .failed, .total)]
+    Failed { failed: usize, total: usize },
+
+    #[error("The tests didn't finish within {:?}", .0)]
+    Timeout(Duration),
+
+    #[error("The connection to the badge was closed")]
+    Closed,
+}
+
+struct Patterns {
+    plan: Regex,
+    unittest_result: Regex,
+    ran: Regex,
+    counts: Regex,
+}
+
+impl Patterns {
+    fn new() -> Patterns {
+        Patterns {
+            plan: Regex::new(r"^1\.\.(\d+)").unwrap(),
+            unittest_result: Regex::new(r"^(.+) \.\.\. (ok|FAIL|ERROR)").unwrap(),
+            ran: Regex::new(r"^Ran (\d+) tests?").unwrap(),
+            counts: Regex::new(r"(failures|errors)=(\d+)").unwrap(),
+        }
+    }
+}
+
+/// The results reported so far
+struct Results {
+    patterns: Patterns,
+    /// Whether each test passed, and its name
+    tests: Vec<(bool, String)>,
+    /// The number of tests announced by a TAP plan
+    plan: Option<usize>,
+    /// The number of tests according to unittest's summary
+    ran: Option<usize>,
+    /// The number of failures and errors according to unittest, once it finished
+    unittest_failed: Option<usize>,
+    /// A traceback that is being printed, if the app crashed
+    traceback: Option<String>,
+}
+
+impl Results {
+    fn new() -> Results {
+        Results {
+            patterns: Patterns::new(),
+            tests: Vec::new(),
+            plan: None,
+            ran: None,
+            unittest_failed: None,
+            traceback: None,
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        let line = line.trim();
+        if let Some(traceback) = &mut self.traceback {
+            traceback.push('\n');
+            traceback.push_str(line);
+            return;
+        }
+
+        if line.starts_with(run::TRACEBACK) {
+            self.traceback = Some(line.to_owned());
+        } else if let Some(rest) = line.strip_prefix("not ok") {
+            self.tests.push((tap_excused(rest), tap_name(rest)));
+        } else if let Some(rest) = line.strip_prefix("ok") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                self.tests.push((true, tap_name(rest)));
+            }
+        } else if let Some(captures) = self.patterns.plan.captures(line) {
+            self.plan = captures[1].parse().ok();
+        } else if let Some(captures) = self.patterns.unittest_result.captures(line) {
+            self.tests
+                .push((&captures[2] == "ok", captures[1].to_owned()));
+        } else if let Some(captures) = self.patterns.ran.captures(line) {
+            self.ran = captures[1].parse().ok();
+        } else if self.ran.is_some() && (line == "OK" || line.starts_with("OK ")) {
+            self.unittest_failed = Some(0);
+        } else if self.ran.is_some() && line.starts_with("FAILED") {
+            let failed = self
+                .patterns
+                .counts
+                .captures_iter(line)
+                .filter_map(|captures| captures[2].parse::<usize>().ok())
+                .sum::<usize>();
+            // Some versions of unittest don't print the counts
+            self.unittest_failed = Some(failed.max(1));
+        }
+    }
+
+    /// The number of failed tests and the total, once all results have been reported
+    fn summary(&self) -> Option<(usize, usize)> {
+        if let (Some(failed), Some(ran)) = (self.unittest_failed, self.ran) {
+            return Some((failed, ran));
+        }
+
+        match self.plan {
+            Some(plan) if self.tests.len() >= plan => Some((
+                self.tests.iter().filter(|(passed, _)| !passed).count(),
+                self.tests.len(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The name of a TAP result: `ok 1 - name # comment` becomes `name`
+fn tap_name(rest: &str) -> String {
+    let rest = rest
+        .trim_start()
+        .trim_start_matches(|c: char| c.is_ascii_digit());
+    let rest = rest.split(" # ").next().unwrap_or_default();
+    rest.trim_start_matches(&[' ', '-'][..]).trim().to_owned()
+}
+
+/// Whether a TAP result is marked `# TODO` or `# SKIP`, in which case failing doesn't count
+fn tap_excused(rest: &str) -> bool {
+    let directive = match rest.split_once(" # ") {
+        Some((_, directive)) => directive.trim_start().to_ascii_uppercase(),
+        None => return false,
+    };
+    directive.starts_with("TODO") || directive.starts_with("SKIP")
+}
+
+/// Reads the output of the badge until the results are complete, printing it unless `output` is
+/// porcelain
+async fn collect(
+    badge_output: &mut broadcast::Receiver<String>,
+    limit: Duration,
+    output: Output,
+) -> Result<Results, Box<dyn Error + Send + Sync>> {
+    let deadline = Instant::now() + limit;
+    let mut renderer = Renderer::new();
+    let mut results = Results::new();
+    let mut line = String::new();
+    while results.summary().is_none() {
+        // Once a traceback starts, only wait for the rest of it
+        let wait = if results.traceback.is_some() {
+            run::TRACEBACK_IDLE
+        } else {
+            deadline.saturating_duration_since(Instant::now())
+        };
+
+        let text = match timeout(wait, badge_output.recv()).await {
+            Ok(Ok(text)) => text,
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) => Err(TestError::Closed)?,
+            Err(_) => match results.traceback.take() {
+                Some(traceback) => Err(RunError::Failed(traceback))?,
+                None => Err(TestError::Timeout(limit))?,
+            },
+        };
+        if output == Output::Human {
+            renderer.write(&text)?;
+        }
+
+        for c in strip_escapes(&text).chars() {
+            match c {
+                '\n' => {
+                    results.line(&line);
+                    line.clear();
+                }
+                '\r' => {}
+                c => line.push(c),
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// Uploads the app in `local` to `/flash/apps/<name>`, runs it and waits up to `limit` for its
+/// test results. Fails unless all tests passed.
+pub async fn test(
+    badge: &Badge,
+    cache: &MetadataCache,
+    local: &Path,
+    name: Option<String>,
+    limit: Duration,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let name = match name {
+        Some(name) => name,
+        None => local
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    if name.is_empty() || name.contains('/') {
+        Err(TestError::InvalidName(name.clone()))?;
+    }
+    let remote = BadgePath::new("/flash/apps")?.join(&name)?;
+
+    let mut rules = ExcludeRules::new();
+    rules.add_file(&local.join(ignore::IGNORE_FILE))?;
+    push::push(
+        badge, cache, local, &remote, &rules, false, None, false, None, output,
+    )
+    .await?;
+
+    let mut badge_output = badge.subscribe();
+    run::run(badge, &remote, &[]).await?;
+    let results = collect(&mut badge_output, limit, output).await?;
+
+    for (passed, name) in results.tests.iter() {
+        output.test_result(*passed, name);
+    }
+
+    let (failed, total) = results.summary().unwrap();
+    if failed > 0 {
+        Err(TestError::Failed { failed, total })?;
+    }
+
+    if output == Output::Human {
+        println!("All {} tests passed", total);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(lines: &[&str]) -> Results {
+        let mut results = Results::new();
+        for line in lines {
+            results.line(line);
+        }
+        results
+    }
+
+    #[test]
+    fn tap_names() {
+        let cases = [
+            (" 1 - adds", "adds"),
+            (" 2 adds", "adds"),
+            (" 3 - adds # TODO not yet", "adds"),
+            (" 4", ""),
+            ("", ""),
+        ];
+        for (rest, expected) in cases.iter() {
+            assert_eq!(tap_name(rest), *expected, "{:?}", rest);
+        }
+    }
+
+    #[test]
+    fn tap_with_a_plan() {
+        let mut results = results(&["1..3", "ok 1 - adds", "not ok 2 - subtracts"]);
+        assert_eq!(results.summary(), None);
+        results.line("ok 3 - multiplies\r");
+        assert_eq!(results.summary(), Some((1, 3)));
+        assert_eq!(
+            results.tests,
+            [
+                (true, "adds".to_owned()),
+                (false, "subtracts".to_owned()),
+                (true, "multiplies".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_with_the_plan_last() {
+        let results = results(&["ok 1 - adds", "not ok 2 - subtracts", "1..2"]);
+        assert_eq!(results.summary(), Some((1, 2)));
+    }
+
+    #[test]
+    fn tap_without_a_plan_never_finishes() {
+        let mut results = results(&["ok 1 - adds", "not ok 2 - subtracts"]);
+        for _ in 0..10 {
+            results.line("ok");
+        }
+        assert_eq!(results.summary(), None);
+        assert_eq!(results.tests.len(), 12);
+    }
+
+    #[test]
+    fn tap_todo_and_skip_are_not_failures() {
+        let results = results(&[
+            "1..4",
+            "not ok 1 - flies # TODO later",
+            "not ok 2 - swims # skip no water",
+            "ok 3 - walks # SKIP",
+            "not ok 4 - runs # because",
+        ]);
+        assert_eq!(results.summary(), Some((1, 4)));
+    }
+
+    #[test]
+    fn ignores_other_output() {
+        let results = results(&["okay then", "1..1", "Running", "ok 1"]);
+        assert_eq!(results.tests, [(true, String::new())]);
+        assert_eq!(results.summary(), Some((0, 1)));
+    }
+
+    #[test]
+    fn unittest_ok() {
+        let mut results = results(&[
+            "test_add (tests.Math) ... ok",
+            "test_sub (tests.Math) ... ok",
+            "",
+            "Ran 2 tests in 0.010s",
+            "",
+        ]);
+        assert_eq!(results.summary(), None);
+        results.line("OK");
+        assert_eq!(results.summary(), Some((0, 2)));
+        assert_eq!(results.tests[1], (true, "test_sub (tests.Math)".to_owned()));
+    }
+
+    #[test]
+    fn unittest_failed() {
+        let results = results(&[
+            "test_add (tests.Math) ... FAIL",
+            "test_sub (tests.Math) ... ERROR",
+            "test_mul (tests.Math) ... ERROR",
+            "test_div (tests.Math) ... ok",
+            "Ran 4 tests",
+            "FAILED (failures=1, errors=2)",
+        ]);
+        assert_eq!(results.summary(), Some((3, 4)));
+        assert_eq!(
+            results.tests.iter().filter(|(passed, _)| !passed).count(),
+            3
+        );
+    }
+
+    #[test]
+    fn unittest_failed_without_counts() {
+        let results = results(&["Ran 1 test", "FAILED"]);
+        assert_eq!(results.summary(), Some((1, 1)));
+    }
+
+    #[test]
+    fn ok_before_unittest_finished_is_ignored() {
+        let results = results(&["OK", "FAILED (errors=1)"]);
+        assert_eq!(results.summary(), None);
+    }
+
+    #[test]
+    fn traceback_mid_run() {
+        let results = results(&[
+            "1..2",
+            "ok 1 - adds",
+            "Traceback (most recent call last):",
+            "  File \"tests.py\", line 3, in <module>",
+            "ZeroDivisionError: divide by zero",
+            "ok 2 - after the crash",
+        ]);
+        assert_eq!(results.summary(), None);
+        assert_eq!(results.tests.len(), 1);
+        assert_eq!(
+            results.traceback.as_deref(),
+            Some(
+                "Traceback (most recent call last):\n\
+                 File \"tests.py\", line 3, in <module>\n\
+                 ZeroDivisionError: divide by zero\n\
+                 ok 2 - after the crash"
+            )
+        );
+    }
+}
@@ -0,0 +1,58 @@
+/// Whether `data` looks like a text file: valid UTF-8 without NUL bytes
+pub fn is_text(data: &[u8]) -> bool {
+    !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Converts CRLF line endings to LF. Stray `\r`s elsewhere are kept.
+pub fn to_lf(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b != b'\r' || data.get(i + 1) != Some(&b'\n') {
+            result.push(b);
+        }
+    }
+
+    result
+}
+
+/// Converts LF line endings to CRLF, without doubling line endings that already are CRLF
+pub fn to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            result.push(b'\r');
+        }
+
+        result.push(b);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_lf() {
+        assert_eq!(to_lf(b"a\r\nb\r\n"), b"a\nb\n");
+        assert_eq!(to_lf(b"a\nb"), b"a\nb");
+        // Only \r directly before \n is removed
+        assert_eq!(to_lf(b"a\rb\r\r\n"), b"a\rb\r\n");
+        assert_eq!(to_lf(b"a\r"), b"a\r");
+    }
+
+    #[test]
+    fn converts_to_crlf() {
+        assert_eq!(to_crlf(b"\na\nb"), b"\r\na\r\nb");
+        assert_eq!(to_crlf(b"a\r\nb\n"), b"a\r\nb\r\n");
+        assert_eq!(to_lf(&to_crlf(b"a\nb\n")), b"a\nb\n");
+    }
+
+    #[test]
+    fn detects_text() {
+        assert!(is_text("print('é')\n".as_bytes()));
+        assert!(!is_text(b"a\0b"));
+        assert!(!is_text(b"\xff\xfe"));
+    }
+}
@@ -0,0 +1,253 @@
+//! Finds MicroPython tracebacks in the output of the badge for `shell` and `monitor`, highlights
+//! them and optionally adds the local file that each frame came from:
+//!
+//! ```text
+//! Traceback (most recent call last):
+//!   File "/flash/apps/synthesizer/__init__.py", line 12, in <module> (src/__init__.py:12)
+//! NameError: name 'buzzer' isn't defined
+//! ```
+
+use crate::{
+    listing::stdout_is_tty,
+    notify,
+    path::BadgePath,
+    render::{strip_escapes, ESC, RESET},
+    sourcemap::SourceMap,
+};
+use regex::Regex;
+
+const HEADER: &str = "Traceback (most recent call last):";
+const RED: &str = "\u{1b}[31m";
+const BOLD_RED: &str = "\u{1b}[1;31m";
+const CYAN: &str = "\u{1b}[36m";
+const DIM: &str = "\u{1b}[2m";
+
+/// The path in a frame, which is relative to `/flash` unless it is absolute
+fn frame_path(path: &str) -> Option<BadgePath> {
+    if path.starts_with('<') {
+        // <stdin> and friends
+        return None;
+    }
+
+    if path.starts_with('/') {
+        BadgePath::new(path).ok()
+    } else {
+        BadgePath::new(format!("/flash/{}", path)).ok()
+    }
+}
+
+/// Passes output through, except for lines that could be part of a traceback, which are held back
+/// until they are complete
+pub struct Tracebacks {
+    colors: bool,
+    sources: Option<SourceMap>,
+    frame: Regex,
+    in_traceback: bool,
+    /// The current line, of which the first `emitted` bytes were passed on already
+    line: String,
+    emitted: usize,
+}
+
+impl Default for Tracebacks {
+    fn default() -> Tracebacks {
+        Tracebacks::new()
+    }
+}
+
+impl Tracebacks {
+    /// Colors are used when stdout is a terminal, unless `$NO_COLOR` is set
+    pub fn new() -> Tracebacks {
+        Tracebacks {
+            colors: std::env::var_os("NO_COLOR").is_none() && stdout_is_tty(),
+            sources: None,
+            frame: Regex::new(r#"^\s+File "([^"]+)", line (\d+)"#).unwrap(),
+            in_traceback: false,
+            line: String::new(),
+            emitted: 0,
+        }
+    }
+
+    /// Adds the local file and line to frames of files that were uploaded from this computer
+    pub fn sources(mut self, sources: SourceMap) -> Tracebacks {
+        self.sources = Some(sources);
+        self
+    }
+
+    /// Whether the incomplete current line could still turn out to be part of a traceback
+    fn undecided(&self) -> bool {
+        let plain = strip_escapes(&self.line);
+        self.in_traceback || HEADER.starts_with(plain.as_str()) || plain.starts_with(HEADER)
+    }
+
+    fn paint(&self, color: &str, line: &str, suffix: &str, out: &mut String) {
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        if self.colors && !line.contains(ESC) {
+            out.push_str(color);
+            out.push_str(content);
+            out.push_str(RESET);
+        } else {
+            out.push_str(content);
+        }
+        out.push_str(suffix);
+        out.push_str(&line[content.len()..]);
+    }
+
+    /// The local file and line of a frame, like ` (src/main.py:12)`
+    fn source(&self, plain: &str) -> Option<String> {
+        let captures = self.frame.captures(plain)?;
+        let local = self
+            .sources
+            .as_ref()?
+            .local_path(&frame_path(&captures[1])?)
+            .filter(|local| local.exists())?;
+        let source = format!(" ({}:{})", local.display(), &captures[2]);
+        if self.colors {
+            Some(format!("{}{}{}", DIM, source, RESET))
+        } else {
+            Some(source)
+        }
+    }
+
+    fn format_line(&mut self, line: &str, out: &mut String) {
+        let plain = strip_escapes(line);
+        if !self.in_traceback {
+            if plain.starts_with(HEADER) {
+                self.in_traceback = true;
+                self.paint(RED, line, "", out);
+            } else {
+                out.push_str(line);
+            }
+        } else if plain.starts_with(' ') {
+            let source = self.source(&plain).unwrap_or_default();
+            self.paint(CYAN, line, &source, out);
+        } else {
+            // The exception ends the traceback
+            self.in_traceback = false;
+            notify::notify("App crashed", plain.trim_end());
+            self.paint(BOLD_RED, line, "", out);
+        }
+    }
+
+    pub fn format(&mut self, text: &str) -> String {
+        let mut out = String::new();
+        for piece in text.split_inclusive('\n') {
+            self.line.push_str(piece);
+            if self.line.ends_with('\n') {
+                let line = std::mem::take(&mut self.line);
+                if self.emitted == 0 {
+                    self.format_line(&line, &mut out);
+                } else {
+                    out.push_str(&line[self.emitted..]);
+                }
+                self.emitted = 0;
+            } else if self.emitted > 0 || !self.undecided() {
+                out.push_str(&self.line[self.emitted..]);
+                self.emitted = self.line.len();
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const TRACEBACK: &str = "Traceback (most recent call last):\r\n  File \"main.py\", line 3, in <module>\r\n  File \"/flash/apps/x/__init__.py\", line 12, in run\r\nNameError: name 'buzzer' isn't defined\r\n";
+
+    fn tracebacks(colors: bool) -> Tracebacks {
+        Tracebacks {
+            colors,
+            ..Tracebacks::new()
+        }
+    }
+
+    #[test]
+    fn frame_paths() {
+        let cases = [
+            ("main.py", Some("/flash/main.py")),
+            ("apps/x/__init__.py", Some("/flash/apps/x/__init__.py")),
+            (
+                "/flash/apps/x/__init__.py",
+                Some("/flash/apps/x/__init__.py"),
+            ),
+            ("/sdcard/x.py", Some("/sdcard/x.py")),
+            ("<stdin>", None),
+        ];
+        for (path, expected) in cases.iter() {
+            assert_eq!(
+                frame_path(path).as_ref().map(|path| path.to_string()),
+                expected.map(str::to_owned),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn paints_a_traceback() {
+        let out = tracebacks(true).format(&format!(">>> run()\r\n{}>>> ", TRACEBACK));
+        assert_eq!(
+            out,
+            ">>> run()\r\n\
+             \u{1b}[31mTraceback (most recent call last):\u{1b}[0m\r\n\
+             \u{1b}[36m  File \"main.py\", line 3, in <module>\u{1b}[0m\r\n\
+             \u{1b}[36m  File \"/flash/apps/x/__init__.py\", line 12, in run\u{1b}[0m\r\n\
+             \u{1b}[1;31mNameError: name 'buzzer' isn't defined\u{1b}[0m\r\n\
+             >>> "
+        );
+    }
+
+    #[test]
+    fn passes_text_through_without_colors() {
+        let text = format!("hello\n{}>>> ", TRACEBACK);
+        assert_eq!(tracebacks(false).format(&text), text);
+    }
+
+    #[test]
+    fn formats_a_traceback_split_across_calls() {
+        let whole = tracebacks(true).format(TRACEBACK);
+        for split in 1..TRACEBACK.len() {
+            let mut split_tracebacks = tracebacks(true);
+            let mut out = split_tracebacks.format(&TRACEBACK[..split]);
+            out.push_str(&split_tracebacks.format(&TRACEBACK[split..]));
+            assert_eq!(out, whole, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn holds_back_only_what_could_be_a_traceback() {
+        let mut tracebacks = tracebacks(true);
+        assert_eq!(tracebacks.format(">>> Trace"), ">>> Trace");
+        assert_eq!(tracebacks.format("\r\nTrace"), "\r\n");
+        assert_eq!(tracebacks.format("s of sand\n"), "Traces of sand\n");
+    }
+
+    #[test]
+    fn leaves_other_lines_starting_with_traceback_alone() {
+        let text = "Traceback printing is enabled\r\n  indented\r\nDone\r\n";
+        assert_eq!(tracebacks(true).format(text), text);
+    }
+
+    #[test]
+    fn adds_the_local_source() {
+        let local = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/traceback.rs");
+        let mut sources = SourceMap::in_memory();
+        sources.record(
+            &BadgePath::new("/flash/apps/x/__init__.py").unwrap(),
+            &local,
+        );
+
+        let out = tracebacks(false).sources(sources).format(TRACEBACK);
+        let local = local.canonicalize().unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "Traceback (most recent call last):\r\n  File \"main.py\", line 3, in <module>\r\n  File \"/flash/apps/x/__init__.py\", line 12, in run ({}:12)\r\nNameError: name 'buzzer' isn't defined\r\n",
+                local.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! `rm --trash` moves paths to `/flash/.trash/<timestamp>/` instead of deleting them, so they can be
+//! restored later. Each trashed path gets its own directory, named after the time it was deleted,
+//! with a `.origin` file that holds the path it came from.
+
+use crate::{
+    cache::MetadataCache,
+    client::{BadgeFs, ClientError},
+    cmds::FsEntry,
+    device::Badge,
+    output::Output,
+    path::BadgePath,
+};
+use std::{
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+use thiserror::Error;
+use tracing::info;
+
+const TRASH_DIR: &str = "/flash/.trash";
+const ORIGIN_FILE: &str = ".origin";
+
+#[derive(Error, Debug)]
+pub enum TrashError {
+    #[error("Only paths in /flash can be moved to the trash: {}", .0)]
+    NotOnFlash(BadgePath),
+
+    #[error("The trash can't be moved to the trash")]
+    InTrash,
+
+    #[error("Nothing in the trash is called {:?}", .0)]
+    UnknownEntry(String),
+
+    #[error("Can't restore {} because it exists. Move it away first.", .0)]
+    Exists(BadgePath),
+}
+
+#[derive(StructOpt, Clone)]
+pub enum TrashCommand {
+    #[structopt(about = "Lists the deleted paths in the trash, oldest first")]
+    List,
+
+    #[structopt(about = "Moves a path in the trash back to where it was deleted from")]
+    Restore {
+        #[structopt(help = "The name of the entry, as printed by `trash list`")]
+        entry: String,
+    },
+
+    #[structopt(about = "Permanently deletes everything in the trash")]
+    Empty,
+}
+
+/// The entries in `dir`, or nothing if it doesn't exist
+async fn children(
+    client: &BadgeFs,
+    dir: &BadgePath,
+) -> Result<Vec<FsEntry>, Box<dyn Error + Send + Sync>> {
+    let mut entries = client.read_dir(dir.clone());
+    let mut children = Vec::new();
+    loop {
+        match entries.next_entry().await {
+            Ok(Some(entry)) => children.push(entry),
+            Ok(None) => break,
+            Err(e) if e.downcast_ref::<ClientError>().is_some() => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(children)
+}
+
+/// Moves `path` to a new entry in the trash
+pub async fn trash(
+    badge: &Badge,
+    client: &BadgeFs,
+    path: &BadgePath,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let trash_dir = BadgePath::new(TRASH_DIR)?;
+    if !path.is_on_flash() || path.as_str() == "/flash" {
+        Err(TrashError::NotOnFlash(path.clone()))?
+    }
+    if *path == trash_dir || path.as_str().starts_with(&format!("{}/", TRASH_DIR)) {
+        Err(TrashError::InTrash)?
+    }
+
+    // Fails before anything is created if `path` doesn't exist
+    client.metadata(path.clone()).await?;
+
+    let existing = children(client, &trash_dir.parent().unwrap()).await?;
+    if !existing.iter().any(|entry| entry.name() == ".trash") {
+        badge.create_dir(&trash_dir).await?;
+    }
+
+    // More than one path may be deleted in the same second
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let taken = children(client, &trash_dir).await?;
+    let name = (0..)
+        .map(|i| match i {
+            0 => timestamp.to_string(),
+            i => format!("{}-{}", timestamp, i),
+        })
+        .find(|name| !taken.iter().any(|entry| entry.name() == name))
+        .unwrap();
+    let entry = trash_dir.join(&name)?;
+
+    badge.create_dir(&entry).await?;
+    badge
+        .write_file(entry.join(ORIGIN_FILE)?, path.as_str())
+        .await?;
+    badge.move_file(path, entry.join(path.file_name())?).await?;
+    info!("Moved {} to {}", path, entry);
+
+    Ok(())
+}
+
+/// Deletes `path` and everything below it
+async fn remove_tree(
+    badge: &Badge,
+    client: &BadgeFs,
+    path: &BadgePath,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Directories come before the directories below them, so deleting in reverse empties each one
+    // before it is deleted
+    let mut paths = Vec::new();
+    let mut stack = vec![path.clone()];
+    while let Some(path) = stack.pop() {
+        for entry in children(client, &path).await? {
+            match entry {
+                FsEntry::Directory(name) => stack.push(path.join(name)?),
+                FsEntry::File(name) | FsEntry::Other(_, name) => paths.push(path.join(name)?),
+            }
+        }
+        paths.push(path);
+    }
+
+    for path in paths.iter().rev() {
+        badge.delete_path(path).await?;
+    }
+
+    Ok(())
+}
+
+/// The path an entry of the trash was deleted from
+async fn origin(
+    client: &BadgeFs,
+    entry: &BadgePath,
+) -> Result<BadgePath, Box<dyn Error + Send + Sync>> {
+    let data = client.open(entry.join(ORIGIN_FILE)?).read_to_end().await?;
+    Ok(BadgePath::new(String::from_utf8_lossy(&data).trim())?)
+}
+
+/// Runs a `trash` subcommand
+pub async fn command(
+    badge: &Badge,
+    client: &BadgeFs,
+    cache: &MetadataCache,
+    command: TrashCommand,
+    output: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let trash_dir = BadgePath::new(TRASH_DIR)?;
+    match command {
+        TrashCommand::List => {
+            let mut names = children(client, &trash_dir)
+                .await?
+                .into_iter()
+                .map(|entry| entry.name().to_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                let origin = origin(client, &trash_dir.join(&name)?).await?;
+                output.trashed(&name, &origin);
+            }
+        }
+        TrashCommand::Restore { entry: name } => {
+            let entry = trash_dir.join(&name)?;
+            if !children(client, &trash_dir)
+                .await?
+                .iter()
+                .any(|other| other.name() == name)
+            {
+                Err(TrashError::UnknownEntry(name))?
+            }
+
+            let origin = origin(client, &entry).await?;
+            if client.metadata(origin.clone()).await.is_ok() {
+                Err(TrashError::Exists(origin.clone()))?
+            }
+
+            badge
+                .move_file(entry.join(origin.file_name())?, &origin)
+                .await?;
+            remove_tree(badge, client, &entry).await?;
+            cache.remove(&entry);
+        }
+        TrashCommand::Empty => {
+            for entry in children(client, &trash_dir).await? {
+                let entry = trash_dir.join(entry.name())?;
+                remove_tree(badge, client, &entry).await?;
+                cache.remove(&entry);
+            }
+        }
+    }
+
+    Ok(())
+}